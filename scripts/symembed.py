@@ -0,0 +1,88 @@
+#!/usr/bin/env python3
+# Writes the kernel's function symbols into its .ksymtab section, so that
+# backtraces can show names. Run on the kernel ELF after every build, e.g.
+#   scripts/symembed.py target/x86_64-solstice/debug/solstice
+# See src/kernel/symbols.rs for the format.
+import struct
+import subprocess
+import sys
+
+MAGIC = b"SOLSYMS\0"
+HEADER_LEN = 16
+ENTRY_LEN = 16
+# Has to match SYMTAB_SIZE in src/kernel/symbols.rs
+SYMTAB_SIZE = 256 * 1024
+
+
+def find_section(elf, name):
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+
+    def header(idx):
+        # name, type, flags, addr, offset, size
+        return struct.unpack_from("<IIQQQQ", elf, shoff + idx * shentsize)
+
+    strtab = header(shstrndx)[4]
+    for idx in range(shnum):
+        sh_name, _, _, _, offset, size = header(idx)
+        end = elf.index(b"\0", strtab + sh_name)
+        if elf[strtab + sh_name:end].decode() == name:
+            return offset, size
+
+    sys.exit("symembed: no {} section".format(name))
+
+
+def function_symbols(path):
+    out = subprocess.run(
+        ["nm", "--defined-only", "--print-size", "--numeric-sort", "--demangle", path],
+        check=True,
+        stdout=subprocess.PIPE,
+        universal_newlines=True,
+    ).stdout
+
+    for line in out.splitlines():
+        parts = line.split(" ", 3)
+        if len(parts) == 4 and parts[2] in ("T", "t") and int(parts[1], 16) > 0:
+            yield int(parts[0], 16), int(parts[1], 16), parts[3]
+
+
+def build(symbols):
+    names = bytearray()
+    entries = bytearray()
+    for start, size, name in symbols:
+        # Drop the hash from legacy Rust mangled names
+        if len(name) > 19 and name[-19:-16] == "::h":
+            name = name[:-19]
+        entries += struct.pack("<QII", start, min(size, 0xFFFFFFFF), len(names))
+        names += name.encode() + b"\0"
+
+    strings = HEADER_LEN + len(entries)
+    return MAGIC + struct.pack("<II", len(symbols), strings) + entries + names
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: symembed.py <kernel elf>")
+
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+
+    offset, size = find_section(elf, ".ksymtab")
+    if size < SYMTAB_SIZE:
+        sys.exit("symembed: .ksymtab is only {} bytes".format(size))
+
+    symbols = list(function_symbols(path))
+    table = build(symbols)
+    if len(table) > size:
+        sys.exit("symembed: table is {} bytes, raise SYMTAB_SIZE".format(len(table)))
+
+    elf[offset:offset + size] = table.ljust(size, b"\0")
+    with open(path, "wb") as f:
+        f.write(elf)
+
+    print("symembed: {} symbols, {} bytes".format(len(symbols), len(table)))
+
+
+if __name__ == "__main__":
+    main()
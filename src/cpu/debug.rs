@@ -0,0 +1,121 @@
+use crate::kernel::symbols::Symbolized;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    registers::rflags::{self, RFlags},
+    structures::idt::InterruptStackFrame,
+};
+
+// Single stepping, for tracing through suspicious code during bring-up. With
+// the trap flag set, the CPU raises a debug exception after each instruction,
+// and the handler logs where it got to.
+
+// DR6.BS, set by the CPU when a debug exception came from single stepping
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+const UNLIMITED: u64 = u64::MAX;
+
+// Steps left before the trap flag is cleared again
+static STEPS_LEFT: AtomicU64 = AtomicU64::new(0);
+
+pub fn with_trap_flag(flags: RFlags, enable: bool) -> RFlags {
+    if enable {
+        flags | RFlags::TRAP_FLAG
+    } else {
+        flags - RFlags::TRAP_FLAG
+    }
+}
+
+// Counts off a step, returning whether to keep stepping afterwards
+pub fn take_step(steps: &AtomicU64) -> bool {
+    let prev = steps.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| match n {
+        0 => None,
+        UNLIMITED => Some(UNLIMITED),
+        n => Some(n - 1),
+    });
+
+    matches!(prev, Ok(n) if n > 1)
+}
+
+fn set_trap_flag(enable: bool) {
+    unsafe { rflags::write(with_trap_flag(rflags::read(), enable)) };
+}
+
+// Steps until turned off again
+#[allow(dead_code)]
+pub fn single_step(enable: bool) {
+    STEPS_LEFT.store(if enable { UNLIMITED } else { 0 }, Ordering::Relaxed);
+    set_trap_flag(enable);
+}
+
+// Steps through the next `steps` instructions, then stops on its own
+#[allow(dead_code)]
+pub fn single_step_for(steps: u64) {
+    STEPS_LEFT.store(steps, Ordering::Relaxed);
+    set_trap_flag(steps != 0);
+}
+
+fn read_dr6() -> u64 {
+    let value;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+fn write_dr6(value: u64) {
+    unsafe { asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+
+// Called from the debug exception handler. Returns false if the exception
+// wasn't from single stepping, and should be treated as unexpected.
+pub fn handle_debug(frame: &mut InterruptStackFrame) -> bool {
+    // The CPU never clears DR6 itself
+    let dr6 = read_dr6();
+    if dr6 & DR6_SINGLE_STEP == 0 {
+        return false;
+    }
+    write_dr6(dr6 & !DR6_SINGLE_STEP);
+
+    debug!("step: {}", Symbolized(frame.instruction_pointer.as_u64()));
+
+    // The flags are restored from the frame on return, so that's where TF
+    // has to be cleared
+    if !take_step(&STEPS_LEFT) {
+        unsafe {
+            frame.as_mut().update(|frame| {
+                let flags = RFlags::from_bits_truncate(frame.cpu_flags);
+                frame.cpu_flags = with_trap_flag(flags, false).bits();
+            })
+        };
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(trap_flag_helper, {
+        let flags = RFlags::INTERRUPT_FLAG | RFlags::ZERO_FLAG;
+        assert_eq!(with_trap_flag(flags, true), flags | RFlags::TRAP_FLAG);
+        assert_eq!(with_trap_flag(flags | RFlags::TRAP_FLAG, false), flags);
+        assert_eq!(with_trap_flag(flags, false), flags);
+    });
+
+    test_case!(step_count_termination, {
+        let steps = AtomicU64::new(3);
+        assert!(take_step(&steps));
+        assert!(take_step(&steps));
+        // The third step is the last one
+        assert!(!take_step(&steps));
+        assert_eq!(steps.load(Ordering::Relaxed), 0);
+
+        // A stray step with nothing left stops too
+        assert!(!take_step(&steps));
+        assert_eq!(steps.load(Ordering::Relaxed), 0);
+
+        let steps = AtomicU64::new(UNLIMITED);
+        for _ in 0..10 {
+            assert!(take_step(&steps));
+        }
+        assert_eq!(steps.load(Ordering::Relaxed), UNLIMITED);
+    });
+}
@@ -0,0 +1,180 @@
+use crate::{
+    cpu::fpu::{Cpuid, HardwareCpuid},
+    kernel::early_panic::{RawSerial, RawVga},
+};
+use core::fmt::Write;
+
+// CPU features the kernel is built to assume. Without one of these, boot would
+// stop somewhere much later with a fault that doesn't say why.
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Ecx,
+    Edx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    pub name: &'static str,
+    leaf: u32,
+    reg: Reg,
+    bit: u32,
+}
+
+const fn feature(name: &'static str, leaf: u32, reg: Reg, bit: u32) -> Feature {
+    Feature { name, leaf, reg, bit }
+}
+
+const EXTENDED_LEAF: u32 = 0x8000_0000;
+
+pub const REQUIRED: [Feature; 11] = [
+    feature("fpu", 1, Reg::Edx, 0),
+    feature("tsc", 1, Reg::Edx, 4),
+    feature("msr", 1, Reg::Edx, 5),
+    feature("pae", 1, Reg::Edx, 6),
+    feature("cx8", 1, Reg::Edx, 8),
+    feature("pge", 1, Reg::Edx, 13),
+    feature("fxsr", 1, Reg::Edx, 24),
+    feature("sse", 1, Reg::Edx, 25),
+    feature("sse2", 1, Reg::Edx, 26),
+    feature("nx", EXTENDED_LEAF | 1, Reg::Edx, 20),
+    feature("long mode", EXTENDED_LEAF | 1, Reg::Edx, 29),
+];
+
+impl Feature {
+    // Leaves past the highest one the CPU reports aren't safe to read
+    fn present<C: Cpuid>(&self, cpuid: &C) -> bool {
+        let max_leaf = cpuid.cpuid(self.leaf & EXTENDED_LEAF, 0).eax;
+        if self.leaf > max_leaf {
+            return false;
+        }
+
+        let result = cpuid.cpuid(self.leaf, 0);
+        let reg = match self.reg {
+            Reg::Ecx => result.ecx,
+            Reg::Edx => result.edx,
+        };
+        reg & (1 << self.bit) != 0
+    }
+}
+
+pub fn missing<C: Cpuid>(cpuid: &C) -> impl Iterator<Item = &'static str> + '_ {
+    REQUIRED
+        .iter()
+        .filter(move |feature| !feature.present(cpuid))
+        .map(|feature| feature.name)
+}
+
+// Returns false if nothing's missing, without writing anything
+fn report_missing<C: Cpuid>(cpuid: &C, outputs: &mut [&mut dyn Write]) -> bool {
+    if missing(cpuid).next().is_none() {
+        return false;
+    }
+
+    for output in outputs.iter_mut() {
+        let _ = write!(output, "unsupported CPU: missing");
+        for (i, name) in missing(cpuid).enumerate() {
+            let _ = write!(output, "{} {}", if i == 0 { "" } else { "," }, name);
+        }
+        let _ = writeln!(output);
+    }
+
+    true
+}
+
+// Has to run before anything that uses the console, since it draws straight
+// to the screen. Halts for good if a feature is missing.
+pub fn require_features() {
+    // Safety: nothing else is using the VGA buffer yet
+    let mut vga = unsafe { RawVga::hardware() };
+    if report_missing(&HardwareCpuid, &mut [&mut vga, &mut RawSerial]) {
+        x86_64::instructions::interrupts::disable();
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+    use core::arch::x86_64::CpuidResult;
+
+    struct MockCpuid {
+        max_extended: u32,
+        edx_1: u32,
+        edx_ext_1: u32,
+    }
+
+    impl MockCpuid {
+        fn complete() -> Self {
+            let mut cpuid = Self {
+                max_extended: EXTENDED_LEAF | 8,
+                edx_1: 0,
+                edx_ext_1: 0,
+            };
+            for feature in REQUIRED.iter() {
+                cpuid.set(feature.name, true);
+            }
+            cpuid
+        }
+
+        fn set(&mut self, name: &str, present: bool) {
+            let feature = REQUIRED.iter().find(|feature| feature.name == name).unwrap();
+            let reg = if feature.leaf == 1 { &mut self.edx_1 } else { &mut self.edx_ext_1 };
+            if present {
+                *reg |= 1 << feature.bit;
+            } else {
+                *reg &= !(1 << feature.bit);
+            }
+        }
+    }
+
+    impl Cpuid for MockCpuid {
+        fn cpuid(&self, leaf: u32, _subleaf: u32) -> CpuidResult {
+            let (eax, edx) = match leaf {
+                0 => (0xD, 0),
+                1 => (0, self.edx_1),
+                EXTENDED_LEAF => (self.max_extended, 0),
+                _ if leaf == EXTENDED_LEAF | 1 => (0, self.edx_ext_1),
+                _ => (0, 0),
+            };
+
+            CpuidResult { eax, ebx: 0, ecx: 0, edx }
+        }
+    }
+
+    test_case!(required_features_present, {
+        let cpuid = MockCpuid::complete();
+        assert_eq!(missing(&cpuid).count(), 0);
+
+        let mut out = ArrayString::<[u8; 64]>::new();
+        assert!(!report_missing(&cpuid, &mut [&mut out]));
+        assert_eq!(out.as_str(), "");
+
+        // Whatever's running the tests has everything too
+        assert_eq!(missing(&HardwareCpuid).count(), 0);
+    });
+
+    test_case!(required_features_missing, {
+        let mut cpuid = MockCpuid::complete();
+        cpuid.set("sse2", false);
+        cpuid.set("nx", false);
+        let mut names = ArrayString::<[u8; 64]>::new();
+        missing(&cpuid).for_each(|name| names.push_str(name));
+        assert_eq!(names.as_str(), "sse2nx");
+
+        let mut out = ArrayString::<[u8; 64]>::new();
+        assert!(report_missing(&cpuid, &mut [&mut out]));
+        assert_eq!(out.as_str(), "unsupported CPU: missing sse2, nx\n");
+
+        // No extended leaves at all, so nothing in them can be trusted
+        let mut cpuid = MockCpuid::complete();
+        cpuid.max_extended = EXTENDED_LEAF;
+        let mut names = ArrayString::<[u8; 64]>::new();
+        missing(&cpuid).for_each(|name| names.push_str(name));
+        assert_eq!(names.as_str(), "nxlong mode");
+    });
+}
@@ -0,0 +1,232 @@
+use crate::ds::InitCell;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::{
+    arch::x86_64::{CpuidResult, __cpuid_count},
+    ptr::NonNull,
+};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// x87/SSE state, plus AVX where the CPU has XSAVE. The save area's size and
+// layout depend on what's enabled in XCR0, so it's only known once init() has
+// probed the CPU.
+
+pub const XCR0_X87: u64 = 1 << 0;
+pub const XCR0_SSE: u64 = 1 << 1;
+pub const XCR0_AVX: u64 = 1 << 2;
+// The state components we're prepared to enable
+const XCR0_KNOWN: u64 = XCR0_X87 | XCR0_SSE | XCR0_AVX;
+
+const CPUID_XSAVE: u32 = 1 << 26;
+const XSAVE_LEAF: u32 = 0xD;
+
+const FXSAVE_SIZE: usize = 512;
+// The legacy FXSAVE region plus the XSAVE header
+const XSAVE_BASE_SIZE: usize = FXSAVE_SIZE + 64;
+const SAVE_AREA_ALIGN: usize = 64;
+
+// Lets tests make up CPUID values
+pub trait Cpuid {
+    fn cpuid(&self, leaf: u32, subleaf: u32) -> CpuidResult;
+}
+
+pub struct HardwareCpuid;
+
+impl Cpuid for HardwareCpuid {
+    fn cpuid(&self, leaf: u32, subleaf: u32) -> CpuidResult {
+        unsafe { __cpuid_count(leaf, subleaf) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveMethod {
+    Fxsave,
+    Xsave { xcr0: u64, size: usize },
+}
+
+impl SaveMethod {
+    pub fn size(&self) -> usize {
+        match *self {
+            SaveMethod::Fxsave => FXSAVE_SIZE,
+            SaveMethod::Xsave { size, .. } => size,
+        }
+    }
+}
+
+// x87 state can't be disabled, and AVX state can't be enabled without SSE
+pub fn xcr0_mask(supported: u64) -> u64 {
+    let mask = supported & XCR0_KNOWN | XCR0_X87;
+    if mask & XCR0_SSE == 0 {
+        mask & !XCR0_AVX
+    } else {
+        mask
+    }
+}
+
+// Subleaf n of leaf 0xD gives the size (EAX) and offset (EBX) of component n in
+// the standard format. Components 0 and 1 live in the legacy region.
+pub fn save_area_size<C: Cpuid>(cpuid: &C, xcr0: u64) -> usize {
+    (2..64)
+        .filter(|bit| xcr0 & 1 << bit != 0)
+        .map(|bit| {
+            let leaf = cpuid.cpuid(XSAVE_LEAF, bit);
+            (leaf.ebx + leaf.eax) as usize
+        })
+        .fold(XSAVE_BASE_SIZE, usize::max)
+}
+
+pub fn probe<C: Cpuid>(cpuid: &C) -> SaveMethod {
+    if cpuid.cpuid(0, 0).eax < XSAVE_LEAF || cpuid.cpuid(1, 0).ecx & CPUID_XSAVE == 0 {
+        return SaveMethod::Fxsave;
+    }
+
+    let leaf = cpuid.cpuid(XSAVE_LEAF, 0);
+    let xcr0 = xcr0_mask((leaf.edx as u64) << 32 | leaf.eax as u64);
+    SaveMethod::Xsave {
+        xcr0,
+        size: save_area_size(cpuid, xcr0),
+    }
+}
+
+static METHOD: InitCell<SaveMethod> = InitCell::new();
+
+fn method() -> SaveMethod {
+    *METHOD.try_get().expect("fpu: init() hasn't run")
+}
+
+unsafe fn xsetbv(xcr: u32, value: u64) {
+    asm!(
+        "xsetbv",
+        in("ecx") xcr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nomem, nostack, preserves_flags)
+    );
+}
+
+pub fn init() {
+    let method = probe(&HardwareCpuid);
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+
+        if let SaveMethod::Xsave { xcr0, .. } = method {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+            xsetbv(0, xcr0);
+        }
+    }
+
+    METHOD.init(method);
+    match method {
+        SaveMethod::Fxsave => info!("fpu: using fxsave"),
+        SaveMethod::Xsave { xcr0, size } => info!("fpu: using xsave, xcr0={:#x}, {} bytes", xcr0, size),
+    }
+}
+
+// Somewhere to keep a context's FPU registers while it isn't running
+pub struct FpuState {
+    area: NonNull<u8>,
+    layout: Layout,
+}
+
+#[allow(dead_code)]
+impl FpuState {
+    pub fn new() -> Self {
+        let layout = Layout::from_size_align(method().size(), SAVE_AREA_ALIGN).unwrap();
+        // XRSTOR faults on a garbage header, and an all-zero one is valid
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) }).expect("fpu: failed to allocate save area");
+
+        Self { area, layout }
+    }
+
+    pub fn save(&mut self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            match method() {
+                SaveMethod::Fxsave => asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags)),
+                SaveMethod::Xsave { xcr0, .. } => asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") xcr0 as u32,
+                    in("edx") (xcr0 >> 32) as u32,
+                    options(nostack, preserves_flags)
+                ),
+            }
+        }
+    }
+
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            match method() {
+                SaveMethod::Fxsave => asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags, readonly)),
+                SaveMethod::Xsave { xcr0, .. } => asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") xcr0 as u32,
+                    in("edx") (xcr0 >> 32) as u32,
+                    options(nostack, preserves_flags, readonly)
+                ),
+            }
+        }
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Leaf 0xD as reported by a CPU with AVX and AVX-512, subleaves 0 to 7
+    struct MockCpuid {
+        xsave: bool,
+    }
+
+    impl Cpuid for MockCpuid {
+        fn cpuid(&self, leaf: u32, subleaf: u32) -> CpuidResult {
+            let (eax, ebx, ecx, edx) = match (leaf, subleaf) {
+                (0, _) => (0xD, 0, 0, 0),
+                (1, _) => (0, 0, if self.xsave { CPUID_XSAVE } else { 0 }, 0),
+                (0xD, 0) => (0xE7, 0x240, 0xA80, 0),
+                (0xD, 2) => (256, 576, 0, 0),
+                (0xD, 5) => (64, 1088, 0, 0),
+                (0xD, 6) => (512, 1152, 0, 0),
+                (0xD, 7) => (1024, 1664, 0, 0),
+                _ => (0, 0, 0, 0),
+            };
+
+            CpuidResult { eax, ebx, ecx, edx }
+        }
+    }
+
+    test_case!(xsave_probe, {
+        // AVX-512 and anything else we don't know about is left off
+        assert_eq!(xcr0_mask(0xE7), XCR0_X87 | XCR0_SSE | XCR0_AVX);
+        assert_eq!(xcr0_mask(XCR0_X87 | XCR0_AVX), XCR0_X87);
+        assert_eq!(xcr0_mask(0), XCR0_X87);
+
+        let cpuid = MockCpuid { xsave: true };
+        assert_eq!(save_area_size(&cpuid, XCR0_X87 | XCR0_SSE), XSAVE_BASE_SIZE);
+        assert_eq!(save_area_size(&cpuid, XCR0_X87 | XCR0_SSE | XCR0_AVX), 832);
+        // Matches the size leaf 0xD reports for everything
+        assert_eq!(save_area_size(&cpuid, 0xE7), 0xA80);
+
+        assert_eq!(
+            probe(&cpuid),
+            SaveMethod::Xsave {
+                xcr0: XCR0_X87 | XCR0_SSE | XCR0_AVX,
+                size: 832
+            }
+        );
+        assert_eq!(probe(&MockCpuid { xsave: false }), SaveMethod::Fxsave);
+        assert_eq!(SaveMethod::Fxsave.size(), 512);
+    });
+}
@@ -2,26 +2,48 @@ use lazy_static::lazy_static;
 use x86_64::{
     instructions::tables::load_tss,
     structures::{
-        gdt::{Descriptor, GlobalDescriptorTable},
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
     },
+    PrivilegeLevel,
     VirtAddr,
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// SYSENTER takes the kernel's SS from the entry after its CS, and SYSEXIT the
+// user CS and SS from 32 and 40 bytes after it, so the TSS goes in the gap
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring0);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring3);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(6, PrivilegeLevel::Ring3);
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+// The bottom and top of the stack the double fault handler runs on
+#[allow(dead_code)]
+pub fn double_fault_stack() -> (VirtAddr, VirtAddr) {
+    let start = VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK });
+    (start, start + DOUBLE_FAULT_STACK_SIZE)
+}
+
+// Where interrupts from ring 3 land, since the CPU switches to the TSS's RSP0
+// for them. User code only ever runs one task at a time for now, so one will
+// do until tasks get their own.
+const RING0_STACK_SIZE: usize = 4096 * 4;
+static mut RING0_STACK: [u8; RING0_STACK_SIZE] = [0; RING0_STACK_SIZE];
 
-            stack_start + STACK_SIZE
-        };
+pub fn ring0_stack() -> (VirtAddr, VirtAddr) {
+    let start = VirtAddr::from_ptr(unsafe { &RING0_STACK });
+    (start, (start + RING0_STACK_SIZE).align_down(16u64))
+}
 
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack().1;
+        tss.privilege_stack_table[0] = ring0_stack().1;
         tss
     };
 }
@@ -29,12 +51,18 @@ lazy_static! {
 lazy_static! {
     static ref GDT: GlobalDescriptorTable = {
         let mut gdt = GlobalDescriptorTable::new();
-
-        // Kernel code segment
-        gdt.add_entry(Descriptor::kernel_code_segment());
-
-        // TSS segment
-        gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let selectors = [
+            gdt.add_entry(Descriptor::kernel_code_segment()),
+            gdt.add_entry(Descriptor::kernel_data_segment()),
+            // Takes two entries
+            gdt.add_entry(Descriptor::tss_segment(&TSS)),
+            gdt.add_entry(Descriptor::user_code_segment()),
+            gdt.add_entry(Descriptor::user_data_segment()),
+        ];
+        assert_eq!(
+            selectors,
+            [KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, TSS_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR]
+        );
 
         gdt
     };
@@ -44,15 +72,11 @@ pub fn load() {
     GDT.load();
 
     unsafe {
-        use x86_64::{
-            instructions::segmentation as seg,
-            structures::gdt::SegmentSelector,
-            PrivilegeLevel,
-        };
+        use x86_64::instructions::segmentation as seg;
 
         let null_segment = SegmentSelector::new(0, PrivilegeLevel::Ring0);
-        let code_segment = SegmentSelector::new(1, PrivilegeLevel::Ring0);
-        let tss_segment = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+        let code_segment = KERNEL_CODE_SELECTOR;
+        let tss_segment = TSS_SELECTOR;
         seg::load_ds(null_segment);
         seg::load_es(SegmentSelector::new(0, PrivilegeLevel::Ring0));
         seg::load_fs(SegmentSelector::new(0, PrivilegeLevel::Ring0));
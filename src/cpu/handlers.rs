@@ -0,0 +1,252 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{
+    structures::{
+        gdt::SegmentSelector,
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    },
+    PrivilegeLevel, VirtAddr,
+};
+
+// Handlers installed at runtime, for drivers that don't get their own IDT entry.
+// Some exceptions push an error code and nothing else does, and a handler that
+// gets that wrong reads the wrong stack slots, so each kind has its own
+// registration function, and registering the wrong kind for a vector fails.
+//
+// Device interrupts get the vectors in DYNAMIC_VECTORS. The exceptions in
+// HOOKABLE_EXCEPTIONS can be hooked too, to handle what the kernel would
+// otherwise panic on.
+
+pub type Handler = fn(&InterruptStackFrame);
+pub type HandlerWithCode = fn(&InterruptStackFrame, u64);
+// Gets the faulting address too. It has to deal with the fault one way or
+// another, by fixing up the mapping or by killing the process.
+pub type UserFaultHandler = fn(&mut InterruptStackFrame, VirtAddr, PageFaultErrorCode);
+
+pub const DYNAMIC_VECTORS: core::ops::Range<u8> = 0x30..0x40;
+// Not double fault or machine check, which can't return, nor the ones the
+// kernel always deals with itself. A hook only runs once the kernel has passed
+// on the exception, e.g. a page fault that wasn't demand paging.
+const HOOKABLE_EXCEPTIONS: &[u8] = &[5, 6, 7, 10, 11, 12, 13, 14, 16, 17, 19, 20, 30];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    // Not a vector that can be registered at all
+    Reserved,
+    // The vector pushes an error code and the handler doesn't take one, or
+    // the other way round
+    WrongKind,
+    InUse,
+}
+
+// Whether the CPU pushes an error code for the vector. Only exceptions do, and
+// only some of them.
+pub fn pushes_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+fn registerable(vector: u8) -> bool {
+    DYNAMIC_VECTORS.contains(&vector) || HOOKABLE_EXCEPTIONS.contains(&vector)
+}
+
+// Function pointers, or 0 for none. Which type each one is follows from the
+// vector.
+static HANDLERS: [AtomicUsize; 256] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; 256]
+};
+
+fn register(vector: u8, with_code: bool, handler: usize) -> Result<(), RegisterError> {
+    if !registerable(vector) {
+        return Err(RegisterError::Reserved);
+    }
+    if pushes_error_code(vector) != with_code {
+        return Err(RegisterError::WrongKind);
+    }
+
+    HANDLERS[vector as usize]
+        .compare_exchange(0, handler, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| RegisterError::InUse)
+}
+
+#[allow(dead_code)]
+pub fn register_handler(vector: u8, handler: Handler) -> Result<(), RegisterError> {
+    register(vector, false, handler as usize)
+}
+
+#[allow(dead_code)]
+pub fn register_handler_with_code(vector: u8, handler: HandlerWithCode) -> Result<(), RegisterError> {
+    register(vector, true, handler as usize)
+}
+
+// The vector goes back to its default behaviour: panicking for exceptions, and
+// being ignored for anything else
+#[allow(dead_code)]
+pub fn unregister(vector: u8) {
+    HANDLERS[vector as usize].store(0, Ordering::Release);
+}
+
+// Called by the trampolines. Each returns whether there was a handler to run.
+pub fn dispatch(vector: u8, frame: &InterruptStackFrame) -> bool {
+    BUG_ON!(pushes_error_code(vector), "handlers: vector {} dispatched without its error code", vector);
+    match HANDLERS[vector as usize].load(Ordering::Acquire) {
+        0 => false,
+        handler => {
+            let handler: Handler = unsafe { core::mem::transmute(handler) };
+            handler(frame);
+            true
+        }
+    }
+}
+
+pub fn dispatch_with_code(vector: u8, frame: &InterruptStackFrame, error_code: u64) -> bool {
+    BUG_ON!(!pushes_error_code(vector), "handlers: vector {} dispatched with an error code", vector);
+    match HANDLERS[vector as usize].load(Ordering::Acquire) {
+        0 => false,
+        handler => {
+            let handler: HandlerWithCode = unsafe { core::mem::transmute(handler) };
+            handler(frame, error_code);
+            true
+        }
+    }
+}
+
+// Page faults from user space go to whoever runs processes, for demand paging,
+// copy on write and the like, or for delivering a segfault. Faults in the
+// kernel are bugs, and never get here.
+static USER_FAULT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(dead_code)]
+pub fn register_user_fault_handler(handler: UserFaultHandler) -> Result<(), RegisterError> {
+    USER_FAULT_HANDLER
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| RegisterError::InUse)
+}
+
+#[allow(dead_code)]
+pub fn unregister_user_fault_handler() {
+    USER_FAULT_HANDLER.store(0, Ordering::Release);
+}
+
+// The handler for a page fault taken with `code_segment` loaded, if the fault
+// came from ring 3 and there is one
+pub fn user_fault_handler(code_segment: u64) -> Option<UserFaultHandler> {
+    if SegmentSelector(code_segment as u16).rpl() != PrivilegeLevel::Ring3 {
+        return None;
+    }
+    match USER_FAULT_HANDLER.load(Ordering::Acquire) {
+        0 => None,
+        handler => Some(unsafe { core::mem::transmute::<usize, UserFaultHandler>(handler) }),
+    }
+}
+
+extern "x86-interrupt" fn dynamic_trampoline<const VECTOR: u8>(frame: InterruptStackFrame) {
+    let _irq = crate::cpu::irq_frames::enter(&frame);
+    if !dispatch(VECTOR, &frame) {
+        warn!("handlers: unhandled interrupt on vector {:#x}", VECTOR);
+    }
+}
+
+macro_rules! install_dynamic {
+    ($idt:expr, $($vector:literal),*) => {
+        $( $idt[$vector].set_handler_fn(dynamic_trampoline::<$vector>); )*
+    };
+}
+
+// The exceptions call dispatch() themselves, from their own handlers
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    install_dynamic!(
+        idt, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicU64;
+    use x86_64::{structures::idt::InterruptStackFrameValue, VirtAddr};
+
+    static PLAIN_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static LAST_CODE: AtomicU64 = AtomicU64::new(0);
+
+    fn plain(_frame: &InterruptStackFrame) {
+        PLAIN_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn with_code(_frame: &InterruptStackFrame, error_code: u64) {
+        LAST_CODE.store(error_code, Ordering::Relaxed);
+    }
+
+    test_case!(handler_kinds, {
+        // Each kind only fits vectors that push, or don't push, an error code
+        assert_eq!(register_handler(13, plain), Err(RegisterError::WrongKind));
+        assert_eq!(register_handler_with_code(0x31, with_code), Err(RegisterError::WrongKind));
+        assert_eq!(register_handler_with_code(8, with_code), Err(RegisterError::Reserved));
+        assert_eq!(register_handler(0x80, plain), Err(RegisterError::Reserved));
+
+        register_handler_with_code(17, with_code).unwrap();
+        assert_eq!(register_handler_with_code(17, with_code), Err(RegisterError::InUse));
+        register_handler(0x31, plain).unwrap();
+
+        // The error code is passed through as it was pushed
+        let value = InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(0x1000),
+            code_segment: 0x08,
+            cpu_flags: 0x2,
+            stack_pointer: VirtAddr::new(0x2000),
+            stack_segment: 0,
+        };
+        // InterruptStackFrame is a repr(C) wrapper around the value
+        let frame = unsafe { &*(&value as *const InterruptStackFrameValue as *const InterruptStackFrame) };
+        assert!(dispatch_with_code(17, frame, 0xC0DE));
+        assert_eq!(LAST_CODE.load(Ordering::Relaxed), 0xC0DE);
+        assert!(!dispatch_with_code(13, frame, 0xBAD));
+        assert_eq!(LAST_CODE.load(Ordering::Relaxed), 0xC0DE);
+
+        // Through the real trampoline, which has no code to pass on
+        let calls = PLAIN_CALLS.load(Ordering::Relaxed);
+        unsafe { asm!("int 0x31") };
+        assert_eq!(PLAIN_CALLS.load(Ordering::Relaxed), calls + 1);
+        assert_eq!(LAST_CODE.load(Ordering::Relaxed), 0xC0DE);
+
+        unregister(0x31);
+        unregister(17);
+        assert!(!dispatch(0x31, frame));
+        assert!(!dispatch_with_code(17, frame, 0));
+    });
+
+    static USER_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn user_fault(_frame: &mut InterruptStackFrame, _addr: VirtAddr, _error_code: PageFaultErrorCode) {
+        USER_FAULTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    test_case!(user_fault_delegation, {
+        // Kernel and user code selectors, going by their RPL alone
+        let (kernel_cs, user_cs) = (0x08, 0x1B);
+        assert!(user_fault_handler(user_cs).is_none());
+
+        register_user_fault_handler(user_fault).unwrap();
+        assert_eq!(register_user_fault_handler(user_fault), Err(RegisterError::InUse));
+
+        // Kernel faults are never handed over, whatever's registered
+        assert!(user_fault_handler(kernel_cs).is_none());
+        assert!(user_fault_handler(0x10).is_none());
+
+        let mut value = InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(0x40_0000),
+            code_segment: user_cs,
+            cpu_flags: 0x202,
+            stack_pointer: VirtAddr::new(0x7FFF_0000),
+            stack_segment: 0x23,
+        };
+        let frame = unsafe { &mut *(&mut value as *mut InterruptStackFrameValue as *mut InterruptStackFrame) };
+        let handler = user_fault_handler(user_cs).unwrap();
+        handler(frame, VirtAddr::new(0x1234), PageFaultErrorCode::USER_MODE);
+        assert_eq!(USER_FAULTS.load(Ordering::Relaxed), 1);
+
+        unregister_user_fault_handler();
+        assert!(user_fault_handler(user_cs).is_none());
+    });
+}
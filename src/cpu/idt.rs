@@ -2,7 +2,19 @@
 use lazy_static::lazy_static;
 use x86_64::structures::idt;
 use x86_64::registers::control::Cr2;
+use x86_64::VirtAddr;
+use x86_64::PrivilegeLevel;
+use x86_64::structures::gdt::SegmentSelector;
 use crate::cpu::gdt::DOUBLE_FAULT_IST_INDEX;
+use crate::cpu::irq_frames;
+use crate::kernel::sched;
+use crate::cpu::handlers;
+use crate::ds::Counter;
+use crate::cpu::pic8259::{self, MASTER_OFFSET, SLAVE_OFFSET};
+use crate::mm::addr_space::{walk_active, AddrSpace, WalkEnd};
+use crate::mm::kstack;
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 lazy_static! {
     static ref IDT: idt::InterruptDescriptorTable = {
@@ -27,10 +39,98 @@ lazy_static! {
         idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
         idt.virtualization.set_handler_fn(virtualization_handler);
         idt.security_exception.set_handler_fn(security_exception_handler);
+        idt[(MASTER_OFFSET + pic8259::TIMER_IRQ) as usize].set_handler_fn(timer_handler);
+        idt[(MASTER_OFFSET + 7) as usize].set_handler_fn(pic_irq7_handler);
+        idt[(SLAVE_OFFSET + 7) as usize].set_handler_fn(pic_irq15_handler);
+        idt[APIC_SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious_handler);
+        idt[APIC_ERROR_VECTOR as usize].set_handler_fn(apic_error_handler);
+        crate::cpu::syscall::install(&mut idt);
+        handlers::install(&mut idt);
         idt
     };
 }
 
+// The local APIC's spurious vector register points here out of reset
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xFF;
+// For lapic::enable_error_interrupt()
+pub const APIC_ERROR_VECTOR: u8 = 0xFE;
+
+static SPURIOUS_INTERRUPTS: Counter = Counter::new();
+
+#[allow(dead_code)]
+pub fn spurious_interrupts() -> u64 {
+    SPURIOUS_INTERRUPTS.get()
+}
+
+// Exceptions seen since boot, by vector. Each handler counts itself before
+// anything else, so even the fatal ones show up.
+const EXCEPTION_VECTORS: usize = 32;
+
+static EXCEPTION_COUNTS: [Counter; EXCEPTION_VECTORS] = {
+    const ZERO: Counter = Counter::new();
+    [ZERO; EXCEPTION_VECTORS]
+};
+
+pub const EXCEPTION_NAMES: [&str; EXCEPTION_VECTORS] = [
+    "divide error", "debug", "nmi", "breakpoint", "overflow", "bound range exceeded", "invalid opcode", "device not available",
+    "double fault", "coprocessor segment overrun", "invalid tss", "segment not present", "stack segment fault", "general protection fault", "page fault", "reserved",
+    "x87 floating point", "alignment check", "machine check", "simd floating point", "virtualization", "control protection", "reserved", "reserved",
+    "reserved", "reserved", "reserved", "reserved", "hypervisor injection", "vmm communication", "security exception", "reserved",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionStats {
+    counts: [u64; EXCEPTION_VECTORS],
+}
+
+#[allow(dead_code)]
+impl ExceptionStats {
+    pub fn get(&self, vector: u8) -> u64 {
+        self.counts[vector as usize]
+    }
+
+    // The name and count of each exception that's happened at least once
+    pub fn seen(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        EXCEPTION_NAMES.iter().zip(self.counts.iter()).filter(|(_, &count)| count != 0).map(|(&name, &count)| (name, count))
+    }
+}
+
+pub fn exception_stats() -> ExceptionStats {
+    let mut counts = [0; EXCEPTION_VECTORS];
+    for (count, counter) in counts.iter_mut().zip(EXCEPTION_COUNTS.iter()) {
+        *count = counter.get();
+    }
+
+    ExceptionStats { counts }
+}
+
+fn count_exception(vector: u8) {
+    EXCEPTION_COUNTS[vector as usize].inc();
+}
+
+// Lets a test see a double fault it caused on purpose. The handler still
+// panics if the hook returns, so a hook that's happy has to end the run itself.
+#[cfg(test)]
+pub type DoubleFaultHook = fn(&idt::InterruptStackFrame, u64);
+
+#[cfg(test)]
+static DOUBLE_FAULT_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub fn set_double_fault_hook(hook: Option<DoubleFaultHook>) {
+    DOUBLE_FAULT_HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::SeqCst);
+}
+
+#[cfg(test)]
+fn run_double_fault_hook(frame: &idt::InterruptStackFrame, error_code: u64) {
+    let hook = DOUBLE_FAULT_HOOK.load(Ordering::SeqCst);
+    if hook != 0 {
+        let hook: DoubleFaultHook = unsafe { core::mem::transmute(hook) };
+        hook(frame, error_code);
+    }
+}
+
 pub fn load() {
     IDT.load();
     //debug!("idt: loaded");
@@ -40,82 +140,605 @@ test_case!(int3_handler, {
     x86_64::instructions::interrupts::int3();
 });
 
+// Runs off the end of the kernel stack into the guard page. The page fault
+// can't be delivered on the same stack, so it becomes a double fault, which
+// runs on its own stack. This ends the run, so it's kept out of the usual one.
+#[cfg(feature = "stack-overflow-test")]
+test_case!(stack_overflow_double_faults, {
+    fn hook(_frame: &idt::InterruptStackFrame, _error_code: u64) {
+        let marker = 0u8;
+        let here = VirtAddr::from_ptr(&marker);
+        let (bottom, top) = crate::cpu::gdt::double_fault_stack();
+        assert!(here >= bottom && here < top, "double fault handler ran on {:?}, not the IST stack", here);
+        println!("[ok]");
+        crate::testing::exit_success();
+    }
+
+    #[allow(unconditional_recursion)]
+    fn overflow(depth: u64) -> u64 {
+        // The volatile read keeps this from becoming a loop
+        let depth = unsafe { core::ptr::read_volatile(&depth) };
+        overflow(depth + 1) + 1
+    }
+
+    set_double_fault_hook(Some(hook));
+    let depth = overflow(0);
+    set_double_fault_hook(None);
+    assert_eq!(depth, 0, "stack overflow returned");
+});
+
+test_case!(exception_counters, {
+    let before = exception_stats();
+    x86_64::instructions::interrupts::int3();
+    let after = exception_stats();
+
+    for vector in 0..EXCEPTION_VECTORS as u8 {
+        let expected = if vector == 3 { 1 } else { 0 };
+        assert_eq!(after.get(vector) - before.get(vector), expected, "{}", EXCEPTION_NAMES[vector as usize]);
+    }
+    assert!(after.seen().any(|(name, _)| name == "breakpoint"));
+});
+
+// Copies as many bytes starting at `rip` into `buf` as are mapped, returning the number copied.
+// Goes through whatever CR3 points at without taking any locks, since the fault
+// may have come from user space, or from code holding the kernel's table lock.
+pub fn read_code_bytes(rip: VirtAddr, buf: &mut [u8]) -> usize {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let addr = rip + i as u64;
+        if !matches!(walk_active(addr).end, WalkEnd::Mapped(_)) {
+            return i;
+        }
+
+        *byte = unsafe { core::ptr::read_volatile(addr.as_ptr()) };
+    }
+
+    buf.len()
+}
+
+// Best-effort guess at what kind of instruction the bytes start with
+pub fn opcode_category(bytes: &[u8]) -> &'static str {
+    // Skip legacy prefixes, remembering the ones that select SSE encodings
+    let mut sse_prefix = false;
+    let mut rest = bytes;
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            0x66 | 0xF2 | 0xF3 => sse_prefix = true,
+            0xF0 | 0x2E | 0x36 | 0x3E | 0x26 | 0x64 | 0x65 | 0x67 => {}
+            0x40..=0x4F => {} // REX
+            _ => break,
+        }
+        rest = tail;
+    }
+
+    match rest {
+        [] => "unknown",
+        [0xC4, ..] | [0xC5, ..] => "AVX (VEX encoded)",
+        [0x62, ..] => "AVX-512 (EVEX encoded)",
+        [0x0F, 0x0B, ..] => "ud2",
+        [0x0F, 0xB9, ..] | [0x0F, 0xFF, ..] => "undefined (ud1/ud0)",
+        [0x0F, 0xA2, ..] => "cpuid",
+        [0x0F, 0x01, ..] => "system (0f 01 group)",
+        [0x0F, 0x05, ..] | [0x0F, 0x07, ..] => "syscall/sysret",
+        [0x0F, 0x34, ..] | [0x0F, 0x35, ..] => "sysenter/sysexit",
+        [0x0F, 0x38, ..] | [0x0F, 0x3A, ..] => "SSSE3/SSE4 (three byte opcode)",
+        [0x0F, 0xAE, ..] => "fxsave/xsave group",
+        [0x0F, 0x10..=0x17, ..] | [0x0F, 0x28..=0x2F, ..] | [0x0F, 0x50..=0x7F, ..] | [0x0F, 0xC2..=0xC6, ..] | [0x0F, 0xD0..=0xFF, ..] => {
+            if sse_prefix { "SSE2+" } else { "SSE/MMX" }
+        }
+        [0x0F, ..] => "two byte opcode",
+        [0xD8..=0xDF, ..] => "x87",
+        _ => "unknown",
+    }
+}
+
+test_case!(read_code_bytes_synthetic, {
+    static CODE: [u8; 16] = [
+        0x0F, 0x0B, 0x90, 0x90, 0xC4, 0xE2, 0x79, 0x18, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC,
+    ];
+
+    let mut buf = [0u8; 8];
+    assert_eq!(read_code_bytes(VirtAddr::from_ptr(&CODE), &mut buf), 8);
+    assert_eq!(buf, [0x0F, 0x0B, 0x90, 0x90, 0xC4, 0xE2, 0x79, 0x18]);
+    assert_eq!(opcode_category(&buf), "ud2");
+    assert_eq!(opcode_category(&buf[4..]), "AVX (VEX encoded)");
+    assert_eq!(opcode_category(&[0x66, 0x0F, 0x38, 0x00]), "SSSE3/SSE4 (three byte opcode)");
+
+    // Nothing is mapped in the lower half, so nothing can be read
+    assert_eq!(read_code_bytes(VirtAddr::new(0x7000_0000_0000), &mut buf), 0);
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultDescription {
+    pub mode: &'static str,
+    pub access: &'static str,
+    pub cause: &'static str,
+    pub region: &'static str,
+}
+
+impl core::fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{} {} in {}: {}", self.mode, self.access, self.region, self.cause)
+    }
+}
+
+pub fn describe_page_fault(code: idt::PageFaultErrorCode, addr: VirtAddr) -> PageFaultDescription {
+    use idt::PageFaultErrorCode as Code;
+
+    let access = if code.contains(Code::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if code.contains(Code::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+
+    // A reserved bit is only checked for on present entries, so it takes
+    // priority over the protection violation bit
+    let cause = if code.contains(Code::MALFORMED_TABLE) {
+        "reserved bit set in page table"
+    } else if code.contains(Code::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+
+    PageFaultDescription {
+        mode: if code.contains(Code::USER_MODE) { "user" } else { "kernel" },
+        access,
+        cause,
+        region: address_region(addr),
+    }
+}
+
+// The error code pushed by faults caused by a segment selector or IDT entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode {
+    // Raised while delivering an external event, like an interrupt
+    pub external: bool,
+    pub table: &'static str,
+    pub index: u16,
+}
+
+impl core::fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if self.table == "IDT" {
+            write!(f, "IDT vector {:#x}", self.index)?;
+        } else {
+            write!(f, "{} selector {:#x} (index {})", self.table, self.index << 3, self.index)?;
+        }
+
+        if self.external {
+            f.write_str(", during external event")?;
+        }
+        Ok(())
+    }
+}
+
+// A zero error code means the fault had nothing to do with a selector
+pub fn decode_selector_error(code: u64) -> Option<SelectorErrorCode> {
+    if code == 0 {
+        return None;
+    }
+
+    Some(SelectorErrorCode {
+        external: code & 1 != 0,
+        table: match (code >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        },
+        index: ((code >> 3) & 0x1FFF) as u16,
+    })
+}
+
+fn address_region(addr: VirtAddr) -> &'static str {
+    use crate::mm::{
+        addr_space::is_user_addr, KERNEL_BASE, KERNEL_STACK_PAGES, KERNEL_STACK_START, PAGE_INFO_OFFSET, PAGE_INFO_SIZE,
+        PAGE_SIZE, PHYS_OFFSET,
+    };
+
+    // The bootloader leaves an unmapped guard page below the kernel stack
+    let addr = addr.as_u64();
+    let stack_start = KERNEL_STACK_START + PAGE_SIZE;
+    match addr {
+        _ if addr < PAGE_SIZE => "null page",
+        _ if is_user_addr(VirtAddr::new(addr)) => "user space",
+        _ if addr >= KERNEL_BASE => "kernel image",
+        _ if addr >= KERNEL_STACK_START && addr < stack_start => "kernel stack guard page (stack overflow?)",
+        _ if addr >= stack_start && addr < stack_start + KERNEL_STACK_PAGES * PAGE_SIZE => "kernel stack",
+        _ if addr >= PAGE_INFO_OFFSET && addr < PAGE_INFO_OFFSET + PAGE_INFO_SIZE => "page info array",
+        _ if addr >= PHYS_OFFSET && addr < PAGE_INFO_OFFSET => "direct map (heap, page tables)",
+        _ => "unmapped kernel space",
+    }
+}
+
+test_case!(decode_selector_error_codes, {
+    use arrayvec::ArrayString;
+    use core::fmt::Write;
+
+    assert_eq!(decode_selector_error(0), None);
+
+    // Loading GDT entry 2 (selector 0x10)
+    let code = decode_selector_error(0x10).unwrap();
+    assert_eq!(code, SelectorErrorCode { external: false, table: "GDT", index: 2 });
+
+    // Both encodings of the IDT, with the vector in the index
+    assert_eq!(decode_selector_error(0x0D << 3 | 0b010).unwrap().table, "IDT");
+    let code = decode_selector_error(0x0D << 3 | 0b111).unwrap();
+    assert_eq!(code, SelectorErrorCode { external: true, table: "IDT", index: 0x0D });
+
+    let code = decode_selector_error(0xFFFC).unwrap();
+    assert_eq!((code.table, code.index), ("LDT", 0x1FFF));
+
+    let mut s = ArrayString::<[u8; 64]>::new();
+    write!(s, "{}", decode_selector_error(0x2D).unwrap()).unwrap();
+    assert_eq!(s.as_str(), "LDT selector 0x28 (index 5), during external event");
+    s.clear();
+    write!(s, "{}", decode_selector_error(0x0D << 3 | 0b010).unwrap()).unwrap();
+    assert_eq!(s.as_str(), "IDT vector 0xd");
+});
+
+test_case!(describe_page_fault_codes, {
+    use core::fmt::Write;
+    use idt::PageFaultErrorCode as Code;
+    use crate::mm::{KERNEL_STACK_START, PHYS_OFFSET};
+
+    let desc = describe_page_fault(Code::empty(), VirtAddr::new(0x10));
+    assert_eq!(desc.mode, "kernel");
+    assert_eq!(desc.access, "read");
+    assert_eq!(desc.cause, "page not present");
+    assert_eq!(desc.region, "null page");
+
+    let desc = describe_page_fault(
+        Code::USER_MODE | Code::CAUSED_BY_WRITE | Code::PROTECTION_VIOLATION,
+        VirtAddr::new(0x40_0000),
+    );
+    assert_eq!(
+        (desc.mode, desc.access, desc.cause, desc.region),
+        ("user", "write", "protection violation", "user space")
+    );
+
+    let desc = describe_page_fault(
+        Code::INSTRUCTION_FETCH | Code::PROTECTION_VIOLATION,
+        VirtAddr::new(PHYS_OFFSET + 0x1234),
+    );
+    assert_eq!(desc.access, "instruction fetch");
+    assert_eq!(desc.region, "direct map (heap, page tables)");
+
+    let desc = describe_page_fault(
+        Code::MALFORMED_TABLE | Code::PROTECTION_VIOLATION | Code::CAUSED_BY_WRITE,
+        VirtAddr::new(KERNEL_STACK_START + 8),
+    );
+    assert_eq!(desc.cause, "reserved bit set in page table");
+    assert_eq!(desc.region, "kernel stack guard page (stack overflow?)");
+
+    let desc = describe_page_fault(Code::empty(), VirtAddr::new(describe_page_fault as usize as u64));
+    assert_eq!(desc.region, "kernel image");
+
+    let mut s = arrayvec::ArrayString::<[u8; 128]>::new();
+    write!(s, "{}", describe_page_fault(Code::CAUSED_BY_WRITE, VirtAddr::new(0x8000))).unwrap();
+    assert_eq!(s.as_str(), "kernel write in user space: page not present");
+});
+
+// The privilege level of the code that was interrupted, going by the saved CS.
+// The kernel only ever runs in ring 0.
+pub fn from_user(frame: &idt::InterruptStackFrame) -> bool {
+    selector_is_user(frame.code_segment)
+}
+
+fn selector_is_user(cs: u64) -> bool {
+    SegmentSelector(cs as u16).rpl() != PrivilegeLevel::Ring0
+}
+
+test_case!(user_selectors, {
+    // Kernel code, and the null selector an early fault might report
+    assert!(!selector_is_user(0x08));
+    assert!(!selector_is_user(0));
+    // The usual ring 3 code selectors, with and without a 32 bit one below them
+    assert!(selector_is_user(0x1B));
+    assert!(selector_is_user(0x23));
+    assert!(selector_is_user(0x2B));
+    // Only the RPL counts, not which entry it is
+    assert!(selector_is_user(0x08 | 3));
+    assert!(!selector_is_user(0x18));
+    // The high bits of the saved CS slot aren't part of the selector
+    assert!(!selector_is_user(0xFFFF_0000_0000_0008));
+});
+
+// A divide error or overflow in user code is that program's problem, so only
+// the task should go. In the kernel it's a bug.
+fn arithmetic_fault(name: &str, frame: &idt::InterruptStackFrame, irq: irq_frames::FrameGuard) {
+    if from_user(frame) {
+        user_fault(name, frame, irq);
+    }
+
+    panic!("EXCEPTION: {}\n{:#?}", name, frame);
+}
+
+// Ends the task that faulted. The handler never returns to it, so its frame is
+// dropped here and GS stays the kernel's.
+fn user_fault(name: &str, frame: &idt::InterruptStackFrame, irq: irq_frames::FrameGuard) -> ! {
+    warn!(
+        "idt: {} in user code at {:?}, ending task {:?}",
+        name,
+        frame.instruction_pointer,
+        sched::current()
+    );
+    irq.leave_in_kernel();
+    sched::exit()
+}
+
+test_case!(user_arithmetic_fault_ends_task, {
+    use crate::cpu::gdt::USER_CODE_SELECTOR;
+    use core::sync::atomic::AtomicU8;
+    use x86_64::structures::idt::InterruptStackFrameValue;
+
+    static FRAMES: irq_frames::FrameStack = irq_frames::FrameStack::new();
+    // 1 once the task faults, 2 if it ever carries on after
+    static REACHED: AtomicU8 = AtomicU8::new(0);
+
+    fn task(_: usize) {
+        let value = InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(0x40_0100),
+            code_segment: USER_CODE_SELECTOR.0 as u64,
+            cpu_flags: 0x202,
+            stack_pointer: VirtAddr::new(0x7FFF_FFFF_0000),
+            stack_segment: 0,
+        };
+        // InterruptStackFrame is a repr(C) wrapper around the value
+        let frame = unsafe { &*(&value as *const InterruptStackFrameValue as *const idt::InterruptStackFrame) };
+
+        REACHED.store(1, Ordering::Relaxed);
+        arithmetic_fault("Divide Error", frame, irq_frames::enter_on(&FRAMES, frame));
+        REACHED.store(2, Ordering::Relaxed);
+    }
+
+    sched::spawn(task, 0);
+    sched::yield_now();
+    assert_eq!(REACHED.load(Ordering::Relaxed), 1);
+    assert_eq!(FRAMES.depth(), 0);
+});
+
 extern "x86-interrupt" fn divide_error_handler(frame: idt::InterruptStackFrame) {
-    panic!("EXCEPTION: Zero Division\n{:#?}", frame);
+    let irq = irq_frames::enter(&frame);
+    count_exception(0);
+    // Either a divide by zero or a quotient too big for the destination
+    arithmetic_fault("Divide Error", &frame, irq);
 }
 
-extern "x86-interrupt" fn debug_handler(frame: idt::InterruptStackFrame) {
+extern "x86-interrupt" fn debug_handler(mut frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(1);
+    if crate::cpu::debug::handle_debug(&mut frame) {
+        return;
+    }
+
     panic!("EXCEPTION: Debug\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn non_maskable_interrupt_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(2);
+    if crate::cpu::watchdog::handle_nmi() {
+        return;
+    }
+
     panic!("EXCEPTION: Non-Maskable Interrupt\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(3);
     trace!("EXCEPTION: Breakpoint\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn overflow_handler(frame: idt::InterruptStackFrame) {
-    panic!("EXCEPTION: Overflow\n{:#?}", frame);
+    let irq = irq_frames::enter(&frame);
+    count_exception(4);
+    // Only raised by INTO, which doesn't exist in long mode, or INT 4
+    arithmetic_fault("Overflow", &frame, irq);
 }
 
 extern "x86-interrupt" fn bound_range_exceeded_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(5);
+    if handlers::dispatch(5, &frame) {
+        return;
+    }
     panic!("EXCEPTION: Bound Range Exceeded\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(frame: idt::InterruptStackFrame) {
-    panic!("EXCEPTION: Invalid Opcode\n{:#?}", frame);
+    let _irq = irq_frames::enter(&frame);
+    count_exception(6);
+    if handlers::dispatch(6, &frame) {
+        return;
+    }
+    let mut bytes = [0u8; 8];
+    let len = read_code_bytes(frame.instruction_pointer, &mut bytes);
+    panic!("EXCEPTION: Invalid Opcode ({}) at {:?}, bytes {:02x?}\n{:#?}", opcode_category(&bytes[..len]), frame.instruction_pointer, &bytes[..len], frame);
 }
 
 extern "x86-interrupt" fn device_not_available_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(7);
+    if handlers::dispatch(7, &frame) {
+        return;
+    }
     panic!("EXCEPTION: Device Not Available\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(frame: idt::InterruptStackFrame, error_code: u64) -> ! {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(8);
+    #[cfg(test)]
+    run_double_fault_hook(&frame, error_code);
+    // Overflowing a stack faults again pushing the page fault's frame, so it
+    // ends up here, with CR2 still pointing into the guard page
+    if let Some(overflow) = kstack::overflow_at(Cr2::read()) {
+        panic!("EXCEPTION: Double Fault: {}\n{:#?}", overflow, frame);
+    }
     panic!("EXCEPTION: Double Fault with error code {}\n{:#?}", error_code, frame);
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(frame: idt::InterruptStackFrame, error_code: u64) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(10);
+    if handlers::dispatch_with_code(10, &frame, error_code) {
+        return;
+    }
     panic!("EXCEPTION: Invalid TSS with error code {}\n{:#?}", error_code, frame);
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(frame: idt::InterruptStackFrame, error_code: u64) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(11);
+    if handlers::dispatch_with_code(11, &frame, error_code) {
+        return;
+    }
     panic!("EXCEPTION: Segment Not Present with error code {}\n{:#?}", error_code, frame);
 }
 
 extern "x86-interrupt" fn stack_segment_fault_handler(frame: idt::InterruptStackFrame, error_code: u64) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(12);
+    if handlers::dispatch_with_code(12, &frame, error_code) {
+        return;
+    }
     panic!("EXCEPTION: Stack Segment Fault with error code {}\n{:#?}", error_code, frame);
 }
 
 extern "x86-interrupt" fn general_protection_fault_handler(frame: idt::InterruptStackFrame, error_code: u64) {
-    panic!("EXCEPTION: General Protection Fault with error code {}\n{:#?}", error_code, frame);
+    let _irq = irq_frames::enter(&frame);
+    count_exception(13);
+    if handlers::dispatch_with_code(13, &frame, error_code) {
+        return;
+    }
+    let mut bytes = [0u8; 8];
+    let len = read_code_bytes(frame.instruction_pointer, &mut bytes);
+    match decode_selector_error(error_code) {
+        Some(selector) => panic!("EXCEPTION: General Protection Fault loading {} at {:?}, bytes {:02x?}\n{:#?}", selector, frame.instruction_pointer, &bytes[..len], frame),
+        None => panic!("EXCEPTION: General Protection Fault ({}) at {:?}, bytes {:02x?}\n{:#?}", opcode_category(&bytes[..len]), frame.instruction_pointer, &bytes[..len], frame),
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(frame: idt::InterruptStackFrame, error_code: idt::PageFaultErrorCode) {
-    panic!("EXCEPTION: Page Fault with error code {:#?}\nAddress {:?}\n{:#?}", error_code, Cr2::read(), frame);
+extern "x86-interrupt" fn page_fault_handler(mut frame: idt::InterruptStackFrame, error_code: idt::PageFaultErrorCode) {
+    // Before anything else can fault and overwrite it
+    let addr = Cr2::read();
+    let _irq = irq_frames::enter(&frame);
+    count_exception(14);
+    if let Some(handler) = handlers::user_fault_handler(frame.code_segment) {
+        handler(&mut frame, addr, error_code);
+        return;
+    }
+    if AddrSpace::kernel().handle_page_fault(addr, error_code) {
+        return;
+    }
+    if crate::cpu::uaccess::fixup_fault(&mut frame) {
+        return;
+    }
+    if handlers::dispatch_with_code(14, &frame, error_code.bits()) {
+        return;
+    }
+
+    if let Some(overflow) = kstack::overflow_at(addr) {
+        panic!("EXCEPTION: Page Fault: {}\n{:#?}", overflow, frame);
+    }
+    panic!(
+        "EXCEPTION: Page Fault ({}) with error code {:#?}\nAddress {:?}\n{:#?}",
+        describe_page_fault(error_code, addr),
+        error_code,
+        addr,
+        frame
+    );
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(16);
+    if handlers::dispatch(16, &frame) {
+        return;
+    }
     panic!("EXCEPTION: x87 Floating Point\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn alignment_check_handler(frame: idt::InterruptStackFrame, error_code: u64) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(17);
+    if handlers::dispatch_with_code(17, &frame, error_code) {
+        return;
+    }
     panic!("EXCEPTION: Alignment Check with error code {}\n{:#?}", error_code, frame);
 }
 
 extern "x86-interrupt" fn machine_check_handler(frame: idt::InterruptStackFrame) -> ! {
-    panic!("EXCEPTION: Machine Check\n{:#?}", frame);
+    let _irq = irq_frames::enter(&frame);
+    count_exception(18);
+    match crate::cpu::mce::report() {
+        Some(report) => panic!("EXCEPTION: Machine Check\n{}{:#?}", report, frame),
+        None => panic!("EXCEPTION: Machine Check\n{:#?}", frame),
+    }
 }
 
 extern "x86-interrupt" fn simd_floating_point_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(19);
+    if handlers::dispatch(19, &frame) {
+        return;
+    }
     panic!("EXCEPTION: SIMD Floating Point\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn virtualization_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(20);
+    if handlers::dispatch(20, &frame) {
+        return;
+    }
     panic!("EXCEPTION: Virtualization\n{:#?}", frame);
 }
 
+extern "x86-interrupt" fn timer_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    crate::kernel::time::tick();
+    pic8259::end_of_interrupt(pic8259::TIMER_IRQ);
+
+    #[cfg(test)]
+    crate::testing::check_deadline();
+    crate::kernel::deferred::irq_exit(&frame);
+}
+
+// Nothing drives IRQ7 or IRQ15 yet, so these are usually spurious
+extern "x86-interrupt" fn pic_irq7_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    if !pic8259::acknowledge(7) {
+        SPURIOUS_INTERRUPTS.inc();
+    }
+}
+
+extern "x86-interrupt" fn pic_irq15_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    if !pic8259::acknowledge(15) {
+        SPURIOUS_INTERRUPTS.inc();
+    }
+}
+
+// The APIC doesn't treat a spurious interrupt as in service, so there's no EOI
+extern "x86-interrupt" fn apic_spurious_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    SPURIOUS_INTERRUPTS.inc();
+}
+
+extern "x86-interrupt" fn apic_error_handler(frame: idt::InterruptStackFrame) {
+    let _irq = irq_frames::enter(&frame);
+    crate::cpu::lapic::handle_error_interrupt();
+}
+
 extern "x86-interrupt" fn security_exception_handler(frame: idt::InterruptStackFrame, error_code: u64, ) {
+    let _irq = irq_frames::enter(&frame);
+    count_exception(30);
+    if handlers::dispatch_with_code(30, &frame, error_code) {
+        return;
+    }
     panic!("EXCEPTION: Security Exception with error code {}\n{:#?}", error_code, frame);
 }
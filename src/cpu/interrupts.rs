@@ -0,0 +1,179 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Interrupt disabling that can be nested. Each disable() hands back whether
+// interrupts were on, for the matching restore(). Only the outermost restore()
+// can turn them back on, so an inner section that finishes early can't open up
+// the one around it.
+
+// Lets tests swap out the real flag
+pub trait InterruptFlag {
+    fn are_enabled(&self) -> bool;
+    fn enable(&self);
+    fn disable(&self);
+}
+
+pub struct HardwareFlag;
+
+impl InterruptFlag for HardwareFlag {
+    fn are_enabled(&self) -> bool {
+        x86_64::instructions::interrupts::are_enabled()
+    }
+
+    fn enable(&self) {
+        x86_64::instructions::interrupts::enable();
+    }
+
+    fn disable(&self) {
+        x86_64::instructions::interrupts::disable();
+    }
+}
+
+#[must_use = "pass this to restore(), or interrupts stay off"]
+#[derive(Debug)]
+pub struct IrqFlags {
+    was_enabled: bool,
+}
+
+pub struct Nesting {
+    depth: AtomicUsize,
+}
+
+#[allow(dead_code)]
+impl Nesting {
+    pub const fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn disable_using<F: InterruptFlag>(&self, flag: &F) -> IrqFlags {
+        let was_enabled = flag.are_enabled();
+        flag.disable();
+        self.depth.fetch_add(1, Ordering::Relaxed);
+
+        IrqFlags { was_enabled }
+    }
+
+    pub fn restore_using<F: InterruptFlag>(&self, flag: &F, flags: IrqFlags) {
+        let depth = self.depth.load(Ordering::Relaxed);
+        BUG_ON!(depth == 0, "interrupts: restore() without disable()");
+        self.depth.store(depth - 1, Ordering::Relaxed);
+
+        if !flags.was_enabled {
+            return;
+        }
+
+        // Interrupts can only have been on when an inner section started if
+        // something turned them on behind our back
+        if WARN_ONCE!(depth > 1, "interrupts: enabled inside a critical section") {
+            return;
+        }
+
+        flag.enable();
+    }
+
+    pub fn without_interrupts_using<F, R>(&self, flag: &F, f: impl FnOnce() -> R) -> R
+    where
+        F: InterruptFlag,
+    {
+        let flags = self.disable_using(flag);
+        let rv = f();
+        self.restore_using(flag, flags);
+        rv
+    }
+}
+
+static NESTING: Nesting = Nesting::new(); // TODO: SMP
+
+#[allow(dead_code)]
+pub fn are_enabled() -> bool {
+    HardwareFlag.are_enabled()
+}
+
+#[allow(dead_code)]
+pub fn depth() -> usize {
+    NESTING.depth()
+}
+
+#[allow(dead_code)]
+pub fn disable() -> IrqFlags {
+    NESTING.disable_using(&HardwareFlag)
+}
+
+#[allow(dead_code)]
+pub fn restore(flags: IrqFlags) {
+    NESTING.restore_using(&HardwareFlag, flags)
+}
+
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    NESTING.without_interrupts_using(&HardwareFlag, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    struct MockFlag(Cell<bool>);
+
+    impl InterruptFlag for MockFlag {
+        fn are_enabled(&self) -> bool {
+            self.0.get()
+        }
+
+        fn enable(&self) {
+            self.0.set(true);
+        }
+
+        fn disable(&self) {
+            self.0.set(false);
+        }
+    }
+
+    test_case!(interrupt_nesting, {
+        let flag = MockFlag(Cell::new(true));
+        let nesting = Nesting::new();
+
+        let outer = nesting.disable_using(&flag);
+        let inner = nesting.disable_using(&flag);
+        assert_eq!(nesting.depth(), 2);
+        assert!(!flag.are_enabled());
+
+        // The inner section ending leaves interrupts off
+        nesting.restore_using(&flag, inner);
+        assert_eq!(nesting.depth(), 1);
+        assert!(!flag.are_enabled());
+
+        nesting.restore_using(&flag, outer);
+        assert_eq!(nesting.depth(), 0);
+        assert!(flag.are_enabled());
+
+        // Starting with interrupts off, they stay off
+        let flag = MockFlag(Cell::new(false));
+        nesting.without_interrupts_using(&flag, || {
+            assert_eq!(nesting.without_interrupts_using(&flag, || nesting.depth()), 2);
+        });
+        assert_eq!(nesting.depth(), 0);
+        assert!(!flag.are_enabled());
+    });
+
+    test_case!(interrupt_nesting_out_of_order, {
+        let flag = MockFlag(Cell::new(true));
+        let nesting = Nesting::new();
+
+        // Something re-enables interrupts inside the outer section, so the
+        // inner one thinks they were on. Only the outer restore turns them on.
+        let outer = nesting.disable_using(&flag);
+        flag.enable();
+        let inner = nesting.disable_using(&flag);
+        nesting.restore_using(&flag, inner);
+        assert!(!flag.are_enabled());
+
+        nesting.restore_using(&flag, outer);
+        assert!(flag.are_enabled());
+    });
+}
@@ -0,0 +1,395 @@
+use crate::{
+    cpu::interrupts,
+    ds::{InitCell, SpinLock},
+    mm::addr_space::AddrSpace,
+};
+use acpi::platform::interrupt::{self as madt, Apic};
+use arrayvec::ArrayVec;
+use core::ptr;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+// I/O APICs, which route global system interrupts (GSIs) to vectors on a
+// chosen CPU. Each one handles a run of GSIs starting at its base. Registers
+// are reached indirectly, by writing the register number to IOREGSEL and then
+// reading or writing IOWIN.
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const MMIO_SIZE: u64 = 0x20;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const MAX_IOAPICS: usize = 8;
+const ISA_IRQS: usize = 16;
+
+const ENTRY_MASKED: u64 = 1 << 16;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    ExtInt = 0b111,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+// Only physical destination mode, so `dest` is a local APIC id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub delivery: DeliveryMode,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+    pub masked: bool,
+    pub dest: u8,
+}
+
+impl RedirectionEntry {
+    pub fn encode(&self) -> u64 {
+        let mut value = self.vector as u64 | (self.delivery as u64) << 8 | (self.dest as u64) << 56;
+        if self.polarity == Polarity::ActiveLow {
+            value |= 1 << 13;
+        }
+        if self.trigger == TriggerMode::Level {
+            value |= 1 << 15;
+        }
+        if self.masked {
+            value |= ENTRY_MASKED;
+        }
+        value
+    }
+}
+
+// An ISA IRQ that the MADT says isn't wired to the GSI of the same number, or
+// isn't edge triggered and active high like ISA normally is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+pub fn isa_route(overrides: &[IsaOverride], irq: u8) -> IsaRoute {
+    match overrides.iter().find(|ov| ov.irq == irq) {
+        Some(ov) => IsaRoute {
+            gsi: ov.gsi,
+            polarity: ov.polarity,
+            trigger: ov.trigger,
+        },
+        None => IsaRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger: TriggerMode::Edge,
+        },
+    }
+}
+
+// "Same as bus" means the ISA defaults, since these only come from ISA
+fn isa_override(ov: &madt::InterruptSourceOverride) -> IsaOverride {
+    IsaOverride {
+        irq: ov.isa_source,
+        gsi: ov.global_system_interrupt,
+        polarity: match ov.polarity {
+            madt::Polarity::ActiveLow => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        },
+        trigger: match ov.trigger_mode {
+            madt::TriggerMode::Level => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        },
+    }
+}
+
+// Lets tests stand in for an I/O APIC's registers
+pub trait IoApicRegs {
+    fn read(&self, reg: u32) -> u32;
+    fn write(&self, reg: u32, value: u32);
+}
+
+pub struct MmioRegs {
+    base: VirtAddr,
+}
+
+impl IoApicRegs for MmioRegs {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::read_volatile((self.base + IOWIN).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::write_volatile((self.base + IOWIN).as_mut_ptr::<u32>(), value);
+        }
+    }
+}
+
+pub struct IoApic<R: IoApicRegs> {
+    regs: R,
+    gsi_base: u32,
+    entries: u32,
+}
+
+#[allow(dead_code)]
+impl<R: IoApicRegs> IoApic<R> {
+    pub fn new(regs: R, gsi_base: u32) -> Self {
+        let entries = (regs.read(REG_VERSION) >> 16 & 0xFF) + 1;
+        Self { regs, gsi_base, entries }
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    fn entry_reg(&self, gsi: u32) -> u32 {
+        BUG_ON!(!self.handles(gsi), "ioapic: gsi {} isn't handled here", gsi);
+        REG_REDIRECTION + 2 * (gsi - self.gsi_base)
+    }
+
+    pub fn read_entry(&self, gsi: u32) -> u64 {
+        let reg = self.entry_reg(gsi);
+        self.regs.read(reg) as u64 | (self.regs.read(reg + 1) as u64) << 32
+    }
+
+    // The high half goes first, so the entry never points at the wrong CPU
+    // while it's unmasked
+    fn write_entry(&self, gsi: u32, value: u64) {
+        let reg = self.entry_reg(gsi);
+        self.regs.write(reg + 1, (value >> 32) as u32);
+        self.regs.write(reg, value as u32);
+    }
+
+    pub fn set_entry(&self, gsi: u32, entry: RedirectionEntry) {
+        self.write_entry(gsi, entry.encode());
+    }
+
+    pub fn mask(&self, gsi: u32) {
+        self.write_entry(gsi, self.read_entry(gsi) | ENTRY_MASKED);
+    }
+
+    pub fn unmask(&self, gsi: u32) {
+        self.write_entry(gsi, self.read_entry(gsi) & !ENTRY_MASKED);
+    }
+
+    pub fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.entries {
+            self.mask(gsi);
+        }
+    }
+}
+
+// One lock for all of them, since every register access is a pair of writes
+static IOAPICS: InitCell<SpinLock<ArrayVec<[IoApic<MmioRegs>; MAX_IOAPICS]>>> = InitCell::new();
+static OVERRIDES: InitCell<ArrayVec<[IsaOverride; ISA_IRQS]>> = InitCell::new();
+
+// Maps every I/O APIC in the MADT and masks all of their entries. Nothing is
+// routed through them until a driver asks for it.
+pub fn init(apic: &Apic) {
+    let mut ioapics = ArrayVec::new();
+    for info in apic.io_apics.iter() {
+        let base = match AddrSpace::kernel().map_mmio(PhysAddr::new(info.address as u64), MMIO_SIZE, PageTableFlags::WRITABLE) {
+            Ok(base) => base,
+            Err(err) => {
+                error!("ioapic: failed to map ioapic {}: {:?}", info.id, err);
+                continue;
+            }
+        };
+
+        let ioapic = IoApic::new(MmioRegs { base }, info.global_system_interrupt_base);
+        ioapic.mask_all();
+        debug!(
+            "ioapic: id {} handles gsis {}..{}",
+            info.id,
+            ioapic.gsi_base,
+            ioapic.gsi_base + ioapic.entries
+        );
+        if ioapics.try_push(ioapic).is_err() {
+            warn!("ioapic: ignoring ioapic {}, too many", info.id);
+        }
+    }
+
+    IOAPICS.init(SpinLock::new(ioapics));
+    OVERRIDES.init(apic.interrupt_source_overrides.iter().map(isa_override).collect());
+}
+
+fn with_ioapic<T>(gsi: u32, f: impl FnOnce(&IoApic<MmioRegs>) -> T) -> Option<T> {
+    let ioapics = IOAPICS.try_get()?;
+    interrupts::without_interrupts(|| ioapics.lock().iter().find(|ioapic| ioapic.handles(gsi)).map(f))
+}
+
+// Returns false if no I/O APIC handles the GSI
+#[allow(dead_code)]
+pub fn route(gsi: u32, entry: RedirectionEntry) -> bool {
+    with_ioapic(gsi, |ioapic| ioapic.set_entry(gsi, entry)).is_some()
+}
+
+// Routes a legacy IRQ to `vector` on the CPU with local APIC id `dest`, going
+// through the MADT's overrides. The entry starts masked.
+#[allow(dead_code)]
+pub fn route_isa(irq: u8, vector: u8, dest: u8) -> Option<u32> {
+    let isa = isa_route(OVERRIDES.try_get().map_or(&[], |overrides| &overrides[..]), irq);
+    let entry = RedirectionEntry {
+        vector,
+        delivery: DeliveryMode::Fixed,
+        polarity: isa.polarity,
+        trigger: isa.trigger,
+        masked: true,
+        dest,
+    };
+
+    if route(isa.gsi, entry) {
+        Some(isa.gsi)
+    } else {
+        None
+    }
+}
+
+#[allow(dead_code)]
+pub fn mask(gsi: u32) -> bool {
+    with_ioapic(gsi, |ioapic| ioapic.mask(gsi)).is_some()
+}
+
+#[allow(dead_code)]
+pub fn unmask(gsi: u32) -> bool {
+    with_ioapic(gsi, |ioapic| ioapic.unmask(gsi)).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    // Version 0x20 with 24 entries, like the one QEMU has
+    struct MockRegs {
+        regs: [Cell<u32>; 0x40],
+    }
+
+    impl MockRegs {
+        fn new() -> Self {
+            const ZERO: Cell<u32> = Cell::new(0);
+            let mock = Self { regs: [ZERO; 0x40] };
+            mock.regs[REG_VERSION as usize].set(23 << 16 | 0x20);
+            mock
+        }
+    }
+
+    impl IoApicRegs for MockRegs {
+        fn read(&self, reg: u32) -> u32 {
+            self.regs[reg as usize].get()
+        }
+
+        fn write(&self, reg: u32, value: u32) {
+            self.regs[reg as usize].set(value);
+        }
+    }
+
+    test_case!(redirection_entry_encoding, {
+        let mut entry = RedirectionEntry {
+            vector: 0x30,
+            delivery: DeliveryMode::Fixed,
+            polarity: Polarity::ActiveHigh,
+            trigger: TriggerMode::Edge,
+            masked: false,
+            dest: 0,
+        };
+        assert_eq!(entry.encode(), 0x30);
+
+        entry.delivery = DeliveryMode::LowestPriority;
+        entry.polarity = Polarity::ActiveLow;
+        entry.trigger = TriggerMode::Level;
+        entry.masked = true;
+        entry.dest = 3;
+        assert_eq!(entry.encode(), 0x0300_0000_0001_A130);
+
+        entry.delivery = DeliveryMode::ExtInt;
+        assert_eq!(entry.encode() >> 8 & 0b111, 0b111);
+
+        // Entries land in two registers each, after the ioapic's own
+        let ioapic = IoApic::new(MockRegs::new(), 24);
+        assert!(!ioapic.handles(23) && ioapic.handles(24) && ioapic.handles(47) && !ioapic.handles(48));
+        ioapic.set_entry(25, entry);
+        assert_eq!(ioapic.regs.read(0x12), 0x0001_A730);
+        assert_eq!(ioapic.regs.read(0x13), 0x0300_0000);
+
+        ioapic.unmask(25);
+        assert_eq!(ioapic.read_entry(25), entry.encode() & !ENTRY_MASKED);
+        ioapic.mask(25);
+        assert_eq!(ioapic.read_entry(25), entry.encode());
+
+        ioapic.mask_all();
+        assert!((24..48).all(|gsi| ioapic.read_entry(gsi) & ENTRY_MASKED != 0));
+    });
+
+    test_case!(isa_override_lookup, {
+        // What QEMU reports: the PIT is on GSI 2, and the PCI interrupts are
+        // level triggered
+        let overrides = [
+            IsaOverride {
+                irq: 0,
+                gsi: 2,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            },
+            IsaOverride {
+                irq: 9,
+                gsi: 9,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Level,
+            },
+        ];
+
+        assert_eq!(isa_route(&overrides, 0).gsi, 2);
+        assert_eq!(isa_route(&overrides, 9).trigger, TriggerMode::Level);
+        assert_eq!(
+            isa_route(&overrides, 1),
+            IsaRoute {
+                gsi: 1,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            }
+        );
+        assert_eq!(isa_route(&[], 0).gsi, 0);
+
+        let ov = isa_override(&madt::InterruptSourceOverride {
+            isa_source: 10,
+            global_system_interrupt: 20,
+            polarity: madt::Polarity::ActiveLow,
+            trigger_mode: madt::TriggerMode::SameAsBus,
+        });
+        assert_eq!(
+            ov,
+            IsaOverride {
+                irq: 10,
+                gsi: 20,
+                polarity: Polarity::ActiveLow,
+                trigger: TriggerMode::Edge,
+            }
+        );
+    });
+}
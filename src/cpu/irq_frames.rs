@@ -0,0 +1,187 @@
+use crate::cpu::percpu::{swapgs_if_from_user, PerCpu};
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+
+// The interrupt frames a CPU is currently inside. Handlers push theirs on entry
+// and pop it on the way out, so when something faults inside a handler, the
+// panic code can report where each level of interrupt came from, not just the
+// last one.
+
+// Anything nested deeper than this is counted but not recorded
+pub const MAX_DEPTH: usize = 8;
+
+pub struct FrameStack {
+    frames: [AtomicU64; MAX_DEPTH],
+    // Can go past MAX_DEPTH, so that pops still pair up with pushes
+    depth: AtomicUsize,
+}
+
+#[allow(dead_code)]
+impl FrameStack {
+    pub const fn new() -> Self {
+        const EMPTY: AtomicU64 = AtomicU64::new(0);
+        Self {
+            frames: [EMPTY; MAX_DEPTH],
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    // Interrupts only nest on top of each other, so each push is undone by a
+    // pop before anything under it can pop
+    pub fn push(&self, frame: &InterruptStackFrame) {
+        let depth = self.depth.load(Ordering::Relaxed);
+        if depth < MAX_DEPTH {
+            self.frames[depth].store(frame as *const _ as u64, Ordering::Relaxed);
+        }
+        self.depth.store(depth + 1, Ordering::Release);
+    }
+
+    pub fn pop(&self) {
+        let depth = self.depth.load(Ordering::Relaxed);
+        BUG_ON!(depth == 0, "irq_frames: pop() without push()");
+        self.depth.store(depth - 1, Ordering::Release);
+    }
+
+    // Copies of the recorded frames, innermost first. Only safe while the
+    // handlers that pushed them are still running, which is always the case
+    // for the current CPU's stack.
+    pub fn walk(&self) -> ArrayVec<[InterruptStackFrameValue; MAX_DEPTH]> {
+        let depth = self.depth.load(Ordering::Acquire).min(MAX_DEPTH);
+        (0..depth)
+            .rev()
+            .map(|i| {
+                let frame = self.frames[i].load(Ordering::Relaxed) as *const InterruptStackFrame;
+                unsafe { **frame }
+            })
+            .collect()
+    }
+}
+
+// Pops the frame when the handler returns. A handler that panics never does,
+// which leaves its frame for the panic code to find.
+pub struct FrameGuard<'a> {
+    stack: &'a FrameStack,
+    // Whether GS was swapped on the way in, so has to be swapped back
+    swapped_gs: bool,
+}
+
+impl FrameGuard<'_> {
+    // For a handler that won't return to the code it interrupted, like one
+    // that ends the task: the frame goes, but GS stays the kernel's
+    pub fn leave_in_kernel(mut self) {
+        self.swapped_gs = false;
+    }
+}
+
+impl Drop for FrameGuard<'_> {
+    fn drop(&mut self) {
+        self.stack.pop();
+        if self.swapped_gs {
+            unsafe { x86_64::instructions::segmentation::GS::swap() };
+        }
+    }
+}
+
+pub fn enter_on<'a>(stack: &'a FrameStack, frame: &InterruptStackFrame) -> FrameGuard<'a> {
+    stack.push(frame);
+    FrameGuard {
+        stack,
+        swapped_gs: false,
+    }
+}
+
+// Call first thing in an interrupt handler, and keep the guard until it
+// returns. Nothing before it may touch per-CPU data, since GS is still the
+// user's if the interrupt came from ring 3.
+pub fn enter(frame: &InterruptStackFrame) -> FrameGuard<'static> {
+    let swapped_gs = unsafe { swapgs_if_from_user(frame) };
+    trace_event!(Irq, frame.instruction_pointer.as_u64());
+    let mut guard = enter_on(&PerCpu::current().irq_frames, frame);
+    guard.swapped_gs = swapped_gs;
+    guard
+}
+
+// The frames of every interrupt the current CPU is inside, innermost first
+pub fn current_frames() -> ArrayVec<[InterruptStackFrameValue; MAX_DEPTH]> {
+    PerCpu::current().irq_frames.walk()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::VirtAddr;
+
+    fn fake_frame(rip: u64) -> InterruptStackFrameValue {
+        InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(rip),
+            code_segment: 0x08,
+            cpu_flags: 0x2,
+            stack_pointer: VirtAddr::new(0x1000),
+            stack_segment: 0,
+        }
+    }
+
+    // InterruptStackFrame is a repr(C) wrapper around the value
+    fn as_frame(value: &InterruptStackFrameValue) -> &InterruptStackFrame {
+        unsafe { &*(value as *const InterruptStackFrameValue as *const InterruptStackFrame) }
+    }
+
+    fn rips(stack: &FrameStack) -> ArrayVec<[u64; MAX_DEPTH]> {
+        stack.walk().iter().map(|frame| frame.instruction_pointer.as_u64()).collect()
+    }
+
+    test_case!(frame_stack_nesting, {
+        let stack = FrameStack::new();
+        let outer = fake_frame(0x1000);
+        let inner = fake_frame(0x2000);
+        assert!(stack.walk().is_empty());
+
+        {
+            let _outer = enter_on(&stack, as_frame(&outer));
+            {
+                let _inner = enter_on(&stack, as_frame(&inner));
+                assert_eq!(stack.depth(), 2);
+                assert_eq!(rips(&stack).as_slice(), &[0x2000, 0x1000]);
+            }
+            assert_eq!(rips(&stack).as_slice(), &[0x1000]);
+
+            // A handler that never returns keeps its frame recorded
+            core::mem::forget(enter_on(&stack, as_frame(&inner)));
+            assert_eq!(rips(&stack).as_slice(), &[0x2000, 0x1000]);
+            stack.pop();
+        }
+        assert_eq!(stack.depth(), 0);
+        assert!(stack.walk().is_empty());
+    });
+
+    test_case!(frame_stack_overflow, {
+        let stack = FrameStack::new();
+        let frames: ArrayVec<[InterruptStackFrameValue; MAX_DEPTH + 2]> =
+            (0..MAX_DEPTH as u64 + 2).map(|i| fake_frame(0x1000 * (i + 1))).collect();
+
+        for frame in frames.iter() {
+            stack.push(as_frame(frame));
+        }
+        assert_eq!(stack.depth(), MAX_DEPTH + 2);
+
+        // Only the outermost MAX_DEPTH are kept, still innermost first
+        let recorded = rips(&stack);
+        assert_eq!(recorded.len(), MAX_DEPTH);
+        assert_eq!(recorded[0], 0x1000 * MAX_DEPTH as u64);
+        assert_eq!(recorded[MAX_DEPTH - 1], 0x1000);
+
+        // Popping the unrecorded ones leaves the recorded ones alone
+        stack.pop();
+        stack.pop();
+        assert_eq!(rips(&stack), recorded);
+        for _ in 0..MAX_DEPTH {
+            stack.pop();
+        }
+        assert_eq!(stack.depth(), 0);
+    });
+}
@@ -0,0 +1,156 @@
+use crate::mm::addr_space::AddrSpace;
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{registers::model_specific::Msr, structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+// The local APIC's registers. There's no driver for it yet, so this only covers
+// what the watchdog and error reporting need.
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+pub const EOI: u64 = 0x0B0;
+pub const ESR: u64 = 0x280;
+pub const LVT_ERROR: u64 = 0x370;
+pub const LVT_PERF: u64 = 0x340;
+
+// Where the local APIC's registers are mapped, once something has used them
+static LAPIC: AtomicU64 = AtomicU64::new(0);
+
+fn base() -> VirtAddr {
+    let virt = LAPIC.load(Ordering::Relaxed);
+    if virt != 0 {
+        return VirtAddr::new(virt);
+    }
+
+    // Every CPU's APIC is at the same physical address, so losing a race here
+    // only costs a second mapping
+    let phys = PhysAddr::new(unsafe { Msr::new(IA32_APIC_BASE).read() } & 0xF_FFFF_F000);
+    let virt = AddrSpace::kernel()
+        .map_mmio(phys, 0x1000, PageTableFlags::WRITABLE)
+        .expect("lapic: failed to map the local APIC");
+    LAPIC.store(virt.as_u64(), Ordering::Relaxed);
+    virt
+}
+
+pub unsafe fn read(reg: u64) -> u32 {
+    ptr::read_volatile((base() + reg).as_ptr::<u32>())
+}
+
+pub unsafe fn write(reg: u64, value: u32) {
+    ptr::write_volatile((base() + reg).as_mut_ptr::<u32>(), value);
+}
+
+// What the Error Status Register reports, one bit per kind of error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicErrors(pub u32);
+
+const ERROR_NAMES: [&str; 8] = [
+    "send checksum error",
+    "receive checksum error",
+    "send accept error",
+    "receive accept error",
+    "redirectable IPI",
+    "send illegal vector",
+    "received illegal vector",
+    "illegal register address",
+];
+
+impl ApicErrors {
+    pub fn is_empty(self) -> bool {
+        self.0 & 0xFF == 0
+    }
+
+    // The errors that are set, lowest bit first. Reserved bits are ignored.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        ERROR_NAMES
+            .iter()
+            .enumerate()
+            .filter(move |&(bit, _)| self.0 & 1 << bit != 0)
+            .map(|(_, &name)| name)
+    }
+}
+
+impl fmt::Display for ApicErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no errors");
+        }
+
+        for (i, name) in self.names().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+// The ESR only latches new errors when it's written, and clears them at the
+// same time, so each read sees what's happened since the last one
+pub fn read_errors() -> ApicErrors {
+    unsafe {
+        write(ESR, 0);
+        ApicErrors(read(ESR))
+    }
+}
+
+// Logs anything that's gone wrong since the last check. Returns the errors.
+pub fn check_errors() -> ApicErrors {
+    let errors = read_errors();
+    if !errors.is_empty() {
+        warn!("lapic: {} (esr {:#x})", errors, errors.0);
+    }
+    errors
+}
+
+// Has the APIC raise `vector` as errors happen, rather than waiting for
+// someone to check. The APIC has to be software enabled for this to fire.
+#[allow(dead_code)]
+pub fn enable_error_interrupt(vector: u8) {
+    unsafe { write(LVT_ERROR, vector as u32) };
+    // Start from a clean slate
+    read_errors();
+}
+
+// Called from the error interrupt handler
+pub fn handle_error_interrupt() {
+    check_errors();
+    unsafe { write(EOI, 0) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::{ArrayString, ArrayVec};
+    use core::fmt::Write;
+
+    test_case!(apic_error_decoding, {
+        assert!(ApicErrors(0).is_empty());
+        assert_eq!(ApicErrors(0).names().count(), 0);
+
+        // Send illegal vector alone, e.g. an IPI to vector 0
+        let names: ArrayVec<[&str; 8]> = ApicErrors(0x20).names().collect();
+        assert_eq!(names.as_slice(), &["send illegal vector"]);
+
+        let names: ArrayVec<[&str; 8]> = ApicErrors(0xC1).names().collect();
+        assert_eq!(
+            names.as_slice(),
+            &["send checksum error", "received illegal vector", "illegal register address"]
+        );
+        assert_eq!(ApicErrors(0xFF).names().count(), 8);
+
+        // Reserved bits don't count as errors
+        assert!(ApicErrors(0xFFFF_FF00).is_empty());
+        assert_eq!(ApicErrors(0x100 | 0x40).names().count(), 1);
+
+        let mut s = ArrayString::<[u8; 64]>::new();
+        write!(s, "{}", ApicErrors(0x60)).unwrap();
+        assert_eq!(s.as_str(), "send illegal vector, received illegal vector");
+        s.clear();
+        write!(s, "{}", ApicErrors(0)).unwrap();
+        assert_eq!(s.as_str(), "no errors");
+    });
+}
@@ -0,0 +1,262 @@
+use arrayvec::ArrayVec;
+use core::{arch::x86_64::__cpuid, fmt};
+use x86_64::registers::model_specific::Msr;
+
+// Decodes the machine check banks, so a machine check panic says what actually
+// went wrong. Reading a bank that doesn't exist faults, which inside the
+// machine check handler is the end of the machine, so only the banks that
+// IA32_MCG_CAP reports are touched, and only if CPUID says MCA is there at all.
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MC0_STATUS: u32 = 0x401;
+const IA32_MC0_ADDR: u32 = 0x402;
+const IA32_MC0_MISC: u32 = 0x403;
+// Each bank has CTL, STATUS, ADDR and MISC registers
+const BANK_STRIDE: u32 = 4;
+
+const CPUID_MCE: u32 = 1 << 7;
+const CPUID_MCA: u32 = 1 << 14;
+
+const MCG_CAP_COUNT: u64 = 0xFF;
+// MCG_CAP's count is 8 bits, but nothing real has more than a few dozen banks
+pub const MAX_BANKS: usize = 32;
+
+pub const STATUS_VAL: u64 = 1 << 63;
+pub const STATUS_OVER: u64 = 1 << 62;
+pub const STATUS_UC: u64 = 1 << 61;
+pub const STATUS_MISCV: u64 = 1 << 59;
+pub const STATUS_ADDRV: u64 = 1 << 58;
+pub const STATUS_PCC: u64 = 1 << 57;
+
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+// Lets tests make up MSR values
+pub trait Msrs {
+    fn read(&self, msr: u32) -> u64;
+}
+
+pub struct HardwareMsrs;
+
+impl Msrs for HardwareMsrs {
+    fn read(&self, msr: u32) -> u64 {
+        unsafe { Msr::new(msr).read() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankError {
+    pub bank: u8,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+#[allow(dead_code)]
+impl BankError {
+    pub fn mca_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    pub fn model_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    pub fn uncorrected(&self) -> bool {
+        self.status & STATUS_UC != 0
+    }
+
+    pub fn context_corrupt(&self) -> bool {
+        self.status & STATUS_PCC != 0
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "bank {}: {} error, code {:#06x} ({}), model code {:#06x}",
+            self.bank,
+            if self.uncorrected() { "uncorrected" } else { "corrected" },
+            self.mca_code(),
+            mca_category(self.mca_code()),
+            self.model_code()
+        )?;
+        if self.status & STATUS_OVER != 0 {
+            f.write_str(", overflowed")?;
+        }
+        if self.context_corrupt() {
+            f.write_str(", context corrupt")?;
+        }
+        if let Some(addr) = self.addr {
+            write!(f, ", addr {:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {:#x}", misc)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The architectural part of the error code, from the SDM's "Interpreting the
+// MCA Error Codes". Bit 12 only says whether corrected errors were filtered.
+pub fn mca_category(code: u16) -> &'static str {
+    let code = code & !(1 << 12);
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified",
+        0x0002 => "microcode ROM parity",
+        0x0003 => "external",
+        0x0004 => "FRC",
+        0x0005 => "internal parity",
+        0x0006 => "SMM handler code access violation",
+        0x0400 => "internal timer",
+        _ if code & 0xFFFC == 0x000C => "generic cache hierarchy",
+        _ if code & 0xFFF0 == 0x0010 => "TLB",
+        _ if code & 0xFF80 == 0x0080 => "memory controller",
+        _ if code & 0xFF00 == 0x0100 => "cache hierarchy",
+        _ if code & 0xF800 == 0x0800 => "bus or interconnect",
+        _ if code & 0xFC00 == 0x0400 => "internal unclassified",
+        _ => "unknown",
+    }
+}
+
+pub fn decode_bank<M: Msrs>(msrs: &M, bank: u8) -> Option<BankError> {
+    let base = bank as u32 * BANK_STRIDE;
+    let status = msrs.read(IA32_MC0_STATUS + base);
+    if status & STATUS_VAL == 0 {
+        return None;
+    }
+
+    Some(BankError {
+        bank,
+        status,
+        addr: if status & STATUS_ADDRV != 0 { Some(msrs.read(IA32_MC0_ADDR + base)) } else { None },
+        misc: if status & STATUS_MISCV != 0 { Some(msrs.read(IA32_MC0_MISC + base)) } else { None },
+    })
+}
+
+pub struct Report {
+    pub mcg_status: u64,
+    pub banks: ArrayVec<[BankError; MAX_BANKS]>,
+}
+
+pub fn report_using<M: Msrs>(msrs: &M) -> Report {
+    let count = (msrs.read(IA32_MCG_CAP) & MCG_CAP_COUNT).min(MAX_BANKS as u64) as u8;
+
+    Report {
+        mcg_status: msrs.read(IA32_MCG_STATUS),
+        banks: (0..count).filter_map(|bank| decode_bank(msrs, bank)).collect(),
+    }
+}
+
+// None if the CPU doesn't have the machine check architecture
+pub fn report() -> Option<Report> {
+    let features = unsafe { __cpuid(1) }.edx;
+    if features & (CPUID_MCE | CPUID_MCA) != CPUID_MCE | CPUID_MCA {
+        return None;
+    }
+
+    Some(report_using(&HardwareMsrs))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "mcg status {:#x}, restart ip {}valid, error ip {}valid",
+            self.mcg_status,
+            if self.mcg_status & MCG_STATUS_RIPV != 0 { "" } else { "in" },
+            if self.mcg_status & MCG_STATUS_EIPV != 0 { "" } else { "in" }
+        )?;
+        if self.banks.is_empty() {
+            writeln!(f, "no valid banks")?;
+        }
+        for bank in &self.banks {
+            writeln!(f, "{}", bank)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+    use core::fmt::Write;
+
+    // Any register not listed would fault on real hardware
+    struct MockMsrs(&'static [(u32, u64)]);
+
+    impl Msrs for MockMsrs {
+        fn read(&self, msr: u32) -> u64 {
+            match self.0.iter().find(|(reg, _)| *reg == msr) {
+                Some(&(_, value)) => value,
+                None => panic!("read of unimplemented MSR {:#x}", msr),
+            }
+        }
+    }
+
+    test_case!(machine_check_banks, {
+        // Three banks: nothing in 0, an uncorrected memory controller error
+        // with an address in 1, and an overflowed corrected one in 2
+        let msrs = MockMsrs(&[
+            (IA32_MCG_CAP, 0x0C03),
+            (IA32_MCG_STATUS, MCG_STATUS_EIPV),
+            (0x401, 0),
+            (0x405, STATUS_VAL | STATUS_UC | STATUS_ADDRV | STATUS_PCC | 0x0001_009F),
+            (0x406, 0x1234_5000),
+            (0x409, STATUS_VAL | STATUS_OVER | STATUS_MISCV | 0x0135),
+            (0x40B, 0x86),
+        ]);
+
+        let report = report_using(&msrs);
+        assert_eq!(report.mcg_status, MCG_STATUS_EIPV);
+        assert_eq!(report.banks.len(), 2);
+
+        let bank = report.banks[0];
+        assert_eq!((bank.bank, bank.addr, bank.misc), (1, Some(0x1234_5000), None));
+        assert_eq!((bank.mca_code(), bank.model_code()), (0x009F, 1));
+        assert!(bank.uncorrected() && bank.context_corrupt());
+        assert_eq!(mca_category(bank.mca_code()), "memory controller");
+
+        let bank = report.banks[1];
+        assert_eq!((bank.bank, bank.addr, bank.misc), (2, None, Some(0x86)));
+        assert!(!bank.uncorrected());
+        assert_eq!(mca_category(bank.mca_code()), "cache hierarchy");
+
+        let mut s = ArrayString::<[u8; 128]>::new();
+        write!(s, "{}", bank).unwrap();
+        assert_eq!(
+            s.as_str(),
+            "bank 2: corrected error, code 0x0135 (cache hierarchy), model code 0x0000, overflowed, misc 0x86"
+        );
+    });
+
+    test_case!(machine_check_bank_count, {
+        // Claims 255 banks, but faults on anything past MAX_BANKS
+        struct ManyBanks;
+
+        impl Msrs for ManyBanks {
+            fn read(&self, msr: u32) -> u64 {
+                match msr {
+                    IA32_MCG_CAP => 0xFF,
+                    IA32_MCG_STATUS => 0,
+                    _ if msr < IA32_MC0_STATUS + MAX_BANKS as u32 * BANK_STRIDE => STATUS_VAL,
+                    _ => panic!("read of unimplemented MSR {:#x}", msr),
+                }
+            }
+        }
+
+        assert_eq!(report_using(&ManyBanks).banks.len(), MAX_BANKS);
+        assert!(report_using(&MockMsrs(&[(IA32_MCG_CAP, 0), (IA32_MCG_STATUS, 0)])).banks.is_empty());
+
+        assert_eq!(mca_category(0x1000), "no error");
+        assert_eq!(mca_category(0x0E0B), "bus or interconnect");
+        assert_eq!(mca_category(0x0017), "TLB");
+        assert_eq!(mca_category(0x000F), "generic cache hierarchy");
+    });
+}
@@ -1,3 +1,25 @@
+pub mod debug;
+pub mod features;
+pub mod fpu;
 pub mod gdt;
+pub mod handlers;
 pub mod idt;
+pub mod interrupts;
+pub mod ioapic;
+pub mod irq_frames;
+pub mod lapic;
+pub mod mce;
 pub mod percpu;
+pub mod pic8259;
+pub mod pit;
+pub mod syscall;
+pub mod uaccess;
+pub mod watchdog;
+pub mod wp;
+
+pub use features::require_features;
+pub use idt::exception_stats;
+#[allow(unused_imports)]
+pub use percpu::current_cpu;
+#[allow(unused_imports)]
+pub use wp::with_wp_disabled;
@@ -1,10 +1,27 @@
-use crate::mm::addr_space::AddrSpace;
+use crate::{cpu::irq_frames::FrameStack, kernel::deferred::DeferredQueue, mm::addr_space::AddrSpace};
 use arrayvec::ArrayVec;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::{
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
 #[allow(dead_code)]
 pub struct PerCpu {
     addr_space: *const AddrSpace,
     preempt_count: AtomicUsize,
+    // Bumped by the timer tick, and checked by the watchdog
+    pub heartbeat: AtomicU64,
+    pub watchdog_last: AtomicU64,
+    pub watchdog_stale: AtomicU32,
+    pub deferred: DeferredQueue,
+    // Where a user copy that faults carries on from, while one is running
+    pub uaccess_fixup: AtomicU64,
+    // The interrupt handlers currently running, for panic reports
+    pub irq_frames: FrameStack,
 }
 
 unsafe impl Send for PerCpu {}
@@ -19,6 +36,12 @@ lazy_static! {
         cpus.push(PerCpu {
             addr_space: AddrSpace::kernel(),
             preempt_count: AtomicUsize::new(0),
+            heartbeat: AtomicU64::new(0),
+            watchdog_last: AtomicU64::new(0),
+            watchdog_stale: AtomicU32::new(0),
+            deferred: DeferredQueue::new(),
+            uaccess_fixup: AtomicU64::new(0),
+            irq_frames: FrameStack::new(),
         });
 
         cpus
@@ -26,8 +49,13 @@ lazy_static! {
 }
 #[allow(dead_code)]
 impl PerCpu {
+    // Locks are taken long before GS is set up, so until then this is the BSP
     pub fn current() -> &'static PerCpu {
-        &CPUS[0] // TODO: SMP
+        if GS_READY.load(Ordering::Relaxed) {
+            unsafe { &*current_cpu().percpu }
+        } else {
+            &CPUS[0]
+        }
     }
 
     pub unsafe fn preempt_inc(&self) {
@@ -56,3 +84,115 @@ impl PerCpu {
         self.preempt_count.load(ordering)
     }
 }
+
+// Per-CPU control block, reached through the GS base. While in the kernel,
+// IA32_GS_BASE points at the current CPU's block and IA32_KERNEL_GS_BASE holds
+// the user's GS base. Entry points reachable from ring 3 swapgs on entry and
+// again before returning: interrupt handlers through irq_frames::enter(), and
+// the syscall stubs themselves.
+#[repr(C)]
+pub struct CpuLocal {
+    // Must stay first, since current_cpu() loads it from gs:0
+    self_ptr: *const CpuLocal,
+    pub id: u32,
+    percpu: *const PerCpu,
+}
+
+unsafe impl Sync for CpuLocal {}
+
+impl CpuLocal {
+    pub const fn new(id: u32) -> Self {
+        Self {
+            self_ptr: ptr::null(),
+            id,
+            percpu: ptr::null(),
+        }
+    }
+}
+
+static mut BSP_LOCAL: CpuLocal = CpuLocal::new(0);
+// Set once the BSP's GS base is in place
+static GS_READY: AtomicBool = AtomicBool::new(false);
+
+// Point GS at `local` for the calling CPU. Must be done after the GDT is loaded,
+// since loading a GS selector clears the base.
+pub unsafe fn install_cpu_local(local: &'static mut CpuLocal) {
+    local.self_ptr = local;
+    GsBase::write(VirtAddr::from_ptr(local));
+    KernelGsBase::write(VirtAddr::new(0));
+}
+
+pub fn init_bsp() {
+    unsafe {
+        BSP_LOCAL.id = apic_id();
+        BSP_LOCAL.percpu = &CPUS[0];
+        install_cpu_local(&mut BSP_LOCAL);
+    }
+    GS_READY.store(true, Ordering::Release);
+}
+
+pub fn current_cpu() -> &'static CpuLocal {
+    let local: *const CpuLocal;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) local, options(nostack, preserves_flags, readonly));
+        &*local
+    }
+}
+
+// Only swaps if the interrupt arrived from user mode, since GS is already the
+// kernel's otherwise. Returns whether it did, so the way out can undo it.
+pub unsafe fn swapgs_if_from_user(frame: &InterruptStackFrame) -> bool {
+    let from_user = frame.code_segment & 0b11 != 0;
+    if from_user {
+        x86_64::instructions::segmentation::GS::swap();
+    }
+    from_user
+}
+
+pub fn apic_id() -> u32 {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(0x1) };
+    cpuid.ebx >> 24
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(current_cpu_id, {
+        let cpu = current_cpu();
+        assert_eq!(cpu.id, apic_id());
+        assert_eq!(cpu as *const CpuLocal, cpu.self_ptr);
+        assert_eq!(PerCpu::current() as *const PerCpu, &CPUS[0] as *const PerCpu);
+    });
+
+    test_case!(swapgs_only_from_user, {
+        use crate::cpu::gdt::{KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR};
+        use x86_64::structures::idt::InterruptStackFrameValue;
+
+        let frame = |cs: u16| InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(0x1000),
+            code_segment: cs as u64,
+            cpu_flags: 0x2,
+            stack_pointer: VirtAddr::new(0x2000),
+            stack_segment: 0,
+        };
+        // InterruptStackFrame is a repr(C) wrapper around the value
+        fn as_frame(value: &InterruptStackFrameValue) -> &InterruptStackFrame {
+            unsafe { &*(value as *const InterruptStackFrameValue as *const InterruptStackFrame) }
+        }
+        let kernel = GsBase::read();
+
+        // Nothing may look at per-CPU data while GS is the user's
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            assert!(!swapgs_if_from_user(as_frame(&frame(KERNEL_CODE_SELECTOR.0))));
+            assert_eq!(GsBase::read(), kernel);
+
+            assert!(swapgs_if_from_user(as_frame(&frame(USER_CODE_SELECTOR.0))));
+            let user = GsBase::read();
+            x86_64::instructions::segmentation::GS::swap();
+            assert_eq!(user, VirtAddr::new(0));
+        });
+        assert_eq!(GsBase::read(), kernel);
+        assert_eq!(current_cpu().id, apic_id());
+    });
+}
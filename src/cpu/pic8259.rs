@@ -0,0 +1,337 @@
+use crate::{cpu::interrupts, ds::SpinLock};
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+// The legacy pair of 8259 PICs. The slave is cascaded through IRQ2 on the
+// master, so slave lines only get through while IRQ2 is unmasked too.
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+const CASCADE_IRQ: u8 = 2;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0B;
+pub const MASTER_OFFSET: u8 = 32;
+pub const SLAVE_OFFSET: u8 = MASTER_OFFSET + 8;
+
+pub const TIMER_IRQ: u8 = 0;
+#[allow(dead_code)]
+pub const KEYBOARD_IRQ: u8 = 1;
+
+// Lets tests swap out the real ports
+pub trait PicPorts {
+    fn read(&self, port: u16) -> u8;
+    unsafe fn write(&self, port: u16, value: u8);
+    // Gives the PIC time to act on the last command during initialisation
+    fn io_wait(&self);
+}
+
+pub struct HardwarePorts;
+
+impl PicPorts for HardwarePorts {
+    fn read(&self, port: u16) -> u8 {
+        unsafe { PortRead::read_from_port(port) }
+    }
+
+    unsafe fn write(&self, port: u16, value: u8) {
+        PortWrite::write_to_port(port, value)
+    }
+
+    fn io_wait(&self) {
+        // Port 0x80 is unused after POST, so writing to it is harmless
+        unsafe { PortWrite::write_to_port(0x80u16, 0u8) };
+    }
+}
+
+// Serialises the read-modify-write of the mask registers
+static LOCK: SpinLock<()> = SpinLock::new(());
+
+// Remap the PICs out of the way of CPU exceptions, and mask every line.
+// Drivers unmask the lines they handle.
+pub fn init_using<P: PicPorts>(ports: &P) {
+    let icw = [
+        // ICW1: start initialisation, ICW4 follows
+        (0x11, 0x11),
+        // ICW2: vector offsets
+        (MASTER_OFFSET, SLAVE_OFFSET),
+        // ICW3: slave on IRQ2, and the slave's cascade identity
+        (1 << CASCADE_IRQ, CASCADE_IRQ),
+        // ICW4: 8086 mode
+        (0x01, 0x01),
+    ];
+
+    for (i, &(master, slave)) in icw.iter().enumerate() {
+        let (master_port, slave_port) = if i == 0 {
+            (MASTER_COMMAND, SLAVE_COMMAND)
+        } else {
+            (MASTER_DATA, SLAVE_DATA)
+        };
+
+        unsafe {
+            ports.write(master_port, master);
+            ports.io_wait();
+            ports.write(slave_port, slave);
+            ports.io_wait();
+        }
+    }
+
+    unsafe {
+        ports.write(MASTER_DATA, 0xFF);
+        ports.write(SLAVE_DATA, 0xFF);
+    }
+}
+
+pub fn mask_using<P: PicPorts>(ports: &P, irq: u8) {
+    check_irq(irq);
+
+    if irq < 8 {
+        let mask = ports.read(MASTER_DATA) | 1 << irq;
+        unsafe { ports.write(MASTER_DATA, mask) };
+    } else {
+        let mask = ports.read(SLAVE_DATA) | 1 << (irq - 8);
+        unsafe { ports.write(SLAVE_DATA, mask) };
+
+        // Nothing left to cascade
+        if mask == 0xFF {
+            let master = ports.read(MASTER_DATA) | 1 << CASCADE_IRQ;
+            unsafe { ports.write(MASTER_DATA, master) };
+        }
+    }
+}
+
+pub fn unmask_using<P: PicPorts>(ports: &P, irq: u8) {
+    check_irq(irq);
+
+    let master_bits = if irq < 8 {
+        1 << irq
+    } else {
+        let mask = ports.read(SLAVE_DATA) & !(1 << (irq - 8));
+        unsafe { ports.write(SLAVE_DATA, mask) };
+        1 << CASCADE_IRQ
+    };
+
+    let master = ports.read(MASTER_DATA);
+    if master & master_bits != 0 {
+        unsafe { ports.write(MASTER_DATA, master & !master_bits) };
+    }
+}
+
+// In-service register of both PICs, with the slave in the high byte
+pub fn read_isr_using<P: PicPorts>(ports: &P) -> u16 {
+    unsafe {
+        ports.write(MASTER_COMMAND, OCW3_READ_ISR);
+        ports.write(SLAVE_COMMAND, OCW3_READ_ISR);
+    }
+
+    (ports.read(SLAVE_COMMAND) as u16) << 8 | ports.read(MASTER_COMMAND) as u16
+}
+
+pub fn end_of_interrupt_using<P: PicPorts>(ports: &P, irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            ports.write(SLAVE_COMMAND, OCW2_EOI);
+        }
+        ports.write(MASTER_COMMAND, OCW2_EOI);
+    }
+}
+
+// When a line drops before the CPU acknowledges it, the PIC raises its lowest
+// priority line (IRQ7 or IRQ15) anyway, without marking it as in service. An
+// EOI for one of those would end some other interrupt instead, so they must
+// not be acknowledged. A spurious IRQ15 did come through the master's cascade
+// line though, so the master still needs its EOI.
+// Returns false if the interrupt was spurious and should be ignored.
+pub fn acknowledge_using<P: PicPorts>(ports: &P, irq: u8) -> bool {
+    let spurious = match irq {
+        7 | 15 => read_isr_using(ports) & 1 << irq == 0,
+        _ => false,
+    };
+
+    if !spurious {
+        end_of_interrupt_using(ports, irq);
+    } else if irq >= 8 {
+        end_of_interrupt_using(ports, CASCADE_IRQ);
+    }
+
+    !spurious
+}
+
+fn check_irq(irq: u8) {
+    BUG_ON!(irq >= 16, "pic: no such irq {}", irq);
+    // The cascade line is managed along with the slave's lines
+    BUG_ON!(irq == CASCADE_IRQ, "pic: irq 2 is the slave cascade");
+}
+
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        init_using(&HardwarePorts);
+    });
+}
+
+#[allow(dead_code)]
+pub fn mask(irq: u8) {
+    interrupts::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        mask_using(&HardwarePorts, irq);
+    });
+}
+
+#[allow(dead_code)]
+pub fn unmask(irq: u8) {
+    interrupts::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        unmask_using(&HardwarePorts, irq);
+    });
+}
+
+#[allow(dead_code)]
+pub fn end_of_interrupt(irq: u8) {
+    interrupts::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        end_of_interrupt_using(&HardwarePorts, irq);
+    });
+}
+
+pub fn acknowledge(irq: u8) -> bool {
+    interrupts::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        acknowledge_using(&HardwarePorts, irq)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+    use core::cell::{Cell, RefCell};
+
+    struct MockPorts {
+        master: Cell<u8>,
+        slave: Cell<u8>,
+        isr: Cell<u16>,
+        writes: RefCell<ArrayVec<[(u16, u8); 16]>>,
+    }
+
+    impl MockPorts {
+        fn masked() -> Self {
+            Self {
+                master: Cell::new(0xFF),
+                slave: Cell::new(0xFF),
+                isr: Cell::new(0),
+                writes: RefCell::new(ArrayVec::new()),
+            }
+        }
+
+        fn take_writes(&self) -> ArrayVec<[(u16, u8); 16]> {
+            core::mem::take(&mut *self.writes.borrow_mut())
+        }
+    }
+
+    impl PicPorts for MockPorts {
+        fn read(&self, port: u16) -> u8 {
+            match port {
+                MASTER_DATA => self.master.get(),
+                SLAVE_DATA => self.slave.get(),
+                MASTER_COMMAND => self.isr.get() as u8,
+                SLAVE_COMMAND => (self.isr.get() >> 8) as u8,
+                _ => panic!("unexpected read from port {:#x}", port),
+            }
+        }
+
+        unsafe fn write(&self, port: u16, value: u8) {
+            match port {
+                MASTER_DATA => self.master.set(value),
+                SLAVE_DATA => self.slave.set(value),
+                _ => {}
+            }
+            self.writes.borrow_mut().push((port, value));
+        }
+
+        fn io_wait(&self) {}
+    }
+
+    test_case!(pic_master_lines, {
+        let ports = MockPorts::masked();
+
+        unmask_using(&ports, KEYBOARD_IRQ);
+        assert_eq!(&ports.take_writes()[..], &[(MASTER_DATA, 0xFD)]);
+        unmask_using(&ports, 7);
+        assert_eq!(&ports.take_writes()[..], &[(MASTER_DATA, 0x7D)]);
+
+        mask_using(&ports, KEYBOARD_IRQ);
+        assert_eq!(&ports.take_writes()[..], &[(MASTER_DATA, 0x7F)]);
+        assert_eq!(ports.slave.get(), 0xFF);
+    });
+
+    test_case!(pic_slave_lines, {
+        let ports = MockPorts::masked();
+
+        // Unmasking a slave line opens the cascade as well
+        unmask_using(&ports, 8);
+        assert_eq!(&ports.take_writes()[..], &[(SLAVE_DATA, 0xFE), (MASTER_DATA, 0xFB)]);
+
+        // ...but only once
+        unmask_using(&ports, 12);
+        assert_eq!(&ports.take_writes()[..], &[(SLAVE_DATA, 0xEE)]);
+
+        mask_using(&ports, 8);
+        assert_eq!(&ports.take_writes()[..], &[(SLAVE_DATA, 0xEF)]);
+
+        // The cascade closes with the last slave line
+        mask_using(&ports, 12);
+        assert_eq!(&ports.take_writes()[..], &[(SLAVE_DATA, 0xFF), (MASTER_DATA, 0xFF)]);
+
+        unmask_using(&ports, 15);
+        assert_eq!(ports.slave.get(), 0x7F);
+        assert_eq!(ports.master.get(), 0xFB);
+    });
+
+    test_case!(pic_init_masks_everything, {
+        let ports = MockPorts {
+            master: Cell::new(0),
+            slave: Cell::new(0),
+            isr: Cell::new(0),
+            writes: RefCell::new(ArrayVec::new()),
+        };
+
+        init_using(&ports);
+        let writes = ports.take_writes();
+        assert_eq!(writes[0], (MASTER_COMMAND, 0x11));
+        assert_eq!(writes[2], (MASTER_DATA, MASTER_OFFSET));
+        assert_eq!(writes[3], (SLAVE_DATA, SLAVE_OFFSET));
+        assert_eq!((ports.master.get(), ports.slave.get()), (0xFF, 0xFF));
+    });
+
+    test_case!(pic_spurious_irqs, {
+        let ports = MockPorts::masked();
+        let read_isr = [(MASTER_COMMAND, OCW3_READ_ISR), (SLAVE_COMMAND, OCW3_READ_ISR)];
+
+        // A real IRQ7 is in service, and gets its EOI
+        ports.isr.set(1 << 7);
+        assert!(acknowledge_using(&ports, 7));
+        assert_eq!(&ports.take_writes()[..], &[read_isr[0], read_isr[1], (MASTER_COMMAND, OCW2_EOI)]);
+
+        // A spurious one isn't, even if something else is
+        ports.isr.set(1 << 1);
+        assert!(!acknowledge_using(&ports, 7));
+        assert_eq!(&ports.take_writes()[..], &read_isr[..]);
+
+        // A spurious IRQ15 still ends the master's cascade interrupt
+        ports.isr.set(1 << CASCADE_IRQ);
+        assert!(!acknowledge_using(&ports, 15));
+        assert_eq!(&ports.take_writes()[..], &[read_isr[0], read_isr[1], (MASTER_COMMAND, OCW2_EOI)]);
+
+        ports.isr.set(1 << 15 | 1 << CASCADE_IRQ);
+        assert!(acknowledge_using(&ports, 15));
+        assert_eq!(
+            &ports.take_writes()[2..],
+            &[(SLAVE_COMMAND, OCW2_EOI), (MASTER_COMMAND, OCW2_EOI)]
+        );
+
+        // Other lines can't be spurious, so the ISR isn't read at all
+        assert!(acknowledge_using(&ports, KEYBOARD_IRQ));
+        assert_eq!(&ports.take_writes()[..], &[(MASTER_COMMAND, OCW2_EOI)]);
+    });
+}
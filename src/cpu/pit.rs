@@ -0,0 +1,140 @@
+use crate::{cpu::interrupts, kernel::time};
+use core::arch::x86_64::_rdtsc;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+// Channel 2 of the 8254 PIT, used as a known-good clock for short delays before
+// anything better is set up. Unlike channel 0 it's gated by software and its
+// output can be polled, so nothing here needs interrupts.
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0_DATA: u16 = 0x40;
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// Bit 0 gates channel 2, bit 1 connects it to the speaker, and bit 5 reads back
+// its output
+const SPEAKER_CONTROL: u16 = 0x61;
+
+// Channel 2, low then high byte, mode 0 (output goes high at terminal count)
+const CHANNEL_2_ONESHOT: u8 = 0b1011_0000;
+// Channel 0, low then high byte, mode 2 (rate generator)
+const CHANNEL_0_PERIODIC: u8 = 0b0011_0100;
+
+const CALIBRATION_MS: u64 = 10;
+const CALIBRATION_RUNS: usize = 3;
+
+fn count_for_ms(ms: u64) -> u64 {
+    PIT_FREQUENCY * ms / 1000
+}
+
+// Counts down from `count` and waits for it to run out
+fn oneshot(count: u16) {
+    unsafe {
+        // Gate off and speaker disconnected while programming
+        let control: u8 = PortRead::read_from_port(SPEAKER_CONTROL);
+        PortWrite::write_to_port(SPEAKER_CONTROL, control & !0b11);
+
+        PortWrite::write_to_port(COMMAND, CHANNEL_2_ONESHOT);
+        PortWrite::write_to_port(CHANNEL_2_DATA, count as u8);
+        PortWrite::write_to_port(CHANNEL_2_DATA, (count >> 8) as u8);
+
+        // Raising the gate starts the count
+        PortWrite::write_to_port(SPEAKER_CONTROL, control & !0b10 | 0b1);
+        while u8::read_from_port(SPEAKER_CONTROL) & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Busy-waits, so it's only meant for early boot (or after a panic)
+pub fn sleep_ms(ms: u64) {
+    let mut remaining = count_for_ms(ms);
+    while remaining > 0 {
+        let count = remaining.min(u16::MAX as u64);
+        oneshot(count as u16);
+        remaining -= count;
+    }
+}
+
+// Reload value for a periodic rate as close to `hz` as the PIT can get
+pub fn divisor_for(hz: u64) -> u16 {
+    let divisor = (PIT_FREQUENCY + hz / 2) / hz;
+    divisor.max(1).min(u16::MAX as u64) as u16
+}
+
+// The rate a reload value gives, to the nearest Hz. Rounding down would make
+// 100Hz come out as 99, and the uptime run 1% fast.
+pub fn rate_for(divisor: u16) -> u64 {
+    let divisor = divisor.max(1) as u64;
+    (PIT_FREQUENCY + divisor / 2) / divisor
+}
+
+// Drives the timer tick from channel 0 on IRQ0. Whoever calls this still has to
+// unmask the IRQ.
+#[allow(dead_code)]
+pub fn start_periodic(hz: u64) {
+    let divisor = divisor_for(hz);
+    interrupts::without_interrupts(|| unsafe {
+        PortWrite::write_to_port(COMMAND, CHANNEL_0_PERIODIC);
+        PortWrite::write_to_port(CHANNEL_0_DATA, divisor as u8);
+        PortWrite::write_to_port(CHANNEL_0_DATA, (divisor >> 8) as u8);
+    });
+
+    time::set_frequency(rate_for(divisor));
+}
+
+pub fn tsc_frequency(tsc_delta: u64, pit_count: u64) -> u64 {
+    (tsc_delta as u128 * PIT_FREQUENCY as u128 / pit_count as u128) as u64
+}
+
+// Returns the TSC frequency in Hz. Takes the shortest of a few runs, since an
+// SMI or a slow port access can only ever make a run look longer.
+pub fn calibrate_tsc() -> u64 {
+    let count = count_for_ms(CALIBRATION_MS);
+
+    let delta = interrupts::without_interrupts(|| {
+        (0..CALIBRATION_RUNS)
+            .map(|_| unsafe {
+                let start = _rdtsc();
+                oneshot(count as u16);
+                _rdtsc() - start
+            })
+            .min()
+            .unwrap()
+    });
+
+    tsc_frequency(delta, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(tsc_frequency_from_pit, {
+        let count = count_for_ms(CALIBRATION_MS);
+        assert_eq!(count, 11_931);
+
+        // A 2GHz TSC over the same 11931 PIT ticks, which is just short of 10ms
+        assert_eq!(tsc_frequency(19_998_625, count), 1_999_999_947);
+
+        // Exact when the TSC delta is a whole multiple of the PIT count
+        assert_eq!(tsc_frequency(3 * PIT_FREQUENCY, PIT_FREQUENCY), 3 * PIT_FREQUENCY);
+        assert_eq!(tsc_frequency(0, count), 0);
+    });
+
+    test_case!(pit_divisor, {
+        assert_eq!(divisor_for(100), 11_932);
+        assert_eq!(divisor_for(1000), 1193);
+        // Out of range either way gets clamped
+        assert_eq!(divisor_for(1), u16::MAX);
+        assert_eq!(divisor_for(10 * PIT_FREQUENCY), 1);
+    });
+
+    test_case!(pit_rate, {
+        // 1193182 / 11932 is 99.998
+        assert_eq!(rate_for(divisor_for(100)), 100);
+        assert_eq!(rate_for(divisor_for(1000)), 1000);
+        assert_eq!(rate_for(1), PIT_FREQUENCY);
+        assert_eq!(rate_for(u16::MAX), 18);
+    });
+}
@@ -0,0 +1,358 @@
+use crate::{
+    cpu::{
+        fpu::{Cpuid, HardwareCpuid},
+        gdt::KERNEL_CODE_SELECTOR,
+    },
+    ds::InitCell,
+    kernel::time,
+};
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::{
+        gdt::SegmentSelector,
+        idt::{self, InterruptDescriptorTable},
+    },
+    PrivilegeLevel,
+    VirtAddr,
+};
+
+// The legacy `int 0x80` syscall entry, which is simpler to bring up than
+// SYSCALL/SYSRET. The number goes in rax and up to six arguments in rdi, rsi,
+// rdx, r10, r8 and r9, the same registers SYSCALL will use. The result comes
+// back in rax, with errors as a negated errno. Every other register is
+// preserved.
+
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+pub const SYS_UPTIME_MS: u64 = 0;
+#[cfg(test)]
+const SYS_TEST: u64 = 0x7E57;
+
+const ENOSYS: i64 = 38;
+
+// Caller-saved registers, as pushed by the entry stub, lowest address first.
+// The interrupt stack frame sits right above.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallRegs {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+}
+
+impl SyscallRegs {
+    fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+// The stub only has to save what a Rust function is allowed to clobber. The
+// CPU leaves the stack 16 byte aligned less the 5 word frame, and the 9 pushes
+// bring it back to alignment for the call. GS only needs swapping if the
+// interrupt came from user mode.
+global_asm!(
+    "
+    .global syscall_entry
+    syscall_entry:
+        test qword ptr [rsp + 8], 3
+        jz 1f
+        swapgs
+    1:
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rax
+        mov rdi, rsp
+        cld
+        call syscall_dispatch
+        pop rax
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+        test qword ptr [rsp + 8], 3
+        jz 2f
+        swapgs
+    2:
+        iretq
+    "
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// DPL 3, so user code is allowed to raise it
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // Not really an x86-interrupt function, but the IDT only wants the address
+    let entry: idt::HandlerFunc = unsafe { core::mem::transmute(syscall_entry as unsafe extern "C" fn()) };
+    idt[SYSCALL_VECTOR as usize]
+        .set_handler_fn(entry)
+        .set_privilege_level(PrivilegeLevel::Ring3);
+}
+
+// The fast way in for user code, picked at boot from what the CPU has. Nothing
+// enters through SYSCALL yet, so for now it only means int 0x80 will do until
+// that entry exists. SYSENTER is the fallback for CPUs without it, which AMD
+// only allows outside long mode, and int 0x80 always works.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastPath {
+    Syscall,
+    Sysenter,
+    Int80,
+}
+
+const CPUID_EXTENDED: u32 = 0x8000_0000;
+// In edx of leaf 0x8000_0001 and leaf 1
+const CPUID_SYSCALL: u32 = 1 << 11;
+const CPUID_SEP: u32 = 1 << 11;
+// The vendor string, in ebx, edx and ecx
+const AMD_VENDOR: [u32; 3] = [0x6874_7541, 0x6974_6E65, 0x444D_4163];
+
+pub fn select_fast_path<C: Cpuid>(cpuid: &C) -> FastPath {
+    let extended = cpuid.cpuid(CPUID_EXTENDED, 0).eax;
+    if extended >= CPUID_EXTENDED | 1 && cpuid.cpuid(CPUID_EXTENDED | 1, 0).edx & CPUID_SYSCALL != 0 {
+        return FastPath::Syscall;
+    }
+
+    let vendor = cpuid.cpuid(0, 0);
+    let amd = [vendor.ebx, vendor.edx, vendor.ecx] == AMD_VENDOR;
+    if !amd && cpuid.cpuid(1, 0).edx & CPUID_SEP != 0 {
+        FastPath::Sysenter
+    } else {
+        FastPath::Int80
+    }
+}
+
+const IA32_SYSENTER_CS: u32 = 0x174;
+const IA32_SYSENTER_ESP: u32 = 0x175;
+const IA32_SYSENTER_EIP: u32 = 0x176;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysenterMsrs {
+    pub cs: u64,
+    pub esp: u64,
+    pub eip: u64,
+}
+
+// SYSENTER takes CS from the MSR and SS from the selector after it, and
+// SYSEXIT the user ones 32 and 40 bytes on, which is how gdt lays them out
+pub fn sysenter_msrs(kernel_cs: SegmentSelector, stack_top: VirtAddr, entry: VirtAddr) -> SysenterMsrs {
+    SysenterMsrs {
+        cs: kernel_cs.0 as u64,
+        esp: stack_top.as_u64(),
+        eip: entry.as_u64(),
+    }
+}
+
+// SYSENTER doesn't switch stacks through the TSS, it just loads this
+const SYSENTER_STACK_SIZE: usize = 4096 * 4;
+static mut SYSENTER_STACK: [u8; SYSENTER_STACK_SIZE] = [0; SYSENTER_STACK_SIZE]; // TODO: SMP
+
+fn sysenter_config() -> SysenterMsrs {
+    let stack = VirtAddr::from_ptr(unsafe { &SYSENTER_STACK });
+    let entry = VirtAddr::new(sysenter_entry as unsafe extern "C" fn() as usize as u64);
+    sysenter_msrs(KERNEL_CODE_SELECTOR, (stack + SYSENTER_STACK_SIZE).align_down(16u64), entry)
+}
+
+static FAST_PATH: InitCell<FastPath> = InitCell::new();
+
+pub fn init_fast_path() {
+    let path = select_fast_path(&HardwareCpuid);
+    if path == FastPath::Sysenter {
+        let msrs = sysenter_config();
+        unsafe {
+            Msr::new(IA32_SYSENTER_CS).write(msrs.cs);
+            Msr::new(IA32_SYSENTER_ESP).write(msrs.esp);
+            Msr::new(IA32_SYSENTER_EIP).write(msrs.eip);
+        }
+    }
+
+    FAST_PATH.init(path);
+    info!("syscall: fast path is {:?}", path);
+}
+
+#[allow(dead_code)]
+pub fn fast_path() -> FastPath {
+    *FAST_PATH.try_get().expect("syscall: init_fast_path() hasn't run")
+}
+
+// The same registers and results as int 0x80, except that SYSENTER doesn't
+// save where it came from, so user code puts its stack pointer in rcx and the
+// address to return to in r11, and gets rdx back clobbered. SYSENTER turns
+// interrupts off and SYSEXIT doesn't turn them back on, hence the sti, which
+// only takes effect after the sysexit. The MSR's stack top is 16 byte aligned,
+// so the 9 pushes need one more word to keep it that way for the call.
+global_asm!(
+    "
+    .global sysenter_entry
+    sysenter_entry:
+        swapgs
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rax
+        mov rdi, rsp
+        sub rsp, 8
+        cld
+        call syscall_dispatch
+        add rsp, 8
+        pop rax
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+        mov rdx, r11
+        swapgs
+        sti
+        sysexitq
+    "
+);
+
+extern "C" {
+    fn sysenter_entry();
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(regs: &mut SyscallRegs) {
+    regs.rax = dispatch(regs.rax, regs.args());
+}
+
+// Only the test syscall takes arguments so far
+#[allow(unused_variables)]
+fn dispatch(nr: u64, args: [u64; 6]) -> u64 {
+    match nr {
+        SYS_UPTIME_MS => time::uptime().as_millis() as u64,
+        #[cfg(test)]
+        SYS_TEST => tests::sys_test(args),
+        _ => {
+            debug!("syscall: unknown syscall {:#x}", nr);
+            -ENOSYS as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        arch::x86_64::CpuidResult,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    static TEST_CALLS: AtomicU64 = AtomicU64::new(0);
+
+    pub fn sys_test(args: [u64; 6]) -> u64 {
+        TEST_CALLS.fetch_add(1, Ordering::Relaxed);
+        args.iter().enumerate().map(|(i, arg)| arg << (i * 8)).sum()
+    }
+
+    unsafe fn int80(nr: u64, args: [u64; 6]) -> u64 {
+        let ret;
+        asm!(
+            "int 0x80",
+            inlateout("rax") nr => ret,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
+        );
+        ret
+    }
+
+    struct MockCpuid {
+        vendor: [u32; 3],
+        max_extended: u32,
+        edx_1: u32,
+        edx_ext_1: u32,
+    }
+
+    impl Cpuid for MockCpuid {
+        fn cpuid(&self, leaf: u32, _subleaf: u32) -> CpuidResult {
+            let (eax, ebx, ecx, edx) = match leaf {
+                0 => (1, self.vendor[0], self.vendor[2], self.vendor[1]),
+                1 => (0, 0, 0, self.edx_1),
+                CPUID_EXTENDED => (self.max_extended, 0, 0, 0),
+                _ if leaf == CPUID_EXTENDED | 1 => (0, 0, 0, self.edx_ext_1),
+                _ => (0, 0, 0, 0),
+            };
+            CpuidResult { eax, ebx, ecx, edx }
+        }
+    }
+
+    test_case!(fast_path_selection, {
+        // "GenuineIntel"
+        let intel = [0x756E_6547, 0x4965_6E69, 0x6C65_746E];
+        let cpu = |vendor, max_extended, edx_1, edx_ext_1| MockCpuid { vendor, max_extended, edx_1, edx_ext_1 };
+
+        assert_eq!(select_fast_path(&cpu(intel, CPUID_EXTENDED | 8, CPUID_SEP, CPUID_SYSCALL)), FastPath::Syscall);
+        assert_eq!(select_fast_path(&cpu(AMD_VENDOR, CPUID_EXTENDED | 8, 0, CPUID_SYSCALL)), FastPath::Syscall);
+
+        // No SYSCALL, or no extended leaf to say so
+        assert_eq!(select_fast_path(&cpu(intel, CPUID_EXTENDED | 8, CPUID_SEP, 0)), FastPath::Sysenter);
+        assert_eq!(select_fast_path(&cpu(intel, CPUID_EXTENDED, CPUID_SEP, CPUID_SYSCALL)), FastPath::Sysenter);
+
+        // AMD has SYSENTER, but not in long mode
+        assert_eq!(select_fast_path(&cpu(AMD_VENDOR, CPUID_EXTENDED | 8, CPUID_SEP, 0)), FastPath::Int80);
+        assert_eq!(select_fast_path(&cpu(intel, CPUID_EXTENDED | 8, 0, 0)), FastPath::Int80);
+    });
+
+    test_case!(sysenter_msr_values, {
+        let msrs = sysenter_msrs(KERNEL_CODE_SELECTOR, VirtAddr::new(0x8000), VirtAddr::new(0x1234));
+        assert_eq!(msrs, SysenterMsrs { cs: 0x08, esp: 0x8000, eip: 0x1234 });
+
+        // The real thing enters the stub on its own stack, aligned for the call
+        let msrs = sysenter_config();
+        assert_eq!(msrs.cs, 0x08);
+        assert_eq!(msrs.eip, sysenter_entry as unsafe extern "C" fn() as usize as u64);
+        assert_eq!(msrs.esp % 16, 0);
+        let stack = unsafe { SYSENTER_STACK.as_ptr() } as u64;
+        assert!(msrs.esp <= stack + SYSENTER_STACK_SIZE as u64 && msrs.esp > stack + SYSENTER_STACK_SIZE as u64 - 16);
+
+        // The segments the MSR implies are the ones in the GDT
+        use crate::cpu::gdt::{KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+        assert_eq!(KERNEL_DATA_SELECTOR.0 as u64, msrs.cs + 8);
+        assert_eq!(USER_CODE_SELECTOR.0 as u64 & !3, msrs.cs + 32);
+        assert_eq!(USER_DATA_SELECTOR.0 as u64 & !3, msrs.cs + 40);
+    });
+
+    test_case!(int80_dispatch, {
+        let calls = TEST_CALLS.load(Ordering::Relaxed);
+        let ret = unsafe { int80(SYS_TEST, [1, 2, 3, 4, 5, 6]) };
+        assert_eq!(ret, 0x06_05_04_03_02_01);
+        assert_eq!(TEST_CALLS.load(Ordering::Relaxed), calls + 1);
+
+        // Routed elsewhere, the test handler doesn't run
+        let before = time::uptime().as_millis() as u64;
+        let uptime = unsafe { int80(SYS_UPTIME_MS, [0; 6]) };
+        assert!(uptime >= before && uptime <= time::uptime().as_millis() as u64);
+        assert_eq!(unsafe { int80(0xDEAD, [0; 6]) }, -ENOSYS as u64);
+        assert_eq!(TEST_CALLS.load(Ordering::Relaxed), calls + 1);
+    });
+}
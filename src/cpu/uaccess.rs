@@ -0,0 +1,175 @@
+use crate::{
+    cpu::percpu::PerCpu,
+    mm::addr_space::{self, USER_END},
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{Page, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+// Reading and writing memory on behalf of userspace. Pointers are checked
+// against the active address space first, but the user can unmap memory at
+// any time, so the copies themselves survive a page fault too: the fault
+// handler sends them to a fixup that reports how much was left.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Efault;
+
+// Length of the `rep movsb` the fixup follows
+const COPY_INSN_LEN: u64 = 2;
+
+// Only checks where the range lies, not what's mapped there
+pub fn user_range(addr: VirtAddr, len: usize) -> Result<(), Efault> {
+    let end = addr.as_u64().checked_add(len as u64).ok_or(Efault)?;
+    if end > USER_END {
+        return Err(Efault);
+    }
+
+    Ok(())
+}
+
+fn validate(addr: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), Efault> {
+    user_range(addr, len)?;
+    if len == 0 {
+        return Ok(());
+    }
+
+    let first = Page::<Size4KiB>::containing_address(addr);
+    let last = Page::<Size4KiB>::containing_address(addr + (len - 1));
+    if Page::range_inclusive(first, last).all(|page| addr_space::walk_active(page.start_address()).allows(flags)) {
+        Ok(())
+    } else {
+        Err(Efault)
+    }
+}
+
+// The whole range is in the user half and mapped for userspace to read
+pub fn validate_user_ptr(addr: VirtAddr, len: usize) -> Result<(), Efault> {
+    validate(addr, len, PageTableFlags::USER_ACCESSIBLE)
+}
+
+#[allow(dead_code)]
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), Efault> {
+    validate_user_ptr(src, dst.len())?;
+    match unsafe { copy_catching_faults(dst.as_mut_ptr(), src.as_ptr(), dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Efault),
+    }
+}
+
+#[allow(dead_code)]
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), Efault> {
+    validate(dst, src.len(), PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE)?;
+    match unsafe { copy_catching_faults(dst.as_mut_ptr(), src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Efault),
+    }
+}
+
+// Returns the number of bytes that weren't copied, which is only non-zero if
+// it faulted. rcx counts down as `rep movsb` goes, and still holds what's
+// left when the fault handler moves it on to the fixup.
+unsafe fn copy_catching_faults(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let fixup: *const AtomicU64 = &PerCpu::current().uaccess_fixup;
+    let left: usize;
+    asm!(
+        "lea {tmp}, [rip + 2f]",
+        "mov [{fixup}], {tmp}",
+        "rep movsb",
+        "2:",
+        "mov qword ptr [{fixup}], 0",
+        fixup = in(reg) fixup,
+        tmp = out(reg) _,
+        inout("rcx") len => left,
+        inout("rdi") dst => _,
+        inout("rsi") src => _,
+        options(nostack, preserves_flags),
+    );
+    left
+}
+
+// Called by the page fault handler for faults it couldn't resolve. Only a
+// fault on the copy instruction itself is fixed up, not one in some interrupt
+// handler that happened to run during the copy.
+pub fn fixup_fault(frame: &mut InterruptStackFrame) -> bool {
+    let fixup = PerCpu::current().uaccess_fixup.load(Ordering::Relaxed);
+    if fixup == 0 || frame.instruction_pointer.as_u64() != fixup - COPY_INSN_LEN {
+        return false;
+    }
+
+    unsafe { frame.as_mut().update(|frame| frame.instruction_pointer = VirtAddr::new(fixup)) };
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::{addr_space::AddrSpace, phys_to_kernel_virt, pmm::PhysAllocator};
+    use x86_64::structures::paging::PhysFrame;
+
+    test_case!(user_range_checks, {
+        let v = VirtAddr::new;
+        assert_eq!(user_range(v(0x1000), 0x10), Ok(()));
+        assert_eq!(user_range(v(0), 0), Ok(()));
+        assert_eq!(user_range(v(USER_END - 0x10), 0x10), Ok(()));
+
+        // Running into the kernel half, or starting there
+        assert_eq!(user_range(v(USER_END - 0x10), 0x11), Err(Efault));
+        assert_eq!(user_range(v(0xFFFF_8000_0000_0000), 1), Err(Efault));
+        assert_eq!(user_range(v(0xFFFF_8000_0000_0000), 0), Err(Efault));
+
+        // Wrapping around the top of the address space
+        assert_eq!(user_range(v(0xFFFF_FFFF_FFFF_F000), 0x2000), Err(Efault));
+        assert_eq!(user_range(v(0x1000), usize::MAX), Err(Efault));
+    });
+
+    test_case!(user_copies, {
+        let space = AddrSpace::new_user();
+        let base = VirtAddr::new(0x0000_7000_0000_0000);
+        let page = 0x1000u64;
+        let frames = PhysAllocator::alloc(1);
+        let (rw, ro) = (frames.start, frames.start + 1);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        // Read-write, read-only, and kernel-only pages, then a hole
+        space.map_user(base, rw.start_address(), flags).unwrap().ignore();
+        space.map_user(base + page, ro.start_address(), PageTableFlags::PRESENT).unwrap().ignore();
+        let kernel_only = PhysAllocator::alloc(0).start;
+        space.map_to(base + 2 * page, kernel_only.start_address(), flags).unwrap().ignore();
+        unsafe { *phys_to_kernel_virt(ro.start_address()).as_mut_ptr::<u32>() = 0x1234_5678 };
+
+        unsafe { space.switch_to() };
+        let checks = (
+            validate_user_ptr(base + 0xFF0u64, 0x20),
+            validate_user_ptr(base + 0xFF0u64, 0x1011),
+            validate_user_ptr(base + 3 * page, 1),
+            validate(base + 0xFF0u64, 0x20, PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE),
+        );
+
+        let mut buf = [0u8; 4];
+        let read = copy_from_user(&mut buf, base + page);
+        let write = copy_to_user(base + 0x10u64, &[1, 2, 3]);
+        let write_ro = copy_to_user(base + page, &[1]);
+
+        // As if the page had been unmapped between the check and the copy
+        let fault = unsafe { copy_catching_faults(buf.as_mut_ptr(), (base + 3 * page).as_ptr(), 4) };
+        unsafe { AddrSpace::kernel().switch_to() };
+
+        assert_eq!(checks, (Ok(()), Err(Efault), Err(Efault), Err(Efault)));
+        assert_eq!((read, buf), (Ok(()), 0x1234_5678u32.to_ne_bytes()));
+        assert_eq!(write, Ok(()));
+        assert_eq!(write_ro, Err(Efault));
+        assert_eq!(fault, 4);
+        assert_eq!(PerCpu::current().uaccess_fixup.load(Ordering::Relaxed), 0);
+        let written = unsafe { *phys_to_kernel_virt(rw.start_address() + 0x10u64).as_ptr::<[u8; 3]>() };
+        assert_eq!(written, [1, 2, 3]);
+
+        drop(space);
+        PhysAllocator::free(frames);
+        PhysAllocator::free(PhysFrame::range(kernel_only, kernel_only + 1));
+    });
+}
@@ -0,0 +1,180 @@
+use crate::cpu::{lapic, percpu::PerCpu};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+// Catches hard hangs, e.g. a deadlock with interrupts disabled. The timer tick
+// bumps a per-CPU heartbeat, and the local APIC's performance counter fires an
+// NMI every `period` cycles, which gets through even with interrupts off. If
+// the heartbeat hasn't moved for `threshold` NMIs in a row, the CPU is stuck.
+//
+// Not wired up yet: time::tick() feeds the heartbeat, but nothing drives the
+// tick at boot, so kernel init doesn't call start(), which would only see a
+// hang straight away. Only the tests run it for now.
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+// PMC0's bit in the global status and overflow control MSRs
+const PMC0_OVERFLOW: u64 = 1;
+
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+// Unhalted core cycles, in both rings, interrupting on overflow
+const EVENT_UNHALTED_CYCLES: u64 = 0x3C | 1 << 16 | 1 << 17 | 1 << 20 | 1 << 22;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static THRESHOLD: AtomicU32 = AtomicU32::new(0);
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Alive,
+    // Number of NMIs in a row without a heartbeat
+    Stale(u32),
+    Hung(u32),
+}
+
+// `last` is the heartbeat seen at the previous NMI, and `stale` the number of
+// NMIs it had already been stuck for
+pub fn check(last: u64, now: u64, stale: u32, threshold: u32) -> Verdict {
+    if now != last {
+        Verdict::Alive
+    } else if stale + 1 >= threshold {
+        Verdict::Hung(stale + 1)
+    } else {
+        Verdict::Stale(stale + 1)
+    }
+}
+
+// Whether IA32_PERF_GLOBAL_STATUS says the watchdog's counter overflowed, which
+// is the only way an NMI can be the watchdog's
+pub fn counter_overflowed(global_status: u64) -> bool {
+    global_status & PMC0_OVERFLOW != 0
+}
+
+// Called from the timer tick
+pub fn heartbeat() {
+    PerCpu::current().heartbeat.fetch_add(1, Ordering::Relaxed);
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    NoPerfCounters,
+}
+
+// Start the NMI source on this CPU. Only call this once something is driving
+// the timer tick, or the watchdog will fire straight away. `period` is in
+// cycles, and is limited to 31 bits by how the counter is reloaded.
+#[allow(dead_code)]
+pub fn start(period: u32, threshold: u32) -> Result<(), WatchdogError> {
+    assert!(period > 0 && period < 1 << 31 && threshold > 0);
+
+    // Architectural performance monitoring, version 2 or later for the global
+    // status MSRs
+    let leaf = unsafe { core::arch::x86_64::__cpuid(0xA) };
+    if leaf.eax & 0xFF < 2 || (leaf.eax >> 8) & 0xFF == 0 {
+        return Err(WatchdogError::NoPerfCounters);
+    }
+
+    PERIOD.store(period, Ordering::Relaxed);
+    THRESHOLD.store(threshold, Ordering::Relaxed);
+
+    let cpu = PerCpu::current();
+    cpu.watchdog_last.store(cpu.heartbeat.load(Ordering::Relaxed), Ordering::Relaxed);
+    cpu.watchdog_stale.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+
+    unsafe {
+        write_lvt_perf(LVT_DELIVERY_NMI);
+        reload_counter(period);
+        Msr::new(IA32_PERFEVTSEL0).write(EVENT_UNHALTED_CYCLES);
+    }
+    // Catch a bad LVT write now rather than when the first NMI doesn't come
+    lapic::check_errors();
+
+    info!("watchdog: started, {} cycle period, threshold {}", period, threshold);
+    Ok(())
+}
+
+// Called from the NMI handler. Returns false if the NMI isn't the watchdog's,
+// because it isn't running or its counter hasn't overflowed, in which case the
+// NMI came from somewhere else.
+pub fn handle_nmi() -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return false;
+    }
+    if !counter_overflowed(unsafe { Msr::new(IA32_PERF_GLOBAL_STATUS).read() }) {
+        return false;
+    }
+    unsafe { Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(PMC0_OVERFLOW) };
+
+    let cpu = PerCpu::current();
+    let now = cpu.heartbeat.load(Ordering::Relaxed);
+    let last = cpu.watchdog_last.swap(now, Ordering::Relaxed);
+    let stale = cpu.watchdog_stale.load(Ordering::Relaxed);
+
+    match check(last, now, stale, THRESHOLD.load(Ordering::Relaxed)) {
+        Verdict::Alive => cpu.watchdog_stale.store(0, Ordering::Relaxed),
+        Verdict::Stale(n) => cpu.watchdog_stale.store(n, Ordering::Relaxed),
+        Verdict::Hung(n) => {
+            // Don't fire again while panicking
+            ENABLED.store(false, Ordering::Relaxed);
+            panic!("watchdog: hard hang, no timer tick in {} watchdog periods", n);
+        }
+    }
+
+    // The LVT entry masks itself when it fires
+    unsafe {
+        reload_counter(PERIOD.load(Ordering::Relaxed));
+        write_lvt_perf(LVT_DELIVERY_NMI);
+    }
+
+    true
+}
+
+// Writes to PMC0 only set the low 32 bits, and sign extend them
+unsafe fn reload_counter(period: u32) {
+    Msr::new(IA32_PMC0).write((-(period as i64)) as u64 & 0xFFFF_FFFF);
+}
+
+unsafe fn write_lvt_perf(value: u32) {
+    lapic::write(lapic::LVT_PERF, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(watchdog_staleness, {
+        assert_eq!(check(5, 6, 0, 3), Verdict::Alive);
+        // A tick arriving resets things, however stale it had got
+        assert_eq!(check(5, 6, 2, 3), Verdict::Alive);
+
+        assert_eq!(check(5, 5, 0, 3), Verdict::Stale(1));
+        assert_eq!(check(5, 5, 1, 3), Verdict::Stale(2));
+        assert_eq!(check(5, 5, 2, 3), Verdict::Hung(3));
+
+        assert_eq!(check(0, 0, 0, 1), Verdict::Hung(1));
+        // Wrapping counts as progress
+        assert_eq!(check(u64::MAX, 0, 2, 3), Verdict::Alive);
+    });
+
+    test_case!(watchdog_claims_own_overflow, {
+        assert!(counter_overflowed(PMC0_OVERFLOW));
+        assert!(counter_overflowed(PMC0_OVERFLOW | 1 << 62));
+        // Nothing overflowed, or only other counters, e.g. PMC1 and fixed
+        // counter 0
+        assert!(!counter_overflowed(0));
+        assert!(!counter_overflowed(1 << 1 | 1 << 32));
+    });
+
+    test_case!(heartbeat_advances, {
+        let cpu = PerCpu::current();
+        let before = cpu.heartbeat.load(Ordering::Relaxed);
+        heartbeat();
+        assert_eq!(cpu.heartbeat.load(Ordering::Relaxed), before + 1);
+    });
+}
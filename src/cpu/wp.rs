@@ -0,0 +1,130 @@
+use crate::cpu::interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+// Lets tests swap out the real register
+pub trait Cr0Access {
+    fn read(&self) -> Cr0Flags;
+    unsafe fn write(&self, flags: Cr0Flags);
+}
+
+pub struct HardwareCr0;
+
+impl Cr0Access for HardwareCr0 {
+    fn read(&self) -> Cr0Flags {
+        Cr0::read()
+    }
+
+    unsafe fn write(&self, flags: Cr0Flags) {
+        Cr0::write(flags)
+    }
+}
+
+// Restores WP once the closure returns. The kernel is built with panic=abort,
+// so nothing unwinds: after a panic inside, WP stays clear.
+struct RestoreWp<'a, C: Cr0Access> {
+    cr0: &'a C,
+    was_set: bool,
+}
+
+impl<C: Cr0Access> Drop for RestoreWp<'_, C> {
+    fn drop(&mut self) {
+        if self.was_set {
+            unsafe { self.cr0.write(self.cr0.read() | Cr0Flags::WRITE_PROTECT) };
+        }
+    }
+}
+
+pub fn with_wp_disabled_using<C, F, R>(cr0: &C, f: F) -> R
+where
+    C: Cr0Access,
+    F: FnOnce() -> R,
+{
+    // Interrupts stay off so nothing else runs while supervisor writes ignore
+    // page protections
+    interrupts::without_interrupts(|| {
+        let flags = cr0.read();
+        let _restore = RestoreWp {
+            cr0,
+            was_set: flags.contains(Cr0Flags::WRITE_PROTECT),
+        };
+
+        unsafe { cr0.write(flags - Cr0Flags::WRITE_PROTECT) };
+        f()
+    })
+}
+
+// Runs `f` with CR0.WP cleared, so it can write through read-only mappings such
+// as kernel text. The previous WP state is restored when `f` returns.
+#[allow(dead_code)]
+pub fn with_wp_disabled<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    with_wp_disabled_using(&HardwareCr0, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    struct MockCr0 {
+        flags: Cell<Cr0Flags>,
+        writes: Cell<usize>,
+    }
+
+    impl MockCr0 {
+        fn new(flags: Cr0Flags) -> Self {
+            Self {
+                flags: Cell::new(flags),
+                writes: Cell::new(0),
+            }
+        }
+    }
+
+    impl Cr0Access for MockCr0 {
+        fn read(&self) -> Cr0Flags {
+            self.flags.get()
+        }
+
+        unsafe fn write(&self, flags: Cr0Flags) {
+            self.flags.set(flags);
+            self.writes.set(self.writes.get() + 1);
+        }
+    }
+
+    test_case!(wp_toggle, {
+        let base = Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING;
+        let cr0 = MockCr0::new(base | Cr0Flags::WRITE_PROTECT);
+        let interrupts_were_on = interrupts::are_enabled();
+
+        let inside = with_wp_disabled_using(&cr0, || {
+            assert!(!interrupts::are_enabled());
+            assert_eq!(cr0.writes.get(), 1);
+            cr0.read()
+        });
+        assert_eq!(inside, base);
+
+        // Set again, with nothing else touched, and interrupts as they were
+        assert_eq!(cr0.read(), base | Cr0Flags::WRITE_PROTECT);
+        assert_eq!(cr0.writes.get(), 2);
+        assert_eq!(interrupts::are_enabled(), interrupts_were_on);
+    });
+
+    test_case!(wp_toggle_preserves_prior_state, {
+        let base = Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING;
+
+        // Already clear, so it stays clear
+        let cr0 = MockCr0::new(base);
+        with_wp_disabled_using(&cr0, || {});
+        assert_eq!(cr0.read(), base);
+
+        // Nested: only the outermost call turns WP back on
+        let cr0 = MockCr0::new(base | Cr0Flags::WRITE_PROTECT);
+        with_wp_disabled_using(&cr0, || {
+            with_wp_disabled_using(&cr0, || {});
+            assert_eq!(cr0.read(), base);
+        });
+        assert_eq!(cr0.read(), base | Cr0Flags::WRITE_PROTECT);
+    });
+}
@@ -48,6 +48,7 @@ pub fn init() {
         keyboard_output_withwait(STATUS_COMMAND , 0x60);
         keyboard_output_withwait(Ports::DATA, response_byte);
     }
+    crate::cpu::pic8259::unmask(crate::cpu::pic8259::KEYBOARD_IRQ);
 }
 #[allow(unused_variables)]
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(frame: &mut idt::InterruptStackFrame) {
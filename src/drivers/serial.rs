@@ -1,5 +1,5 @@
 #![allow(unused)]
-use x86_64::instructions::port::PortWrite;
+use x86_64::instructions::port::{PortRead, PortWrite};
 
 #[repr(u16)]
 #[allow(unused)]
@@ -36,3 +36,23 @@ pub fn write_str(s: &str) {
         write_byte(byte);
     }
 }
+
+// Non-blocking: returns None if nothing has been received
+pub fn read_byte() -> Option<u8> {
+    unsafe {
+        let status: u8 = PortRead::read_from_port(PORT + 5);
+        if status & 1 == 0 {
+            return None;
+        }
+
+        Some(PortRead::read_from_port(PORT))
+    }
+}
+
+pub struct SerialConsole;
+
+impl crate::kernel::console::Console for SerialConsole {
+    fn write_str(&self, s: &str) {
+        write_str(s);
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{drivers::vga::ransid::RansidState, macros};
+use crate::{
+    drivers::vga::ransid::RansidState,
+    ds::SpinLock,
+    kernel::{console::Console, early_panic},
+    macros,
+};
 use log::{LevelFilter, SetLoggerError};
 use volatile::Volatile;
 use x86_64::instructions::port::{PortRead, PortWrite};
@@ -38,6 +43,16 @@ impl Writer {
         self.update_cursor();
     }
 
+    pub fn clear(&mut self) {
+        for ch in self.buf.iter_mut() {
+            ch.write(0xf00);
+        }
+
+        self.x = 0;
+        self.y = 0;
+        self.update_cursor();
+    }
+
     fn draw_char(&mut self, style: u8, ch: u8) {
         let formatted = (u16::from(style) << 8) | u16::from(ch);
         self.buf[self.y * WIDTH + self.x].write(formatted);
@@ -101,6 +116,22 @@ impl Default for Writer {
     }
 }
 
+pub struct VgaConsole(SpinLock<Writer>);
+
+impl Console for VgaConsole {
+    fn write_str(&self, s: &str) {
+        self.0.lock().write_str(s);
+    }
+
+    fn clear(&self) {
+        self.0.lock().clear();
+    }
+}
+
+lazy_static! {
+    pub static ref VGA: VgaConsole = VgaConsole(SpinLock::new(Writer::default()));
+}
+
 pub fn init() -> Result<(), SetLoggerError> {
     // Enable cursor
     const BEGIN_SCANLINE: u16 = 0;
@@ -117,11 +148,13 @@ pub fn init() -> Result<(), SetLoggerError> {
     }
 
     // Allows use of logging macros
-    log::set_logger(&*macros::SCREEN).map(|()| {
+    log::set_logger(&macros::LOGGER).map(|()| {
         #[cfg(debug_assertions)]
         log::set_max_level(LevelFilter::Trace);
 
         #[cfg(not(debug_assertions))]
         log::set_max_level(LevelFilter::Info);
+
+        early_panic::set_logger_ready();
     })
 }
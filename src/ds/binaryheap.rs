@@ -0,0 +1,208 @@
+use core::{fmt, mem::MaybeUninit, ptr};
+
+// Fixed-capacity binary min-heap that doesn't need an allocator. The maximum is
+// also available, at the cost of a scan over the leaves.
+pub struct BinaryHeap<T: Ord, const N: usize> {
+    data: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+#[allow(dead_code)]
+impl<T: Ord, const N: usize> BinaryHeap<T, N> {
+    pub fn new() -> Self {
+        Self {
+            // An array of MaybeUninit doesn't need initialising
+            data: unsafe { MaybeUninit::uninit().assume_init() },
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    // Hands the item back if the heap is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.len == N {
+            return Err(item);
+        }
+
+        self.data[self.len] = MaybeUninit::new(item);
+        self.len += 1;
+        self.sift_up(self.len - 1);
+        Ok(())
+    }
+
+    pub fn peek(&self) -> Option<&T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.get(0))
+        }
+    }
+
+    pub fn peek_max(&self) -> Option<&T> {
+        self.max_index().map(|i| self.get(i))
+    }
+
+    pub fn pop_min(&mut self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.remove(0))
+        }
+    }
+
+    pub fn pop_max(&mut self) -> Option<T> {
+        self.max_index().map(|i| self.remove(i))
+    }
+
+    fn get(&self, idx: usize) -> &T {
+        debug_assert!(idx < self.len);
+        unsafe { &*self.data[idx].as_ptr() }
+    }
+
+    // The maximum is always a leaf
+    fn max_index(&self) -> Option<usize> {
+        (self.len / 2..self.len).max_by(|&a, &b| self.get(a).cmp(self.get(b)))
+    }
+
+    fn remove(&mut self, idx: usize) -> T {
+        self.len -= 1;
+        self.data.swap(idx, self.len);
+        let out = unsafe { self.data[self.len].as_ptr().read() };
+
+        if idx < self.len {
+            self.sift_down(idx);
+            self.sift_up(idx);
+        }
+
+        out
+    }
+
+    fn sift_up(&mut self, mut idx: usize) {
+        while idx > 0 {
+            let parent = (idx - 1) / 2;
+            if self.get(idx) >= self.get(parent) {
+                break;
+            }
+
+            self.data.swap(idx, parent);
+            idx = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut idx: usize) {
+        loop {
+            let mut smallest = idx;
+            for child in [2 * idx + 1, 2 * idx + 2].iter().copied() {
+                if child < self.len && self.get(child) < self.get(smallest) {
+                    smallest = child;
+                }
+            }
+
+            if smallest == idx {
+                break;
+            }
+
+            self.data.swap(idx, smallest);
+            idx = smallest;
+        }
+    }
+}
+
+impl<T: Ord, const N: usize> Default for BinaryHeap<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord, const N: usize> Drop for BinaryHeap<T, N> {
+    fn drop(&mut self) {
+        for slot in &mut self.data[..self.len] {
+            unsafe { ptr::drop_in_place(slot.as_mut_ptr()) };
+        }
+    }
+}
+
+impl<T: Ord + fmt::Debug, const N: usize> fmt::Debug for BinaryHeap<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries((0..self.len).map(|i| self.get(i)))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(heap_ordering, {
+        let mut heap: BinaryHeap<u32, 16> = BinaryHeap::new();
+        for x in [5, 1, 9, 3, 7, 3, 0, 8].iter().copied() {
+            heap.push(x).unwrap();
+        }
+
+        assert_eq!(heap.peek(), Some(&0));
+        assert_eq!(heap.peek_max(), Some(&9));
+        for expected in [0, 1, 3, 3, 5, 7, 8, 9].iter() {
+            assert_eq!(heap.pop_min(), Some(*expected));
+        }
+        assert_eq!(heap.pop_min(), None);
+        assert_eq!(heap.pop_max(), None);
+    });
+
+    test_case!(heap_overflow, {
+        let mut heap: BinaryHeap<u32, 3> = BinaryHeap::new();
+        assert_eq!(heap.push(2), Ok(()));
+        assert_eq!(heap.push(1), Ok(()));
+        assert_eq!(heap.push(3), Ok(()));
+        assert_eq!(heap.push(0), Err(0));
+        assert_eq!(heap.len(), 3);
+
+        // Making room lets the next push succeed
+        assert_eq!(heap.pop_max(), Some(3));
+        assert_eq!(heap.push(0), Ok(()));
+        assert_eq!(heap.pop_min(), Some(0));
+    });
+
+    test_case!(heap_interleaved, {
+        let mut heap: BinaryHeap<u64, 32> = BinaryHeap::new();
+        let mut x: u64 = 12345;
+        let mut popped_min = 0;
+        for i in 0..200 {
+            // Cheap LCG so the sequence isn't sorted
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let v = x >> 40;
+
+            if heap.len() == heap.capacity() || i % 3 == 2 {
+                let min = *heap.peek().unwrap();
+                let max = *heap.peek_max().unwrap();
+                assert!(min <= max);
+                if i % 2 == 0 {
+                    assert_eq!(heap.pop_min(), Some(min));
+                    popped_min += 1;
+                } else {
+                    assert_eq!(heap.pop_max(), Some(max));
+                }
+            } else {
+                heap.push(v).unwrap();
+            }
+        }
+        assert!(popped_min > 0);
+
+        let mut prev = 0;
+        while let Some(v) = heap.pop_min() {
+            assert!(v >= prev);
+            prev = v;
+        }
+    });
+}
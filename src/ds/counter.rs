@@ -0,0 +1,89 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// A statistics counter. Everything is relaxed, since counters are only ever
+// read to be reported and never used to order other memory accesses.
+pub struct Counter(AtomicU64);
+
+#[allow(dead_code)]
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+// Writes one line per counter, with the values lined up. Used by the Display
+// impls that counter_group! generates.
+#[allow(dead_code)]
+pub fn write_table(f: &mut fmt::Formatter, counters: &[(&str, &Counter)]) -> fmt::Result {
+    let width = counters.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, counter) in counters {
+        writeln!(f, "  {:<width$}  {}", name, counter.get(), width = width)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+    use core::fmt::Write;
+
+    counter_group! {
+        struct TestStats {
+            hits,
+            misses,
+            evictions,
+        }
+    }
+
+    test_case!(counter_inc_reset, {
+        let counter = Counter::new();
+        counter.inc();
+        counter.inc();
+        counter.add(5);
+        assert_eq!(counter.get(), 7);
+
+        counter.reset();
+        assert_eq!(counter.get(), 0);
+    });
+
+    test_case!(counter_group_table, {
+        static STATS: TestStats = TestStats::new();
+        STATS.hits.add(12);
+        STATS.evictions.inc();
+
+        let mut s = ArrayString::<[u8; 128]>::new();
+        write!(s, "{}", STATS).unwrap();
+        assert_eq!(s.as_str(), "  hits       12\n  misses     0\n  evictions  1\n");
+
+        STATS.reset();
+        assert_eq!(STATS.hits.get(), 0);
+        assert_eq!(STATS.evictions.get(), 0);
+    });
+}
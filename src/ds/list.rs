@@ -0,0 +1,347 @@
+use core::{cell::Cell, marker::PhantomData, ptr::NonNull};
+
+// Doubly-linked list threaded through a Links field in each node, so that
+// nothing is allocated to put a node on a list. The list only holds pointers:
+// whoever owns the nodes has to keep them alive and in place for as long as
+// they're linked, and each Links can only be on one list at a time.
+
+pub struct Links<T> {
+    next: Cell<Option<NonNull<T>>>,
+    prev: Cell<Option<NonNull<T>>>,
+    linked: Cell<bool>,
+}
+
+#[allow(dead_code)]
+impl<T> Links<T> {
+    pub const fn new() -> Self {
+        Self {
+            next: Cell::new(None),
+            prev: Cell::new(None),
+            linked: Cell::new(false),
+        }
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+
+    pub fn next(&self) -> Option<NonNull<T>> {
+        self.next.get()
+    }
+
+    pub fn prev(&self) -> Option<NonNull<T>> {
+        self.prev.get()
+    }
+}
+
+impl<T> Default for Links<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Safety: links() has to return the same Links every time it's called on a
+// node
+pub unsafe trait Linked: Sized {
+    fn links(&self) -> &Links<Self>;
+}
+
+pub struct IntrusiveList<T: Linked> {
+    head: Option<NonNull<T>>,
+    tail: Option<NonNull<T>>,
+    len: usize,
+}
+
+#[allow(dead_code)]
+impl<T: Linked> IntrusiveList<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn front(&self) -> Option<NonNull<T>> {
+        self.head
+    }
+
+    pub fn back(&self) -> Option<NonNull<T>> {
+        self.tail
+    }
+
+    fn links<'a>(node: NonNull<T>) -> &'a Links<T> {
+        // Safety: everything on the list is alive, which push_*() makes the
+        // caller promise
+        unsafe { &*node.as_ptr() }.links()
+    }
+
+    // Safety: `node` must stay valid and not move until it's removed again
+    pub unsafe fn push_front(&mut self, node: NonNull<T>) {
+        let links = Self::links(node);
+        BUG_ON!(links.is_linked(), "list: node is already on a list");
+
+        links.linked.set(true);
+        links.prev.set(None);
+        links.next.set(self.head);
+        match self.head {
+            Some(head) => Self::links(head).prev.set(Some(node)),
+            None => self.tail = Some(node),
+        }
+
+        self.head = Some(node);
+        self.len += 1;
+    }
+
+    // Safety: as for push_front()
+    pub unsafe fn push_back(&mut self, node: NonNull<T>) {
+        let links = Self::links(node);
+        BUG_ON!(links.is_linked(), "list: node is already on a list");
+
+        links.linked.set(true);
+        links.next.set(None);
+        links.prev.set(self.tail);
+        match self.tail {
+            Some(tail) => Self::links(tail).next.set(Some(node)),
+            None => self.head = Some(node),
+        }
+
+        self.tail = Some(node);
+        self.len += 1;
+    }
+
+    // Safety: `at` must be on this list, and `node` as for push_front()
+    pub unsafe fn insert_before(&mut self, at: NonNull<T>, node: NonNull<T>) {
+        let links = Self::links(node);
+        BUG_ON!(links.is_linked(), "list: node is already on a list");
+
+        let prev = Self::links(at).prev.get();
+        links.linked.set(true);
+        links.prev.set(prev);
+        links.next.set(Some(at));
+        Self::links(at).prev.set(Some(node));
+        match prev {
+            Some(prev) => Self::links(prev).next.set(Some(node)),
+            None => self.head = Some(node),
+        }
+
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<NonNull<T>> {
+        let node = self.head?;
+        // Safety: it's the head, so it's on this list
+        unsafe { self.remove(node) };
+        Some(node)
+    }
+
+    pub fn pop_back(&mut self) -> Option<NonNull<T>> {
+        let node = self.tail?;
+        unsafe { self.remove(node) };
+        Some(node)
+    }
+
+    // Safety: `node` must be on this list, not just any list
+    pub unsafe fn remove(&mut self, node: NonNull<T>) {
+        let links = Self::links(node);
+        BUG_ON!(!links.is_linked(), "list: removing a node that isn't on a list");
+
+        let (prev, next) = (links.prev.get(), links.next.get());
+        match prev {
+            Some(prev) => Self::links(prev).next.set(next),
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => Self::links(next).prev.set(prev),
+            None => self.tail = prev,
+        }
+
+        links.prev.set(None);
+        links.next.set(None);
+        links.linked.set(false);
+        self.len -= 1;
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            next: self.head,
+            _list: PhantomData,
+        }
+    }
+}
+
+impl<T: Linked> Default for IntrusiveList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Iter<'a, T: Linked> {
+    next: Option<NonNull<T>>,
+    _list: PhantomData<&'a IntrusiveList<T>>,
+}
+
+impl<'a, T: Linked> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let node = self.next?;
+        self.next = IntrusiveList::links(node).next.get();
+        Some(unsafe { &*node.as_ptr() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    struct Node {
+        value: u32,
+        links: Links<Node>,
+    }
+
+    unsafe impl Linked for Node {
+        fn links(&self) -> &Links<Self> {
+            &self.links
+        }
+    }
+
+    fn nodes() -> ArrayVec<[Node; 16]> {
+        (0..16)
+            .map(|value| Node {
+                value,
+                links: Links::new(),
+            })
+            .collect()
+    }
+
+    fn ptr(node: &Node) -> NonNull<Node> {
+        NonNull::from(node)
+    }
+
+    // Walks the list, checking the back links and tail agree with the forward
+    // links, and the length with len()
+    fn check(list: &IntrusiveList<Node>) -> ArrayVec<[u32; 16]> {
+        let mut values = ArrayVec::new();
+        let mut prev = None;
+        let mut cur = list.front();
+        while let Some(node) = cur {
+            let links = IntrusiveList::links(node);
+            assert!(links.is_linked());
+            assert_eq!(links.prev.get(), prev);
+            values.push(unsafe { node.as_ref() }.value);
+            prev = cur;
+            cur = links.next.get();
+        }
+
+        assert_eq!(list.back(), prev);
+        assert_eq!(values.len(), list.len());
+        assert_eq!(list.iter().map(|n| n.value).collect::<ArrayVec<[u32; 16]>>(), values);
+        values
+    }
+
+    test_case!(list_insertion_order, {
+        let nodes = nodes();
+        let mut list = IntrusiveList::new();
+        assert!(list.is_empty());
+
+        unsafe {
+            list.push_front(ptr(&nodes[1]));
+            list.push_front(ptr(&nodes[0]));
+            list.push_back(ptr(&nodes[2]));
+            list.push_back(ptr(&nodes[3]));
+        }
+        assert_eq!(&check(&list)[..], &[0, 1, 2, 3]);
+
+        assert_eq!(list.pop_front(), Some(ptr(&nodes[0])));
+        assert_eq!(list.pop_back(), Some(ptr(&nodes[3])));
+        assert!(!nodes[0].links.is_linked());
+        assert_eq!(&check(&list)[..], &[1, 2]);
+    });
+
+    test_case!(list_remove_anywhere, {
+        let nodes = nodes();
+        let mut list = IntrusiveList::new();
+        for node in nodes.iter().take(5) {
+            unsafe { list.push_back(ptr(node)) };
+        }
+
+        unsafe {
+            // Middle, head, then tail
+            list.remove(ptr(&nodes[2]));
+            assert_eq!(&check(&list)[..], &[0, 1, 3, 4]);
+            list.remove(ptr(&nodes[0]));
+            assert_eq!(&check(&list)[..], &[1, 3, 4]);
+            list.remove(ptr(&nodes[4]));
+            assert_eq!(&check(&list)[..], &[1, 3]);
+            list.remove(ptr(&nodes[1]));
+            list.remove(ptr(&nodes[3]));
+        }
+        assert!(list.is_empty());
+        assert_eq!((list.front(), list.back()), (None, None));
+
+        // Removed nodes can go straight back on
+        unsafe { list.push_front(ptr(&nodes[2])) };
+        assert_eq!(&check(&list)[..], &[2]);
+    });
+
+    test_case!(list_insert_before, {
+        let nodes = nodes();
+        let mut list = IntrusiveList::new();
+        unsafe {
+            list.push_back(ptr(&nodes[1]));
+            list.push_back(ptr(&nodes[3]));
+
+            // At the head, then in the middle
+            list.insert_before(ptr(&nodes[1]), ptr(&nodes[0]));
+            list.insert_before(ptr(&nodes[3]), ptr(&nodes[2]));
+        }
+        assert_eq!(&check(&list)[..], &[0, 1, 2, 3]);
+    });
+
+    test_case!(list_interleaved, {
+        let nodes = nodes();
+        let mut list = IntrusiveList::new();
+        let mut model: ArrayVec<[u32; 16]> = ArrayVec::new();
+
+        // A fixed pseudo-random sequence of operations, checked against a
+        // plain array after every step
+        let mut seed = 0x2545_F491u32;
+        for _ in 0..200 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+
+            let node = &nodes[(seed >> 8) as usize % 16];
+            match seed % 4 {
+                _ if node.links.is_linked() => {
+                    unsafe { list.remove(ptr(node)) };
+                    model.retain(|v| *v != node.value);
+                }
+                0 | 1 => {
+                    unsafe { list.push_back(ptr(node)) };
+                    model.push(node.value);
+                }
+                2 => {
+                    unsafe { list.push_front(ptr(node)) };
+                    model.insert(0, node.value);
+                }
+                _ => {
+                    let popped = list.pop_front().map(|n| unsafe { n.as_ref() }.value);
+                    assert_eq!(popped, if model.is_empty() { None } else { Some(model.remove(0)) });
+                }
+            }
+
+            assert_eq!(check(&list), model);
+        }
+    });
+}
@@ -1,2 +1,16 @@
+pub mod binaryheap;
+pub mod counter;
+pub mod list;
 pub mod sync;
-pub use sync::{rwspinlock::RwSpinLock, spinlock::SpinLock};
+#[allow(unused_imports)]
+pub use binaryheap::BinaryHeap;
+pub use counter::Counter;
+#[allow(unused_imports)]
+pub use list::{IntrusiveList, Linked, Links};
+pub use sync::{
+    initcell::InitCell,
+    reentrant::ReentrantSpinLock,
+    rwspinlock::RwSpinLock,
+    seqlock::SeqLock,
+    spinlock::SpinLock,
+};
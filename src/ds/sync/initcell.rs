@@ -0,0 +1,116 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const UNINIT: u8 = 0;
+const BUSY: u8 = 1;
+const INIT: u8 = 2;
+
+// A cell that is written exactly once (usually during boot) and only read after
+// that, so reads don't need to take a lock. The state is only checked on the
+// read path in debug builds.
+pub struct InitCell<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for InitCell<T> {}
+unsafe impl<T: Send> Send for InitCell<T> {}
+
+impl<T> InitCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINIT),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn try_init(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(UNINIT, BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(value);
+        }
+
+        unsafe { (*self.data.get()).as_mut_ptr().write(value) };
+        self.state.store(INIT, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn init(&self, value: T) {
+        if self.try_init(value).is_err() {
+            panic!("InitCell initialised twice");
+        }
+    }
+
+    pub fn is_initialised(&self) -> bool {
+        self.state.load(Ordering::Acquire) == INIT
+    }
+
+    pub fn try_get(&self) -> Option<&T> {
+        if self.is_initialised() {
+            Some(unsafe { &*(*self.data.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    // Safety: init() must have completed before this is called. This is only
+    // checked in debug builds.
+    pub unsafe fn get(&self) -> &T {
+        debug_assert!(self.is_initialised(), "InitCell used before initialisation");
+        &*(*self.data.get()).as_ptr()
+    }
+}
+
+impl<T> Drop for InitCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == INIT {
+            unsafe { core::ptr::drop_in_place((*self.data.get()).as_mut_ptr()) };
+        }
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for InitCell<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_get() {
+            Some(data) => f.debug_struct("InitCell").field("data", data).finish(),
+            None => f
+                .debug_struct("InitCell")
+                .field("data", b"<uninit>")
+                .finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(init_once, {
+        let c = InitCell::new();
+        assert!(!c.is_initialised());
+        assert_eq!(c.try_init(1), Ok(()));
+        assert_eq!(c.try_init(2), Err(2));
+        assert_eq!(unsafe { *c.get() }, 1);
+    });
+
+    test_case!(get_before_init, {
+        let c: InitCell<u32> = InitCell::new();
+        assert!(c.try_get().is_none());
+        c.init(5);
+        assert_eq!(c.try_get(), Some(&5));
+    });
+
+    // Only debug builds check, and the panic ends the run
+    #[cfg(all(feature = "initcell-panic-test", debug_assertions))]
+    exit_test_case!(get_before_init_panics, {
+        let c: InitCell<u32> = InitCell::new();
+        crate::testing::expect_panic();
+        let _ = unsafe { c.get() };
+    });
+}
@@ -1,2 +1,5 @@
+pub mod initcell;
+pub mod reentrant;
 pub mod rwspinlock;
+pub mod seqlock;
 pub mod spinlock;
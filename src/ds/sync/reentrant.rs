@@ -0,0 +1,141 @@
+use crate::cpu::percpu::{apic_id, PerCpu};
+use core::{
+    cell::Cell,
+    hint::spin_loop,
+    ops::Deref,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+// A spinlock that the CPU holding it can take again, e.g. for the console,
+// where a panic while formatting a log record prints while the record's lock is
+// still held. Since the holder can have several guards at once, they only give
+// out shared references, so the data needs its own interior mutability.
+
+const NO_OWNER: u32 = u32::MAX;
+
+pub struct ReentrantSpinLock<T> {
+    owner: AtomicU32,
+    // Only touched by the owner
+    count: Cell<usize>,
+    data: T,
+}
+
+unsafe impl<T: Send> Sync for ReentrantSpinLock<T> {}
+unsafe impl<T: Send> Send for ReentrantSpinLock<T> {}
+
+#[allow(dead_code)]
+impl<T> ReentrantSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicU32::new(NO_OWNER),
+            count: Cell::new(0),
+            data,
+        }
+    }
+
+    pub fn lock(&self) -> ReentrantSpinLockGuard<'_, T> {
+        self.lock_as(apic_id())
+    }
+
+    pub fn try_lock(&self) -> Option<ReentrantSpinLockGuard<'_, T>> {
+        self.try_lock_as(apic_id())
+    }
+
+    fn lock_as(&self, cpu: u32) -> ReentrantSpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock_as(cpu) {
+                return guard;
+            }
+
+            while self.owner.load(Ordering::Relaxed) != NO_OWNER {
+                spin_loop();
+            }
+        }
+    }
+
+    fn try_lock_as(&self, cpu: u32) -> Option<ReentrantSpinLockGuard<'_, T>> {
+        unsafe { PerCpu::current().preempt_inc() };
+
+        // Only this CPU could have stored its own id, so there's no race here
+        let owned = self.owner.load(Ordering::Relaxed) == cpu
+            || self
+                .owner
+                .compare_exchange(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok();
+        if !owned {
+            unsafe { PerCpu::current().preempt_dec() };
+            return None;
+        }
+
+        self.count.set(self.count.get() + 1);
+        Some(ReentrantSpinLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != NO_OWNER
+    }
+}
+
+pub struct ReentrantSpinLockGuard<'a, T> {
+    lock: &'a ReentrantSpinLock<T>,
+}
+
+impl<T> Deref for ReentrantSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.lock.data
+    }
+}
+
+impl<T> Drop for ReentrantSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let count = self.lock.count.get() - 1;
+        self.lock.count.set(count);
+        if count == 0 {
+            self.lock.owner.store(NO_OWNER, Ordering::Release);
+        }
+
+        unsafe { PerCpu::current().preempt_dec() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(reentrant_same_owner, {
+        let lock = ReentrantSpinLock::new(5);
+        {
+            let outer = lock.lock_as(1);
+            let inner = lock.lock_as(1);
+            assert_eq!((*outer, *inner), (5, 5));
+            assert_eq!(lock.count.get(), 2);
+
+            // Dropping the inner guard leaves the lock held
+            drop(inner);
+            assert!(lock.is_locked());
+        }
+        assert!(!lock.is_locked());
+        assert_eq!(lock.count.get(), 0);
+    });
+
+    test_case!(reentrant_other_owner, {
+        let pc = || PerCpu::current().preempt_count(Ordering::SeqCst);
+        let lock = ReentrantSpinLock::new(());
+
+        let outer = lock.lock_as(1);
+        let inner = lock.try_lock_as(1).unwrap();
+        assert!(lock.try_lock_as(2).is_none());
+        assert_eq!(pc(), 2);
+
+        // Still shut out until the last of the owner's guards goes
+        drop(outer);
+        assert!(lock.try_lock_as(2).is_none());
+        drop(inner);
+        assert_eq!(pc(), 0);
+
+        let _other = lock.try_lock_as(2).unwrap();
+        assert!(lock.try_lock_as(1).is_none());
+    });
+}
@@ -45,6 +45,14 @@ pub struct RwSpinLockUpgradeableGuard<'a, T: 'a + ?Sized> {
     _invariant: PhantomData<&'a mut T>,
 }
 
+// A read guard narrowed down to part of the locked data, like parking_lot's
+// `MappedRwLockReadGuard`. The whole lock stays read locked until it's dropped.
+#[derive(Debug)]
+pub struct MappedRwSpinLockReadGuard<'a, T: 'a + ?Sized> {
+    lock: &'a AtomicUsize,
+    data: NonNull<T>,
+}
+
 // Same unsafe impls as `std::sync::RwSpinLock`
 unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
 unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
@@ -290,6 +298,39 @@ impl<'rwlock, T: ?Sized> RwSpinLockUpgradeableGuard<'rwlock, T> {
     }
 }
 
+impl<'rwlock, T: ?Sized> RwSpinLockReadGuard<'rwlock, T> {
+    #[inline]
+    pub fn map<U: ?Sized, F>(self, f: F) -> MappedRwSpinLockReadGuard<'rwlock, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let guard = MappedRwSpinLockReadGuard {
+            lock: self.lock,
+            data: NonNull::from(f(unsafe { self.data.as_ref() })),
+        };
+
+        // The mapped guard takes over our read lock and preempt count
+        mem::forget(self);
+        guard
+    }
+}
+
+impl<'rwlock, T: ?Sized> MappedRwSpinLockReadGuard<'rwlock, T> {
+    #[inline]
+    pub fn map<U: ?Sized, F>(self, f: F) -> MappedRwSpinLockReadGuard<'rwlock, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let guard = MappedRwSpinLockReadGuard {
+            lock: self.lock,
+            data: NonNull::from(f(unsafe { self.data.as_ref() })),
+        };
+
+        mem::forget(self);
+        guard
+    }
+}
+
 impl<'rwlock, T: ?Sized> RwSpinLockWriteGuard<'rwlock, T> {
     #[inline]
     pub fn downgrade(self) -> RwSpinLockReadGuard<'rwlock, T> {
@@ -315,6 +356,14 @@ impl<'rwlock, T: ?Sized> Deref for RwSpinLockReadGuard<'rwlock, T> {
     }
 }
 
+impl<'rwlock, T: ?Sized> Deref for MappedRwSpinLockReadGuard<'rwlock, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.data.as_ref() }
+    }
+}
+
 impl<'rwlock, T: ?Sized> Deref for RwSpinLockUpgradeableGuard<'rwlock, T> {
     type Target = T;
 
@@ -345,6 +394,14 @@ impl<'rwlock, T: ?Sized> Drop for RwSpinLockReadGuard<'rwlock, T> {
     }
 }
 
+impl<'rwlock, T: ?Sized> Drop for MappedRwSpinLockReadGuard<'rwlock, T> {
+    fn drop(&mut self) {
+        debug_assert!(self.lock.load(Ordering::Relaxed) & !(WRITER | UPGRADED) > 0);
+        self.lock.fetch_sub(READER, Ordering::Release);
+        unsafe { PerCpu::current().preempt_dec() };
+    }
+}
+
 impl<'rwlock, T: ?Sized> Drop for RwSpinLockUpgradeableGuard<'rwlock, T> {
     fn drop(&mut self) {
         debug_assert_eq!(
@@ -461,6 +518,36 @@ mod tests {
         assert!(m.try_upgradeable_read().unwrap().try_upgrade().is_ok());
     });
 
+    test_case!(mapped_read_guard, {
+        let m = RwSpinLock::new((NonCopy(1), [2, 3, 4]));
+        let first = m.read().map(|inner| &inner.0);
+        assert_eq!(*first, NonCopy(1));
+
+        // Still read locked, so other readers get in but writers don't
+        assert!(m.try_write().is_none());
+        assert!(m.try_read().is_some());
+
+        let third = m.read().map(|inner| &inner.1[..]).map(|slice| &slice[1]);
+        assert_eq!(*third, 3);
+        drop(first);
+        assert!(m.try_write().is_none());
+        drop(third);
+
+        m.write().1[1] = 5;
+        assert_eq!(*m.read().map(|inner| &inner.1[1]), 5);
+    });
+
+    test_case!(mapped_read_guard_preempt_count, {
+        let pc = || PerCpu::current().preempt_count(core::sync::atomic::Ordering::SeqCst);
+        let m = RwSpinLock::new(NonCopy(7));
+        {
+            let inner = m.read().map(|inner| &inner.0);
+            assert_eq!((*inner, pc()), (7, 1));
+        }
+        assert_eq!(pc(), 0);
+        assert!(m.try_write().is_some());
+    });
+
     test_case!(preempt_count, {
         let pc = || PerCpu::current().preempt_count(core::sync::atomic::Ordering::SeqCst);
         assert_eq!(pc(), 0);
@@ -0,0 +1,135 @@
+use crate::cpu::interrupts;
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+// For small Copy data that's written rarely, or from an interrupt handler, and
+// read often. Writers make the sequence odd for the duration of an update, and
+// readers never block them: a reader just tries again if the sequence was odd
+// or moved while it was copying the data out.
+
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+#[allow(dead_code)]
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(data) = self.try_read() {
+                return data;
+            }
+            spin_loop();
+        }
+    }
+
+    // None if a write was in progress, or finished while reading
+    pub fn try_read(&self) -> Option<T> {
+        let seq = self.read_begin()?;
+        // The copy can be torn, but then the sequence will have moved and it's
+        // thrown away
+        let data = unsafe { ptr::read_volatile(self.data.get()) };
+        if self.read_retry(seq) {
+            None
+        } else {
+            Some(data)
+        }
+    }
+
+    fn read_begin(&self) -> Option<usize> {
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq % 2 == 0 {
+            Some(seq)
+        } else {
+            None
+        }
+    }
+
+    fn read_retry(&self, seq: usize) -> bool {
+        fence(Ordering::Acquire);
+        self.seq.load(Ordering::Relaxed) != seq
+    }
+
+    // Interrupts are off for the update, so a reader in an interrupt handler
+    // can't spin forever on a write it interrupted
+    pub fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        interrupts::without_interrupts(|| {
+            self.write_begin();
+            let result = f(unsafe { &mut *self.data.get() });
+            self.write_end();
+            result
+        })
+    }
+
+    // Writers only wait for each other
+    fn write_begin(&self) {
+        loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+            if seq % 2 == 0
+                && self
+                    .seq
+                    .compare_exchange_weak(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                break;
+            }
+            spin_loop();
+        }
+        fence(Ordering::Release);
+    }
+
+    fn write_end(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Pair {
+        a: u64,
+        b: u64,
+    }
+
+    test_case!(seqlock_reader_retries, {
+        let lock = SeqLock::new(Pair { a: 1, b: 1 });
+        assert_eq!(lock.try_read(), Some(Pair { a: 1, b: 1 }));
+
+        // Caught halfway through an update, which a reader mustn't see
+        lock.write_begin();
+        unsafe { (*lock.data.get()).a = 2 };
+        assert_eq!(lock.try_read(), None);
+        unsafe { (*lock.data.get()).b = 2 };
+        lock.write_end();
+        assert_eq!(lock.try_read(), Some(Pair { a: 2, b: 2 }));
+
+        // A whole write between a reader's start and finish
+        let seq = lock.read_begin().unwrap();
+        lock.write(|pair| *pair = Pair { a: 3, b: 3 });
+        assert!(lock.read_retry(seq));
+        assert_eq!(lock.read(), Pair { a: 3, b: 3 });
+    });
+
+    test_case!(seqlock_write_returns, {
+        let lock = SeqLock::new(5u64);
+        assert_eq!(lock.write(|n| core::mem::replace(n, 6)), 5);
+        assert_eq!(lock.read(), 6);
+        assert_eq!(lock.seq.load(Ordering::Relaxed), 4);
+    });
+}
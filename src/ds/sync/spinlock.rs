@@ -6,28 +6,73 @@ use core::{
     sync::atomic::{AtomicBool, Ordering},
 };
 
-pub struct SpinLock<T> {
+// What a lock does while it waits for another holder to let go. `spins` is the
+// number of times it has already waited during this acquire.
+pub trait SpinStrategy {
+    fn spin(&self, spins: usize);
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Spin;
+
+impl SpinStrategy for Spin {
+    fn spin(&self, _spins: usize) {
+        spin_loop();
+    }
+}
+
+// Spins like Spin, but gives up and panics once it looks like it'll never get
+// the lock
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeadlockTimeout<const N: usize>;
+
+impl<const N: usize> SpinStrategy for DeadlockTimeout<N> {
+    fn spin(&self, spins: usize) {
+        BUG_ON!(spins >= N, "spinlock: no progress after {} spins, probably a deadlock", N);
+        spin_loop();
+    }
+}
+
+pub struct SpinLock<T, S = Spin> {
     locked: AtomicBool,
+    strategy: S,
     data: UnsafeCell<T>,
 }
 
-unsafe impl<T: Send> Sync for SpinLock<T> {}
-unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send, S: Sync> Sync for SpinLock<T, S> {}
+unsafe impl<T: Send, S: Send> Send for SpinLock<T, S> {}
 
 impl<T> SpinLock<T> {
     pub const fn new(data: T) -> Self {
+        Self::with_strategy(data, Spin)
+    }
+}
+
+#[allow(dead_code)]
+impl<T, S> SpinLock<T, S> {
+    pub const fn with_strategy(data: T, strategy: S) -> Self {
         Self {
             locked: AtomicBool::new(false),
+            strategy,
             data: UnsafeCell::new(data),
         }
     }
 
+    pub fn strategy(&self) -> &S {
+        &self.strategy
+    }
+}
+
+impl<T, S: SpinStrategy> SpinLock<T, S> {
     pub fn lock(&self) -> SpinLockGuard<T> {
         // Acquire the lock
         unsafe { PerCpu::current().preempt_inc() };
+        let mut spins = 0;
         while self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Acquire).is_err() {
             while self.locked.load(Ordering::Relaxed) {
-                spin_loop();
+                self.strategy.spin(spins);
+                spins += 1;
             }
         }
 
@@ -40,7 +85,7 @@ impl<T> SpinLock<T> {
     pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
         unsafe { PerCpu::current().preempt_inc() };
 
-        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Acquire).is_err() {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Acquire).is_ok() {
             Some(SpinLockGuard {
                 locked: &self.locked,
                 data: unsafe { &mut *self.data.get() },
@@ -58,7 +103,7 @@ impl<T: Default> Default for SpinLock<T> {
     }
 }
 
-impl<T: core::fmt::Debug> core::fmt::Debug for SpinLock<T> {
+impl<T: core::fmt::Debug, S: SpinStrategy> core::fmt::Debug for SpinLock<T, S> {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self.try_lock() {
             Some(temp) => f
@@ -102,6 +147,7 @@ impl<T> Drop for SpinLockGuard<'_, T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use core::sync::atomic::AtomicUsize;
 
     test_case!(lock, {
         let m = SpinLock::new(());
@@ -137,4 +183,42 @@ mod tests {
         }
         assert_eq!(pc(), 0);
     });
+
+    // Stands in for another CPU holding the lock, and lets go of it after a
+    // fixed number of spins
+    struct ReleaseAfter {
+        release_after: usize,
+        spins: AtomicUsize,
+        held: UnsafeCell<Option<SpinLockGuard<'static, u32>>>,
+    }
+
+    unsafe impl Sync for ReleaseAfter {}
+
+    impl SpinStrategy for ReleaseAfter {
+        fn spin(&self, spins: usize) {
+            assert_eq!(spins, self.spins.fetch_add(1, Ordering::Relaxed));
+            if spins + 1 == self.release_after {
+                unsafe { *self.held.get() = None };
+            }
+        }
+    }
+
+    test_case!(contended_spins, {
+        static LOCK: SpinLock<u32, ReleaseAfter> = SpinLock::with_strategy(
+            0,
+            ReleaseAfter {
+                release_after: 5,
+                spins: AtomicUsize::new(0),
+                held: UnsafeCell::new(None),
+            },
+        );
+
+        // Uncontended acquires never spin
+        *LOCK.lock() += 1;
+        assert_eq!(LOCK.strategy().spins.load(Ordering::Relaxed), 0);
+
+        unsafe { *LOCK.strategy().held.get() = Some(LOCK.lock()) };
+        assert_eq!(*LOCK.lock(), 1);
+        assert_eq!(LOCK.strategy().spins.load(Ordering::Relaxed), 5);
+    });
 }
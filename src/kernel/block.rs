@@ -0,0 +1,219 @@
+use crate::mm::{
+    phys_to_kernel_virt,
+    pmm::{PhysAllocator, MAX_ORDER},
+    PAGE_SIZE,
+};
+use core::slice;
+use x86_64::structures::paging::frame::PhysFrameRange;
+
+// Fixed-size block storage, for filesystems to sit on. Blocks are numbered from
+// 0, and are always read and written whole.
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // Past the end of the device
+    OutOfRange,
+    // The buffer isn't exactly one block long
+    BadBufferSize,
+    ReadOnly,
+}
+
+#[allow(dead_code)]
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+    fn num_blocks(&self) -> u64;
+    fn read_block(&self, index: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    fn write_block(&mut self, index: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+// Where block `index` starts, if it's on the device and `len` is a whole block
+#[allow(dead_code)]
+fn block_offset(dev: &dyn BlockDevice, index: u64, len: usize) -> Result<usize, BlockError> {
+    if index >= dev.num_blocks() {
+        return Err(BlockError::OutOfRange);
+    }
+    if len != dev.block_size() {
+        return Err(BlockError::BadBufferSize);
+    }
+    Ok(index as usize * dev.block_size())
+}
+
+// A zeroed device in physically contiguous frames from the PMM, which go back
+// when it's dropped
+pub struct MemBlockDevice {
+    frames: PhysFrameRange,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+#[allow(dead_code)]
+impl MemBlockDevice {
+    // None if the device wouldn't fit in one allocation, or there's no memory
+    pub fn new(block_size: usize, num_blocks: u64) -> Option<Self> {
+        assert!(block_size > 0 && num_blocks > 0);
+        let bytes = (block_size as u64).checked_mul(num_blocks)?;
+        let pages = (bytes / PAGE_SIZE + if bytes % PAGE_SIZE == 0 { 0 } else { 1 }).checked_next_power_of_two()?;
+        let order = pages.trailing_zeros() as u8;
+        if order > MAX_ORDER as u8 {
+            return None;
+        }
+
+        let frames = PhysAllocator::try_alloc(order)?;
+        frames.for_each(crate::mm::zero_frame);
+        Some(Self {
+            frames,
+            block_size,
+            num_blocks,
+        })
+    }
+
+    fn bytes(&self) -> *mut u8 {
+        phys_to_kernel_virt(self.frames.start.start_address()).as_mut_ptr()
+    }
+}
+
+impl BlockDevice for MemBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&self, index: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let offset = block_offset(self, index, buf.len())?;
+        unsafe { buf.copy_from_slice(slice::from_raw_parts(self.bytes().add(offset), buf.len())) };
+        Ok(())
+    }
+
+    fn write_block(&mut self, index: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let offset = block_offset(self, index, buf.len())?;
+        unsafe { slice::from_raw_parts_mut(self.bytes().add(offset), buf.len()).copy_from_slice(buf) };
+        Ok(())
+    }
+}
+
+impl Drop for MemBlockDevice {
+    fn drop(&mut self) {
+        PhysAllocator::free(self.frames);
+    }
+}
+
+// A read-only view of some memory, like the initrd. A partial block at the end
+// reads back zero padded.
+pub struct RamdiskDevice<'a> {
+    data: &'a [u8],
+    block_size: usize,
+}
+
+#[allow(dead_code)]
+impl<'a> RamdiskDevice<'a> {
+    pub fn new(data: &'a [u8], block_size: usize) -> Self {
+        assert!(block_size > 0);
+        Self { data, block_size }
+    }
+}
+
+impl BlockDevice for RamdiskDevice<'_> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        ((self.data.len() + self.block_size - 1) / self.block_size) as u64
+    }
+
+    fn read_block(&self, index: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let offset = block_offset(self, index, buf.len())?;
+        let data = &self.data[offset..self.data.len().min(offset + buf.len())];
+        let (head, tail) = buf.split_at_mut(data.len());
+        head.copy_from_slice(data);
+        tail.iter_mut().for_each(|byte| *byte = 0);
+        Ok(())
+    }
+
+    fn write_block(&mut self, index: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block_offset(self, index, buf.len())?;
+        Err(BlockError::ReadOnly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::pmm::fixture;
+
+    // hello.txt, bin/ and bin/init, zero padded to 512 bytes
+    static INITRD: &[u8] = include_bytes!("testdata/initrd.cpio");
+
+    test_case!(
+        mem_block_round_trip,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            let free = PhysAllocator::free_pages();
+            // Blocks that straddle a page boundary
+            let mut dev = MemBlockDevice::new(1536, 5).unwrap();
+            assert_eq!(PhysAllocator::free_pages(), free - 2);
+            assert_eq!(dev.num_blocks(), 5);
+
+            let mut buf = [0xAAu8; 1536];
+            dev.read_block(4, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == 0));
+
+            let mut data = [0u8; 1536];
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+            dev.write_block(2, &data).unwrap();
+            dev.write_block(3, &[0x55; 1536]).unwrap();
+            dev.read_block(2, &mut buf).unwrap();
+            assert_eq!(&buf[..], &data[..]);
+            dev.read_block(1, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == 0));
+
+            drop(dev);
+            assert_eq!(PhysAllocator::free_pages(), free);
+
+            // Bigger than the fixture's whole zone
+            assert!(MemBlockDevice::new(4096, 1 << fixture::ORDER).is_none());
+            // Bigger than any allocation, and too big to even work out the size
+            assert!(MemBlockDevice::new(4096, 2 << MAX_ORDER).is_none());
+            assert!(MemBlockDevice::new(usize::MAX, u64::MAX).is_none());
+            assert!(MemBlockDevice::new(4096, u64::MAX / 2).is_none());
+        }
+    );
+
+    test_case!(
+        block_index_out_of_range,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            let mut dev = MemBlockDevice::new(512, 8).unwrap();
+            let mut buf = [0u8; 512];
+            assert_eq!(dev.read_block(8, &mut buf), Err(BlockError::OutOfRange));
+            assert_eq!(dev.write_block(u64::MAX, &buf), Err(BlockError::OutOfRange));
+            assert_eq!(dev.read_block(0, &mut buf[..100]), Err(BlockError::BadBufferSize));
+            assert_eq!(dev.read_block(7, &mut buf), Ok(()));
+        }
+    );
+
+    test_case!(ramdisk_blocks, {
+        let mut dev = RamdiskDevice::new(INITRD, 200);
+        // 512 bytes is two whole blocks and a bit
+        assert_eq!(dev.num_blocks(), 3);
+
+        let mut buf = [0xAAu8; 200];
+        dev.read_block(0, &mut buf).unwrap();
+        assert_eq!(&buf[..], &INITRD[..200]);
+        dev.read_block(2, &mut buf).unwrap();
+        assert_eq!(&buf[..112], &INITRD[400..]);
+        assert!(buf[112..].iter().all(|&byte| byte == 0));
+
+        assert_eq!(dev.read_block(3, &mut buf), Err(BlockError::OutOfRange));
+        assert_eq!(dev.write_block(0, &buf), Err(BlockError::ReadOnly));
+        assert_eq!(dev.write_block(3, &buf), Err(BlockError::OutOfRange));
+    });
+}
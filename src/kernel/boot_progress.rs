@@ -0,0 +1,128 @@
+use super::init::Stage;
+use crate::ds::SpinLock;
+use arrayvec::ArrayString;
+use core::fmt::Write;
+
+// Feedback during bring-up on machines where nothing else is visible yet.
+// Each milestone is reported as it's reached, either to a registered display
+// (e.g. a framebuffer progress bar) or as a status line on the console.
+
+const BAR_WIDTH: usize = 20;
+
+pub trait ProgressDisplay: Sync {
+    fn show(&self, milestone: &str, percent: u8);
+}
+
+// Anything with a name can be a milestone, e.g. the stages in kernel_main
+pub trait Milestone {
+    fn name(&self) -> &str;
+}
+
+impl Milestone for &str {
+    fn name(&self) -> &str {
+        self
+    }
+}
+
+impl Milestone for Stage {
+    fn name(&self) -> &str {
+        self.name
+    }
+}
+
+pub struct Progress<M: 'static> {
+    milestones: &'static [M],
+    // Bit n is set once milestones[n] has been reached
+    done: u32,
+}
+
+impl<M> Progress<M> {
+    pub const fn new(milestones: &'static [M]) -> Self {
+        Self { milestones, done: 0 }
+    }
+}
+
+#[allow(dead_code)]
+impl<M: Milestone> Progress<M> {
+
+    // Returns the new percentage, or None if the milestone isn't in the list.
+    // Reaching a milestone twice doesn't count twice.
+    pub fn step(&mut self, name: &str) -> Option<u8> {
+        let idx = self.milestones.iter().position(|m| m.name() == name)?;
+        BUG_ON!(idx >= 32, "boot_progress: too many milestones");
+
+        self.done |= 1 << idx;
+        Some(self.percent())
+    }
+
+    pub fn percent(&self) -> u8 {
+        if self.milestones.is_empty() {
+            return 100;
+        }
+
+        (self.done.count_ones() as usize * 100 / self.milestones.len()) as u8
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.percent() == 100
+    }
+}
+
+// One milestone per stage
+static PROGRESS: SpinLock<Progress<Stage>> = SpinLock::new(Progress::new(super::STAGES));
+static DISPLAY: SpinLock<Option<&'static dyn ProgressDisplay>> = SpinLock::new(None);
+
+// For a framebuffer driver to take over from the console status lines
+#[allow(dead_code)]
+pub fn set_display(display: &'static dyn ProgressDisplay) {
+    *DISPLAY.lock() = Some(display);
+}
+
+fn status_line(milestone: &str, percent: u8) -> ArrayString<[u8; 64]> {
+    let filled = percent as usize * BAR_WIDTH / 100;
+
+    let mut line = ArrayString::new();
+    let _ = line.try_push_str("boot: [");
+    for i in 0..BAR_WIDTH {
+        let _ = line.try_push(if i < filled { '#' } else { ' ' });
+    }
+    let _ = write!(line, "] {:3}% {}", percent, milestone);
+    line
+}
+
+pub fn step(milestone: &str) {
+    let percent = match PROGRESS.lock().step(milestone) {
+        Some(percent) => percent,
+        None => {
+            warn!("boot_progress: unknown milestone {:?}", milestone);
+            return;
+        }
+    };
+
+    match *DISPLAY.lock() {
+        Some(display) => display.show(milestone, percent),
+        None => info!("{}", status_line(milestone, percent)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(boot_progress_milestones, {
+        static TEST_MILESTONES: &[&str] = &["a", "b", "c"];
+        let mut progress = Progress::new(TEST_MILESTONES);
+        assert_eq!(progress.percent(), 0);
+
+        assert_eq!(progress.step("b"), Some(33));
+        assert_eq!(progress.step("b"), Some(33));
+        assert_eq!(progress.step("nope"), None);
+        assert_eq!(progress.step("a"), Some(66));
+        assert!(!progress.is_complete());
+        assert_eq!(progress.step("c"), Some(100));
+        assert!(progress.is_complete());
+
+        assert_eq!(Progress::<&str>::new(&[]).percent(), 100);
+        assert_eq!(status_line("pmm", 50).as_str(), "boot: [##########          ]  50% pmm");
+    });
+}
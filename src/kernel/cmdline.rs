@@ -0,0 +1,44 @@
+// The bootloader has no way to pass a command line through, so for now it's
+// baked in at build time:
+//
+//   SOLSTICE_CMDLINE="panic=reboot:30" cargo xrun
+//
+// Options are whitespace separated `key=value` (or bare `key`) words. When a key
+// appears more than once, the last one wins.
+const CMDLINE: &str = match option_env!("SOLSTICE_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+// The value given for `key`, which is empty for a bare `key`
+pub fn get(key: &str) -> Option<&'static str> {
+    find(CMDLINE, key)
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|word| {
+            let mut parts = word.splitn(2, '=');
+            if parts.next()? == key {
+                Some(parts.next().unwrap_or(""))
+            } else {
+                None
+            }
+        })
+        .last()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(cmdline_lookup, {
+        let cmdline = "quiet panic=halt  log=debug panic=reboot:5";
+        assert_eq!(find(cmdline, "panic"), Some("reboot:5"));
+        assert_eq!(find(cmdline, "log"), Some("debug"));
+        assert_eq!(find(cmdline, "quiet"), Some(""));
+        assert_eq!(find(cmdline, "pan"), None);
+        assert_eq!(find("", "panic"), None);
+    });
+}
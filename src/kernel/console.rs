@@ -0,0 +1,180 @@
+use crate::{
+    cpu::interrupts,
+    drivers::{serial, vga::text_mode},
+    ds::{ReentrantSpinLock, SpinLock},
+};
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::{self, Write};
+
+const MAX_CONSOLES: usize = 4;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Color {
+    Black = 0,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+// An output device that print!() and the logger can write to. Backends are
+// shared, so they do their own locking.
+pub trait Console: Sync {
+    fn write_str(&self, s: &str);
+
+    // Both defaults go through ANSI escapes, which the VGA driver and any serial
+    // terminal understand
+    fn clear(&self) {
+        self.write_str("\x1B[2J\x1B[H");
+    }
+
+    fn set_color(&self, fg: Color, bg: Color) {
+        let mut escape = ArrayString::<[u8; 16]>::new();
+        let _ = write!(escape, "\x1B[{};{}m", 30 + fg as u8, 40 + bg as u8);
+        self.write_str(&escape);
+    }
+}
+
+// Every registered backend gets a copy of all output. The lock around this can
+// be held more than once at a time (see ReentrantSpinLock), so output only
+// needs a shared reference. The backend list has a lock of its own, which is
+// only ever found taken by an interrupt that came in while this CPU held it, so
+// output then goes to serial instead of waiting.
+pub struct Consoles {
+    backends: SpinLock<ArrayVec<[&'static dyn Console; MAX_CONSOLES]>>,
+}
+
+#[allow(dead_code)]
+impl Consoles {
+    fn new() -> Self {
+        Self {
+            backends: SpinLock::new(ArrayVec::new()),
+        }
+    }
+
+    // Hands the backend back if there's no room for it
+    pub fn register(&self, backend: &'static dyn Console) -> Result<(), &'static dyn Console> {
+        interrupts::without_interrupts(|| self.backends.lock().try_push(backend).map_err(|e| e.element()))
+    }
+
+    // Returns false if the backend wasn't registered
+    pub fn unregister(&self, backend: &'static dyn Console) -> bool {
+        let addr = |c: &dyn Console| c as *const dyn Console as *const u8;
+        interrupts::without_interrupts(|| {
+            let mut backends = self.backends.lock();
+            match backends.iter().position(|&c| addr(c) == addr(backend)) {
+                Some(idx) => {
+                    backends.remove(idx);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    // Nothing to do if the list is busy, since serial has no screen to clear
+    pub fn clear(&self) {
+        if let Some(backends) = self.backends.try_lock() {
+            for backend in backends.iter() {
+                backend.clear();
+            }
+        }
+    }
+
+    pub fn set_color(&self, fg: Color, bg: Color) {
+        if let Some(backends) = self.backends.try_lock() {
+            for backend in backends.iter() {
+                backend.set_color(fg, bg);
+            }
+        }
+    }
+}
+
+impl fmt::Write for &Consoles {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.backends.try_lock() {
+            Some(backends) => {
+                for backend in backends.iter() {
+                    backend.write_str(s);
+                }
+            }
+            None => serial::write_str(s),
+        }
+
+        #[cfg(test)]
+        crate::macros::capture::push(s);
+
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref CONSOLE: ReentrantSpinLock<Consoles> = {
+        let consoles = Consoles::new();
+        #[cfg(any(debug_assertions, test))]
+        let _ = consoles.register(&serial::SerialConsole);
+        let _ = consoles.register(&*text_mode::VGA);
+
+        ReentrantSpinLock::new(consoles)
+    };
+}
+
+pub fn console() -> &'static ReentrantSpinLock<Consoles> {
+    &CONSOLE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockConsole(SpinLock<ArrayString<[u8; 64]>>);
+
+    impl Console for MockConsole {
+        fn write_str(&self, s: &str) {
+            let _ = self.0.lock().try_push_str(s);
+        }
+    }
+
+    lazy_static! {
+        static ref FIRST: MockConsole = MockConsole(SpinLock::new(ArrayString::new()));
+        static ref SECOND: MockConsole = MockConsole(SpinLock::new(ArrayString::new()));
+        static ref THIRD: MockConsole = MockConsole(SpinLock::new(ArrayString::new()));
+    }
+
+    test_case!(console_tee, {
+        assert!(console().lock().register(&*FIRST).is_ok());
+        assert!(console().lock().register(&*SECOND).is_ok());
+
+        print!("hello");
+        console().lock().set_color(Color::Red, Color::Black);
+
+        assert!(console().lock().unregister(&*FIRST));
+        assert!(console().lock().unregister(&*SECOND));
+        assert!(!console().lock().unregister(&*SECOND));
+        print!("");
+
+        assert_eq!(FIRST.0.lock().as_str(), "hello\x1B[31;40m");
+        assert_eq!(SECOND.0.lock().as_str(), "hello\x1B[31;40m");
+    });
+
+    test_case!(console_backends_busy, {
+        let consoles = Consoles::new();
+        assert!(consoles.register(&*THIRD).is_ok());
+        let _ = (&consoles).write_str("a");
+
+        // As if an interrupt came in while this CPU was going through the list
+        {
+            let _busy = consoles.backends.lock();
+            let _ = (&consoles).write_str("b");
+            consoles.set_color(Color::Red, Color::Black);
+        }
+
+        let _ = (&consoles).write_str("c");
+        assert_eq!(THIRD.0.lock().as_str(), "ac");
+    });
+}
@@ -0,0 +1,209 @@
+use crate::{
+    cpu::{interrupts, percpu::PerCpu},
+    ds::{Counter, SpinLock},
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// Work that interrupt handlers hand off to run later, with interrupts enabled.
+// Each CPU has its own queue, drained on the way out of an interrupt (if the
+// interrupted code had interrupts enabled) and from the idle loop. Items are
+// plain function pointers plus an argument, so queueing never allocates.
+
+pub const QUEUE_SIZE: usize = 32;
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    pub func: fn(usize),
+    pub arg: usize,
+}
+
+struct Ring {
+    items: [Option<Work>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+pub struct DeferredQueue {
+    ring: SpinLock<Ring>,
+    // Set while draining, so an interrupt that arrives partway through
+    // doesn't start draining the same queue on top of it
+    draining: AtomicBool,
+    // Work turned away because the queue was full
+    pub dropped: Counter,
+}
+
+#[allow(dead_code)]
+impl DeferredQueue {
+    pub const fn new() -> Self {
+        Self {
+            ring: SpinLock::new(Ring {
+                items: [None; QUEUE_SIZE],
+                head: 0,
+                len: 0,
+            }),
+            draining: AtomicBool::new(false),
+            dropped: Counter::new(),
+        }
+    }
+
+    // Safe from an interrupt handler. Hands the work back if the queue is full.
+    pub fn push(&self, work: Work) -> Result<(), Work> {
+        interrupts::without_interrupts(|| {
+            let mut ring = self.ring.lock();
+            if ring.len == QUEUE_SIZE {
+                self.dropped.inc();
+                return Err(work);
+            }
+
+            let tail = (ring.head + ring.len) % QUEUE_SIZE;
+            ring.items[tail] = Some(work);
+            ring.len += 1;
+            Ok(())
+        })
+    }
+
+    fn pop(&self) -> Option<Work> {
+        interrupts::without_interrupts(|| {
+            let mut ring = self.ring.lock();
+            if ring.len == 0 {
+                return None;
+            }
+
+            let head = ring.head;
+            ring.head = (head + 1) % QUEUE_SIZE;
+            ring.len -= 1;
+            ring.items[head].take()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.ring.lock().len == 0)
+    }
+
+    // Runs everything queued, oldest first, including anything queued while
+    // it runs. Returns how many items ran, which is 0 if this CPU was already
+    // draining the queue further down the stack.
+    pub fn run_pending(&self) -> usize {
+        if self.draining.swap(true, Ordering::Acquire) {
+            return 0;
+        }
+
+        let mut ran = 0;
+        while let Some(work) = self.pop() {
+            (work.func)(work.arg);
+            ran += 1;
+        }
+
+        self.draining.store(false, Ordering::Release);
+        ran
+    }
+}
+
+// Queue `func(arg)` on the current CPU
+#[allow(dead_code)]
+pub fn defer(func: fn(usize), arg: usize) -> Result<(), Work> {
+    PerCpu::current().deferred.push(Work { func, arg })
+}
+
+pub fn run_pending() -> usize {
+    PerCpu::current().deferred.run_pending()
+}
+
+// Called at the end of an interrupt handler, after its EOI. Code that was
+// running with interrupts disabled can't have them turned on under it, so the
+// queue is left for later in that case.
+pub fn irq_exit(frame: &InterruptStackFrame) {
+    if frame.cpu_flags & RFLAGS_IF == 0 {
+        return;
+    }
+
+    x86_64::instructions::interrupts::enable();
+    run_pending();
+    x86_64::instructions::interrupts::disable();
+}
+
+pub fn idle() -> ! {
+    loop {
+        run_pending();
+
+        x86_64::instructions::interrupts::disable();
+        if PerCpu::current().deferred.is_empty() {
+            // Interrupts only come back on after the hlt, so anything queued
+            // since the check still wakes it
+            x86_64::instructions::interrupts::enable_and_hlt();
+        } else {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    // The args of the work that's run, in order
+    const MAX_RAN: usize = 64;
+    static RAN: SpinLock<([usize; MAX_RAN], usize)> = SpinLock::new(([0; MAX_RAN], 0));
+
+    fn record(arg: usize) {
+        let mut ran = RAN.lock();
+        let len = ran.1;
+        ran.0[len] = arg;
+        ran.1 += 1;
+    }
+
+    fn ran() -> ArrayVec<[usize; MAX_RAN]> {
+        let ran = RAN.lock();
+        ran.0[..ran.1].iter().copied().collect()
+    }
+
+    static QUEUE: DeferredQueue = DeferredQueue::new();
+
+    // Queues more work from inside the drain, which runs in the same drain
+    fn requeue(arg: usize) {
+        record(arg);
+        QUEUE.push(Work { func: record, arg: arg + 1 }).unwrap();
+    }
+
+    test_case!(deferred_work_fifo, {
+        RAN.lock().1 = 0;
+
+        // As if from an interrupt handler
+        interrupts::without_interrupts(|| {
+            for arg in 0..3 {
+                QUEUE.push(Work { func: record, arg }).unwrap();
+            }
+            QUEUE.push(Work { func: requeue, arg: 10 }).unwrap();
+        });
+        assert!(ran().is_empty());
+
+        assert_eq!(QUEUE.run_pending(), 5);
+        assert_eq!(&ran()[..], &[0, 1, 2, 10, 11]);
+
+        // Nothing runs twice
+        assert_eq!(QUEUE.run_pending(), 0);
+        assert_eq!(ran().len(), 5);
+        assert!(QUEUE.is_empty());
+    });
+
+    test_case!(deferred_queue_full, {
+        let queue = DeferredQueue::new();
+        RAN.lock().1 = 0;
+
+        for arg in 0..QUEUE_SIZE {
+            queue.push(Work { func: record, arg }).unwrap();
+        }
+        assert!(queue.push(Work { func: record, arg: QUEUE_SIZE }).is_err());
+        assert_eq!(queue.dropped.get(), 1);
+
+        // Wraps around the end of the ring
+        assert_eq!(queue.run_pending(), QUEUE_SIZE);
+        queue.push(Work { func: record, arg: QUEUE_SIZE }).unwrap();
+        assert_eq!(queue.run_pending(), 1);
+        assert!(ran().iter().copied().eq(0..=QUEUE_SIZE));
+    });
+}
@@ -0,0 +1,140 @@
+use crate::drivers::serial;
+use core::{
+    fmt::{self, Write},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Panics from before the logger is set up would otherwise vanish, since the
+// log macros do nothing until then. These go straight to the VGA buffer and
+// COM1 instead, without taking any locks.
+
+const VGA_BUFFER: usize = 0xB8000;
+const VGA_WIDTH: usize = 80;
+const VGA_HEIGHT: usize = 25;
+// White on red
+const VGA_STYLE: u16 = 0x4F00;
+
+static LOGGER_READY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_logger_ready() {
+    LOGGER_READY.store(true, Ordering::Release);
+}
+
+pub fn logger_ready() -> bool {
+    LOGGER_READY.load(Ordering::Acquire)
+}
+
+// Writes over whatever is on screen from the top left, wrapping back to the
+// top at the bottom rather than scrolling
+pub struct RawVga<'a> {
+    buf: &'a mut [u16],
+    pos: usize,
+}
+
+impl<'a> RawVga<'a> {
+    pub fn new(buf: &'a mut [u16]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    // Safety: nothing else may be using the VGA buffer at the same time
+    pub unsafe fn hardware() -> RawVga<'static> {
+        RawVga::new(core::slice::from_raw_parts_mut(
+            VGA_BUFFER as *mut u16,
+            VGA_WIDTH * VGA_HEIGHT,
+        ))
+    }
+}
+
+impl Write for RawVga<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.pos >= self.buf.len() {
+                self.pos = 0;
+            }
+
+            match byte {
+                b'\n' => self.pos += VGA_WIDTH - self.pos % VGA_WIDTH,
+                0x20..=0x7E => {
+                    unsafe { ptr::write_volatile(&mut self.buf[self.pos], VGA_STYLE | byte as u16) };
+                    self.pos += 1;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct RawSerial;
+
+impl Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::write_str(s);
+        Ok(())
+    }
+}
+
+// Returns false without writing anything if the logger is ready, and should
+// be used instead
+pub fn report_using(ready: &AtomicBool, outputs: &mut [&mut dyn Write], args: fmt::Arguments) -> bool {
+    if ready.load(Ordering::Acquire) {
+        return false;
+    }
+
+    for output in outputs.iter_mut() {
+        let _ = writeln!(output, "early panic: {}", args);
+    }
+
+    true
+}
+
+pub fn report(args: fmt::Arguments) -> bool {
+    if logger_ready() {
+        return false;
+    }
+
+    // Safety: the logger isn't up, so neither is the VGA console
+    let mut vga = unsafe { RawVga::hardware() };
+    report_using(&LOGGER_READY, &mut [&mut vga, &mut RawSerial], args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+
+    test_case!(early_panic_before_logger, {
+        let ready = AtomicBool::new(false);
+        let mut screen = [0u16; VGA_WIDTH * 2];
+        let mut serial = ArrayString::<[u8; 64]>::new();
+
+        {
+            let mut vga = RawVga::new(&mut screen);
+            assert!(report_using(&ready, &mut [&mut vga, &mut serial], format_args!("oops {}", 1)));
+        }
+        assert_eq!(serial.as_str(), "early panic: oops 1\n");
+
+        let text = b"early panic: oops 1";
+        for (i, &ch) in screen[..VGA_WIDTH].iter().enumerate() {
+            let expected = text.get(i).map_or(0, |&byte| VGA_STYLE | byte as u16);
+            assert_eq!(ch, expected, "column {}", i);
+        }
+        // The newline moved on to the next row without drawing anything
+        assert!(screen[VGA_WIDTH..].iter().all(|&ch| ch == 0));
+
+        // Once the logger is up, it's left to handle things
+        ready.store(true, Ordering::Release);
+        serial.clear();
+        assert!(!report_using(&ready, &mut [&mut serial], format_args!("oops")));
+        assert_eq!(serial.as_str(), "");
+    });
+
+    test_case!(raw_vga_wraps, {
+        let mut screen = [0u16; 4];
+        let mut vga = RawVga::new(&mut screen);
+        let _ = vga.write_str("abcdef");
+        assert_eq!(screen.map(|ch| ch as u8), *b"efcd");
+    });
+}
@@ -0,0 +1,357 @@
+use crate::mm::{
+    addr_space::{is_user_addr, USER_END},
+    PAGE_SIZE,
+};
+use arrayvec::ArrayVec;
+use core::{convert::TryInto, ops::Range};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+// Reader for statically linked ELF64 executables, for x86-64 only. It works
+// out what has to be mapped where, and exec::load_elf() does the mapping.
+// Segments can't share pages, so anything linked to put two of them in the
+// same page, which is common without -z separate-code, is refused.
+
+const MAGIC: &[u8] = b"\x7FELF";
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+
+const HEADER_LEN: usize = 64;
+const PROGRAM_HEADER_LEN: usize = 56;
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+pub const MAX_SEGMENTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    // Outside the file or user space, or with the file part bigger than the
+    // memory part
+    BadSegment,
+    OverlappingSegments,
+    TooManySegments,
+    // Not in an executable segment
+    BadEntry,
+}
+
+// A PT_LOAD program header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: VirtAddr,
+    pub offset: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+    pub flags: u32,
+}
+
+impl Segment {
+    // Readable whatever p_flags say, since pages always are. The caller adds
+    // USER_ACCESSIBLE.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+// The pages a segment needs. The file's bytes go at `vaddr`, and everything
+// else in the pages, including the .bss part past the file's bytes, is zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentMapping {
+    pub start: VirtAddr,
+    pub pages: u64,
+    pub flags: PageTableFlags,
+    pub vaddr: VirtAddr,
+    pub file: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: VirtAddr,
+    phoff: usize,
+    phnum: usize,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[allow(dead_code)]
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header = data.get(..HEADER_LEN).ok_or(ElfError::Truncated)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header[4] != CLASS_64 {
+            return Err(ElfError::NotElf64);
+        }
+        if header[5] != DATA_LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if u16_at(header, 16) != TYPE_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if u16_at(header, 18) != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let phoff = u64_at(header, 32) as usize;
+        let phnum = u16_at(header, 56) as usize;
+        if u16_at(header, 54) as usize != PROGRAM_HEADER_LEN
+            || phoff
+                .checked_add(phnum * PROGRAM_HEADER_LEN)
+                .map_or(true, |end| end > data.len())
+        {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        // Entry points outside user space can't be in any segment either
+        let entry = VirtAddr::try_new(u64_at(header, 24)).map_err(|_| ElfError::BadEntry)?;
+        Ok(Self {
+            data,
+            entry,
+            phoff,
+            phnum,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    // The PT_LOAD segments, in the order the file has them. Everything else
+    // is skipped.
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment, ElfError>> + 'a {
+        let (data, phoff) = (self.data, self.phoff);
+        (0..self.phnum)
+            .map(move |i| &data[phoff + i * PROGRAM_HEADER_LEN..][..PROGRAM_HEADER_LEN])
+            .filter(|header| u32_at(header, 0) == PT_LOAD)
+            .map(move |header| {
+                let segment = Segment {
+                    vaddr: VirtAddr::try_new(u64_at(header, 16))
+                        .map_err(|_| ElfError::BadSegment)?,
+                    offset: u64_at(header, 8) as usize,
+                    file_size: u64_at(header, 32) as usize,
+                    mem_size: u64_at(header, 40) as usize,
+                    flags: u32_at(header, 4),
+                };
+
+                let file_end = segment.offset.checked_add(segment.file_size);
+                let mem_end = segment.vaddr.as_u64().checked_add(segment.mem_size as u64);
+                match (file_end, mem_end) {
+                    (Some(file_end), Some(mem_end))
+                        if file_end <= data.len()
+                            && segment.file_size <= segment.mem_size
+                            && segment.mem_size > 0
+                            && is_user_addr(segment.vaddr)
+                            // The end needn't be canonical, so no VirtAddr
+                            && mem_end <= USER_END =>
+                    {
+                        Ok(segment)
+                    }
+                    _ => Err(ElfError::BadSegment),
+                }
+            })
+    }
+
+    // What has to be mapped to load the executable, in address order
+    pub fn mappings(&self) -> Result<ArrayVec<[SegmentMapping; MAX_SEGMENTS]>, ElfError> {
+        let mut segments: ArrayVec<[Segment; MAX_SEGMENTS]> = ArrayVec::new();
+        for segment in self.segments() {
+            segments
+                .try_push(segment?)
+                .map_err(|_| ElfError::TooManySegments)?;
+        }
+        segments.sort_unstable_by_key(|segment| segment.vaddr);
+
+        let mappings: ArrayVec<[SegmentMapping; MAX_SEGMENTS]> = segments
+            .iter()
+            .map(|segment| {
+                let start = segment.vaddr.align_down(PAGE_SIZE);
+                let end = (segment.vaddr + segment.mem_size).align_up(PAGE_SIZE);
+                SegmentMapping {
+                    start,
+                    pages: (end - start) / PAGE_SIZE,
+                    flags: segment.page_flags(),
+                    vaddr: segment.vaddr,
+                    file: segment.offset..segment.offset + segment.file_size,
+                }
+            })
+            .collect();
+
+        if mappings
+            .windows(2)
+            .any(|pair| pair[0].start + pair[0].pages * PAGE_SIZE > pair[1].start)
+        {
+            return Err(ElfError::OverlappingSegments);
+        }
+        if !segments.iter().any(|segment| {
+            segment.is_executable()
+                && self.entry >= segment.vaddr
+                && self.entry < segment.vaddr + segment.mem_size
+        }) {
+            return Err(ElfError::BadEntry);
+        }
+
+        Ok(mappings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A text segment with mov eax, 42; ret at the entry point, a GNU_STACK
+    // header, and a data segment with 8 bytes in the file and .bss running on
+    // into the next page
+    pub static FIXTURE: &[u8] = include_bytes!("testdata/exit42.elf");
+
+    test_case!(elf_header, {
+        let elf = Elf::parse(FIXTURE).unwrap();
+        assert_eq!(elf.entry, VirtAddr::new(0x40_0100));
+
+        // Only the PT_LOAD headers
+        let segments: ArrayVec<[Segment; 4]> = elf.segments().map(Result::unwrap).collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            segments[0],
+            Segment {
+                vaddr: VirtAddr::new(0x40_0100),
+                offset: 0x100,
+                file_size: 6,
+                mem_size: 6,
+                flags: 0b101,
+            }
+        );
+        assert_eq!((segments[1].file_size, segments[1].mem_size), (8, 0x1000));
+    });
+
+    test_case!(elf_segment_mappings, {
+        let mappings = Elf::parse(FIXTURE).unwrap().mappings().unwrap();
+        assert_eq!(mappings.len(), 2);
+
+        // Text: read and execute
+        assert_eq!(
+            mappings[0],
+            SegmentMapping {
+                start: VirtAddr::new(0x40_0000),
+                pages: 1,
+                flags: PageTableFlags::PRESENT,
+                vaddr: VirtAddr::new(0x40_0100),
+                file: 0x100..0x106,
+            }
+        );
+
+        // Data: read and write, never executed, and the .bss takes it onto a
+        // second page
+        assert_eq!(
+            mappings[1],
+            SegmentMapping {
+                start: VirtAddr::new(0x40_2000),
+                pages: 2,
+                flags: PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_EXECUTE,
+                vaddr: VirtAddr::new(0x40_2108),
+                file: 0x108..0x110,
+            }
+        );
+    });
+
+    test_case!(elf_header_errors, {
+        let patched = |offset: usize, bytes: &[u8]| {
+            let mut elf = [0u8; 0x110];
+            elf.copy_from_slice(FIXTURE);
+            elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+            elf
+        };
+
+        assert_eq!(Elf::parse(&FIXTURE[..40]).err(), Some(ElfError::Truncated));
+        assert_eq!(
+            Elf::parse(&patched(0, b"\x7FELG")).err(),
+            Some(ElfError::BadMagic)
+        );
+        // 32 bit, big endian, a shared object, and an AArch64 binary
+        assert_eq!(
+            Elf::parse(&patched(4, &[1])).err(),
+            Some(ElfError::NotElf64)
+        );
+        assert_eq!(
+            Elf::parse(&patched(5, &[2])).err(),
+            Some(ElfError::NotLittleEndian)
+        );
+        assert_eq!(
+            Elf::parse(&patched(16, &[3, 0])).err(),
+            Some(ElfError::NotExecutable)
+        );
+        assert_eq!(
+            Elf::parse(&patched(18, &[0xB7, 0])).err(),
+            Some(ElfError::WrongMachine)
+        );
+        // More program headers than the file has room for
+        assert_eq!(
+            Elf::parse(&patched(56, &[9, 0])).err(),
+            Some(ElfError::BadProgramHeaders)
+        );
+
+        // The entry point in the data segment
+        let elf = patched(24, &0x40_2108u64.to_le_bytes());
+        assert_eq!(
+            Elf::parse(&elf).unwrap().mappings().err(),
+            Some(ElfError::BadEntry)
+        );
+
+        // The data segment moved down into the text segment's page
+        let elf = patched(
+            64 + 2 * PROGRAM_HEADER_LEN + 16,
+            &0x40_0108u64.to_le_bytes(),
+        );
+        assert_eq!(
+            Elf::parse(&elf).unwrap().mappings().err(),
+            Some(ElfError::OverlappingSegments)
+        );
+
+        // File bytes past the end of the file
+        let elf = patched(64 + 2 * PROGRAM_HEADER_LEN + 32, &0x20u64.to_le_bytes());
+        assert_eq!(
+            Elf::parse(&elf).unwrap().mappings().err(),
+            Some(ElfError::BadSegment)
+        );
+
+        // Memory running off the end of user space, into the non-canonical hole
+        let elf = patched(64 + 2 * PROGRAM_HEADER_LEN + 40, &(1u64 << 48).to_le_bytes());
+        assert_eq!(
+            Elf::parse(&elf).unwrap().mappings().err(),
+            Some(ElfError::BadSegment)
+        );
+    });
+}
@@ -0,0 +1,308 @@
+use crate::{
+    kernel::elf::{Elf, ElfError, MAX_SEGMENTS},
+    mm::{addr_space::AddrSpace, phys_to_kernel_virt, pmm::PhysAllocator, PAGE_SIZE},
+};
+use arrayvec::ArrayVec;
+use core::ptr;
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+// Loads flat binaries (raw code, no headers) and ELF executables into a fresh
+// user address space. A flat image goes at USER_BASE, an ELF one wherever its
+// segments say, and the stack sits below USER_STACK_TOP.
+// Nothing drops to ring 3 yet, so for now the image can only be run in ring 0,
+// which is enough to check the loader.
+// TODO: iretq into ring 3 with gdt::USER_CODE_SELECTOR and USER_DATA_SELECTOR.
+// The way back in is there (RSP0 and the swapgs on entry), but there's no exit
+// syscall yet for the image to end its task with, and RSP0 is one shared stack
+// rather than the task's own.
+
+pub const USER_BASE: u64 = 0x40_0000;
+pub const USER_STACK_TOP: u64 = 0x7FFF_FFFF_0000;
+pub const USER_STACK_PAGES: u64 = 16;
+// Keeps a stray image from running into the stack
+pub const MAX_IMAGE_SIZE: usize = 64 * 1024 * 1024;
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ExecError {
+    Empty,
+    TooLarge,
+    EntryOutOfBounds,
+    Elf(ElfError),
+    Map(MapToError<Size4KiB>),
+}
+
+#[allow(dead_code)]
+pub struct LoadedImage {
+    pub space: AddrSpace,
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+    // The image's mapped pages, as (first page, pages) runs
+    regions: ArrayVec<[(VirtAddr, u64); MAX_SEGMENTS]>,
+}
+
+// The image is mapped read only, since a flat binary doesn't say which parts
+// are data
+fn code_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT
+}
+
+fn stack_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+fn stack_bottom() -> VirtAddr {
+    VirtAddr::new(USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE)
+}
+
+// Maps a fresh frame at `virt`, with `bytes` copied in at `offset` and zeroes
+// everywhere else
+fn map_page(
+    space: &AddrSpace,
+    virt: VirtAddr,
+    offset: usize,
+    bytes: &[u8],
+    flags: PageTableFlags,
+) -> Result<(), ExecError> {
+    BUG_ON!(offset + bytes.len() > PAGE_SIZE as usize, "exec: {} bytes at {} don't fit in a page", bytes.len(), offset);
+    let frame = PhysAllocator::alloc(0).start;
+    unsafe {
+        let page: *mut u8 = phys_to_kernel_virt(frame.start_address()).as_mut_ptr();
+        ptr::write_bytes(page, 0, PAGE_SIZE as usize);
+        ptr::copy_nonoverlapping(bytes.as_ptr(), page.add(offset), bytes.len());
+    }
+
+    match space.map_user(virt, frame.start_address(), flags) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(e) => {
+            PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+            Err(ExecError::Map(e))
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn load_flat(bytes: &[u8], entry_offset: usize) -> Result<LoadedImage, ExecError> {
+    if bytes.is_empty() {
+        return Err(ExecError::Empty);
+    }
+    if bytes.len() > MAX_IMAGE_SIZE {
+        return Err(ExecError::TooLarge);
+    }
+    if entry_offset >= bytes.len() {
+        return Err(ExecError::EntryOutOfBounds);
+    }
+
+    // Whatever gets mapped before a failure is freed by the image's Drop
+    let mut image = LoadedImage {
+        space: AddrSpace::new_user(),
+        entry: VirtAddr::new(USER_BASE + entry_offset as u64),
+        stack_top: VirtAddr::new(USER_STACK_TOP),
+        regions: ArrayVec::new(),
+    };
+
+    image.regions.push((VirtAddr::new(USER_BASE), 0));
+    for (i, chunk) in bytes.chunks(PAGE_SIZE as usize).enumerate() {
+        let virt = VirtAddr::new(USER_BASE) + i as u64 * PAGE_SIZE;
+        map_page(&image.space, virt, 0, chunk, code_flags())?;
+        image.regions[0].1 += 1;
+    }
+
+    image.map_stack()?;
+    Ok(image)
+}
+
+// Loads a statically linked ELF64 executable. Each PT_LOAD segment gets its own
+// pages, with the file's bytes copied in and the rest, .bss included, zeroed.
+#[allow(dead_code)]
+pub fn load_elf(bytes: &[u8]) -> Result<LoadedImage, ExecError> {
+    let elf = Elf::parse(bytes).map_err(ExecError::Elf)?;
+    let mappings = elf.mappings().map_err(ExecError::Elf)?;
+    // Nothing may reach the stack's guard page
+    if mappings
+        .iter()
+        .any(|mapping| mapping.start + mapping.pages * PAGE_SIZE > stack_bottom() - PAGE_SIZE)
+    {
+        return Err(ExecError::TooLarge);
+    }
+
+    let mut image = LoadedImage {
+        space: AddrSpace::new_user(),
+        entry: elf.entry,
+        stack_top: VirtAddr::new(USER_STACK_TOP),
+        regions: ArrayVec::new(),
+    };
+
+    for mapping in mappings.iter() {
+        let file_end = mapping.vaddr + mapping.file.len();
+        image.regions.push((mapping.start, 0));
+        for i in 0..mapping.pages {
+            let page = mapping.start + i * PAGE_SIZE;
+            // The part of the file's bytes that lands on this page
+            let from = mapping.vaddr.max(page);
+            let to = file_end.min(page + PAGE_SIZE);
+            let (offset, chunk) = if from < to {
+                let file = mapping.file.start + (from - mapping.vaddr) as usize;
+                let len = (to - from) as usize;
+                ((from - page) as usize, &bytes[file..file + len])
+            } else {
+                (0, &[][..])
+            };
+
+            map_page(&image.space, page, offset, chunk, mapping.flags)?;
+            image.regions.last_mut().unwrap().1 += 1;
+        }
+    }
+
+    image.map_stack()?;
+    Ok(image)
+}
+
+#[allow(dead_code)]
+impl LoadedImage {
+    fn map_stack(&self) -> Result<(), ExecError> {
+        for i in 0..USER_STACK_PAGES {
+            map_page(&self.space, stack_bottom() + i * PAGE_SIZE, 0, &[], stack_flags())?;
+        }
+        Ok(())
+    }
+
+    pub fn image_pages(&self) -> u64 {
+        self.regions.iter().map(|&(_, pages)| pages).sum()
+    }
+
+    // Calls the entry point in ring 0, on the current stack, with the image's
+    // address space active. Returns whatever the image left in rax. This only
+    // works while SMEP is off, since the image is in user pages.
+    // Safety: the image has to be code that returns, and leaves the kernel's
+    // state alone
+    pub unsafe fn run_in_kernel(&self) -> u64 {
+        let previous = AddrSpace::kernel();
+        BUG_ON!(!previous.is_active(), "exec: only the kernel's address space can be left for an image");
+
+        self.space.switch_to();
+        let entry: extern "C" fn() -> u64 = core::mem::transmute(self.entry.as_u64());
+        let result = entry();
+        previous.switch_to();
+
+        result
+    }
+}
+
+// The address space only frees its page tables, so the frames behind the image
+// and stack go back here
+impl Drop for LoadedImage {
+    fn drop(&mut self) {
+        let image = self
+            .regions
+            .iter()
+            .flat_map(|&(start, pages)| (0..pages).map(move |i| start + i * PAGE_SIZE));
+        let stack = (0..USER_STACK_PAGES).map(|i| stack_bottom() + i * PAGE_SIZE);
+        for virt in image.chain(stack) {
+            if let Some(phys) = self.space.translate_addr(virt) {
+                let frame = PhysFrame::containing_address(phys);
+                PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mov eax, 42; ret, then enough padding to spill onto a second page
+    fn blob() -> [u8; 4200] {
+        let mut blob = [0x90; 4200];
+        blob[..6].copy_from_slice(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]);
+        blob
+    }
+
+    test_case!(flat_binary_layout, {
+        let blob = blob();
+        let image = load_flat(&blob, 0).unwrap();
+        assert_eq!(image.entry, VirtAddr::new(USER_BASE));
+        assert_eq!(image.image_pages(), 2);
+
+        for i in 0..2 {
+            let flags = image.space.page_flags(VirtAddr::new(USER_BASE) + i * PAGE_SIZE).unwrap();
+            assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE));
+            assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+            assert!(!flags.contains(PageTableFlags::WRITABLE));
+        }
+        assert_eq!(image.space.page_flags(VirtAddr::new(USER_BASE) + 2 * PAGE_SIZE), None);
+
+        for i in 0..USER_STACK_PAGES {
+            let flags = image.space.page_flags(stack_bottom() + i * PAGE_SIZE).unwrap();
+            assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+        }
+        // A guard page below the stack, and nothing above it
+        assert_eq!(image.space.page_flags(stack_bottom() - PAGE_SIZE), None);
+        assert_eq!(image.space.page_flags(image.stack_top), None);
+
+        // The copy is in place, with the rest of the last page zeroed
+        let second = image.space.translate_addr(VirtAddr::new(USER_BASE) + PAGE_SIZE).unwrap();
+        let bytes = unsafe { core::slice::from_raw_parts(phys_to_kernel_virt(second).as_ptr::<u8>(), PAGE_SIZE as usize) };
+        assert!(bytes[..4200 - 4096].iter().all(|&b| b == 0x90));
+        assert!(bytes[4200 - 4096..].iter().all(|&b| b == 0));
+    });
+
+    test_case!(flat_binary_runs, {
+        let image = load_flat(&blob(), 0).unwrap();
+        assert_eq!(unsafe { image.run_in_kernel() }, 42);
+        assert!(AddrSpace::kernel().is_active());
+    });
+
+    test_case!(flat_binary_errors, {
+        assert!(matches!(load_flat(&[], 0), Err(ExecError::Empty)));
+        assert!(matches!(load_flat(&[0xC3], 1), Err(ExecError::EntryOutOfBounds)));
+    });
+
+    // See elf.rs for what's in it
+    static EXIT42: &[u8] = include_bytes!("testdata/exit42.elf");
+
+    test_case!(elf_layout, {
+        let image = load_elf(EXIT42).unwrap();
+        assert_eq!(image.entry, VirtAddr::new(0x40_0100));
+        assert_eq!(image.image_pages(), 3);
+
+        let text = image.space.page_flags(VirtAddr::new(0x40_0000)).unwrap();
+        assert!(text.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE));
+        assert!(!text.intersects(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+        for page in &[0x40_2000, 0x40_3000] {
+            let data = image.space.page_flags(VirtAddr::new(*page)).unwrap();
+            assert!(data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+        }
+        // Nothing between or after the segments
+        assert_eq!(image.space.page_flags(VirtAddr::new(0x40_1000)), None);
+        assert_eq!(image.space.page_flags(VirtAddr::new(0x40_4000)), None);
+
+        // The data sits at its offset into the page, with .bss zeroed around it
+        // and on into the next page
+        let bytes = |virt: u64| {
+            let phys = image.space.translate_addr(VirtAddr::new(virt)).unwrap();
+            unsafe { core::slice::from_raw_parts(phys_to_kernel_virt(phys).as_ptr::<u8>(), PAGE_SIZE as usize) }
+        };
+        let data = bytes(0x40_2000);
+        assert_eq!(&data[0x108..0x110], &0x1122_3344_5566_7788u64.to_le_bytes());
+        assert!(data[..0x108].iter().chain(&data[0x110..]).all(|&b| b == 0));
+        assert!(bytes(0x40_3000).iter().all(|&b| b == 0));
+        assert_eq!(&bytes(0x40_0000)[0x100..0x106], &[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]);
+    });
+
+    test_case!(elf_runs, {
+        let image = load_elf(EXIT42).unwrap();
+        assert_eq!(unsafe { image.run_in_kernel() }, 42);
+
+        assert!(matches!(
+            load_elf(&EXIT42[..32]),
+            Err(ExecError::Elf(ElfError::Truncated))
+        ));
+    });
+}
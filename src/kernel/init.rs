@@ -0,0 +1,98 @@
+use core::fmt;
+
+// Boot is a fixed list of named stages run in order. The first one to fail
+// stops the rest, and the caller gets told which it was and why.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    NoMemory,
+    // Hardware or firmware the kernel can't work with
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InitError::NoMemory => f.write_str("out of memory"),
+            InitError::Unsupported(what) => write!(f, "unsupported {}", what),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Stage {
+    pub name: &'static str,
+    pub run: fn() -> Result<(), InitError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageFailure {
+    pub stage: &'static str,
+    pub error: InitError,
+}
+
+impl fmt::Display for StageFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "init: stage {} failed: {}", self.stage, self.error)
+    }
+}
+
+// `done` is called with the name of each stage as it finishes
+pub fn run(stages: &[Stage], mut done: impl FnMut(&'static str)) -> Result<(), StageFailure> {
+    for stage in stages {
+        debug!("init: {}...", stage.name);
+        (stage.run)().map_err(|error| StageFailure { stage: stage.name, error })?;
+        debug!("init: {} done", stage.name);
+        done(stage.name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    test_case!(failed_stage_stops_init, {
+        // Bit n is set once stage n has run
+        static RAN: AtomicUsize = AtomicUsize::new(0);
+
+        fn first() -> Result<(), InitError> {
+            RAN.fetch_or(1 << 0, Ordering::Relaxed);
+            Ok(())
+        }
+        fn second() -> Result<(), InitError> {
+            RAN.fetch_or(1 << 1, Ordering::Relaxed);
+            Err(InitError::Unsupported("widget"))
+        }
+        fn third() -> Result<(), InitError> {
+            RAN.fetch_or(1 << 2, Ordering::Relaxed);
+            Ok(())
+        }
+
+        let stages = [
+            Stage { name: "first", run: first },
+            Stage { name: "second", run: second },
+            Stage { name: "third", run: third },
+        ];
+
+        let mut done: ArrayVec<[&str; 4]> = ArrayVec::new();
+        let failure = run(&stages, |name| done.push(name)).unwrap_err();
+        assert_eq!(failure, StageFailure { stage: "second", error: InitError::Unsupported("widget") });
+        assert_eq!(&done[..], &["first"]);
+        assert_eq!(RAN.load(Ordering::Relaxed), 0b011);
+
+        let mut message = arrayvec::ArrayString::<[u8; 64]>::new();
+        core::fmt::Write::write_fmt(&mut message, format_args!("{}", failure)).unwrap();
+        assert_eq!(message.as_str(), "init: stage second failed: unsupported widget");
+
+        // Without the failing stage, everything runs
+        RAN.store(0, Ordering::Relaxed);
+        done.clear();
+        assert_eq!(run(&[stages[0], stages[2]], |name| done.push(name)), Ok(()));
+        assert_eq!(&done[..], &["first", "third"]);
+        assert_eq!(RAN.load(Ordering::Relaxed), 0b101);
+    });
+}
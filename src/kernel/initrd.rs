@@ -0,0 +1,213 @@
+use crate::mm::phys_to_kernel_virt;
+use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
+use core::{slice, str};
+use x86_64::PhysAddr;
+
+// Reader for cpio archives in the "newc" format, as produced by
+// `cpio -o -H newc`
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIR: u32 = 0o040000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    Truncated,
+    BadMagic,
+    BadHeader,
+    BadName,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIR
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+#[allow(dead_code)]
+impl<'a> Archive<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    // The whole archive, as the bootloader loaded it
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            data: self.data,
+            pos: 0,
+            done: false,
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&'a [u8]> {
+        self.entries()
+            .filter_map(Result::ok)
+            .find(|entry| entry.name == name && !entry.is_dir())
+            .map(|entry| entry.data)
+    }
+}
+
+// Stops at the trailer entry, or after the first error
+pub struct Entries<'a> {
+    data: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> Entries<'a> {
+    fn parse_next(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let header = self
+            .data
+            .get(self.pos..self.pos + HEADER_LEN)
+            .ok_or(CpioError::Truncated)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(CpioError::BadMagic);
+        }
+
+        let mode = hex_field(header, 1)?;
+        let file_size = hex_field(header, 6)? as usize;
+        let name_size = hex_field(header, 11)? as usize;
+
+        // The name size includes the NUL terminator
+        let name_start = self.pos + HEADER_LEN;
+        let name = match self.data.get(name_start..name_start + name_size) {
+            Some(bytes) => match bytes.split_last() {
+                Some((&0, name)) => str::from_utf8(name).map_err(|_| CpioError::BadName)?,
+                _ => return Err(CpioError::BadName),
+            },
+            None => return Err(CpioError::Truncated),
+        };
+
+        // Both the name and the data are padded out to 4 bytes
+        let data_start = align4(name_start + name_size);
+        let data = self
+            .data
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        self.pos = align4(data_start + file_size);
+
+        if name == TRAILER {
+            Ok(None)
+        } else {
+            Ok(Some(Entry { name, mode, data }))
+        }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.parse_next() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// Header fields after the magic are 8 hex digits each
+fn hex_field(header: &[u8], idx: usize) -> Result<u32, CpioError> {
+    let start = MAGIC.len() + idx * 8;
+    let digits = str::from_utf8(&header[start..start + 8]).map_err(|_| CpioError::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| CpioError::BadHeader)
+}
+
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+// The bootloader loads the initrd as its package, and marks where it put it in
+// the memory map. It's reached through the physical memory map, so nothing
+// needs mapping.
+pub fn find(memory_map: &[MemoryRegion]) -> Option<Archive<'static>> {
+    let rg = memory_map
+        .iter()
+        .find(|rg| rg.region_type == MemoryRegionType::Package)?;
+
+    let start = phys_to_kernel_virt(PhysAddr::new(rg.range.start_addr()));
+    let len = (rg.range.end_addr() - rg.range.start_addr()) as usize;
+    Some(Archive::new(unsafe {
+        slice::from_raw_parts(start.as_ptr(), len)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hello.txt, bin/ and bin/init, zero padded to 512 bytes
+    static FIXTURE: &[u8] = include_bytes!("testdata/initrd.cpio");
+
+    test_case!(cpio_headers, {
+        let mut entries = Archive::new(FIXTURE).entries();
+
+        let hello = entries.next().unwrap().unwrap();
+        assert_eq!(hello.name, "hello.txt");
+        assert_eq!(hello.mode, 0o100644);
+        assert!(!hello.is_dir());
+
+        let bin = entries.next().unwrap().unwrap();
+        assert_eq!(bin.name, "bin");
+        assert!(bin.is_dir());
+        assert!(bin.data.is_empty());
+
+        let init = entries.next().unwrap().unwrap();
+        assert_eq!(init.name, "bin/init");
+    });
+
+    test_case!(cpio_file_boundaries, {
+        let archive = Archive::new(FIXTURE);
+        assert_eq!(archive.find("hello.txt"), Some(&b"hello world\n"[..]));
+        // Not a multiple of 4, so the next header depends on the padding
+        assert_eq!(archive.find("bin/init"), Some(&b"abc"[..]));
+        assert_eq!(archive.find("bin"), None);
+    });
+
+    test_case!(cpio_end_marker, {
+        let archive = Archive::new(FIXTURE);
+        assert_eq!(archive.entries().count(), 3);
+        assert!(archive.entries().all(|e| e.is_ok()));
+        assert_eq!(archive.find(TRAILER), None);
+
+        // Cutting the archive short before the trailer is an error
+        let mut truncated = Archive::new(&FIXTURE[..200]).entries();
+        assert!(truncated.next().unwrap().is_ok());
+        assert_eq!(truncated.next(), Some(Err(CpioError::Truncated)));
+        assert_eq!(truncated.next(), None);
+
+        assert_eq!(
+            Archive::new(&FIXTURE[4..]).entries().next(),
+            Some(Err(CpioError::BadMagic))
+        );
+    });
+}
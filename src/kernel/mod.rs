@@ -0,0 +1,190 @@
+use crate::{
+    cpu,
+    drivers,
+    ds::InitCell,
+    mm::{
+        direct_map,
+        map::{MemoryMap, LAYOUT, MAX_REGIONS},
+        pmm::{PhysAllocator, ZoneInit},
+    },
+};
+use acpi::InterruptModel;
+use arrayvec::ArrayVec;
+use block::BlockDevice;
+use bootloader::bootinfo::{BootInfo, MemoryRegion};
+use core::alloc::Layout;
+use init::{InitError, Stage};
+
+pub mod block;
+pub mod boot_progress;
+pub mod cmdline;
+pub mod console;
+pub mod deferred;
+pub mod early_panic;
+pub mod elf;
+pub mod exec;
+pub mod init;
+pub mod initrd;
+pub mod monitor;
+pub mod panic_log;
+pub mod pci;
+pub mod reboot;
+pub mod sched;
+pub mod symbols;
+pub mod time;
+pub mod trace;
+
+pub fn kernel_main(info: &BootInfo) {
+    drivers::serial::init();
+    cpu::require_features();
+    drivers::vga::text_mode::init().unwrap();
+    // After the logger's up, so what it makes of panic= isn't lost
+    reboot::init();
+    #[rustfmt::skip]
+    {
+        println!("  _____       _     _   _             Developed by:");
+        println!(" / ____|     | |   | | (_)              - Vinc");
+        println!("| (___   ___ | |___| |_ _  ___ ___      - Crally");
+        println!(" \\___ \\ / _ \\| / __| __| |/ __/ _ \\     - Mehodin");
+        println!(" ____) | (_) | \\__ \\ |_| | (_|  __/     - Alex8675");
+        println!("|_____/ \\___/|_|___/\\__|_|\\___\\___|   - trash");
+        println!();
+    };
+    
+    MEMORY_MAP.init(info.memory_map.iter().copied().collect());
+    if let Err(failure) = init::run(STAGES, boot_progress::step) {
+        error!("{}", failure);
+        reboot::halt();
+    }
+}
+
+// Used for boot_progress milestones too
+const STAGES: &[Stage] = &[
+    Stage { name: "gdt", run: init_gdt },
+    Stage { name: "idt", run: init_idt },
+    Stage { name: "percpu", run: init_percpu },
+    Stage { name: "pmm", run: init_pmm },
+    Stage { name: "heap", run: init_heap },
+    Stage { name: "initrd", run: init_initrd },
+    Stage { name: "acpi", run: init_acpi },
+    Stage { name: "pci", run: init_pci },
+    Stage { name: "timer", run: init_timer },
+    Stage { name: "reclaim", run: init_reclaim },
+];
+
+// Copied out of the BootInfo, which lives in bootloader memory and is gone once
+// that's reclaimed
+static MEMORY_MAP: InitCell<ArrayVec<[MemoryRegion; MAX_REGIONS]>> = InitCell::new();
+
+fn memory_map() -> &'static [MemoryRegion] {
+    // Set before any stage runs
+    unsafe { MEMORY_MAP.get() }
+}
+
+fn init_gdt() -> Result<(), InitError> {
+    cpu::gdt::load();
+    Ok(())
+}
+
+fn init_idt() -> Result<(), InitError> {
+    cpu::idt::load();
+    cpu::fpu::init();
+    cpu::pic8259::init();
+    cpu::syscall::init_fast_path();
+    Ok(())
+}
+
+fn init_percpu() -> Result<(), InitError> {
+    cpu::percpu::init_bsp();
+    Ok(())
+}
+
+fn init_pmm() -> Result<(), InitError> {
+    let mut map = MemoryMap::new(memory_map());
+    LAYOUT.init(map.layout().clone());
+    if let Some(frame) = map.reserve_top_frame() {
+        panic_log::init(frame);
+    }
+    PhysAllocator::reserve_low(&mut map);
+
+    let top = map.layout().end();
+    PhysAllocator::init(map, ZoneInit::Lazy);
+    PhysAllocator::init_reserve();
+    direct_map::init(top);
+    Ok(())
+}
+
+// The heap allocates straight from the PMM, so there's nothing to set up, but
+// it's worth finding out now if it doesn't work
+fn init_heap() -> Result<(), InitError> {
+    let layout = Layout::new::<[u64; 8]>();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    if ptr.is_null() {
+        return Err(InitError::NoMemory);
+    }
+
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+    Ok(())
+}
+
+const INITRD_BLOCK_SIZE: usize = 512;
+
+// Not having an initrd is fine
+fn init_initrd() -> Result<(), InitError> {
+    if let Some(initrd) = initrd::find(memory_map()) {
+        debug!(
+            "initrd: found {} entries",
+            initrd.entries().filter_map(Result::ok).count()
+        );
+        let disk = block::RamdiskDevice::new(initrd.as_bytes(), INITRD_BLOCK_SIZE);
+        debug!("initrd: {} blocks of {} bytes", disk.num_blocks(), disk.block_size());
+    }
+    Ok(())
+}
+
+fn init_acpi() -> Result<(), InitError> {
+    let acpi = drivers::acpi::init();
+    match acpi.interrupt_model {
+        InterruptModel::Apic(ref apic) => {
+            if !drivers::acpi::apic_supported() {
+                error!("apic: xapic is not supported");
+            } else {
+                info!("apic: detected xapic support");
+            }
+            // The PIC keeps delivering interrupts until there's a local APIC
+            // driver to send EOIs to
+            cpu::ioapic::init(apic);
+            Ok(())
+        }
+        _ => Err(InitError::Unsupported("acpi interrupt model")),
+    }
+}
+
+// Nothing drives the devices yet, so they're only listed
+fn init_pci() -> Result<(), InitError> {
+    for dev in pci::enumerate() {
+        debug!(
+            "pci: {} {:04x}:{:04x} class {:02x}{:02x}{:02x}",
+            dev.location, dev.vendor, dev.device, dev.class.0, dev.class.1, dev.class.2
+        );
+        for (idx, bar) in dev.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                debug!("pci:     bar{} {:x?}", idx, bar);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn init_timer() -> Result<(), InitError> {
+    time::set_tsc_frequency(cpu::pit::calibrate_tsc());
+    debug!("time: tsc runs at {} MHz", time::tsc_frequency() / 1_000_000);
+    Ok(())
+}
+
+// We've replaced the bootloader's GDT and copied its memory map, so nothing it
+// left behind is needed any more. Has to be the last stage.
+fn init_reclaim() -> Result<(), InitError> {
+    PhysAllocator::reclaim_bootloader();
+    Ok(())
+}
@@ -0,0 +1,381 @@
+use crate::{
+    cpu,
+    drivers::serial,
+    kernel,
+    mm::{
+        inspect::{self, InspectError},
+        pmm::PhysAllocator,
+    },
+};
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::{self, Write};
+use x86_64::PhysAddr;
+
+const MAX_LINE: usize = 128;
+const MAX_ARGS: usize = 8;
+const MAX_DUMP: u64 = 4096;
+const PROMPT: &str = "> ";
+
+// A debug monitor over COM1, for poking at the kernel during bring-up. New
+// commands only need an entry in COMMANDS.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown,
+    Usage,
+    BadNumber,
+    Unmapped,
+    Fmt,
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(_: fmt::Error) -> Self {
+        CommandError::Fmt
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CommandError::Unknown => "unknown command (try `help`)",
+            CommandError::Usage => "bad arguments",
+            CommandError::BadNumber => "couldn't parse number",
+            CommandError::Unmapped => "address isn't in the physical memory map",
+            CommandError::Fmt => "output error",
+        })
+    }
+}
+
+pub type Handler = fn(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: Handler,
+}
+
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "pmm",
+        usage: "pmm stats",
+        help: "show free memory per zone",
+        run: pmm,
+    },
+    Command {
+        name: "mem",
+        usage: "mem <addr> <len>",
+        help: "hexdump physical memory",
+        run: mem,
+    },
+    Command {
+        name: "exceptions",
+        usage: "exceptions",
+        help: "count exceptions since boot",
+        run: exceptions,
+    },
+    Command {
+        name: "trace",
+        usage: "trace [count]",
+        help: "show the latest trace events",
+        run: trace,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "reset the machine",
+        run: reboot,
+    },
+];
+
+// Collects typed characters into a line, echoing them back
+pub struct LineEditor {
+    line: ArrayString<[u8; MAX_LINE]>,
+}
+
+#[allow(dead_code)]
+impl LineEditor {
+    pub fn new() -> Self {
+        Self {
+            line: ArrayString::new(),
+        }
+    }
+
+    // Returns true once a whole line has been entered. The line stays in the
+    // buffer until clear() is called.
+    pub fn feed(&mut self, byte: u8, echo: &mut dyn Write) -> bool {
+        match byte {
+            b'\r' | b'\n' => {
+                let _ = echo.write_str("\r\n");
+                return true;
+            }
+            // Backspace and delete, depending on the terminal
+            0x08 | 0x7F => {
+                if self.line.pop().is_some() {
+                    let _ = echo.write_str("\x08 \x08");
+                }
+            }
+            0x20..=0x7E => {
+                if self.line.try_push(byte as char).is_ok() {
+                    let _ = echo.write_char(byte as char);
+                }
+            }
+            // Ignore anything else, including the rest of escape sequences
+            _ => {}
+        }
+
+        false
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn clear(&mut self) {
+        self.line.clear();
+    }
+}
+
+// Run the command named by the first word of the line. Blank lines do nothing.
+pub fn dispatch(commands: &[Command], line: &str, out: &mut dyn Write) -> Result<(), CommandError> {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+
+    let mut args: ArrayVec<[&str; MAX_ARGS]> = ArrayVec::new();
+    for word in words {
+        args.try_push(word).map_err(|_| CommandError::Usage)?;
+    }
+
+    let command = commands
+        .iter()
+        .find(|command| command.name == name)
+        .ok_or(CommandError::Unknown)?;
+
+    match (command.run)(&args, out) {
+        Err(CommandError::Usage) => {
+            writeln!(out, "usage: {}", command.usage)?;
+            Err(CommandError::Usage)
+        }
+        result => result,
+    }
+}
+
+// Accepts decimal, or hex with a 0x prefix
+pub fn parse_number(s: &str) -> Result<u64, CommandError> {
+    let result = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    result.map_err(|_| CommandError::BadNumber)
+}
+
+fn help(_: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    for command in COMMANDS {
+        writeln!(out, "  {:<20} {}", command.usage, command.help)?;
+    }
+
+    Ok(())
+}
+
+fn pmm(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if args != ["stats"] {
+        return Err(CommandError::Usage);
+    }
+
+    for zone in PhysAllocator::zone_info() {
+        writeln!(
+            out,
+            "  zone {:2}: {:#012x}..{:#012x} ({} pages){}",
+            zone.index,
+            zone.pages.start.start_address().as_u64(),
+            zone.pages.end.start_address().as_u64(),
+            zone.pages.end - zone.pages.start,
+            if zone.online { "" } else { ", offline" }
+        )?;
+    }
+    writeln!(out, "  free: {} pages", PhysAllocator::free_pages())?;
+
+    Ok(())
+}
+
+fn mem(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let (addr, len) = match args {
+        [addr, len] => (parse_number(addr)?, parse_number(len)?),
+        _ => return Err(CommandError::Usage),
+    };
+    if len == 0 || len > MAX_DUMP {
+        return Err(CommandError::Usage);
+    }
+
+    let addr = PhysAddr::try_new(addr).map_err(|_| CommandError::Unmapped)?;
+    inspect::hexdump_to(addr, len as usize, out).map_err(|e| match e {
+        InspectError::Fmt => CommandError::Fmt,
+        _ => CommandError::Unmapped,
+    })
+}
+
+fn exceptions(_: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let stats = cpu::exception_stats();
+    let mut any = false;
+    for (name, count) in stats.seen() {
+        writeln!(out, "  {:<28} {}", name, count)?;
+        any = true;
+    }
+    if !any {
+        writeln!(out, "  none")?;
+    }
+
+    Ok(())
+}
+
+fn trace(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let count = match args {
+        [] => 32,
+        [count] => parse_number(count)? as usize,
+        _ => return Err(CommandError::Usage),
+    };
+    Ok(kernel::trace::dump(out, count)?)
+}
+
+fn reboot(_: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    writeln!(out, "rebooting...")?;
+    kernel::reboot::reboot();
+}
+
+struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Terminals want \r\n, but the rest of the kernel only writes \n
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                serial::write_str("\r\n");
+            }
+            serial::write_str(part);
+        }
+
+        Ok(())
+    }
+}
+
+// Polls COM1 forever, running each line as it's entered
+#[allow(dead_code)]
+pub fn run() -> ! {
+    let mut out = SerialWriter;
+    let mut editor = LineEditor::new();
+
+    let _ = writeln!(out, "solstice monitor, type `help` for commands");
+    let _ = out.write_str(PROMPT);
+    loop {
+        let byte = match serial::read_byte() {
+            Some(byte) => byte,
+            None => {
+                core::hint::spin_loop();
+                continue;
+            }
+        };
+
+        if editor.feed(byte, &mut out) {
+            if let Err(e) = dispatch(COMMANDS, editor.line(), &mut out) {
+                let _ = writeln!(out, "error: {}", e);
+            }
+            editor.clear();
+            let _ = out.write_str(PROMPT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_line(editor: &mut LineEditor, input: &[u8], echo: &mut dyn Write) -> bool {
+        input.iter().any(|&byte| editor.feed(byte, echo))
+    }
+
+    test_case!(line_editor_backspace, {
+        let mut editor = LineEditor::new();
+        let mut echo = ArrayString::<[u8; 64]>::new();
+
+        assert!(type_line(&mut editor, b"pnn\x7F\x7Fmm \x08 stats\r", &mut echo));
+        assert_eq!(editor.line(), "pmm stats");
+        assert_eq!(echo.as_str(), "pnn\x08 \x08\x08 \x08mm \x08 \x08 stats\r\n");
+
+        // Backspace on an empty line does nothing
+        editor.clear();
+        echo.clear();
+        assert!(type_line(&mut editor, b"\x08\x1B\n", &mut echo));
+        assert_eq!(editor.line(), "");
+        assert_eq!(echo.as_str(), "\r\n");
+
+        // Input past the end of the buffer is dropped
+        editor.clear();
+        for _ in 0..MAX_LINE + 10 {
+            assert!(!editor.feed(b'a', &mut echo));
+        }
+        assert_eq!(editor.line().len(), MAX_LINE);
+    });
+
+    fn echo_args(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        match args {
+            [] => Err(CommandError::Usage),
+            _ => {
+                for arg in args {
+                    write!(out, "[{}]", arg)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn add(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        match args {
+            [a, b] => Ok(write!(out, "{}", parse_number(a)? + parse_number(b)?)?),
+            _ => Err(CommandError::Usage),
+        }
+    }
+
+    static TEST_COMMANDS: &[Command] = &[
+        Command {
+            name: "echo",
+            usage: "echo <words...>",
+            help: "",
+            run: echo_args,
+        },
+        Command {
+            name: "add",
+            usage: "add <a> <b>",
+            help: "",
+            run: add,
+        },
+    ];
+
+    test_case!(dispatch_lines, {
+        let cases: &[(&str, Result<(), CommandError>, &str)] = &[
+            ("echo a  b", Ok(()), "[a][b]"),
+            ("   ", Ok(()), ""),
+            ("add 0x10 1", Ok(()), "17"),
+            ("add 1 zz", Err(CommandError::BadNumber), ""),
+            ("add 1", Err(CommandError::Usage), "usage: add <a> <b>\n"),
+            ("echo", Err(CommandError::Usage), "usage: echo <words...>\n"),
+            ("echo 1 2 3 4 5 6 7 8 9", Err(CommandError::Usage), ""),
+            ("nope", Err(CommandError::Unknown), ""),
+        ];
+
+        for &(line, result, output) in cases {
+            let mut out = ArrayString::<[u8; 64]>::new();
+            assert_eq!(dispatch(TEST_COMMANDS, line, &mut out), result, "{:?}", line);
+            assert_eq!(out.as_str(), output, "{:?}", line);
+        }
+    });
+}
@@ -0,0 +1,256 @@
+use crate::{
+    cpu::irq_frames,
+    ds::InitCell,
+    kernel::symbols::Symbolized,
+    mm::{phys_to_kernel_virt, KERNEL_STACK_PAGES, KERNEL_STACK_START, PAGE_SIZE},
+};
+use arrayvec::{ArrayString, ArrayVec};
+use core::{convert::TryInto, fmt::Write, panic::PanicInfo, slice};
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::paging::PhysFrame,
+};
+
+// The last panic is written out to a page of memory that the kernel otherwise
+// leaves alone, so that it survives a soft reboot and can be picked up by the
+// next boot (or anything else that knows where to look).
+//
+// Layout, all little endian:
+//   0   magic
+//   8   message length (u32)
+//   12  backtrace length (u32)
+//   16  checksum of everything from offset 24 on (u32)
+//   20  reserved
+//   24  cr0, cr2, cr3, cr4 (u64 each)
+//   56  backtrace return addresses (MAX_FRAMES u64s)
+//   184 message
+const MAGIC: &[u8; 8] = b"SOLPANIC";
+const MAX_FRAMES: usize = 16;
+const MAX_MESSAGE: usize = 1024;
+
+const REGS_OFFSET: usize = 24;
+const FRAMES_OFFSET: usize = REGS_OFFSET + 4 * 8;
+const MESSAGE_OFFSET: usize = FRAMES_OFFSET + MAX_FRAMES * 8;
+const MAX_RECORD_LEN: usize = MESSAGE_OFFSET + MAX_MESSAGE;
+
+static LOG_FRAME: InitCell<PhysFrame> = InitCell::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicRecord {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub backtrace: ArrayVec<[u64; MAX_FRAMES]>,
+    // Truncated if the panic message doesn't fit
+    pub message: ArrayString<[u8; MAX_MESSAGE]>,
+}
+
+impl PanicRecord {
+    pub fn capture(info: &PanicInfo) -> Self {
+        let mut message = ArrayString::new();
+        let _ = write!(message, "{}", info);
+
+        let (cr3_frame, cr3_flags) = Cr3::read_raw();
+        Self {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: cr3_frame.start_address().as_u64() | cr3_flags as u64,
+            cr4: Cr4::read_raw(),
+            backtrace: backtrace(),
+            message,
+        }
+    }
+
+    // Returns the number of bytes written
+    pub fn serialize(&self, buf: &mut [u8]) -> usize {
+        let len = MESSAGE_OFFSET + self.message.len();
+        assert!(buf.len() >= len, "panic_log: buffer too small");
+
+        let regs = [self.cr0, self.cr2, self.cr3, self.cr4];
+        for (i, reg) in regs.iter().enumerate() {
+            write_u64(buf, REGS_OFFSET + i * 8, *reg);
+        }
+        for i in 0..MAX_FRAMES {
+            write_u64(buf, FRAMES_OFFSET + i * 8, self.backtrace.get(i).copied().unwrap_or(0));
+        }
+        buf[MESSAGE_OFFSET..len].copy_from_slice(self.message.as_bytes());
+
+        buf[8..12].copy_from_slice(&(self.message.len() as u32).to_le_bytes());
+        buf[12..16].copy_from_slice(&(self.backtrace.len() as u32).to_le_bytes());
+        let sum = checksum(&buf[REGS_OFFSET..len]);
+        buf[16..20].copy_from_slice(&sum.to_le_bytes());
+        buf[20..24].copy_from_slice(&[0; 4]);
+
+        // Written last, so a record cut short by a fault never looks valid
+        buf[..MAGIC.len()].copy_from_slice(MAGIC);
+        len
+    }
+
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < MESSAGE_OFFSET || &buf[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+
+        let message_len = read_u32(buf, 8) as usize;
+        let frames = read_u32(buf, 12) as usize;
+        if message_len > MAX_MESSAGE || frames > MAX_FRAMES || buf.len() < MESSAGE_OFFSET + message_len {
+            return None;
+        }
+
+        let len = MESSAGE_OFFSET + message_len;
+        if read_u32(buf, 16) != checksum(&buf[REGS_OFFSET..len]) {
+            return None;
+        }
+
+        let message = core::str::from_utf8(&buf[MESSAGE_OFFSET..len]).ok()?;
+        Some(Self {
+            cr0: read_u64(buf, REGS_OFFSET),
+            cr2: read_u64(buf, REGS_OFFSET + 8),
+            cr3: read_u64(buf, REGS_OFFSET + 16),
+            cr4: read_u64(buf, REGS_OFFSET + 24),
+            backtrace: (0..frames).map(|i| read_u64(buf, FRAMES_OFFSET + i * 8)).collect(),
+            message: ArrayString::from(message).ok()?,
+        })
+    }
+}
+
+// FNV-1a
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0x811c_9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+// Follows the chain of saved frame pointers. Only frames on the kernel stack
+// are followed, so a bogus rbp (e.g. in code built without frame pointers)
+// ends the walk rather than faulting inside the panic handler.
+fn backtrace() -> ArrayVec<[u64; MAX_FRAMES]> {
+    // Skip the guard page at the bottom of the stack
+    let stack_start = KERNEL_STACK_START + PAGE_SIZE;
+    let stack_end = stack_start + KERNEL_STACK_PAGES * PAGE_SIZE;
+
+    let mut frames = ArrayVec::new();
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    while !frames.is_full() && rbp % 8 == 0 && rbp >= stack_start && rbp + 16 <= stack_end {
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+
+        frames.push(ret);
+        // The stack grows down, so callers' frames are always higher up
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+
+    frames
+}
+
+fn log_page(frame: PhysFrame) -> &'static mut [u8] {
+    let addr = phys_to_kernel_virt(frame.start_address());
+    unsafe { slice::from_raw_parts_mut(addr.as_mut_ptr(), PAGE_SIZE as usize) }
+}
+
+// Reports whatever the previous boot left in the page, then claims it for this
+// boot
+pub fn init(frame: PhysFrame) {
+    debug_assert!(MAX_RECORD_LEN <= PAGE_SIZE as usize);
+    let page = log_page(frame);
+    if let Some(record) = PanicRecord::parse(page) {
+        warn!("panic_log: previous boot panicked: {}", record.message);
+        for ret in record.backtrace.iter() {
+            warn!("panic_log:     at {}", Symbolized(*ret));
+        }
+    }
+
+    for byte in page[..MAGIC.len()].iter_mut() {
+        *byte = 0;
+    }
+
+    LOG_FRAME.init(frame);
+    debug!("panic_log: using {:?}", frame);
+}
+
+// Called from the panic handler. The record also goes to the log, which ends up
+// on serial.
+pub fn record(info: &PanicInfo) {
+    let record = PanicRecord::capture(info);
+    error!(
+        "cr0={:#x} cr2={:#x} cr3={:#x} cr4={:#x}",
+        record.cr0, record.cr2, record.cr3, record.cr4
+    );
+    for ret in record.backtrace.iter() {
+        error!("    at {}", Symbolized(*ret));
+    }
+    // Innermost first, like the backtrace
+    for frame in irq_frames::current_frames().iter() {
+        error!(
+            "    in interrupt from {} (rsp {:#x})",
+            Symbolized(frame.instruction_pointer.as_u64()),
+            frame.stack_pointer.as_u64()
+        );
+    }
+
+    super::trace::dump_on_panic();
+
+    if let Some(&frame) = LOG_FRAME.try_get() {
+        record.serialize(log_page(frame));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_record() -> PanicRecord {
+        PanicRecord {
+            cr0: 0x8001_0033,
+            cr2: 0xdead_b000,
+            cr3: 0x1000,
+            cr4: 0x20,
+            backtrace: [0xFFFF_FFFF_8000_1234, 0xFFFF_FFFF_8000_5678].iter().copied().collect(),
+            message: ArrayString::from("panicked at 'oops', src/main.rs:1:1").unwrap(),
+        }
+    }
+
+    test_case!(panic_record_round_trip, {
+        let record = test_record();
+        let mut buf = [0xAAu8; MAX_RECORD_LEN];
+        let len = record.serialize(&mut buf);
+
+        assert_eq!(len, MESSAGE_OFFSET + record.message.len());
+        assert_eq!(&buf[..8], b"SOLPANIC");
+        assert_eq!(PanicRecord::parse(&buf[..len]), Some(record));
+    });
+
+    test_case!(panic_record_rejects_garbage, {
+        let mut buf = [0u8; MAX_RECORD_LEN];
+        assert_eq!(PanicRecord::parse(&buf), None);
+
+        let len = test_record().serialize(&mut buf);
+        buf[MESSAGE_OFFSET] ^= 1;
+        assert_eq!(PanicRecord::parse(&buf), None);
+        buf[MESSAGE_OFFSET] ^= 1;
+
+        // Cut short before the end of the message
+        assert_eq!(PanicRecord::parse(&buf[..len - 1]), None);
+        assert!(PanicRecord::parse(&buf[..len]).is_some());
+    });
+}
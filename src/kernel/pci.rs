@@ -0,0 +1,364 @@
+use alloc::vec::Vec;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+// PCI device discovery through configuration mechanism #1: the address of a
+// config space dword goes out on CONFIG_ADDRESS, then the dword itself is read
+// or written through CONFIG_DATA. Only the first 256 bytes of each function's
+// config space can be reached this way, which is all the standard header needs.
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const ENABLE: u32 = 1 << 31;
+
+// Standard header offsets
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0C;
+const BAR0: u8 = 0x10;
+
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+const MULTI_FUNCTION: u8 = 1 << 7;
+const NO_DEVICE: u16 = 0xFFFF;
+
+pub const MAX_BARS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl core::fmt::Display for Location {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+// What goes out on CONFIG_ADDRESS to select a dword of config space. The low
+// two bits of the offset are dropped, since only whole dwords can be accessed.
+pub fn config_address(loc: Location, offset: u8) -> u32 {
+    ENABLE
+        | (loc.bus as u32) << 16
+        | (loc.device as u32 & 0x1F) << 11
+        | (loc.function as u32 & 0x7) << 8
+        | (offset as u32 & 0xFC)
+}
+
+// Lets tests stand in for the hardware
+pub trait ConfigAccess {
+    fn read(&mut self, loc: Location, offset: u8) -> u32;
+    fn write(&mut self, loc: Location, offset: u8, value: u32);
+}
+
+pub struct PortAccess;
+
+impl ConfigAccess for PortAccess {
+    fn read(&mut self, loc: Location, offset: u8) -> u32 {
+        unsafe {
+            PortWrite::write_to_port(CONFIG_ADDRESS, config_address(loc, offset));
+            PortRead::read_from_port(CONFIG_DATA)
+        }
+    }
+
+    fn write(&mut self, loc: Location, offset: u8, value: u32) {
+        unsafe {
+            PortWrite::write_to_port(CONFIG_ADDRESS, config_address(loc, offset));
+            PortWrite::write_to_port(CONFIG_DATA, value);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { addr: u64, size: u64, prefetchable: bool },
+    Io { port: u32, size: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciDevice {
+    pub location: Location,
+    pub vendor: u16,
+    pub device: u16,
+    // Base class, subclass and programming interface
+    pub class: (u8, u8, u8),
+    // Indexed by BAR number. The upper half of a 64 bit BAR is None, as are
+    // unimplemented ones.
+    pub bars: [Option<Bar>; MAX_BARS],
+}
+
+// The size of a BAR, from what it reads back as after all ones were written to
+// it. Only the bits that can be set are part of the address, so the lowest of
+// them is the size. `mask_high` is the upper half of a 64 bit BAR, and is
+// ignored for a 32 bit one.
+pub fn decode_bar(original: u32, mask: u32, original_high: u32, mask_high: u32) -> Option<Bar> {
+    if original & 1 != 0 {
+        // IO ports only go up to 64K, and the upper half may read back as zero
+        let mask = mask & !0x3 | 0xFFFF_0000;
+        if mask == 0xFFFF_0000 {
+            return None;
+        }
+        return Some(Bar::Io {
+            port: original & !0x3,
+            size: (!mask).wrapping_add(1),
+        });
+    }
+
+    let wide = is_64_bit(original);
+    let (original_high, mask_high) = if wide { (original_high, mask_high) } else { (0, 0) };
+    if mask & !0xF == 0 && mask_high == 0 {
+        return None;
+    }
+
+    // A 32 bit BAR's size only comes from the lower half, so its upper half
+    // counts as all ones
+    let addr = (original_high as u64) << 32 | (original & !0xF) as u64;
+    let mask_high = if wide { mask_high } else { 0xFFFF_FFFF };
+    let mask = (mask_high as u64) << 32 | (mask & !0xF) as u64;
+    Some(Bar::Memory {
+        addr,
+        size: (!mask).wrapping_add(1),
+        prefetchable: original & 0x8 != 0,
+    })
+}
+
+fn is_64_bit(bar: u32) -> bool {
+    bar & 1 == 0 && (bar >> 1) & 0x3 == 0b10
+}
+
+// Writes all ones to a BAR and reads back which bits stick, then puts it back
+fn size_bar(access: &mut impl ConfigAccess, loc: Location, offset: u8) -> u32 {
+    let original = access.read(loc, offset);
+    access.write(loc, offset, 0xFFFF_FFFF);
+    let mask = access.read(loc, offset);
+    access.write(loc, offset, original);
+    mask
+}
+
+fn read_bars(access: &mut impl ConfigAccess, loc: Location, count: usize) -> [Option<Bar>; MAX_BARS] {
+    let mut bars = [None; MAX_BARS];
+
+    // The BARs would briefly decode all ones while they're sized, so turn
+    // decoding off until they're back
+    let command = access.read(loc, COMMAND);
+    access.write(loc, COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let mut idx = 0;
+    while idx < count {
+        let offset = BAR0 + idx as u8 * 4;
+        let original = access.read(loc, offset);
+        let mask = size_bar(access, loc, offset);
+
+        if is_64_bit(original) && idx + 1 < count {
+            let original_high = access.read(loc, offset + 4);
+            let mask_high = size_bar(access, loc, offset + 4);
+            bars[idx] = decode_bar(original, mask, original_high, mask_high);
+            idx += 2;
+        } else {
+            bars[idx] = decode_bar(original, mask, 0, 0);
+            idx += 1;
+        }
+    }
+
+    access.write(loc, COMMAND, command);
+    bars
+}
+
+fn probe(access: &mut impl ConfigAccess, loc: Location) -> Option<PciDevice> {
+    let id = access.read(loc, VENDOR_ID);
+    if id as u16 == NO_DEVICE {
+        return None;
+    }
+
+    let class = access.read(loc, CLASS);
+    // Ordinary devices have six BARs and PCI-to-PCI bridges two. CardBus
+    // bridges don't have any in the same place.
+    let bar_count = match header_type(access, loc) & !MULTI_FUNCTION {
+        0 => 6,
+        1 => 2,
+        _ => 0,
+    };
+
+    Some(PciDevice {
+        location: loc,
+        vendor: id as u16,
+        device: (id >> 16) as u16,
+        class: ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8),
+        bars: read_bars(access, loc, bar_count),
+    })
+}
+
+fn header_type(access: &mut impl ConfigAccess, loc: Location) -> u8 {
+    (access.read(loc, HEADER_TYPE) >> 16) as u8
+}
+
+// Tries every bus, device and function. Functions other than 0 are only looked
+// at if function 0 says there are more.
+pub fn enumerate_with(access: &mut impl ConfigAccess) -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let loc = |function| Location { bus, device, function };
+            let first = match probe(access, loc(0)) {
+                Some(first) => first,
+                None => continue,
+            };
+            devices.push(first);
+
+            if header_type(access, loc(0)) & MULTI_FUNCTION != 0 {
+                devices.extend((1..8).filter_map(|function| probe(access, loc(function))));
+            }
+        }
+    }
+
+    devices
+}
+
+pub fn enumerate() -> Vec<PciDevice> {
+    enumerate_with(&mut PortAccess)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+
+    // A function's config header, where BARs only keep the bits a real one
+    // would
+    struct MockFunction {
+        regs: [u32; 16],
+        bar_masks: [u32; MAX_BARS],
+    }
+
+    struct MockBus {
+        functions: BTreeMap<Location, MockFunction>,
+    }
+
+    impl ConfigAccess for MockBus {
+        fn read(&mut self, loc: Location, offset: u8) -> u32 {
+            match self.functions.get(&loc) {
+                Some(function) => function.regs[offset as usize / 4],
+                None => 0xFFFF_FFFF,
+            }
+        }
+
+        fn write(&mut self, loc: Location, offset: u8, value: u32) {
+            let function = self.functions.get_mut(&loc).expect("write to a missing function");
+            let reg = offset as usize / 4;
+            match reg.checked_sub(BAR0 as usize / 4) {
+                Some(bar) if bar < MAX_BARS => {
+                    // The type bits are read only
+                    let mask = function.bar_masks[bar];
+                    let fixed = function.regs[reg] & !mask;
+                    function.regs[reg] = fixed | value & mask;
+                }
+                _ => function.regs[reg] = value,
+            }
+        }
+    }
+
+    fn function(id: u32, class: u32, header_type: u8, bars: &[(u32, u32)]) -> MockFunction {
+        let mut regs = [0; 16];
+        regs[0] = id;
+        regs[1] = COMMAND_IO | COMMAND_MEMORY;
+        regs[2] = class;
+        regs[3] = (header_type as u32) << 16;
+        let mut bar_masks = [0; MAX_BARS];
+        for (i, &(value, mask)) in bars.iter().enumerate() {
+            regs[4 + i] = value;
+            bar_masks[i] = mask;
+        }
+        MockFunction { regs, bar_masks }
+    }
+
+    fn loc(bus: u8, device: u8, function: u8) -> Location {
+        Location { bus, device, function }
+    }
+
+    test_case!(pci_config_address, {
+        assert_eq!(config_address(loc(0, 0, 0), 0), 0x8000_0000);
+        assert_eq!(config_address(loc(0, 3, 0), 0x10), 0x8000_1810);
+        assert_eq!(config_address(loc(0xFF, 0x1F, 7), 0xFC), 0x80FF_FFFC);
+        // Only whole dwords
+        assert_eq!(config_address(loc(1, 2, 3), 0x0E), 0x8001_130C);
+        // Out of range parts don't spill into their neighbours
+        assert_eq!(config_address(loc(0, 0x20, 8), 0), 0x8000_0000);
+    });
+
+    test_case!(pci_bar_decoding, {
+        // 4K of 32 bit memory
+        assert_eq!(
+            decode_bar(0xFEBF_0000, 0xFFFF_F000, 0, 0),
+            Some(Bar::Memory { addr: 0xFEBF_0000, size: 0x1000, prefetchable: false })
+        );
+        // 256M of 32 bit prefetchable memory, with the upper half ignored
+        assert_eq!(
+            decode_bar(0xE000_0008, 0xF000_0008, 0x1234, 0),
+            Some(Bar::Memory { addr: 0xE000_0000, size: 0x1000_0000, prefetchable: true })
+        );
+        // 64 bit and prefetchable, bigger than 4G
+        assert_eq!(
+            decode_bar(0x0000_000C, 0x0000_000C, 0x8, 0xFFFF_FFFE),
+            Some(Bar::Memory { addr: 0x8_0000_0000, size: 0x2_0000_0000, prefetchable: true })
+        );
+        // 32 IO ports, whether or not the upper half reads back as ones
+        assert_eq!(decode_bar(0xC041, 0xFFE1, 0, 0), Some(Bar::Io { port: 0xC040, size: 32 }));
+        assert_eq!(decode_bar(0xC041, 0xFFFF_FFE1, 0, 0), Some(Bar::Io { port: 0xC040, size: 32 }));
+        // Unimplemented
+        assert_eq!(decode_bar(0, 0, 0, 0), None);
+        assert_eq!(decode_bar(0x1, 0x1, 0, 0), None);
+    });
+
+    test_case!(pci_enumerate_mocked, {
+        let mut functions = BTreeMap::new();
+        // A host bridge with no BARs
+        functions.insert(loc(0, 0, 0), function(0x1237_8086, 0x0600_0002, 0, &[]));
+        // A NIC with 128K of memory, 64 IO ports and a 64 bit 16K BAR
+        functions.insert(
+            loc(0, 3, 0),
+            function(
+                0x100E_8086,
+                0x0200_0003,
+                0,
+                &[(0xFEB8_0000, 0xFFFE_0000), (0xC001, 0xFFFF_FFC0), (0xFEBC_0004, 0xFFFF_C000), (0, 0xFFFF_FFFF)],
+            ),
+        );
+        // Function 2 of a multi-function device is only found through function 0,
+        // and function 1 of a single-function device is never looked at
+        functions.insert(loc(0, 31, 0), function(0x2918_8086, 0x0601_0002, MULTI_FUNCTION, &[]));
+        functions.insert(loc(0, 31, 2), function(0x2922_8086, 0x0106_0102, 0, &[]));
+        functions.insert(loc(2, 0, 1), function(0x1234_1AF4, 0, 0, &[]));
+
+        let mut bus = MockBus { functions };
+        let devices = enumerate_with(&mut bus);
+        let found: Vec<_> = devices.iter().map(|dev| (dev.location, dev.device)).collect();
+        assert_eq!(
+            found,
+            [(loc(0, 0, 0), 0x1237), (loc(0, 3, 0), 0x100E), (loc(0, 31, 0), 0x2918), (loc(0, 31, 2), 0x2922)]
+        );
+
+        let nic = &devices[1];
+        assert_eq!((nic.vendor, nic.class), (0x8086, (0x02, 0x00, 0x00)));
+        assert_eq!(
+            nic.bars,
+            [
+                Some(Bar::Memory { addr: 0xFEB8_0000, size: 0x2_0000, prefetchable: false }),
+                Some(Bar::Io { port: 0xC000, size: 64 }),
+                Some(Bar::Memory { addr: 0xFEBC_0000, size: 0x4000, prefetchable: false }),
+                None,
+                None,
+                None,
+            ]
+        );
+        assert_eq!(devices[3].class, (0x01, 0x06, 0x01));
+
+        // Sizing put every BAR back, and decoding back on
+        let regs = &bus.functions[&loc(0, 3, 0)].regs;
+        assert_eq!(&regs[4..7], &[0xFEB8_0000, 0xC001, 0xFEBC_0004]);
+        assert_eq!(regs[1], COMMAND_IO | COMMAND_MEMORY);
+    });
+}
@@ -0,0 +1,127 @@
+use crate::{cpu::pit, kernel::cmdline};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::{self, interrupts, port::PortWrite};
+
+// What the panic handler does once the panic has been reported and saved. Set
+// with the `panic=` option: `panic=halt`, the default, or `panic=reboot:<secs>`
+// for machines nobody is watching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    Halt,
+    Reboot { delay_secs: u32 },
+}
+
+// Anything else is the reboot delay in seconds
+const HALT: u64 = u64::MAX;
+
+static PANIC_ACTION: AtomicU64 = AtomicU64::new(HALT);
+
+impl PanicAction {
+    fn parse(value: &str) -> Option<Self> {
+        if value == "halt" {
+            return Some(PanicAction::Halt);
+        }
+
+        let delay_secs = value.strip_prefix("reboot:")?.parse().ok()?;
+        Some(PanicAction::Reboot { delay_secs })
+    }
+
+    fn encode(self) -> u64 {
+        match self {
+            PanicAction::Halt => HALT,
+            PanicAction::Reboot { delay_secs } => delay_secs as u64,
+        }
+    }
+
+    fn decode(value: u64) -> Self {
+        match value {
+            HALT => PanicAction::Halt,
+            secs => PanicAction::Reboot { delay_secs: secs as u32 },
+        }
+    }
+}
+
+// A bad value halts, like no value at all, which at least leaves the panic on
+// screen
+fn panic_action_for(option: Option<&str>) -> PanicAction {
+    match option {
+        None => PanicAction::Halt,
+        Some(value) => PanicAction::parse(value).unwrap_or_else(|| {
+            warn!("reboot: ignoring bad option panic={}", value);
+            PanicAction::Halt
+        }),
+    }
+}
+
+pub fn init() {
+    let action = panic_action_for(cmdline::get("panic"));
+    if let PanicAction::Reboot { delay_secs } = action {
+        info!("reboot: rebooting {}s after a panic", delay_secs);
+    }
+    set_panic_action(action);
+}
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action.encode(), Ordering::Relaxed);
+}
+
+pub fn panic_action() -> PanicAction {
+    PanicAction::decode(PANIC_ACTION.load(Ordering::Relaxed))
+}
+
+// Called last by the panic handler
+pub fn after_panic() -> ! {
+    // The panic can come from code that had interrupts on, and nothing else
+    // should run now, e.g. the timer tick during the delay
+    interrupts::disable();
+    match panic_action() {
+        PanicAction::Halt => halt(),
+        PanicAction::Reboot { delay_secs } => {
+            error!("rebooting in {}s", delay_secs);
+            // Interrupts are off and nothing else is going to run, so the
+            // delay has to be a busy-wait
+            pit::sleep_ms(delay_secs as u64 * 1000);
+            reboot();
+        }
+    }
+}
+
+// Pulses the reset line through the keyboard controller. If that doesn't take,
+// all that's left is to halt.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    unsafe { PortWrite::write_to_port(0x64u16, 0xFEu8) };
+    halt();
+}
+
+pub fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        instructions::hlt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(panic_option, {
+        assert_eq!(panic_action_for(None), PanicAction::Halt);
+        assert_eq!(panic_action_for(Some("halt")), PanicAction::Halt);
+        assert_eq!(panic_action_for(Some("reboot:30")), PanicAction::Reboot { delay_secs: 30 });
+        assert_eq!(panic_action_for(Some("reboot:0")), PanicAction::Reboot { delay_secs: 0 });
+
+        // Malformed values fall back to halting
+        for &bad in &["", "reboot", "reboot:", "reboot:-1", "reboot:5s", "reboot:99999999999"] {
+            assert_eq!(panic_action_for(Some(bad)), PanicAction::Halt, "panic={}", bad);
+        }
+
+        // Whatever's picked survives the round trip through the static
+        let old = panic_action();
+        set_panic_action(PanicAction::Reboot { delay_secs: u32::MAX });
+        assert_eq!(panic_action(), PanicAction::Reboot { delay_secs: u32::MAX });
+        set_panic_action(PanicAction::Halt);
+        assert_eq!(panic_action(), PanicAction::Halt);
+        set_panic_action(old);
+    });
+}
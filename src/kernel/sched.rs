@@ -0,0 +1,328 @@
+use crate::{
+    cpu::interrupts,
+    ds::SpinLock,
+    mm::kstack::KernelStack,
+};
+use alloc::{collections::VecDeque, vec::Vec};
+
+// Cooperative kernel tasks. A task runs until it yields, blocks on a WaitQueue
+// or returns, and nothing preempts it. Whatever was running at boot becomes
+// task 0, on the kernel stack; the rest get a stack of their own. Tasks are
+// plain function pointers plus an argument, like deferred work.
+
+const STACK_ORDER: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Runnable,
+    Blocked,
+    Dead,
+}
+
+struct Task {
+    // Saved while the task isn't running
+    rsp: u64,
+    state: State,
+    // None for the boot task
+    stack: Option<KernelStack>,
+    // The next task on the same WaitQueue
+    next_waiter: Option<TaskId>,
+}
+
+struct Scheduler {
+    // Indexed by TaskId. Ids aren't reused, but dead tasks' stacks are freed.
+    tasks: Vec<Task>,
+    run_queue: VecDeque<TaskId>,
+    current: TaskId,
+}
+
+lazy_static! {
+    static ref SCHED: SpinLock<Scheduler> = SpinLock::new(Scheduler {
+        tasks: alloc::vec![Task {
+            rsp: 0,
+            state: State::Running,
+            stack: None,
+            next_waiter: None,
+        }],
+        run_queue: VecDeque::new(),
+        current: TaskId(0),
+    });
+}
+
+impl Scheduler {
+    fn task(&mut self, id: TaskId) -> &mut Task {
+        &mut self.tasks[id.0]
+    }
+
+    fn make_runnable(&mut self, id: TaskId) {
+        self.task(id).state = State::Runnable;
+        self.run_queue.push_back(id);
+    }
+
+    // A task can't free the stack it's running on, so dead tasks are cleaned
+    // up by whichever task runs after them
+    fn reap(&mut self) {
+        let current = self.current;
+        for (idx, task) in self.tasks.iter_mut().enumerate() {
+            if task.state == State::Dead && idx != current.0 {
+                drop(task.stack.take());
+            }
+        }
+    }
+}
+
+// Saves the callee-saved registers on the current stack, stores the stack
+// pointer in `*old_rsp`, then does the reverse from `new_rsp`. A new task's
+// stack is set up to look like it called this, with task_trampoline as the
+// return address.
+global_asm!(
+    "
+    .global switch_stacks
+    switch_stacks:
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        mov [rdi], rsp
+        mov rsp, rsi
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+
+    task_trampoline:
+        mov rdi, r12
+        mov rsi, r13
+        call task_start
+        ud2
+    "
+);
+
+extern "C" {
+    fn switch_stacks(old_rsp: *mut u64, new_rsp: u64);
+    fn task_trampoline();
+}
+
+// `func` is really a fn(usize)
+#[no_mangle]
+extern "C" fn task_start(func: usize, arg: usize) -> ! {
+    let func: fn(usize) = unsafe { core::mem::transmute(func) };
+    SCHED.lock().reap();
+    // Whoever switched here had interrupts off, and it's their stack that
+    // remembers whether to turn them back on
+    x86_64::instructions::interrupts::enable();
+    func(arg);
+    exit();
+}
+
+#[allow(dead_code)]
+pub fn spawn(func: fn(usize), arg: usize) -> TaskId {
+    let stack = KernelStack::new(STACK_ORDER);
+    let top = stack.top().as_u64();
+
+    // What switch_stacks pops, lowest first: r15, r14, r13, r12, rbx, rbp and
+    // the return address. The top stays 16 byte aligned for the call in
+    // task_trampoline.
+    let trampoline = task_trampoline as unsafe extern "C" fn() as usize;
+    let frame = [0, 0, arg as u64, func as usize as u64, 0, 0, trampoline as u64];
+    let rsp = top - 8 * frame.len() as u64;
+    unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()) };
+
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let id = TaskId(sched.tasks.len());
+        stack.set_owner(id.0);
+        sched.tasks.push(Task {
+            rsp,
+            state: State::Runnable,
+            stack: Some(stack),
+            next_waiter: None,
+        });
+        sched.run_queue.push_back(id);
+        id
+    })
+}
+
+#[allow(dead_code)]
+pub fn current() -> TaskId {
+    // Interrupt handlers can wake tasks, which takes the same lock
+    interrupts::without_interrupts(|| SCHED.lock().current)
+}
+
+// Switches to the next runnable task. The current one has to have been queued
+// somewhere first (or be dead), or it'll never run again.
+//
+// The nesting count in cpu::interrupts isn't per task, so this only saves and
+// restores the interrupt flag itself, and mustn't be called from inside a
+// critical section.
+fn schedule() {
+    BUG_ON!(interrupts::depth() != 0, "sched: switching tasks inside a critical section");
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let (old_rsp, new_rsp) = {
+            let mut sched = SCHED.lock();
+            let prev = sched.current;
+            let next = match sched.run_queue.pop_front() {
+                Some(next) => next,
+                None => panic!("sched: every task is blocked or dead"),
+            };
+
+            sched.task(next).state = State::Running;
+            if next == prev {
+                return;
+            }
+
+            sched.current = next;
+            trace_event!(Switch, next.0);
+            let old_rsp: *mut u64 = &mut sched.task(prev).rsp;
+            (old_rsp, sched.task(next).rsp)
+        };
+
+        // Nothing touches the task list until we're back, so the pointer
+        // stays good without the lock
+        unsafe { switch_stacks(old_rsp, new_rsp) };
+        SCHED.lock().reap();
+    });
+}
+
+// Lets every other runnable task have a turn
+#[allow(dead_code)]
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let current = sched.current;
+        sched.make_runnable(current);
+    });
+    schedule();
+}
+
+pub fn exit() -> ! {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let current = sched.current;
+        BUG_ON!(current == TaskId(0), "sched: the boot task can't exit");
+        sched.task(current).state = State::Dead;
+    });
+    schedule();
+    unreachable!("sched: dead task scheduled");
+}
+
+// Tasks waiting for something, woken in the order they started waiting. Safe
+// to wake from interrupt handlers.
+pub struct WaitQueue {
+    // Head and tail of a list linked through Task::next_waiter
+    waiters: SpinLock<(Option<TaskId>, Option<TaskId>)>,
+}
+
+#[allow(dead_code)]
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLock::new((None, None)),
+        }
+    }
+
+    // Blocks until woken by wake_one() or wake_all()
+    pub fn wait(&self) {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let mut sched = SCHED.lock();
+            let current = sched.current;
+            sched.task(current).state = State::Blocked;
+            sched.task(current).next_waiter = None;
+
+            match waiters.1 {
+                Some(tail) => sched.task(tail).next_waiter = Some(current),
+                None => waiters.0 = Some(current),
+            }
+            waiters.1 = Some(current);
+        });
+        schedule();
+    }
+
+    // Makes the longest waiting task runnable. It only runs once the current
+    // task yields.
+    pub fn wake_one(&self) -> Option<TaskId> {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let mut sched = SCHED.lock();
+            let id = waiters.0?;
+
+            waiters.0 = sched.task(id).next_waiter.take();
+            if waiters.0.is_none() {
+                waiters.1 = None;
+            }
+            sched.make_runnable(id);
+            Some(id)
+        })
+    }
+
+    pub fn wake_all(&self) -> usize {
+        let mut count = 0;
+        while self.wake_one().is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().0.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static WAITING: AtomicUsize = AtomicUsize::new(0);
+    // The waiters' numbers as decimal digits, in the order they woke
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn waiter(n: usize) {
+        WAITING.fetch_add(1, Ordering::Relaxed);
+        QUEUE.wait();
+        WOKEN.store(WOKEN.load(Ordering::Relaxed) * 10 + n, Ordering::Relaxed);
+    }
+
+    test_case!(wait_queue_fifo, {
+        let woken = || WOKEN.load(Ordering::Relaxed);
+        let first = spawn(waiter, 1);
+        spawn(waiter, 2);
+        spawn(waiter, 3);
+
+        // Each runs up to its wait, then the test task gets its turn back
+        yield_now();
+        assert_eq!(WAITING.load(Ordering::Relaxed), 3);
+        assert_eq!(woken(), 0);
+
+        // Waking alone doesn't run anything
+        assert_eq!(QUEUE.wake_one(), Some(first));
+        assert_eq!(woken(), 0);
+        yield_now();
+        assert_eq!(woken(), 1);
+
+        // Nothing else moves without a wake
+        yield_now();
+        assert_eq!(woken(), 1);
+
+        assert_eq!(QUEUE.wake_all(), 2);
+        assert!(QUEUE.is_empty());
+        assert_eq!(QUEUE.wake_one(), None);
+        yield_now();
+        assert_eq!(woken(), 123);
+
+        // The waiters are gone, and so are their stacks
+        assert_eq!(current(), TaskId(0));
+        assert!(SCHED.lock().task(first).stack.is_none());
+    });
+}
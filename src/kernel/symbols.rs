@@ -0,0 +1,207 @@
+use core::{convert::TryInto, fmt, slice};
+
+// Function names for backtraces. The kernel image carries a table of every
+// function symbol, written into the .ksymtab section after linking by
+// scripts/symembed.py. A kernel that hasn't been through the script just has
+// an empty table, and nothing resolves.
+//
+// Layout, all little endian:
+//   0   magic
+//   8   number of entries (u32)
+//   12  offset of the string table (u32)
+//   16  entries, sorted by address: start (u64), size (u32), name offset (u32)
+// Names are NUL terminated, and offsets into the string table.
+const MAGIC: &[u8; 8] = b"SOLSYMS\0";
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 16;
+// Has to match scripts/symembed.py
+pub const SYMTAB_SIZE: usize = 256 * 1024;
+
+#[used]
+#[link_section = ".ksymtab"]
+static SYMTAB: [u8; SYMTAB_SIZE] = [0; SYMTAB_SIZE];
+
+// The table's contents change after compilation, so it's only ever read
+// through the linker's symbols, which the compiler can't see into
+extern "C" {
+    static __ksymtab_start: u8;
+    static __ksymtab_end: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    start: u64,
+    size: u64,
+    name: usize,
+}
+
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+}
+
+#[allow(dead_code)]
+impl<'a> SymbolTable<'a> {
+    // Returns None for an empty or corrupt table
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+
+        let count = read_u32(bytes, 8) as usize;
+        let strings = read_u32(bytes, 12) as usize;
+        let entries_end = HEADER_LEN.checked_add(count.checked_mul(ENTRY_LEN)?)?;
+        if entries_end > strings || strings > bytes.len() {
+            return None;
+        }
+
+        Some(Self {
+            entries: &bytes[HEADER_LEN..entries_end],
+            strings: &bytes[strings..],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn entry(&self, idx: usize) -> Entry {
+        let offset = idx * ENTRY_LEN;
+        Entry {
+            start: u64::from_le_bytes(self.entries[offset..offset + 8].try_into().unwrap()),
+            size: read_u32(self.entries, offset + 8) as u64,
+            name: read_u32(self.entries, offset + 12) as usize,
+        }
+    }
+
+    fn name(&self, offset: usize) -> Option<&'a str> {
+        let name = self.strings.get(offset..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&name[..len]).ok()
+    }
+
+    // The symbol containing `addr`, and how far into it `addr` is
+    pub fn resolve(&self, addr: u64) -> Option<(&'a str, usize)> {
+        // The first entry starting after addr, so the one before might contain it
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid).start <= addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let entry = self.entry(lo.checked_sub(1)?);
+        let offset = addr - entry.start;
+        if offset >= entry.size {
+            return None;
+        }
+
+        Some((self.name(entry.name)?, offset as usize))
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn kernel_table() -> Option<SymbolTable<'static>> {
+    let bytes = unsafe {
+        let start = &__ksymtab_start as *const u8;
+        let end = &__ksymtab_end as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    };
+
+    SymbolTable::parse(bytes)
+}
+
+pub fn resolve(addr: u64) -> Option<(&'static str, usize)> {
+    kernel_table()?.resolve(addr)
+}
+
+// Formats as the address, followed by name+offset if it resolves
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)?;
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, " {}+{:#x}", name, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    // Lays out a table the same way scripts/symembed.py does
+    fn build(symbols: &[(u64, u32, &str)]) -> ArrayVec<[u8; 512]> {
+        let mut buf = ArrayVec::new();
+        let strings = HEADER_LEN + symbols.len() * ENTRY_LEN;
+        buf.try_extend_from_slice(MAGIC).unwrap();
+        buf.try_extend_from_slice(&(symbols.len() as u32).to_le_bytes()).unwrap();
+        buf.try_extend_from_slice(&(strings as u32).to_le_bytes()).unwrap();
+
+        let mut name = 0;
+        for &(start, size, sym) in symbols {
+            buf.try_extend_from_slice(&start.to_le_bytes()).unwrap();
+            buf.try_extend_from_slice(&size.to_le_bytes()).unwrap();
+            buf.try_extend_from_slice(&(name as u32).to_le_bytes()).unwrap();
+            name += sym.len() + 1;
+        }
+        for &(_, _, sym) in symbols {
+            buf.try_extend_from_slice(sym.as_bytes()).unwrap();
+            buf.push(0);
+        }
+
+        buf
+    }
+
+    test_case!(symbol_resolution, {
+        let bytes = build(&[
+            (0xFFFF_FFFF_8000_1000, 0x40, "solstice::kernel::kernel_main"),
+            (0xFFFF_FFFF_8000_1040, 0x10, "core::panicking::panic"),
+            (0xFFFF_FFFF_8000_2000, 0x100, "solstice::mm::pmm::PhysAllocator::alloc"),
+        ]);
+        let table = SymbolTable::parse(&bytes).unwrap();
+        assert_eq!(table.len(), 3);
+
+        assert_eq!(
+            table.resolve(0xFFFF_FFFF_8000_1000),
+            Some(("solstice::kernel::kernel_main", 0))
+        );
+        assert_eq!(
+            table.resolve(0xFFFF_FFFF_8000_103F),
+            Some(("solstice::kernel::kernel_main", 0x3F))
+        );
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_1045), Some(("core::panicking::panic", 5)));
+        assert_eq!(
+            table.resolve(0xFFFF_FFFF_8000_20FF),
+            Some(("solstice::mm::pmm::PhysAllocator::alloc", 0xFF))
+        );
+
+        // Before the first symbol, in a gap, and past the end
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_0FFF), None);
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_1050), None);
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_2100), None);
+    });
+
+    test_case!(symbol_table_parse, {
+        assert!(SymbolTable::parse(&[0; 64]).is_none());
+        assert_eq!(SymbolTable::parse(&build(&[])).unwrap().resolve(0x1000), None);
+
+        // A count that runs past the end of the table
+        let mut bytes = build(&[(0x1000, 0x10, "a")]);
+        bytes[8] = 0xFF;
+        assert!(SymbolTable::parse(&bytes).is_none());
+    });
+}
@@ -0,0 +1,114 @@
+#![allow(dead_code)]
+
+use crate::ds::SeqLock;
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// The tick count and the rate it goes up at, which have to be read together
+// to make sense of each other. The timer interrupt is the main writer.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    ticks: u64,
+    frequency: u64,
+}
+
+static CLOCK: SeqLock<Clock> = SeqLock::new(Clock { ticks: 0, frequency: 0 });
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+// Called by whichever timer is driving the tick count
+pub fn set_frequency(hz: u64) {
+    assert_ne!(hz, 0, "time: tick frequency must be non-zero");
+    CLOCK.write(|clock| clock.frequency = hz);
+}
+
+pub fn frequency() -> u64 {
+    CLOCK.read().frequency
+}
+
+// Measured against the PIT at boot. Zero until then.
+pub fn set_tsc_frequency(hz: u64) {
+    TSC_FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Called from the timer interrupt handler
+pub fn tick() {
+    CLOCK.write(|clock| clock.ticks += 1);
+    crate::cpu::watchdog::heartbeat();
+}
+
+pub fn ticks() -> u64 {
+    CLOCK.read().ticks
+}
+
+pub fn ticks_to_ms(ticks: u64, hz: u64) -> u64 {
+    (ticks as u128 * 1000 / hz as u128) as u64
+}
+
+// Rounds up, so that sleeping for ms_to_ticks(x) ticks never waits less than x
+pub fn ms_to_ticks(ms: u64, hz: u64) -> u64 {
+    ((ms as u128 * hz as u128 + 999) / 1000) as u64
+}
+
+pub fn ticks_to_duration(ticks: u64, hz: u64) -> Duration {
+    let nanos = (ticks % hz) as u128 * 1_000_000_000 / hz as u128;
+    Duration::new(ticks / hz, nanos as u32)
+}
+
+pub fn uptime() -> Duration {
+    let clock = CLOCK.read();
+    match clock.frequency {
+        0 => Duration::from_secs(0),
+        hz => ticks_to_duration(clock.ticks, hz),
+    }
+}
+
+// Waits for the tick count to reach `tick`, halting between timer interrupts
+// where possible
+pub fn sleep_until(tick: u64) {
+    while ticks() < tick {
+        if x86_64::instructions::interrupts::are_enabled() {
+            x86_64::instructions::hlt();
+        } else {
+            spin_loop();
+        }
+    }
+}
+
+pub fn sleep_ms(ms: u64) {
+    let hz = frequency();
+    assert_ne!(hz, 0, "time: no tick source configured");
+
+    let target = ticks() + ms_to_ticks(ms, hz);
+    while ticks() < target {
+        spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(tick_conversion, {
+        // PIT default rate
+        assert_eq!(ticks_to_ms(0, 1000), 0);
+        assert_eq!(ticks_to_ms(1500, 1000), 1500);
+        assert_eq!(ms_to_ticks(1500, 1000), 1500);
+
+        // 100Hz timer
+        assert_eq!(ticks_to_ms(1, 100), 10);
+        assert_eq!(ticks_to_ms(250, 100), 2500);
+        assert_eq!(ms_to_ticks(15, 100), 2);
+        assert_eq!(ms_to_ticks(20, 100), 2);
+
+        // Unrounded PIT frequency
+        assert_eq!(ticks_to_ms(1_193_182, 1_193_182), 1000);
+        assert_eq!(ticks_to_duration(3, 2), Duration::from_millis(1500));
+    });
+}
@@ -0,0 +1,216 @@
+use arrayvec::ArrayVec;
+use core::{
+    arch::x86_64::_rdtsc,
+    fmt::{self, Write},
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+// A small ring of timestamped events from hot paths, for working out what
+// happened in what order without logging on every one. Recording never takes a
+// lock or allocates, so it's fine from interrupt handlers and the allocators,
+// and the oldest events are overwritten once the ring is full. Use the
+// trace_event! macro to record.
+
+pub const RING_SIZE: usize = 256;
+// How many of the latest events the panic handler prints
+const PANIC_EVENTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TraceId {
+    // The argument is the physical address of the first frame
+    PmmAlloc = 1,
+    PmmFree,
+    // The argument is the id of the task switched to
+    Switch,
+    // The argument is the interrupted instruction pointer
+    Irq,
+}
+
+impl TraceId {
+    fn from_raw(raw: u64) -> Option<Self> {
+        Some(match raw {
+            1 => TraceId::PmmAlloc,
+            2 => TraceId::PmmFree,
+            3 => TraceId::Switch,
+            4 => TraceId::Irq,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceId::PmmAlloc => "pmm alloc",
+            TraceId::PmmFree => "pmm free",
+            TraceId::Switch => "switch",
+            TraceId::Irq => "irq",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub tsc: u64,
+    pub id: TraceId,
+    pub arg: u64,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>20} {:<10} {:#x}", self.tsc, self.id.name(), self.arg)
+    }
+}
+
+struct Slot {
+    // One more than the event's sequence number once it's all written, and 0
+    // while it's being written
+    seq: AtomicU64,
+    tsc: AtomicU64,
+    id: AtomicU64,
+    arg: AtomicU64,
+}
+
+pub struct TraceRing {
+    next: AtomicU64,
+    slots: [Slot; RING_SIZE],
+}
+
+#[allow(dead_code)]
+impl TraceRing {
+    pub const fn new() -> Self {
+        const EMPTY: Slot = Slot {
+            seq: AtomicU64::new(0),
+            tsc: AtomicU64::new(0),
+            id: AtomicU64::new(0),
+            arg: AtomicU64::new(0),
+        };
+        Self {
+            next: AtomicU64::new(0),
+            slots: [EMPTY; RING_SIZE],
+        }
+    }
+
+    pub fn record(&self, id: TraceId, arg: u64) {
+        self.record_at(unsafe { _rdtsc() }, id, arg);
+    }
+
+    // Each event claims its own slot before writing it, so an interrupt that
+    // records in the middle of another event doesn't clobber it
+    fn record_at(&self, tsc: u64, id: TraceId, arg: u64) {
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[seq as usize % RING_SIZE];
+
+        slot.seq.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.tsc.store(tsc, Ordering::Relaxed);
+        slot.id.store(id as u64, Ordering::Relaxed);
+        slot.arg.store(arg, Ordering::Relaxed);
+        slot.seq.store(seq + 1, Ordering::Release);
+    }
+
+    // The events still in the ring, oldest first. An event that's being
+    // overwritten while this runs is left out rather than read half written.
+    pub fn events(&self) -> ArrayVec<[TraceEvent; RING_SIZE]> {
+        let next = self.next.load(Ordering::Acquire);
+        let oldest = next.saturating_sub(RING_SIZE as u64);
+
+        let mut events: ArrayVec<[TraceEvent; RING_SIZE]> = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                let seq = slot.seq.load(Ordering::Acquire);
+                let (tsc, id, arg) = (
+                    slot.tsc.load(Ordering::Relaxed),
+                    slot.id.load(Ordering::Relaxed),
+                    slot.arg.load(Ordering::Relaxed),
+                );
+                fence(Ordering::Acquire);
+                if seq == 0 || seq <= oldest || slot.seq.load(Ordering::Relaxed) != seq {
+                    return None;
+                }
+                Some(TraceEvent {
+                    tsc,
+                    id: TraceId::from_raw(id)?,
+                    arg,
+                })
+            })
+            .collect();
+
+        // Slots are claimed before the timestamp is taken, so an interrupt can
+        // leave a later slot with an earlier time
+        events.sort_unstable_by_key(|event| event.tsc);
+        events
+    }
+}
+
+static RING: TraceRing = TraceRing::new(); // TODO: SMP
+
+pub fn record(id: TraceId, arg: u64) {
+    RING.record(id, arg);
+}
+
+// Writes out the latest `count` events, oldest first
+pub fn dump(out: &mut dyn Write, count: usize) -> fmt::Result {
+    let events = RING.events();
+    for event in events.iter().skip(events.len().saturating_sub(count)) {
+        writeln!(out, "  {}", event)?;
+    }
+    Ok(())
+}
+
+// For the panic handler
+pub fn dump_on_panic() {
+    let events = RING.events();
+    for event in events.iter().skip(events.len().saturating_sub(PANIC_EVENTS)) {
+        error!("    trace: {}", event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(trace_ring_wraparound, {
+        let ring = TraceRing::new();
+        assert!(ring.events().is_empty());
+
+        for i in 0..10 {
+            ring.record_at(100 + i, TraceId::PmmAlloc, i);
+        }
+        let events = ring.events();
+        assert_eq!(events.len(), 10);
+        assert_eq!(events[0], TraceEvent { tsc: 100, id: TraceId::PmmAlloc, arg: 0 });
+
+        // Going round more than once keeps only the latest RING_SIZE
+        let total = 2 * RING_SIZE as u64 + 5;
+        for i in 10..total {
+            ring.record_at(100 + i, TraceId::PmmFree, i);
+        }
+        let events = ring.events();
+        assert_eq!(events.len(), RING_SIZE);
+        assert_eq!(events[0].arg, total - RING_SIZE as u64);
+        assert_eq!(events[RING_SIZE - 1].arg, total - 1);
+        assert!(events.iter().all(|event| event.id == TraceId::PmmFree));
+    });
+
+    test_case!(trace_events_in_time_order, {
+        let ring = TraceRing::new();
+
+        // An interrupt that claimed its slot after another event but read the
+        // clock before it
+        ring.record_at(1000, TraceId::Switch, 1);
+        ring.record_at(1200, TraceId::PmmAlloc, 2);
+        ring.record_at(1100, TraceId::Irq, 3);
+        ring.record_at(1300, TraceId::PmmFree, 4);
+
+        let args: ArrayVec<[u64; 4]> = ring.events().iter().map(|event| event.arg).collect();
+        assert_eq!(args.as_slice(), &[1, 3, 2, 4]);
+
+        // The real clock only goes forwards
+        ring.record(TraceId::Irq, 5);
+        ring.record(TraceId::Irq, 6);
+        let events = ring.events();
+        assert!(events.windows(2).all(|pair| pair[0].tsc <= pair[1].tsc));
+        assert_eq!(events.last().map(|event| event.arg), Some(6));
+    });
+}
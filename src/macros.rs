@@ -1,37 +1,36 @@
 // TODO: Move into macros/ folder
 
-use crate::{drivers::vga::text_mode::Writer, ds::SpinLock};
+use crate::kernel::console::console;
 use core::fmt;
-use lazy_static::lazy_static;
 use log::{Level, Log, Metadata, Record};
 use core::fmt::Debug;
 use alloc::format;
 use alloc::string::ToString;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-// Need a separate struct so we can implement Log trait
-pub struct ScreenLocker(SpinLock<ScreenWriter>);
+// Sends log records to the console
+pub struct ConsoleLogger;
 
-pub struct ScreenWriter(Writer);
+pub static LOGGER: ConsoleLogger = ConsoleLogger;
 
-impl fmt::Write for ScreenWriter {
+// Used when going through the console could deadlock, so that output still goes
+// somewhere
+struct SerialWriter;
 
+impl fmt::Write for SerialWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        #[cfg(any(debug_assertions, test))]
-        {
-            use crate::drivers::serial;
-            serial::write_str(s);
-        }
-
-        self.0.write_str(s);
-
+        crate::drivers::serial::write_str(s);
         Ok(())
     }
 }
+
+// Nesting depth of _print. Interrupts are disabled while printing, so finding
+// this non-zero means we've re-entered from a fault, an NMI or the panic
+// handler. The console lock would let us back in, but a backend's own lock (like
+// the VGA writer's) could still be held, so waiting for it would deadlock.
+static PRINT_DEPTH: AtomicUsize = AtomicUsize::new(0); // TODO: SMP
+
 // TODO: Macro formatting is broken, maybe due to broken memory alloc
-lazy_static! {
-    pub static ref SCREEN: ScreenLocker =
-        ScreenLocker(SpinLock::new(ScreenWriter(Writer::default())));
-}
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::macros::_print(format_args!($($arg)*)));
@@ -72,25 +71,70 @@ macro_rules! dbg {
         ($($crate::dbg!($val)),+,)
     };
 }
+// Logs a warning with the call site the first time `cond` is true, and stays
+// quiet after that. Evaluates to `cond`.
+#[macro_export]
+macro_rules! WARN_ONCE {
+    ($cond:expr) => {
+        $crate::WARN_ONCE!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {{
+        static WARNED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+        let cond: bool = $cond;
+        if cond && !WARNED.swap(true, core::sync::atomic::Ordering::Relaxed) {
+            log::warn!("{}:{}: {}", file!(), line!(), format_args!($($arg)+));
+        }
+        cond
+    }};
+}
+
+// Unlike debug_assert!, this is checked in release builds too
+#[macro_export]
+macro_rules! BUG_ON {
+    ($cond:expr) => {
+        $crate::BUG_ON!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if $cond {
+            panic!("BUG at {}:{}: {}", file!(), line!(), format_args!($($arg)+));
+        }
+    };
+}
+
+// Records an event in the trace ring, e.g. trace_event!(PmmAlloc, addr). The
+// argument is anything that casts to u64.
+#[macro_export]
+macro_rules! trace_event {
+    ($id:ident, $arg:expr) => {
+        $crate::kernel::trace::record($crate::kernel::trace::TraceId::$id, $arg as u64)
+    };
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        SCREEN
-            .0
-            .lock()
-            .write_fmt(args)
-            .unwrap();
+    // The console lock is reentrant, so this CPU holding it already is fine
+    crate::cpu::interrupts::without_interrupts(|| {
+        if PRINT_DEPTH.fetch_add(1, Ordering::Relaxed) > 0 {
+            SerialWriter.write_fmt(args).unwrap();
+        } else {
+            let console = console().lock();
+            (&*console).write_fmt(args).unwrap();
+        }
+        PRINT_DEPTH.fetch_sub(1, Ordering::Relaxed);
     });
 }
 
-impl Log for ScreenLocker {
+impl Log for ConsoleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
+            #[cfg(test)]
+            LOG_COUNTS[record.level() as usize - 1].fetch_add(1, Ordering::Relaxed);
+
             let color = match record.level() {
                 Level::Info => "\x1B[32m",
                 Level::Error => "\x1B[31m",
@@ -110,14 +154,177 @@ impl Log for ScreenLocker {
 
     fn flush(&self) {}
 }
+// Number of records logged at each level, so tests can check what was logged
+#[cfg(test)]
+static LOG_COUNTS: [AtomicUsize; 5] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+#[cfg(test)]
+pub fn log_count(level: Level) -> usize {
+    LOG_COUNTS[level as usize - 1].load(Ordering::Relaxed)
+}
+
+// Records everything written to the console between start() and stop()
+#[cfg(test)]
+pub mod capture {
+    use crate::ds::SpinLock;
+    use arrayvec::ArrayString;
+
+    pub type Buffer = ArrayString<[u8; 256]>;
+
+    static CAPTURE: SpinLock<Option<Buffer>> = SpinLock::new(None);
+
+    pub fn start() {
+        *CAPTURE.lock() = Some(Buffer::new());
+    }
+
+    pub fn stop() -> Buffer {
+        CAPTURE.lock().take().expect("console capture wasn't started")
+    }
+
+    pub(crate) fn push(s: &str) {
+        if let Some(buf) = CAPTURE.lock().as_mut() {
+            let _ = buf.try_push_str(s);
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! test_case {
     ($test_name:ident, $body:expr) => {
         #[test_case]
         fn $test_name() {
             print!("{}::{}... ", module_path!(), stringify!($test_name));
+            $crate::testing::start_test(concat!(module_path!(), "::", stringify!($test_name)));
             $body;
+            $crate::testing::finish_test();
             println!("[ok]");
         }
     };
+    // Teardown runs even if the body returns early
+    ($test_name:ident, setup = $setup:expr, teardown = $teardown:expr, $body:expr) => {
+        #[test_case]
+        fn $test_name() {
+            print!("{}::{}... ", module_path!(), stringify!($test_name));
+            $crate::testing::start_test(concat!(module_path!(), "::", stringify!($test_name)));
+            $setup;
+            {
+                let _teardown = $crate::testing::Teardown(|| $teardown);
+                $body;
+            }
+            $crate::testing::finish_test();
+            println!("[ok]");
+        }
+    };
+}
+
+// For a test whose result is the status QEMU exits with, because the body ends
+// the run itself, e.g. through one of the exit paths. A body that returns
+// fails. Only the last test to run can be one of these, so each should sit
+// behind its own feature, like stack-overflow-test.
+#[macro_export]
+macro_rules! exit_test_case {
+    ($test_name:ident, $body:expr) => {
+        #[test_case]
+        fn $test_name() {
+            println!("{}::{}... [ends the run]", module_path!(), stringify!($test_name));
+            $crate::testing::start_test(concat!(module_path!(), "::", stringify!($test_name)));
+            $body;
+            $crate::testing::exit_test_returned(concat!(module_path!(), "::", stringify!($test_name)));
+        }
+    };
+}
+
+// Runs `body` for a fixed number of iterations and reports TSC cycles. Only
+// run by `cargo xtest --features bench`, which skips the normal tests.
+#[macro_export]
+macro_rules! bench_case {
+    ($bench_name:ident, iterations = $iterations:expr, $body:expr) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $bench_name: $crate::testing::Benchmark = $crate::testing::Benchmark {
+            name: concat!(module_path!(), "::", stringify!($bench_name)),
+            iterations: $iterations,
+            body: || $body,
+        };
+    };
+}
+
+// Declares a struct of named Counters, which can be reset together and print
+// as a table:
+//   counter_group! {
+//       pub struct CacheStats { hits, misses }
+//   }
+#[macro_export]
+macro_rules! counter_group {
+    ($vis:vis struct $group:ident { $($counter:ident),+ $(,)? }) => {
+        $vis struct $group {
+            $(pub $counter: $crate::ds::Counter,)+
+        }
+
+        #[allow(dead_code)]
+        impl $group {
+            pub const fn new() -> Self {
+                Self {
+                    $($counter: $crate::ds::Counter::new(),)+
+                }
+            }
+
+            pub fn reset(&self) {
+                $(self.$counter.reset();)+
+            }
+        }
+
+        impl core::fmt::Display for $group {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                $crate::ds::counter::write_table(f, &[$((stringify!($counter), &self.$counter)),+])
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(warn_once, {
+        let before = log_count(Level::Warn);
+        for i in 0..3 {
+            assert!(WARN_ONCE!(i < 10, "fired on iteration {}", i));
+        }
+        assert!(!WARN_ONCE!(false));
+        assert_eq!(log_count(Level::Warn), before + 1);
+    });
+
+    test_case!(println_reaches_console, {
+        capture::start();
+        println!("hello {}", 5);
+        assert_eq!(capture::stop().as_str(), "hello 5\n");
+    });
+
+    test_case!(nested_print, {
+        // Pretend we're printing from inside a fault taken while the console
+        // was locked. This must not deadlock.
+        capture::start();
+        {
+            let _console = console().lock();
+            print!("nested");
+        }
+        assert_eq!(capture::stop().as_str(), "nested");
+    });
+
+    test_case!(reentered_print_goes_to_serial, {
+        // Pretend we're printing from a fault taken in the middle of print!(),
+        // which could be holding a backend's lock
+        capture::start();
+        PRINT_DEPTH.fetch_add(1, Ordering::Relaxed);
+        print!("reentered");
+        PRINT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        assert_eq!(capture::stop().as_str(), "");
+    });
 }
@@ -7,6 +7,7 @@
 #![feature(custom_inner_attributes)]
 #![feature(core_intrinsics)]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(alloc_layout_extra)]
 #![feature(alloc_error_handler)]
 #![feature(raw_vec_internals)]
@@ -37,9 +38,14 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     // Run tests
 
 
-    info!("nothing to do, halting...");
+    // Debug builds have a serial console, so drop into the monitor on it
+    #[cfg(not(test))]
+    if cfg!(debug_assertions) {
+        kernel::monitor::run();
+    }
 
-    halt_loop();
+    info!("nothing to do, idling...");
+    kernel::deferred::idle();
 }
 
 #[allow(unused_imports)]
@@ -49,18 +55,14 @@ use core::panic::PanicInfo;
 #[cfg(not(test))]
 #[allow(clippy::empty_loop)]
 fn panic(info: &PanicInfo) -> ! {
-    error!("{}", info);
-    halt_loop();
+    if !kernel::early_panic::report(format_args!("{}", info)) {
+        error!("{}", info);
+    }
+    kernel::panic_log::record(info);
+    kernel::reboot::after_panic();
 }
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
-
-fn halt_loop() -> ! {
-    loop {
-        x86_64::instructions::interrupts::disable();
-        x86_64::instructions::hlt();
-    }
-}
@@ -1,22 +1,181 @@
-use crate::{ds::RwSpinLock, mm::pmm::PhysAllocator};
+use crate::{
+    ds::RwSpinLock,
+    mm::{pmm::PhysAllocator, vmem},
+};
+use arrayvec::ArrayVec;
+use core::fmt;
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{
-        mapper::{MapToError, MapperFlush},
-        page::Size4KiB,
-        FrameAllocator,
-        Mapper,
-        OffsetPageTable,
-        Page,
-        PageTableFlags,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, MapperFlush, TranslateResult, UnmapError},
+            page::{PageRange, Size1GiB, Size2MiB, Size4KiB},
+            FrameAllocator,
+            Mapper,
+            OffsetPageTable,
+            Page,
+            PageSize,
+            PageTable,
+            PageTableFlags,
+        },
     },
     PhysAddr,
     VirtAddr,
 };
 use x86_64::structures::paging::{Translate, PhysFrame};
 
+const MAX_DEMAND_ZERO_RANGES: usize = 16;
+
+// The lower half of the address space belongs to userspace, and the upper half
+// (PML4 entries 256 and up) is shared by every address space
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+const USER_PML4_ENTRIES: usize = 256;
+
+pub fn is_user_addr(addr: VirtAddr) -> bool {
+    addr.as_u64() < USER_END
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    // Back the page with a fresh zeroed frame, mapped with these flags
+    DemandZero(PageTableFlags),
+    Unhandled,
+}
+
+// Virtual ranges that are populated with zeroed frames when first touched
+#[derive(Debug, Default)]
+pub struct DemandZeroRanges {
+    ranges: ArrayVec<[(PageRange<Size4KiB>, PageTableFlags); MAX_DEMAND_ZERO_RANGES]>,
+}
+
+impl DemandZeroRanges {
+    pub fn insert(&mut self, range: PageRange<Size4KiB>, flags: PageTableFlags) -> Result<(), ()> {
+        let overlaps = self
+            .ranges
+            .iter()
+            .any(|(r, _)| range.start < r.end && r.start < range.end);
+        if overlaps {
+            return Err(());
+        }
+
+        self.ranges.try_push((range, flags)).map_err(|_| ())
+    }
+
+    pub fn fault_action(&self, addr: VirtAddr, error: PageFaultErrorCode) -> FaultAction {
+        // Only faults on non-present pages can be demand-zero
+        if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return FaultAction::Unhandled;
+        }
+
+        let page = Page::containing_address(addr);
+        self.ranges
+            .iter()
+            .find(|(r, _)| r.start <= page && page < r.end)
+            .map_or(FaultAction::Unhandled, |&(_, flags)| FaultAction::DemandZero(flags))
+    }
+
+    // The flags for a range that lies entirely within one demand-zero range
+    pub fn flags_for(&self, range: PageRange<Size4KiB>) -> Option<PageTableFlags> {
+        self.ranges
+            .iter()
+            .find(|(r, _)| r.start <= range.start && range.end <= r.end)
+            .map(|&(_, flags)| flags)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum MmioError {
+    NoVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum MapNewError {
+    // Where the page already points, which is left as it was
+    AlreadyMapped(PhysAddr),
+    Map(MapToError<Size4KiB>),
+}
+
+// Device registers can't be cached, and never hold code
+pub fn mmio_flags(flags: PageTableFlags) -> PageTableFlags {
+    flags
+        | PageTableFlags::PRESENT
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE
+}
+
+// One present entry met on the way down a page table walk. Level 4 is the PML4
+// and level 1 the PT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    pub level: u8,
+    pub index: usize,
+    pub addr: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkEnd {
+    // The address the walk was for, translated
+    Mapped(PhysAddr),
+    // The first entry that wasn't present
+    NotPresent { level: u8, index: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Walk {
+    pub addr: VirtAddr,
+    pub steps: ArrayVec<[WalkStep; 4]>,
+    pub end: WalkEnd,
+}
+
+impl Walk {
+    // Whether the address is mapped with every level allowing `flags`, which
+    // is what the CPU checks for USER_ACCESSIBLE and WRITABLE
+    pub fn allows(&self, flags: PageTableFlags) -> bool {
+        matches!(self.end, WalkEnd::Mapped(_)) && self.steps.iter().all(|step| step.flags.contains(flags))
+    }
+}
+
+fn level_name(level: u8) -> &'static str {
+    match level {
+        4 => "PML4",
+        3 => "PDPT",
+        2 => "PD",
+        _ => "PT",
+    }
+}
+
+impl fmt::Display for Walk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "walk of {:?}", self.addr)?;
+        for step in self.steps.iter() {
+            writeln!(f, "  {:<4}[{:>3}] {:?} {:?}", level_name(step.level), step.index, step.addr, step.flags)?;
+        }
+
+        match self.end {
+            WalkEnd::Mapped(addr) => write!(f, "  mapped to {:?}", addr),
+            WalkEnd::NotPresent { level, index } => write!(f, "  {}[{}] not present", level_name(level), index),
+        }
+    }
+}
+
+struct PhysAllocatorProxy;
+
+unsafe impl FrameAllocator<Size4KiB> for PhysAllocatorProxy {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        Some(PhysAllocator::alloc(0).start)
+    }
+}
+
 pub struct AddrSpace {
+    root: PhysFrame,
     table: RwSpinLock<OffsetPageTable<'static>>,
+    demand_zero: RwSpinLock<DemandZeroRanges>,
 }
 
 unsafe impl Send for AddrSpace {}
@@ -28,9 +187,11 @@ lazy_static! {
         let table_virt = super::phys_to_kernel_virt(table_frame.start_address());
 
         AddrSpace {
+            root: table_frame,
             table: RwSpinLock::new(unsafe {
                 OffsetPageTable::new(&mut *table_virt.as_mut_ptr(), VirtAddr::new(super::PHYS_OFFSET))
             }),
+            demand_zero: RwSpinLock::new(DemandZeroRanges::default()),
         }
     };
 }
@@ -40,19 +201,100 @@ impl AddrSpace {
         &*KERNEL
     }
 
-    pub fn map_to(
+    // Creates an address space with an empty user half. The kernel half is
+    // copied from the kernel's PML4, so any PML4 entries the kernel adds later
+    // won't show up here.
+    pub fn new_user() -> AddrSpace {
+        let root = PhysAllocator::alloc(0).start;
+        let table: &'static mut PageTable =
+            unsafe { &mut *super::phys_to_kernel_virt(root.start_address()).as_mut_ptr() };
+        table.zero();
+
+        {
+            let mut kernel = Self::kernel().table.write();
+            let kernel_l4 = kernel.level_4_table();
+            for idx in USER_PML4_ENTRIES..512 {
+                table[idx] = kernel_l4[idx].clone();
+            }
+        }
+
+        AddrSpace {
+            root,
+            table: RwSpinLock::new(unsafe {
+                OffsetPageTable::new(table, VirtAddr::new(super::PHYS_OFFSET))
+            }),
+            demand_zero: RwSpinLock::new(DemandZeroRanges::default()),
+        }
+    }
+
+    // Creates an address space with nothing in either half, for building
+    // kernel tables in before they're moved over with move_to_kernel()
+    pub fn new_empty() -> AddrSpace {
+        let root = PhysAllocator::alloc(0).start;
+        let table: &'static mut PageTable =
+            unsafe { &mut *super::phys_to_kernel_virt(root.start_address()).as_mut_ptr() };
+        table.zero();
+
+        AddrSpace {
+            root,
+            table: RwSpinLock::new(unsafe {
+                OffsetPageTable::new(table, VirtAddr::new(super::PHYS_OFFSET))
+            }),
+            demand_zero: RwSpinLock::new(DemandZeroRanges::default()),
+        }
+    }
+
+    // Points the kernel's PML4 entries covering `start..start + len` at this
+    // space's tables, which the kernel owns from then on. The tables that were
+    // there are left alone. Safety: anything else mapped under those entries is
+    // lost, and address spaces that already copied the kernel half keep the old
+    // tables, so the new ones have to map the same things.
+    pub unsafe fn move_to_kernel(&self, start: VirtAddr, len: u64) {
+        BUG_ON!(is_user_addr(start), "move_to_kernel: {:?} is in the user half", start);
+        let first = usize::from(start.p4_index());
+        let last = usize::from((start + (len - 1)).p4_index());
+
+        let mut table = self.table.write();
+        let mut kernel = Self::kernel().table.write();
+        for idx in first..=last {
+            kernel.level_4_table()[idx] = table.level_4_table()[idx].clone();
+            table.level_4_table()[idx].set_unused();
+        }
+        x86_64::instructions::tlb::flush_all();
+    }
+
+    pub fn root(&self) -> PhysFrame {
+        self.root
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.root
+    }
+
+    // Safety: anything borrowed from the current user half is invalidated
+    pub unsafe fn switch_to(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.root, flags);
+    }
+
+    // Only maps into the user half, so it never touches tables shared with
+    // other address spaces
+    pub fn map_user(
         &self,
         virt: VirtAddr,
         phys: PhysAddr,
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
-        struct PhysAllocatorProxy;
-        unsafe impl FrameAllocator<Size4KiB> for PhysAllocatorProxy {
-            fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-                Some(PhysAllocator::alloc(0).start)
-            }
-        }
+        BUG_ON!(!is_user_addr(virt), "map_user: {:?} is in the kernel half", virt);
+        self.map_to(virt, phys, flags | PageTableFlags::USER_ACCESSIBLE)
+    }
 
+    pub fn map_to(
+        &self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
         self.map_to_with_allocator(virt, phys, flags, &mut PhysAllocatorProxy)
     }
 
@@ -75,7 +317,542 @@ impl AddrSpace {
         }
     }
 
+    // Unmaps a 4KiB page and returns the frame that was behind it, which is
+    // left for the caller to free
+    pub fn unmap(&self, virt: VirtAddr) -> Result<PhysFrame, UnmapError> {
+        let (frame, flush) = self.table.write().unmap(Page::<Size4KiB>::containing_address(virt))?;
+        flush.flush();
+        Ok(frame)
+    }
+
+    // Like map_to_with_allocator(), but fails if anything's mapped at `virt`
+    // already, huge pages included. The check is made under the same lock as
+    // the mapping, so nothing can slip in between.
+    pub fn map_to_new<A: FrameAllocator<Size4KiB>>(
+        &self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+        alloc: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapNewError> {
+        let mut table = self.table.write();
+        if let Some(existing) = table.translate_addr(virt) {
+            return Err(MapNewError::AlreadyMapped(existing));
+        }
+
+        unsafe {
+            table.map_to(
+                Page::containing_address(virt),
+                PhysFrame::containing_address(phys),
+                flags,
+                alloc,
+            )
+        }
+        .map_err(MapNewError::Map)
+    }
+
+    // Maps `virt` to `phys` whatever was there, and returns the frame that got
+    // replaced, if any. That frame isn't freed. Only 4KiB pages can be
+    // replaced; a huge page in the way is an error.
+    #[allow(dead_code)]
+    pub fn map_to_overwrite<A: FrameAllocator<Size4KiB>>(
+        &self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+        alloc: &mut A,
+    ) -> Result<(Option<PhysFrame>, MapperFlush<Size4KiB>), MapToError<Size4KiB>> {
+        let mut table = self.table.write();
+        let page = Page::containing_address(virt);
+        let old = match table.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                Some(frame)
+            }
+            Err(_) => None,
+        };
+
+        let flush = unsafe { table.map_to(page, PhysFrame::containing_address(phys), flags, alloc)? };
+        Ok((old, flush))
+    }
+
+    // Maps a single page of `size` bytes, which has to be 4KiB, 2MiB or 1GiB,
+    // with both addresses aligned to it
+    pub fn map_sized(&self, virt: VirtAddr, phys: PhysAddr, size: u64, flags: PageTableFlags) -> Result<(), ()> {
+        fn map<S: PageSize>(table: &mut OffsetPageTable<'static>, virt: VirtAddr, phys: PhysAddr, flags: PageTableFlags) -> Result<(), ()>
+        where
+            OffsetPageTable<'static>: Mapper<S>,
+        {
+            let page = Page::<S>::from_start_address(virt).map_err(|_| ())?;
+            let frame = PhysFrame::<S>::from_start_address(phys).map_err(|_| ())?;
+            unsafe { table.map_to(page, frame, flags, &mut PhysAllocatorProxy) }
+                .map_err(|_| ())?
+                .flush();
+            Ok(())
+        }
+
+        let mut table = self.table.write();
+        match size {
+            Size4KiB::SIZE => map::<Size4KiB>(&mut table, virt, phys, flags),
+            Size2MiB::SIZE => map::<Size2MiB>(&mut table, virt, phys, flags),
+            Size1GiB::SIZE => map::<Size1GiB>(&mut table, virt, phys, flags),
+            _ => panic!("map_sized: no {:#x} byte pages", size),
+        }
+    }
+
+    // Splits the 2MiB or 1GiB page covering `virt` into 512 pages of the next
+    // size down, with the same flags, so part of it can be given different
+    // flags or remapped. Returns false if there's no huge page there.
+    pub fn split_huge(&self, virt: VirtAddr) -> bool {
+        let _table = self.table.write();
+        let mut table_addr = self.root.start_address();
+        for level in (2..=4u8).rev() {
+            let shift = 12 + 9 * (level as u64 - 1);
+            let index = (virt.as_u64() >> shift) as usize & 0x1FF;
+            let table: &mut PageTable = unsafe { &mut *super::phys_to_kernel_virt(table_addr).as_mut_ptr() };
+            let entry = &mut table[index];
+            let flags = entry.flags();
+
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return false;
+            }
+
+            if level < 4 && flags.contains(PageTableFlags::HUGE_PAGE) {
+                let child_frame = PhysAllocator::alloc(0).start;
+                let child: &mut PageTable =
+                    unsafe { &mut *super::phys_to_kernel_virt(child_frame.start_address()).as_mut_ptr() };
+                // 1GiB pages split into 2MiB ones, which are still huge
+                let child_flags = if level == 2 { flags - PageTableFlags::HUGE_PAGE } else { flags };
+                let child_size = 1u64 << (shift - 9);
+                for (i, child_entry) in child.iter_mut().enumerate() {
+                    child_entry.set_addr(entry.addr() + i as u64 * child_size, child_flags);
+                }
+
+                entry.set_addr(child_frame.start_address(), flags - PageTableFlags::HUGE_PAGE);
+                x86_64::instructions::tlb::flush_all();
+                return true;
+            }
+            table_addr = entry.addr();
+        }
+
+        false
+    }
+
+    // Maps `size` bytes of device memory at `phys` uncached, wherever vmem
+    // finds room, and returns the address `phys` ended up at. Only for the
+    // kernel's address space, since vmem's addresses are in the shared half.
+    pub fn map_mmio(&self, phys: PhysAddr, size: u64, flags: PageTableFlags) -> Result<VirtAddr, MmioError> {
+        BUG_ON!(!core::ptr::eq(self, Self::kernel()), "map_mmio: not the kernel's address space");
+        BUG_ON!(size == 0, "map_mmio: empty range at {:?}", phys);
+
+        let offset = phys.as_u64() % super::PAGE_SIZE;
+        let first = PhysFrame::<Size4KiB>::containing_address(phys);
+        let pages = (offset + size + super::PAGE_SIZE - 1) / super::PAGE_SIZE;
+        let base = vmem::alloc(pages * super::PAGE_SIZE, super::PAGE_SIZE).ok_or(MmioError::NoVirtualSpace)?;
+
+        for (i, frame) in PhysFrame::range(first, first + pages).enumerate() {
+            let virt = base + i as u64 * super::PAGE_SIZE;
+            // On failure the range isn't given back to vmem, since some of it
+            // may already be mapped
+            self.map_to(virt, frame.start_address(), mmio_flags(flags))
+                .map_err(MmioError::Map)?
+                .flush();
+        }
+
+        Ok(base + offset)
+    }
+
     pub fn translate_addr(&self, addr: VirtAddr) -> Option<PhysAddr> {
         self.table.read().translate_addr(addr)
     }
+
+    pub fn page_flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        match self.table.read().translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    // The size of the page `addr` is in, if it's mapped
+    pub fn page_size(&self, addr: VirtAddr) -> Option<u64> {
+        match self.table.read().translate(addr) {
+            TranslateResult::Mapped { frame, .. } => Some(frame.size()),
+            _ => None,
+        }
+    }
+
+    // Every level of the tables for `addr`, for when translate_addr() says
+    // something isn't mapped and it's not obvious why
+    pub fn walk(&self, addr: VirtAddr) -> Walk {
+        // Held so the tables can't change under the walk
+        let _table = self.table.read();
+        walk_from(self.root, addr)
+    }
+
+    pub fn dump_walk(&self, addr: VirtAddr) -> Walk {
+        let walk = self.walk(addr);
+        info!("{}", walk);
+        walk
+    }
+
+    // Nothing is mapped up front; the page fault handler maps each page on first
+    // access
+    pub fn map_demand_zero(&self, range: PageRange<Size4KiB>, flags: PageTableFlags) -> Result<(), ()> {
+        self.demand_zero.write().insert(range, flags | PageTableFlags::PRESENT)
+    }
+
+    // Returns true if the fault was resolved and the faulting access can be
+    // retried
+    pub fn handle_page_fault(&self, addr: VirtAddr, error: PageFaultErrorCode) -> bool {
+        let flags = match self.demand_zero.read().fault_action(addr, error) {
+            FaultAction::DemandZero(flags) => flags,
+            FaultAction::Unhandled => return false,
+        };
+
+        self.populate(Page::containing_address(addr), flags);
+        true
+    }
+
+    // Populate every page of a demand-zero range now rather than on first
+    // access. Returns the number of pages that had to be populated, or Err if
+    // the range isn't demand-zero.
+    pub fn prefault(&self, range: PageRange<Size4KiB>) -> Result<usize, ()> {
+        let flags = self.demand_zero.read().flags_for(range).ok_or(())?;
+
+        Ok(range.filter(|&page| self.populate(page, flags)).count())
+    }
+
+    // Unmap and free the frames behind a demand-zero range. The range stays
+    // reserved, so touching it again just gets fresh zeroed pages. Returns the
+    // number of frames freed, or Err if the range isn't demand-zero.
+    pub fn release(&self, range: PageRange<Size4KiB>) -> Result<usize, ()> {
+        self.demand_zero.read().flags_for(range).ok_or(())?;
+
+        let mut freed = 0;
+        for page in range {
+            let result = self.table.write().unmap(page);
+            match result {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+                    freed += 1;
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(e) => panic!("demand-zero: failed to release {:?}: {:?}", page, e),
+            }
+        }
+
+        Ok(freed)
+    }
+
+    // Back the page with a zeroed frame, unless something else got there first.
+    // Returns true if the page was populated.
+    fn populate(&self, page: Page<Size4KiB>, flags: PageTableFlags) -> bool {
+        if self.translate_addr(page.start_address()).is_some() {
+            return false;
+        }
+
+        let frame = PhysAllocator::alloc(0).start;
+        super::zero_frame(frame);
+
+        match self.map_to(page.start_address(), frame.start_address(), flags) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(MapToError::PageAlreadyMapped(_)) => {
+                PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+                false
+            }
+            Err(e) => panic!("demand-zero: failed to map {:?}: {:?}", page, e),
+        }
+    }
+}
+
+// Walks whichever tables CR3 points at, without any locks. Only for checks
+// that can cope with the answer going stale, since the tables can change
+// underneath.
+pub fn walk_active(addr: VirtAddr) -> Walk {
+    walk_from(Cr3::read().0, addr)
+}
+
+fn walk_from(root: PhysFrame, addr: VirtAddr) -> Walk {
+    let mut walk = Walk {
+        addr,
+        steps: ArrayVec::new(),
+        end: WalkEnd::NotPresent { level: 4, index: 0 },
+    };
+
+    let mut table_addr = root.start_address();
+    for level in (1..=4u8).rev() {
+        let shift = 12 + 9 * (level as u64 - 1);
+        let index = (addr.as_u64() >> shift) as usize & 0x1FF;
+        let table: &PageTable = unsafe { &*super::phys_to_kernel_virt(table_addr).as_ptr() };
+        let entry = &table[index];
+        let flags = entry.flags();
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            walk.end = WalkEnd::NotPresent { level, index };
+            return walk;
+        }
+
+        walk.steps.push(WalkStep {
+            level,
+            index,
+            addr: entry.addr(),
+            flags,
+        });
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let offset = addr.as_u64() & ((1 << shift) - 1);
+            walk.end = WalkEnd::Mapped(entry.addr() + offset);
+            return walk;
+        }
+        table_addr = entry.addr();
+    }
+
+    unreachable!()
+}
+
+// Only user address spaces are ever dropped, since the kernel's lives in a
+// static. Frees the page tables of the user half, but not the frames mapped by
+// them.
+impl Drop for AddrSpace {
+    fn drop(&mut self) {
+        BUG_ON!(self.is_active(), "dropping the active address space");
+        free_table(self.root, 4, USER_PML4_ENTRIES);
+    }
+}
+
+fn free_table(frame: PhysFrame, level: u8, entries: usize) {
+    if level > 1 {
+        let table: &PageTable =
+            unsafe { &*super::phys_to_kernel_virt(frame.start_address()).as_ptr() };
+        for entry in table.iter().take(entries) {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+                free_table(PhysFrame::containing_address(entry.addr()), level - 1, 512);
+            }
+        }
+    }
+
+    PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::phys_to_kernel_virt;
+
+    test_case!(demand_zero_fault_action, {
+        let base = VirtAddr::new(0xFFFF_A000_0000_0000);
+        let start = Page::containing_address(base);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let mut ranges = DemandZeroRanges::default();
+        assert_eq!(ranges.insert(Page::range(start, start + 4), flags), Ok(()));
+        assert_eq!(ranges.insert(Page::range(start + 3, start + 5), flags), Err(()));
+
+        let not_present = PageFaultErrorCode::CAUSED_BY_WRITE;
+        assert_eq!(ranges.fault_action(base + 0x1234u64, not_present), FaultAction::DemandZero(flags));
+        assert_eq!(ranges.fault_action(base + 0x4000u64, not_present), FaultAction::Unhandled);
+        assert_eq!(ranges.fault_action(base - 1u64, not_present), FaultAction::Unhandled);
+
+        // Already-present pages that fault are genuine protection violations
+        assert_eq!(
+            ranges.fault_action(base, not_present | PageFaultErrorCode::PROTECTION_VIOLATION),
+            FaultAction::Unhandled
+        );
+    });
+
+    test_case!(mmio_mapping_is_uncached, {
+        // Any frame will do as the "device", as long as nothing touches it
+        // through both mappings
+        let frames = PhysAllocator::alloc(2);
+        let phys = frames.start.start_address() + 0x10u64;
+        let kernel = AddrSpace::kernel();
+        let virt = kernel.map_mmio(phys, 0x2000, PageTableFlags::WRITABLE).unwrap();
+
+        assert!(virt.as_u64() >= crate::mm::VMEM_START);
+        assert_eq!(virt.as_u64() % crate::mm::PAGE_SIZE, 0x10);
+        for page in 0..3 {
+            let addr = virt + page * crate::mm::PAGE_SIZE;
+            match kernel.table.read().translate(addr) {
+                TranslateResult::Mapped { frame, flags, .. } => {
+                    assert_eq!(frame.start_address(), (frames.start + page).start_address());
+                    assert!(flags.contains(
+                        PageTableFlags::WRITABLE
+                            | PageTableFlags::WRITE_THROUGH
+                            | PageTableFlags::NO_CACHE
+                            | PageTableFlags::NO_EXECUTE
+                    ));
+                }
+                result => panic!("{:?} isn't mapped: {:?}", addr, result),
+            }
+        }
+        assert_eq!(kernel.translate_addr(virt + 0x3000u64 - 0x10u64), None);
+
+        PhysAllocator::free(frames);
+    });
+
+    test_case!(map_to_new_refuses_present_pages, {
+        let kernel = AddrSpace::kernel();
+        let virt = vmem::alloc(crate::mm::PAGE_SIZE, crate::mm::PAGE_SIZE).unwrap();
+        let (first, second) = (PhysAllocator::alloc(0).start, PhysAllocator::alloc(0).start);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        kernel.map_to_new(virt, first.start_address(), flags, &mut PhysAllocatorProxy).unwrap().flush();
+        assert_eq!(kernel.translate_addr(virt), Some(first.start_address()));
+
+        // The second attempt is turned away, and the first mapping survives it
+        match kernel.map_to_new(virt, second.start_address(), flags, &mut PhysAllocatorProxy) {
+            Err(MapNewError::AlreadyMapped(existing)) => assert_eq!(existing, first.start_address()),
+            result => panic!("remapped a present page: {:?}", result.map(|flush| flush.ignore())),
+        }
+        assert_eq!(kernel.translate_addr(virt), Some(first.start_address()));
+
+        // Whatever the direct map has there counts too
+        let direct = crate::mm::phys_to_kernel_virt(first.start_address());
+        assert!(matches!(
+            kernel.map_to_new(direct, second.start_address(), flags, &mut PhysAllocatorProxy),
+            Err(MapNewError::AlreadyMapped(_))
+        ));
+
+        // Replacing it has to be asked for
+        let (old, flush) = kernel
+            .map_to_overwrite(virt, second.start_address(), flags, &mut PhysAllocatorProxy)
+            .unwrap();
+        flush.flush();
+        assert_eq!(old, Some(first));
+        assert_eq!(kernel.translate_addr(virt), Some(second.start_address()));
+
+        assert_eq!(kernel.unmap(virt).unwrap(), second);
+        vmem::free(virt, crate::mm::PAGE_SIZE);
+        PhysAllocator::free(PhysFrame::range(first, first + 1));
+        PhysAllocator::free(PhysFrame::range(second, second + 1));
+    });
+
+    test_case!(user_spaces_share_kernel_half, {
+        let a = AddrSpace::new_user();
+        let b = AddrSpace::new_user();
+        let user = VirtAddr::new(0x0000_7000_0000_0000);
+        let (fa, fb) = (PhysAllocator::alloc(0).start, PhysAllocator::alloc(0).start);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        a.map_user(user, fa.start_address(), flags).unwrap().ignore();
+        b.map_user(user, fb.start_address(), flags).unwrap().ignore();
+        assert_eq!(a.translate_addr(user), Some(fa.start_address()));
+        assert_eq!(b.translate_addr(user), Some(fb.start_address()));
+        assert_eq!(AddrSpace::kernel().translate_addr(user), None);
+
+        {
+            let mut kernel = AddrSpace::kernel().table.write();
+            let (mut ta, mut tb) = (a.table.write(), b.table.write());
+            let (kl4, al4, bl4) = (kernel.level_4_table(), ta.level_4_table(), tb.level_4_table());
+            for idx in USER_PML4_ENTRIES..512 {
+                assert_eq!(al4[idx].addr(), kl4[idx].addr());
+                assert_eq!(al4[idx].flags(), kl4[idx].flags());
+                assert_eq!(bl4[idx].addr(), kl4[idx].addr());
+            }
+        }
+
+        let kernel_addr = VirtAddr::from_ptr(&KERNEL);
+        assert_eq!(a.translate_addr(kernel_addr), AddrSpace::kernel().translate_addr(kernel_addr));
+
+        PhysAllocator::free(PhysFrame::range(fa, fa + 1));
+        PhysAllocator::free(PhysFrame::range(fb, fb + 1));
+    });
+
+    test_case!(switch_changes_translation, {
+        let a = AddrSpace::new_user();
+        let b = AddrSpace::new_user();
+        let user = VirtAddr::new(0x0000_7000_0000_0000);
+        let (fa, fb) = (PhysAllocator::alloc(0).start, PhysAllocator::alloc(0).start);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        unsafe {
+            *phys_to_kernel_virt(fa.start_address()).as_mut_ptr::<u64>() = 0xAAAA;
+            *phys_to_kernel_virt(fb.start_address()).as_mut_ptr::<u64>() = 0xBBBB;
+        }
+        a.map_user(user, fa.start_address(), flags).unwrap().ignore();
+        b.map_user(user, fb.start_address(), flags).unwrap().ignore();
+
+        let read = || unsafe { core::ptr::read_volatile(user.as_ptr::<u64>()) };
+        unsafe {
+            a.switch_to();
+            assert!(a.is_active());
+            assert_eq!(read(), 0xAAAA);
+
+            b.switch_to();
+            assert_eq!(read(), 0xBBBB);
+
+            AddrSpace::kernel().switch_to();
+        }
+        assert!(AddrSpace::kernel().is_active());
+
+        drop((a, b));
+        PhysAllocator::free(PhysFrame::range(fa, fa + 1));
+        PhysAllocator::free(PhysFrame::range(fb, fb + 1));
+    });
+
+    test_case!(page_table_walk, {
+        let space = AddrSpace::new_user();
+        let user = VirtAddr::new(0x0000_7000_1234_5678);
+        let frame = PhysAllocator::alloc(0).start;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        assert_eq!(space.walk(user).end, WalkEnd::NotPresent { level: 4, index: 0xE0 });
+
+        space.map_user(user, frame.start_address(), flags).unwrap().ignore();
+        let walk = space.dump_walk(user);
+        let levels: ArrayVec<[(u8, usize); 4]> = walk.steps.iter().map(|step| (step.level, step.index)).collect();
+        assert_eq!(&levels[..], &[(4, 0xE0), (3, 0x0), (2, 0x91), (1, 0x145)]);
+        assert!(walk.steps.iter().all(|step| step.flags.contains(PageTableFlags::PRESENT)));
+        assert_eq!(walk.steps[3].addr, frame.start_address());
+        assert_eq!(walk.end, WalkEnd::Mapped(frame.start_address() + 0x678u64));
+
+        // The tables above stay behind
+        let (_, flush) = space.table.write().unmap(Page::<Size4KiB>::containing_address(user)).unwrap();
+        flush.ignore();
+        let walk = space.dump_walk(user);
+        assert_eq!(walk.steps.len(), 3);
+        assert_eq!(walk.end, WalkEnd::NotPresent { level: 1, index: 0x145 });
+
+        drop(space);
+        PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+    });
+
+    test_case!(
+        prefault_and_release,
+        setup = crate::mm::pmm::fixture::setup(),
+        teardown = crate::mm::pmm::fixture::teardown(),
+        {
+            let space = AddrSpace::new_user();
+            let start = Page::containing_address(VirtAddr::new(0x4000_0000));
+            let range = Page::range(start, start + 4);
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+            space.map_demand_zero(range, flags).unwrap();
+            assert_eq!(space.translate_addr(start.start_address()), None);
+
+            assert_eq!(space.prefault(range), Ok(4));
+            assert!(range.clone().all(|page| space.translate_addr(page.start_address()).is_some()));
+            assert_eq!(space.prefault(range), Ok(0));
+
+            // The page tables stay behind, but every data frame goes back
+            let free = PhysAllocator::free_pages();
+            assert_eq!(space.release(range), Ok(4));
+            assert_eq!(PhysAllocator::free_pages(), free + 4);
+            assert!(range.clone().all(|page| space.translate_addr(page.start_address()).is_none()));
+            assert_eq!(space.release(range), Ok(0));
+
+            // Still reserved, so it can be faulted back in
+            assert_eq!(
+                space.demand_zero.read().fault_action(start.start_address(), PageFaultErrorCode::empty()),
+                FaultAction::DemandZero(flags | PageTableFlags::PRESENT)
+            );
+            assert_eq!(space.prefault(Page::range(start + 2, start + 3)), Ok(1));
+
+            assert_eq!(space.prefault(Page::range(start + 3, start + 5)), Err(()));
+            assert_eq!(space.release(Page::range(start - 1, start + 1)), Err(()));
+        }
+    );
 }
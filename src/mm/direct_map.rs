@@ -0,0 +1,177 @@
+use crate::{
+    cpu::fpu::{Cpuid, HardwareCpuid},
+    mm::{addr_space::AddrSpace, DIRECT_MAP_SIZE, PAGE_SIZE, PHYS_OFFSET},
+};
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+// Mapping physical memory into the direct map with the biggest pages that
+// fit, which saves page tables and TLB entries. The bootloader's direct map
+// never uses 1GiB pages, so it's rebuilt once the PMM is up.
+
+const FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::GLOBAL.bits()
+        | PageTableFlags::NO_EXECUTE.bits(),
+);
+
+const CPUID_EXTENDED: u32 = 0x8000_0000;
+const CPUID_PDPE1GB: u32 = 1 << 26;
+
+pub fn gib_pages_supported<C: Cpuid>(cpuid: &C) -> bool {
+    cpuid.cpuid(CPUID_EXTENDED, 0).eax >= CPUID_EXTENDED | 1
+        && cpuid.cpuid(CPUID_EXTENDED | 1, 0).edx & CPUID_PDPE1GB != 0
+}
+
+// The largest page that maps from `phys` at `virt` without going past `len`
+pub fn page_size_for(phys: u64, virt: u64, len: u64, gib_pages: bool) -> u64 {
+    [Size1GiB::SIZE, Size2MiB::SIZE]
+        .iter()
+        .copied()
+        .filter(|&size| gib_pages || size != Size1GiB::SIZE)
+        .find(|&size| phys % size == 0 && virt % size == 0 && len >= size)
+        .unwrap_or(Size4KiB::SIZE)
+}
+
+// On failure, returns the physical address it got to
+pub fn map_largest(
+    space: &AddrSpace,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+    flags: PageTableFlags,
+    gib_pages: bool,
+) -> Result<(), PhysAddr> {
+    BUG_ON!(
+        (virt.as_u64() | phys.as_u64() | len) % PAGE_SIZE != 0,
+        "direct map: {:#x} bytes from {:?} to {:?} isn't page aligned",
+        len,
+        phys,
+        virt
+    );
+
+    let mut offset = 0;
+    while offset < len {
+        let size = page_size_for(phys.as_u64() + offset, virt.as_u64() + offset, len - offset, gib_pages);
+        space
+            .map_sized(virt + offset, phys + offset, size, flags)
+            .map_err(|_| phys + offset)?;
+        offset += size;
+    }
+
+    Ok(())
+}
+
+// Builds a new direct map for everything below `top` and swaps it in for the
+// bootloader's. It covers at least as much as the bootloader's does, which goes
+// up to the 2MiB page past the highest region. Has to run before other CPUs are
+// up.
+pub fn init(top: PhysAddr) {
+    let len = x86_64::align_up(top.as_u64(), Size2MiB::SIZE);
+    BUG_ON!(len > DIRECT_MAP_SIZE, "direct map: {:?} is past the end", top);
+
+    let gib_pages = gib_pages_supported(&HardwareCpuid);
+    let space = AddrSpace::new_empty();
+    if let Err(addr) = map_largest(&space, VirtAddr::new(PHYS_OFFSET), PhysAddr::new(0), len, FLAGS, gib_pages) {
+        panic!("direct map: failed to map {:?}", addr);
+    }
+
+    // The old and new tables translate the same way, so it doesn't matter
+    // which one the TLB still has for a while
+    unsafe { space.move_to_kernel(VirtAddr::new(PHYS_OFFSET), len) };
+    debug!("direct map: mapped {:#x} bytes, 1GiB pages {}", len, if gib_pages { "on" } else { "off" });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::arch::x86_64::CpuidResult;
+
+    const GIB: u64 = Size1GiB::SIZE;
+    const MIB2: u64 = Size2MiB::SIZE;
+    const KIB4: u64 = Size4KiB::SIZE;
+
+    struct MockCpuid {
+        max_extended: u32,
+        edx: u32,
+    }
+
+    impl Cpuid for MockCpuid {
+        fn cpuid(&self, leaf: u32, _subleaf: u32) -> CpuidResult {
+            let (eax, edx) = match leaf {
+                CPUID_EXTENDED => (self.max_extended, 0),
+                _ => (0, self.edx),
+            };
+            CpuidResult { eax, ebx: 0, ecx: 0, edx }
+        }
+    }
+
+    test_case!(direct_map_page_sizes, {
+        assert!(gib_pages_supported(&MockCpuid { max_extended: CPUID_EXTENDED | 8, edx: CPUID_PDPE1GB }));
+        assert!(!gib_pages_supported(&MockCpuid { max_extended: CPUID_EXTENDED | 8, edx: 0 }));
+        assert!(!gib_pages_supported(&MockCpuid { max_extended: CPUID_EXTENDED, edx: CPUID_PDPE1GB }));
+
+        let virt = PHYS_OFFSET;
+        assert_eq!(page_size_for(0, virt, 4 * GIB, true), GIB);
+        assert_eq!(page_size_for(0, virt, 4 * GIB, false), MIB2);
+        // Too short for the aligned size
+        assert_eq!(page_size_for(0, virt, GIB - KIB4, true), MIB2);
+        assert_eq!(page_size_for(0, virt, MIB2 - KIB4, true), KIB4);
+        // Misaligned, on either side
+        assert_eq!(page_size_for(MIB2, virt + MIB2, 4 * GIB, true), MIB2);
+        assert_eq!(page_size_for(KIB4, virt + KIB4, 4 * GIB, true), KIB4);
+        assert_eq!(page_size_for(0, virt + MIB2, 4 * GIB, true), MIB2);
+        assert_eq!(page_size_for(0, virt + KIB4, 4 * GIB, true), KIB4);
+    });
+
+    test_case!(direct_map_rebuilt, {
+        use crate::mm::map::LAYOUT;
+
+        // Every part of it uses the page size init() would have picked
+        let len = x86_64::align_up(LAYOUT.try_get().unwrap().end().as_u64(), MIB2);
+        let gib_pages = gib_pages_supported(&HardwareCpuid);
+        let kernel = AddrSpace::kernel();
+        let mut offset = 0;
+        while offset < len {
+            let size = page_size_for(offset, PHYS_OFFSET + offset, len - offset, gib_pages);
+            assert_eq!(kernel.page_size(VirtAddr::new(PHYS_OFFSET + offset)), Some(size), "{:#x}", offset);
+            assert_eq!(kernel.page_flags(VirtAddr::new(PHYS_OFFSET + offset)), Some(FLAGS | PageTableFlags::HUGE_PAGE));
+            offset += size;
+        }
+        assert_eq!(
+            kernel.translate_addr(VirtAddr::new(PHYS_OFFSET + len - 8)),
+            Some(PhysAddr::new(len - 8))
+        );
+    });
+
+    test_case!(direct_map_split, {
+        // The physical memory is never touched, so it doesn't have to exist
+        let space = AddrSpace::new_user();
+        let virt = VirtAddr::new(0x0000_4000_0000_0000);
+        let phys = PhysAddr::new(0x10_0000_0000);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        map_largest(&space, virt, phys, GIB + MIB2 + KIB4, flags, true).unwrap();
+
+        let levels = |offset: u64| space.walk(virt + offset).steps.len();
+        assert_eq!((levels(0), levels(GIB), levels(GIB + MIB2)), (2, 3, 4));
+        assert_eq!(space.translate_addr(virt + GIB + 0x1234u64), Some(phys + GIB + 0x1234u64));
+
+        // 1GiB to 2MiB to 4KiB, with the same translation and flags all the way
+        let addr = virt + 3 * MIB2 + 5 * KIB4 + 0x10u64;
+        for expected in [3, 4].iter() {
+            assert!(space.split_huge(addr));
+            let walk = space.walk(addr);
+            assert_eq!(walk.steps.len(), *expected);
+            assert_eq!(space.translate_addr(addr), Some(phys + (addr - virt)));
+            assert!(walk.allows(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+            assert_eq!(walk.steps.last().unwrap().flags - PageTableFlags::HUGE_PAGE, flags);
+        }
+        assert_eq!(levels(MIB2), 3);
+        assert!(!space.split_huge(addr));
+        assert!(!space.split_huge(virt + 2 * GIB));
+    });
+}
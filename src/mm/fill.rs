@@ -0,0 +1,111 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+use x86_64::PhysAddr;
+
+// What newly allocated frames are filled with, by both the PMM and the boot
+// time bump allocator. Debug builds default to a pattern, so that reads of
+// memory nobody initialised stand out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillPolicy {
+    // Leave whatever was there before, which is fastest
+    None,
+    Zero,
+    Pattern(u8),
+}
+
+const DEBUG_PATTERN: u8 = 0xB8;
+
+// Kept in an atomic, since it's read on every allocation: the high byte says
+// which policy, and the low byte is the pattern
+const TAG_NONE: u16 = 0;
+const TAG_ZERO: u16 = 1 << 8;
+const TAG_PATTERN: u16 = 2 << 8;
+
+static POLICY: AtomicU16 = AtomicU16::new(if cfg!(debug_assertions) {
+    TAG_PATTERN | DEBUG_PATTERN as u16
+} else {
+    TAG_ZERO
+});
+
+fn encode(policy: FillPolicy) -> u16 {
+    match policy {
+        FillPolicy::None => TAG_NONE,
+        FillPolicy::Zero => TAG_ZERO,
+        FillPolicy::Pattern(byte) => TAG_PATTERN | byte as u16,
+    }
+}
+
+fn decode(value: u16) -> FillPolicy {
+    match value & 0xFF00 {
+        TAG_ZERO => FillPolicy::Zero,
+        TAG_PATTERN => FillPolicy::Pattern(value as u8),
+        _ => FillPolicy::None,
+    }
+}
+
+pub fn fill_policy() -> FillPolicy {
+    decode(POLICY.load(Ordering::Relaxed))
+}
+
+// Returns the previous policy, so it can be put back
+#[allow(dead_code)]
+pub fn set_fill_policy(policy: FillPolicy) -> FillPolicy {
+    decode(POLICY.swap(encode(policy), Ordering::Relaxed))
+}
+
+fn fill_byte() -> Option<u8> {
+    match fill_policy() {
+        FillPolicy::None => None,
+        FillPolicy::Zero => Some(0),
+        FillPolicy::Pattern(byte) => Some(byte),
+    }
+}
+
+// Fills `len` bytes of physical memory at `start` according to the policy
+pub fn fill(start: PhysAddr, len: usize) {
+    let byte = match fill_byte() {
+        Some(byte) => byte,
+        None => return,
+    };
+
+    unsafe {
+        let page: *mut u8 = super::phys_to_kernel_virt(start).as_mut_ptr();
+        core::intrinsics::write_bytes(page, byte, len)
+    };
+}
+
+// The same, for memory that isn't reached through the direct map
+#[allow(dead_code)]
+pub fn fill_slice(bytes: &mut [u8]) {
+    if let Some(byte) = fill_byte() {
+        for b in bytes.iter_mut() {
+            *b = byte;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::pmm::PhysAllocator;
+
+    test_case!(fill_policy_encoding, {
+        for &policy in &[FillPolicy::None, FillPolicy::Zero, FillPolicy::Pattern(0), FillPolicy::Pattern(0xFF)] {
+            assert_eq!(decode(encode(policy)), policy);
+        }
+    });
+
+    test_case!(fill_policy_pattern, {
+        let previous = set_fill_policy(FillPolicy::Pattern(0xAA));
+        let range = PhysAllocator::alloc(1);
+        set_fill_policy(previous);
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                super::super::phys_to_kernel_virt(range.start.start_address()).as_ptr::<u8>(),
+                2 * super::super::PAGE_SIZE as usize,
+            )
+        };
+        assert!(bytes.iter().all(|&b| b == 0xAA));
+        PhysAllocator::free(range);
+    });
+}
@@ -0,0 +1,149 @@
+use crate::{
+    kernel::console::console,
+    mm::{self, addr_space::AddrSpace, map::LAYOUT},
+};
+use core::fmt::{self, Write};
+use x86_64::{PhysAddr, VirtAddr};
+
+const BYTES_PER_LINE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectError {
+    // Not covered by the bootloader's memory map, or the map isn't set up yet
+    OutsidePhysMap(PhysAddr),
+    NotMapped(VirtAddr),
+    Fmt,
+}
+
+impl From<fmt::Error> for InspectError {
+    fn from(_: fmt::Error) -> Self {
+        InspectError::Fmt
+    }
+}
+
+// One line of a classic hexdump: the address, up to 16 bytes in hex, then the
+// same bytes as ASCII
+pub fn format_line(addr: u64, bytes: &[u8], out: &mut dyn Write) -> fmt::Result {
+    debug_assert!(bytes.len() <= BYTES_PER_LINE);
+
+    write!(out, "{:016x}:", addr)?;
+    for byte in bytes {
+        write!(out, " {:02x}", byte)?;
+    }
+    for _ in bytes.len()..BYTES_PER_LINE {
+        out.write_str("   ")?;
+    }
+
+    out.write_str("  |")?;
+    for &byte in bytes {
+        let ch = if (0x20..0x7F).contains(&byte) { byte as char } else { '.' };
+        out.write_char(ch)?;
+    }
+    out.write_str("|\n")
+}
+
+pub fn format_lines(addr: u64, bytes: &[u8], out: &mut dyn Write) -> fmt::Result {
+    for (i, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        format_line(addr + (i * BYTES_PER_LINE) as u64, chunk, out)?;
+    }
+
+    Ok(())
+}
+
+// Check that every byte of the range is in a region the bootloader reported.
+// Neighbouring regions are contiguous, so walk from one to the next.
+fn check_phys(phys: PhysAddr, len: usize) -> Result<(), InspectError> {
+    let layout = LAYOUT.try_get().ok_or(InspectError::OutsidePhysMap(phys))?;
+    let end = phys.as_u64().checked_add(len as u64).ok_or(InspectError::OutsidePhysMap(phys))?;
+
+    let mut addr = phys;
+    while addr.as_u64() < end {
+        let rg = layout.region_for(addr).ok_or(InspectError::OutsidePhysMap(addr))?;
+        addr = rg.addr + rg.size;
+    }
+
+    Ok(())
+}
+
+pub fn hexdump_to(phys: PhysAddr, len: usize, out: &mut dyn Write) -> Result<(), InspectError> {
+    check_phys(phys, len)?;
+
+    let virt = mm::phys_to_kernel_virt(phys);
+    let bytes = unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len) };
+    format_lines(phys.as_u64(), bytes, out)?;
+
+    Ok(())
+}
+
+// Reads through the direct map, so it works whatever's mapped in the current
+// address space
+#[allow(dead_code)]
+pub fn hexdump(phys: PhysAddr, len: usize) -> Result<(), InspectError> {
+    hexdump_to(phys, len, &mut &*console().lock())
+}
+
+// Translates each byte through the kernel's page tables, since the range
+// needn't be physically contiguous. Lines are labelled with the virtual
+// address.
+pub fn hexdump_virt_to(virt: VirtAddr, len: usize, out: &mut dyn Write) -> Result<(), InspectError> {
+    let space = AddrSpace::kernel();
+    let mut line = [0u8; BYTES_PER_LINE];
+
+    for line_start in (0..len).step_by(BYTES_PER_LINE) {
+        let line_len = BYTES_PER_LINE.min(len - line_start);
+        for (i, byte) in line[..line_len].iter_mut().enumerate() {
+            let addr = virt + line_start + i;
+            let phys = space.translate_addr(addr).ok_or(InspectError::NotMapped(addr))?;
+            check_phys(phys, 1)?;
+            *byte = unsafe { *mm::phys_to_kernel_virt(phys).as_ptr::<u8>() };
+        }
+
+        format_line((virt + line_start).as_u64(), &line[..line_len], out)?;
+    }
+
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn hexdump_virt(virt: VirtAddr, len: usize) -> Result<(), InspectError> {
+    hexdump_virt_to(virt, len, &mut &*console().lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::pmm::PhysAllocator;
+    use arrayvec::ArrayString;
+
+    test_case!(hexdump_format, {
+        let mut out = ArrayString::<[u8; 256]>::new();
+        format_lines(0x1000, b"Hello, world!\n\x00\xFFab", &mut out).unwrap();
+        assert_eq!(
+            out.as_str(),
+            "0000000000001000: 48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 ff  |Hello, world!...|\n\
+             0000000000001010: 61 62                                            |ab|\n"
+        );
+    });
+
+    test_case!(hexdump_virt_matches_phys, {
+        let frame = PhysAllocator::alloc(0);
+        let phys = frame.start.start_address();
+        let virt = mm::phys_to_kernel_virt(phys);
+        unsafe { *virt.as_mut_ptr::<[u8; 20]>() = *b"solstice hexdump\x00\x01\x02\x03" };
+
+        // Only the address column differs
+        let mut by_virt = ArrayString::<[u8; 256]>::new();
+        let mut by_phys = ArrayString::<[u8; 256]>::new();
+        hexdump_virt_to(virt, 20, &mut by_virt).unwrap();
+        hexdump_to(phys, 20, &mut by_phys).unwrap();
+        assert_eq!(by_virt.lines().count(), 2);
+        for (v, p) in by_virt.lines().zip(by_phys.lines()) {
+            assert_eq!(v[16..], p[16..]);
+        }
+        assert!(by_phys.contains("|solstice hexdump|"));
+        PhysAllocator::free(frame);
+
+        let bogus = PhysAddr::new(0x000F_FFFF_FFFF_F000);
+        assert_eq!(hexdump_to(bogus, 16, &mut by_phys), Err(InspectError::OutsidePhysMap(bogus)));
+    });
+}
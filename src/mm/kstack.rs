@@ -0,0 +1,202 @@
+use crate::{
+    ds::SpinLock,
+    mm::{addr_space::AddrSpace, pmm::PhysAllocator, vmem, PAGE_SIZE},
+};
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PageTableFlags},
+    VirtAddr,
+};
+
+// Kernel stacks with an unmapped guard page underneath, so running off the
+// bottom faults instead of scribbling over whatever's below. Every stack is
+// registered with its guard page, so a fault in any of them, not just the
+// current one, can be put down to an overflow and blamed on its owner.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow {
+    pub guard: VirtAddr,
+    // The task the stack belongs to, if it's been given one
+    pub owner: Option<usize>,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.owner {
+            Some(owner) => write!(f, "task {}'s stack overflowed into its guard page at {:?}", owner, self.guard),
+            None => write!(f, "a stack overflowed into its guard page at {:?}", self.guard),
+        }
+    }
+}
+
+struct Registered {
+    guard: VirtAddr,
+    owner: Option<usize>,
+}
+
+pub struct StackRegistry {
+    stacks: Vec<Registered>,
+}
+
+#[allow(dead_code)]
+impl StackRegistry {
+    pub const fn new() -> Self {
+        Self { stacks: Vec::new() }
+    }
+
+    pub fn insert(&mut self, guard: VirtAddr) {
+        BUG_ON!(self.find(guard).is_some(), "kstack: guard page {:?} registered twice", guard);
+        self.stacks.push(Registered { guard, owner: None });
+    }
+
+    pub fn set_owner(&mut self, guard: VirtAddr, owner: usize) {
+        match self.find(guard) {
+            Some(idx) => self.stacks[idx].owner = Some(owner),
+            None => panic!("kstack: no stack with guard page {:?}", guard),
+        }
+    }
+
+    pub fn remove(&mut self, guard: VirtAddr) {
+        match self.find(guard) {
+            Some(idx) => drop(self.stacks.swap_remove(idx)),
+            None => panic!("kstack: no stack with guard page {:?}", guard),
+        }
+    }
+
+    // The overflow a fault at `addr` would be, if it's in any guard page
+    pub fn overflow_at(&self, addr: VirtAddr) -> Option<Overflow> {
+        self.stacks
+            .iter()
+            .find(|stack| addr >= stack.guard && addr < stack.guard + PAGE_SIZE)
+            .map(|stack| Overflow {
+                guard: stack.guard,
+                owner: stack.owner,
+            })
+    }
+
+    fn find(&self, guard: VirtAddr) -> Option<usize> {
+        self.stacks.iter().position(|stack| stack.guard == guard)
+    }
+}
+
+static STACKS: SpinLock<StackRegistry> = SpinLock::new(StackRegistry::new()); // TODO: SMP
+
+// For the fault handlers. A fault while the registry is locked can't be sorted
+// out, so that just counts as not an overflow.
+pub fn overflow_at(addr: VirtAddr) -> Option<Overflow> {
+    STACKS.try_lock()?.overflow_at(addr)
+}
+
+// 2^order pages of stack, mapped in vmem above their guard page. Everything
+// goes back when it's dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    guard: VirtAddr,
+    frames: PhysFrameRange,
+}
+
+#[allow(dead_code)]
+impl KernelStack {
+    pub fn new(order: u8) -> Self {
+        let pages = 1 << order;
+        let guard = vmem::alloc((pages + 1) * PAGE_SIZE, PAGE_SIZE).expect("kstack: out of virtual address space");
+        let frames = PhysAllocator::alloc(order);
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for (i, frame) in frames.enumerate() {
+            AddrSpace::kernel()
+                .map_to(guard + (i as u64 + 1) * PAGE_SIZE, frame.start_address(), flags)
+                .expect("kstack: failed to map a stack page")
+                .flush();
+        }
+
+        STACKS.lock().insert(guard);
+        Self { guard, frames }
+    }
+
+    pub fn guard(&self) -> VirtAddr {
+        self.guard
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard + PAGE_SIZE
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + (self.frames.end - self.frames.start) * PAGE_SIZE
+    }
+
+    pub fn set_owner(&self, owner: usize) {
+        STACKS.lock().set_owner(self.guard, owner);
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        STACKS.lock().remove(self.guard);
+
+        let pages = self.frames.end - self.frames.start;
+        for i in 0..pages {
+            AddrSpace::kernel()
+                .unmap(self.bottom() + i * PAGE_SIZE)
+                .expect("kstack: stack page wasn't mapped");
+        }
+        PhysAllocator::free(self.frames);
+        vmem::free(self.guard, (pages + 1) * PAGE_SIZE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(guard_page_owners, {
+        let mut registry = StackRegistry::new();
+        let first = VirtAddr::new(0xFFFF_C000_0001_0000);
+        let second = VirtAddr::new(0xFFFF_C000_0002_0000);
+        registry.insert(first);
+        registry.insert(second);
+        registry.set_owner(first, 3);
+        registry.set_owner(second, 7);
+
+        // Anywhere in the guard page is an overflow of the stack above it
+        let hit = registry.overflow_at(second + 0x10u64).unwrap();
+        assert_eq!(hit, Overflow { guard: second, owner: Some(7) });
+        assert_eq!(registry.overflow_at(first + 0xFF8u64).unwrap().owner, Some(3));
+
+        // Neither the stack itself nor whatever's under the guard page
+        assert_eq!(registry.overflow_at(first + PAGE_SIZE), None);
+        assert_eq!(registry.overflow_at(first - 8u64), None);
+        assert_eq!(registry.overflow_at(VirtAddr::new(0x1000)), None);
+
+        registry.remove(second);
+        assert_eq!(registry.overflow_at(second), None);
+        assert!(registry.overflow_at(first).is_some());
+    });
+
+    test_case!(kernel_stack_mapping, {
+        let stack = KernelStack::new(1);
+        assert_eq!(stack.top() - stack.bottom(), 2 * PAGE_SIZE);
+
+        // The guard page is left unmapped and the stack is usable
+        let space = AddrSpace::kernel();
+        assert_eq!(space.translate_addr(stack.guard()), None);
+        assert!(space.translate_addr(stack.bottom()).is_some());
+        unsafe {
+            let word = (stack.top() - 8u64).as_mut_ptr::<u64>();
+            word.write_volatile(0x5AFE);
+            assert_eq!(word.read_volatile(), 0x5AFE);
+        }
+
+        // Registered with the global registry for as long as it lives
+        assert_eq!(overflow_at(stack.guard()).map(|hit| hit.owner), Some(None));
+        stack.set_owner(42);
+        assert_eq!(overflow_at(stack.guard() + 8u64).unwrap().owner, Some(42));
+
+        let (guard, bottom) = (stack.guard(), stack.bottom());
+        drop(stack);
+        assert_eq!(overflow_at(guard), None);
+        assert_eq!(space.translate_addr(bottom), None);
+    });
+}
@@ -1,9 +1,14 @@
 // TODO: This should all be implemented in the bootloader, ideally
-use crate::mm::{self, addr_space::AddrSpace, phys_to_kernel_virt};
+use crate::{
+    ds::InitCell,
+    mm::{self, addr_space::AddrSpace},
+};
 use arrayvec::ArrayVec;
 use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
 use core::{
     alloc::Layout,
+    cmp::Ordering,
+    marker::PhantomData,
     ptr::{self, NonNull},
 };
 use x86_64::{
@@ -19,9 +24,22 @@ pub struct Region {
 }
 
 impl Region {
+    // Both halves have to be non-empty, so `offset` must be strictly between 0
+    // and the size. Something carving up a region that asks for an empty half
+    // has got its sums wrong, e.g. the PMM sizing its block array.
     pub fn split_at(self, offset: usize) -> (Region, Region) {
-        assert!(offset < self.size);
-        (
+        match self.try_split_at(offset) {
+            Some(halves) => halves,
+            None => panic!("map: split_at({:#x}) of a region of {:#x} bytes", offset, self.size),
+        }
+    }
+
+    // None if either half would be empty
+    pub fn try_split_at(self, offset: usize) -> Option<(Region, Region)> {
+        if offset == 0 || offset >= self.size {
+            return None;
+        }
+        Some((
             Region {
                 addr: self.addr,
                 size: offset,
@@ -30,84 +48,400 @@ impl Region {
                 addr: PhysAddr::new(self.addr.as_u64() + offset as u64),
                 size: self.size - offset,
             },
-        )
+        ))
     }
 }
 
 // 64 is the number used in the bootloader crate
-const MAX_REGIONS: usize = 64;
+pub const MAX_REGIONS: usize = 64;
+// Most regions that can be merged into one for the PMM
+pub const MAX_PARTS: usize = 8;
 
-// TODO: Reference the memory map from bootloader crate instead
+// Usable and bootloader memory that touch, merged for the PMM to build one zone
+// over. The parts the bootloader still holds can't be allocated until they're
+// reclaimed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergedRegion {
+    pub region: Region,
+    // In address order, each with whether the bootloader holds it
+    pub parts: ArrayVec<[(Region, bool); MAX_PARTS]>,
+}
+
+impl MergedRegion {
+    pub fn held(&self) -> impl Iterator<Item = Region> + '_ {
+        self.parts.iter().filter(|&&(_, held)| held).map(|&(rg, _)| rg)
+    }
+}
+
+// Every region the bootloader reported, of any type, sorted by address and
+// never modified. Unlike MemoryMap it outlives PMM initialisation, so it can be
+// used to classify physical addresses later on.
 #[derive(Debug, Clone, Default)]
-pub struct MemoryMap {
+pub struct PhysLayout {
+    regions: ArrayVec<[(Region, MemoryRegionType); MAX_REGIONS]>,
+}
+
+pub static LAYOUT: InitCell<PhysLayout> = InitCell::new();
+
+#[allow(dead_code)]
+impl PhysLayout {
+    pub fn new(memory_map: &[MemoryRegion]) -> Self {
+        let mut regions: ArrayVec<[(Region, MemoryRegionType); MAX_REGIONS]> = memory_map
+            .iter()
+            .filter(|reg| reg.range.end_addr() > reg.range.start_addr())
+            .map(|reg| {
+                let rg = Region {
+                    addr: PhysAddr::new(reg.range.start_addr()),
+                    size: (reg.range.end_addr() - reg.range.start_addr()) as usize,
+                };
+                (rg, reg.region_type)
+            })
+            .collect();
+        regions.sort_unstable_by_key(|(rg, _)| rg.addr);
+
+        Self { regions }
+    }
+
+    // The end of the highest region, of any type
+    pub fn end(&self) -> PhysAddr {
+        self.regions
+            .iter()
+            .map(|(rg, _)| rg.addr + rg.size)
+            .max()
+            .unwrap_or_else(|| PhysAddr::new(0))
+    }
+
+    pub fn region_for(&self, addr: PhysAddr) -> Option<Region> {
+        self.entry_for(addr).map(|(rg, _)| rg)
+    }
+
+    pub fn region_type(&self, addr: PhysAddr) -> Option<MemoryRegionType> {
+        self.entry_for(addr).map(|(_, ty)| ty)
+    }
+
+    fn entry_for(&self, addr: PhysAddr) -> Option<(Region, MemoryRegionType)> {
+        // Find the last region starting at or before the address
+        let idx = match self.regions.binary_search_by_key(&addr, |(rg, _)| rg.addr) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        let (rg, ty) = self.regions[idx];
+        if addr < rg.addr + rg.size {
+            Some((rg, ty))
+        } else {
+            None
+        }
+    }
+}
+
+// Where the frames a MemoryMap hands out really live
+#[derive(Debug, Clone, Copy)]
+pub enum Backing {
+    // Physical memory, through the direct map, with the PageInfo array at
+    // PAGE_INFO_OFFSET
+    Phys,
+    // Buffers standing in for physical memory from address 0 up, and for the
+    // PageInfo array. Tests use this to describe whatever layout they like
+    // without writing over real memory.
+    #[cfg(test)]
+    Buffer {
+        memory: VirtAddr,
+        memory_len: usize,
+        page_info: VirtAddr,
+        page_info_len: usize,
+    },
+}
+
+impl Default for Backing {
+    fn default() -> Self {
+        Backing::Phys
+    }
+}
+
+impl Backing {
+    fn clear_frame(self, frame: PhysFrame) {
+        match self {
+            Backing::Phys => mm::fill::fill(frame.start_address(), Size4KiB::SIZE as usize),
+            #[cfg(test)]
+            Backing::Buffer { memory, memory_len, .. } => {
+                let start = frame.start_address().as_u64() as usize;
+                assert!(
+                    start + Size4KiB::SIZE as usize <= memory_len,
+                    "map: {:?} outside the test buffer",
+                    frame
+                );
+                let bytes = unsafe {
+                    core::slice::from_raw_parts_mut(memory.as_mut_ptr::<u8>().add(start), Size4KiB::SIZE as usize)
+                };
+                mm::fill::fill_slice(bytes);
+            }
+        }
+    }
+}
+
+// TODO: Reference the memory map from bootloader crate instead
+#[derive(Debug, Default)]
+pub struct MemoryMap<'a> {
     regions: ArrayVec<[Region; MAX_REGIONS]>,
+    // Still in use while the map is built, so these are kept out of `regions`
+    // until the PMM reclaims them
+    bootloader: ArrayVec<[Region; MAX_REGIONS]>,
+    layout: PhysLayout,
+    backing: Backing,
     pub num_pages: usize,
+    // Backing::Buffer only holds pointers, so this keeps the map from
+    // outliving the buffers. Physical memory is 'static.
+    buffers: PhantomData<&'a mut [u8]>,
 }
 
-impl MemoryMap {
+impl MemoryMap<'static> {
     pub fn new(memory_map: &[MemoryRegion]) -> Self {
+        Self::with_backing(memory_map, Backing::Phys)
+    }
+}
+
+impl<'a> MemoryMap<'a> {
+    #[cfg(test)]
+    pub fn new_for_test(memory_map: &[MemoryRegion], memory: &'a mut [u8], page_info: &'a mut [mm::PageInfo]) -> Self {
+        Self::with_backing(
+            memory_map,
+            Backing::Buffer {
+                memory: VirtAddr::from_ptr(memory.as_mut_ptr()),
+                memory_len: memory.len(),
+                page_info: VirtAddr::from_ptr(page_info.as_mut_ptr()),
+                page_info_len: page_info.len(),
+            },
+        )
+    }
+
+    fn with_backing(memory_map: &[MemoryRegion], backing: Backing) -> Self {
         let mut bump = Self {
             regions: ArrayVec::new(),
+            bootloader: ArrayVec::new(),
+            layout: PhysLayout::new(memory_map),
+            backing,
             num_pages: 0,
+            buffers: PhantomData,
         };
 
-        for reg in memory_map.iter() {
-            if reg.region_type == MemoryRegionType::Usable
-                || reg.region_type == MemoryRegionType::Bootloader
-            {
-                bump.push(Region {
-                    addr: PhysAddr::new(reg.range.start_addr()),
-                    size: (reg.range.end_addr() - reg.range.start_addr()) as usize,
-                });
+        // The layout is sorted, so regions that touch come one after the other.
+        // Usable and bootloader memory are kept apart here so that the bump
+        // allocator can't hand out bootloader memory, and into_merged() puts
+        // them together for the PMM.
+        for &(rg, ty) in bump.layout.regions.iter() {
+            match ty {
+                MemoryRegionType::Usable => push_merged(&mut bump.regions, rg),
+                MemoryRegionType::Bootloader => push_merged(&mut bump.bootloader, rg),
+                _ => {}
             }
         }
+        bump.num_pages = bump.pages_in_regions();
 
         if bump.regions.len() == 0 {
             panic!("no physical usable memory regions found");
         }
 
-        // Create PageInfo array
-        let kernel = AddrSpace::kernel();
-        for rg in bump.clone().regions {
+        // Create PageInfo array, including for the bootloader regions so that
+        // they can be reclaimed later. The array's own frames come out of
+        // `regions`, so they get entries too.
+        let mut cursor = FrameCursor::new(&bump.regions, backing);
+        for page in bump.page_info_frames() {
+            bump.init_page_info(page, &mut cursor);
+        }
+
+        let used = cursor.finish();
+        bump.remove_used(used);
+        bump
+    }
+
+    // The frames that get PageInfo entries, in the order they're set up
+    fn page_info_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.regions.iter().chain(self.bootloader.iter()).flat_map(|rg| {
             let start = PhysFrame::containing_address(rg.addr);
             let end = PhysFrame::containing_address(rg.addr + rg.size);
-            for page in PhysFrame::range_inclusive(start, end) {
+            PhysFrame::range_inclusive(start, end)
+        })
+    }
+
+    fn init_page_info(&self, page: PhysFrame, cursor: &mut FrameCursor) {
+        match self.backing {
+            Backing::Phys => {
+                let kernel = AddrSpace::kernel();
                 let va = VirtAddr::from_ptr(mm::phys_to_page_info(page));
 
-                // If this page is mapped already, just write
-                if kernel.translate_addr(va).is_some() {
-                    unsafe {
-                        ptr::write(va.as_mut_ptr(), mm::PageInfo::default());
-                    }
-                } else {
-                    // Otherwise, allocate and map
-                    let phys_page = bump.allocate_frame().unwrap();
+                // Map the page the entry's in if it isn't already. Entries
+                // for frames outside every region are never looked at, so
+                // there's no need to clear the new page first.
+                if kernel.translate_addr(va).is_none() {
+                    let phys_page = cursor.allocate_frame_uninit().expect("bump allocator - out of memory");
                     kernel
-                        .map_to_with_allocator(
+                        .map_to_new(
                             va,
                             phys_page.start_address(),
                             PageTableFlags::PRESENT
                                 | PageTableFlags::WRITABLE
                                 | PageTableFlags::GLOBAL,
-                            &mut bump,
+                            cursor,
                         )
                         .expect("failed to create PageInfo array")
                         .flush();
                 }
+
+                unsafe {
+                    ptr::write(va.as_mut_ptr(), mm::PageInfo::default());
+                }
+            }
+            // The whole array is there already, so no frames are needed
+            #[cfg(test)]
+            Backing::Buffer {
+                page_info,
+                page_info_len,
+                ..
+            } => {
+                let idx = page.start_address().as_u64() as usize / Size4KiB::SIZE as usize;
+                assert!(idx < page_info_len, "map: {:?} outside the test PageInfo array", page);
+                let entry = unsafe { page_info.as_mut_ptr::<mm::PageInfo>().add(idx) };
+                unsafe { ptr::write(entry, mm::PageInfo::default()) };
             }
         }
+    }
 
-        bump
+    // Only whole frames count, since that's all that can be allocated
+    fn pages_in_regions(&self) -> usize {
+        self.regions.iter().map(|rg| rg.size / Size4KiB::SIZE as usize).sum()
     }
 
-    fn push(&mut self, rg: Region) {
-        self.num_pages += rg.size / Size4KiB::SIZE as usize;
-        self.regions.push(rg);
+    // num_pages is kept up to date by hand as the regions shrink, so check it
+    // after each change
+    fn debug_check_invariant(&self) {
+        debug_assert_eq!(
+            self.num_pages,
+            self.pages_in_regions(),
+            "map: num_pages out of step with the regions"
+        );
     }
-}
 
-unsafe impl FrameAllocator<Size4KiB> for MemoryMap {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+    // Takes the frames a FrameCursor handed out off the front of the regions
+    fn remove_used(&mut self, used: CursorEnd) {
+        let mut idx = 0;
+        self.regions.retain(|rg| {
+            let taken = match idx.cmp(&used.idx) {
+                Ordering::Less => x86_64::align_down(rg.size as u64, Size4KiB::SIZE) as usize,
+                Ordering::Equal => used.offset,
+                Ordering::Greater => 0,
+            };
+            idx += 1;
+
+            rg.addr += taken;
+            rg.size -= taken;
+            taken == 0 || rg.size != 0
+        });
+
+        self.num_pages -= used.frames;
+        self.debug_check_invariant();
+    }
+
+    // Takes the last page of the highest usable region out of the map. It ends
+    // up in the same place each boot, so it can carry data across a reboot.
+    pub fn reserve_top_frame(&mut self) -> Option<PhysFrame> {
+        let (idx, rg) = self
+            .regions
+            .iter_mut()
+            .enumerate()
+            .max_by_key(|(_, rg)| rg.addr.as_u64() + rg.size as u64)?;
+        if rg.size < Size4KiB::SIZE as usize {
+            return None;
+        }
+
+        rg.size -= Size4KiB::SIZE as usize;
+        let frame = PhysFrame::containing_address(rg.addr + rg.size);
+        if rg.size == 0 {
+            self.regions.remove(idx);
+        }
+
+        self.num_pages -= 1;
+        self.debug_check_invariant();
+        Some(frame)
+    }
+
+    // Takes the lowest whole frame out of the map, as long as it ends at or
+    // below `limit`
+    pub fn reserve_low_frame(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let rg = self.regions.iter().find(|rg| rg.size >= Size4KiB::SIZE as usize)?;
+        if rg.addr + Size4KiB::SIZE > limit {
+            return None;
+        }
+
+        self.allocate_frame_uninit()
+    }
+
+    // The region as the bootloader reported it, regardless of how much of it
+    // has been allocated since
+    #[allow(dead_code)]
+    pub fn region_for(&self, addr: PhysAddr) -> Option<Region> {
+        self.layout.region_for(addr)
+    }
+
+    pub fn layout(&self) -> &PhysLayout {
+        &self.layout
+    }
+
+    #[allow(dead_code)]
+    pub fn bootloader_regions(&self) -> &[Region] {
+        &self.bootloader
+    }
+
+    // What's left of the usable regions and the bootloader regions, with any
+    // that touch merged. Bootloader memory is only merged onto the end of usable
+    // memory, since the PMM puts a zone's block array at its start.
+    pub fn into_merged(self) -> ArrayVec<[MergedRegion; MAX_REGIONS]> {
+        let mut parts: ArrayVec<[(Region, bool); MAX_REGIONS]> = self
+            .regions
+            .iter()
+            .map(|&rg| (rg, false))
+            .chain(self.bootloader.iter().map(|&rg| (rg, true)))
+            .collect();
+        parts.sort_unstable_by_key(|&(rg, _)| rg.addr);
+
+        let mut merged: ArrayVec<[MergedRegion; MAX_REGIONS]> = ArrayVec::new();
+        for (rg, held) in parts {
+            if let Some(last) = merged.last_mut() {
+                if !last.parts[0].1 && last.region.addr + last.region.size == rg.addr && !last.parts.is_full() {
+                    debug!("map: merged {:?} into {:?}", rg, last.region);
+                    last.region.size += rg.size;
+                    last.parts.push((rg, held));
+                    continue;
+                }
+            }
+
+            let mut region = MergedRegion {
+                region: rg,
+                parts: ArrayVec::new(),
+            };
+            region.parts.push((rg, held));
+            merged.push(region);
+        }
+
+        merged
+    }
+
+    // The end of the highest region the PMM will manage, bootloader memory
+    // included
+    pub fn managed_top(&self) -> PhysAddr {
+        self.regions
+            .iter()
+            .chain(self.bootloader.iter())
+            .map(|rg| rg.addr + rg.size)
+            .max()
+            .unwrap_or_else(|| PhysAddr::new(0))
+    }
+
+    // Like allocate_frame(), but the frame keeps whatever it held before. Only
+    // for callers that overwrite it themselves, or never read what they don't
+    // write.
+    pub fn allocate_frame_uninit(&mut self) -> Option<PhysFrame> {
         let (idx, found_region) = self
             .regions
             .iter_mut()
@@ -126,22 +460,98 @@ unsafe impl FrameAllocator<Size4KiB> for MemoryMap {
             self.regions.remove(idx);
         }
 
-        // Clear the page
-        #[cfg(not(test))]
-        unsafe {
-            let page: *mut u8 = phys_to_kernel_virt(out.start_address()).as_u64() as *mut u8;
-            core::intrinsics::write_bytes(
-                page,
-                if cfg!(debug_assertions) { 0xB8 } else { 0x00 },
-                Size4KiB::SIZE as usize,
-            )
-        };
+        self.debug_check_invariant();
+        Some(out)
+    }
+}
 
-        unsafe { Some(PhysFrame::from(out)) }
+// Adds a region to the end of the list, or extends the last one if the new
+// region starts where it ends
+fn push_merged(regions: &mut ArrayVec<[Region; MAX_REGIONS]>, rg: Region) {
+    if let Some(last) = regions.last_mut() {
+        if last.addr + last.size == rg.addr {
+            debug!("map: merged {:?} into {:?}", rg, last);
+            last.size += rg.size;
+            return;
+        }
     }
+
+    regions.push(rg);
 }
 
-impl IntoIterator for MemoryMap {
+// Allocates the way MemoryMap does, from the first region with a whole frame
+// left, but only keeps its place rather than shrinking the regions. That lets
+// them be read while it allocates, e.g. to build the PageInfo array.
+struct FrameCursor<'a> {
+    regions: &'a [Region],
+    backing: Backing,
+    idx: usize,
+    // Bytes taken from the start of regions[idx]
+    offset: usize,
+    frames: usize,
+}
+
+// Where a FrameCursor got to, once it's done
+#[derive(Debug, Clone, Copy)]
+struct CursorEnd {
+    idx: usize,
+    offset: usize,
+    frames: usize,
+}
+
+impl<'a> FrameCursor<'a> {
+    fn new(regions: &'a [Region], backing: Backing) -> Self {
+        Self {
+            regions,
+            backing,
+            idx: 0,
+            offset: 0,
+            frames: 0,
+        }
+    }
+
+    fn allocate_frame_uninit(&mut self) -> Option<PhysFrame> {
+        while let Some(rg) = self.regions.get(self.idx) {
+            if rg.size - self.offset >= Size4KiB::SIZE as usize {
+                let frame = PhysFrame::containing_address(rg.addr + self.offset);
+                self.offset += Size4KiB::SIZE as usize;
+                self.frames += 1;
+                return Some(frame);
+            }
+
+            self.idx += 1;
+            self.offset = 0;
+        }
+
+        None
+    }
+
+    fn finish(self) -> CursorEnd {
+        CursorEnd {
+            idx: self.idx,
+            offset: self.offset,
+            frames: self.frames,
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for FrameCursor<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.allocate_frame_uninit()?;
+        self.backing.clear_frame(frame);
+        Some(frame)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for MemoryMap<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.allocate_frame_uninit()?;
+        self.backing.clear_frame(frame);
+        Some(frame)
+    }
+}
+
+impl IntoIterator for MemoryMap<'_> {
     type Item = Region;
     type IntoIter = RegionIter;
 
@@ -206,11 +616,51 @@ impl From<Region> for RegionBumpAllocator {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicU32;
+    use x86_64::structures::paging::Page;
+
+    const TEST_FRAMES: usize = 16;
+
+    // Stand-in physical memory and PageInfo array for the made-up regions,
+    // filled with junk
+    struct TestMemory {
+        memory: Vec<u8>,
+        page_info: Vec<mm::PageInfo>,
+    }
+
+    impl TestMemory {
+        fn new() -> Self {
+            Self {
+                memory: alloc::vec![0xEE; TEST_FRAMES * Size4KiB::SIZE as usize],
+                page_info: (0..TEST_FRAMES)
+                    .map(|_| mm::PageInfo {
+                        refcount: AtomicU32::new(0xEEEE),
+                    })
+                    .collect(),
+            }
+        }
+
+        fn map(&mut self, memory_map: &[MemoryRegion]) -> MemoryMap<'_> {
+            MemoryMap::new_for_test(memory_map, &mut self.memory, &mut self.page_info)
+        }
+
+        fn frame(&self, frame: PhysFrame) -> &[u8] {
+            let start = frame.start_address().as_u64() as usize;
+            &self.memory[start..start + Size4KiB::SIZE as usize]
+        }
+
+        fn refcount(&self, addr: u64) -> u32 {
+            let idx = (addr / Size4KiB::SIZE) as usize;
+            self.page_info[idx].refcount.load(core::sync::atomic::Ordering::Relaxed)
+        }
+    }
 
     test_case!(allocate, {
         use bootloader::bootinfo::FrameRange;
 
-        let mut bump = MemoryMap::new(&[
+        let mut mem = TestMemory::new();
+        let mut bump = mem.map(&[
             MemoryRegion {
                 range: FrameRange::new(0x1000, 0x2000),
                 region_type: MemoryRegionType::Usable,
@@ -225,7 +675,7 @@ mod tests {
             },
         ]);
 
-        let a = |addr: usize| Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+        let a = |addr: u64| Some(PhysFrame::containing_address(PhysAddr::new(addr)));
 
         assert_eq!(bump.num_pages, 3);
         assert_eq!(bump.allocate_frame(), a(0x1000));
@@ -236,6 +686,368 @@ mod tests {
         assert_eq!(bump.num_pages, 0);
     });
 
+    test_case!(allocate_uninit, {
+        use bootloader::bootinfo::FrameRange;
+        use mm::fill::{set_fill_policy, FillPolicy};
+
+        let mut mem = TestMemory::new();
+        let mut bump = mem.map(&[MemoryRegion {
+            range: FrameRange::new(0x1000, 0x3000),
+            region_type: MemoryRegionType::Usable,
+        }]);
+
+        let previous = set_fill_policy(FillPolicy::Pattern(0x5A));
+        let a = |addr: u64| PhysFrame::containing_address(PhysAddr::new(addr));
+        assert_eq!(bump.allocate_frame_uninit(), Some(a(0x1000)));
+        // Both variants allocate from the same place
+        assert_eq!(bump.allocate_frame(), Some(a(0x2000)));
+        set_fill_policy(previous);
+        assert_eq!(bump.num_pages, 0);
+
+        // Only the second one was filled in, and nothing around it was touched
+        assert!(mem.frame(a(0x1000)).iter().all(|&b| b == 0xEE));
+        assert!(mem.frame(a(0x2000)).iter().all(|&b| b == 0x5A));
+        assert!(mem.frame(a(0x3000)).iter().all(|&b| b == 0xEE));
+    });
+
+    test_case!(page_info_entries_reset, {
+        use bootloader::bootinfo::FrameRange;
+
+        let mut mem = TestMemory::new();
+        let _map = mem.map(&[
+            MemoryRegion {
+                range: FrameRange::new(0x1000, 0x3000),
+                region_type: MemoryRegionType::Usable,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0x4000, 0x5000),
+                region_type: MemoryRegionType::Bootloader,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0x7000, 0x9000),
+                region_type: MemoryRegionType::Reserved,
+            },
+        ]);
+
+        // Usable and bootloader frames get fresh entries, nothing else does
+        for &addr in &[0x1000, 0x2000, 0x4000] {
+            assert_eq!(mem.refcount(addr), 0, "{:#x}", addr);
+        }
+        for &addr in &[0x0, 0x7000, 0x8000] {
+            assert_eq!(mem.refcount(addr), 0xEEEE, "{:#x}", addr);
+        }
+    });
+
+    test_case!(bootloader_not_allocatable, {
+        use bootloader::bootinfo::FrameRange;
+
+        let mut mem = TestMemory::new();
+        let mut bump = mem.map(&[
+            MemoryRegion {
+                range: FrameRange::new(0x1000, 0x2000),
+                region_type: MemoryRegionType::Bootloader,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0x2000, 0x3000),
+                region_type: MemoryRegionType::Usable,
+            },
+        ]);
+
+        assert_eq!(bump.num_pages, 1);
+        assert_eq!(bump.managed_top(), PhysAddr::new(0x3000));
+        assert_eq!(
+            bump.bootloader_regions(),
+            &[Region {
+                addr: PhysAddr::new(0x1000),
+                size: 0x1000,
+            }]
+        );
+        assert_eq!(
+            bump.allocate_frame(),
+            Some(PhysFrame::containing_address(PhysAddr::new(0x2000)))
+        );
+        assert_eq!(bump.num_pages, 0);
+        assert_eq!(bump.into_iter().count(), 0);
+    });
+
+    test_case!(adjacent_regions_merged, {
+        use bootloader::bootinfo::FrameRange;
+
+        let mut mem = TestMemory::new();
+        let region = |start, end, region_type| MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        };
+        let rg = |addr, size| Region {
+            addr: PhysAddr::new(addr),
+            size,
+        };
+
+        // Out of order, and the two halves of 0x4000..0x6000 only make a whole
+        // frame between them
+        let map = mem.map(&[
+            region(0x2000, 0x3000, MemoryRegionType::Usable),
+            region(0x1000, 0x2000, MemoryRegionType::Usable),
+            region(0x4000, 0x4800, MemoryRegionType::Usable),
+            region(0x4800, 0x6000, MemoryRegionType::Usable),
+            region(0x6000, 0x7000, MemoryRegionType::Reserved),
+            region(0x7000, 0x8000, MemoryRegionType::Usable),
+            region(0x8000, 0x9000, MemoryRegionType::Bootloader),
+            region(0x9000, 0xA000, MemoryRegionType::Bootloader),
+            region(0xB000, 0xC000, MemoryRegionType::Bootloader),
+            region(0xC000, 0xD000, MemoryRegionType::Usable),
+        ]);
+
+        assert_eq!(
+            &map.regions[..],
+            &[rg(0x1000, 0x2000), rg(0x4000, 0x2000), rg(0x7000, 0x1000), rg(0xC000, 0x1000)]
+        );
+        assert_eq!(map.num_pages, 6);
+
+        // Touching usable memory, but kept apart from it for the bump allocator
+        assert_eq!(map.bootloader_regions(), &[rg(0x8000, 0x2000), rg(0xB000, 0x1000)]);
+
+        // For the PMM, bootloader memory after usable memory joins it, but not
+        // the other way round, and nothing joins across a gap
+        let parts: Vec<Vec<(Region, bool)>> = map.into_merged().iter().map(|m| m.parts.to_vec()).collect();
+        assert_eq!(
+            parts,
+            [
+                alloc::vec![(rg(0x1000, 0x2000), false)],
+                alloc::vec![(rg(0x4000, 0x2000), false)],
+                alloc::vec![(rg(0x7000, 0x1000), false), (rg(0x8000, 0x2000), true)],
+                alloc::vec![(rg(0xB000, 0x1000), true)],
+                alloc::vec![(rg(0xC000, 0x1000), false)],
+            ]
+        );
+    });
+
+    test_case!(merged_region_covers_parts, {
+        use bootloader::bootinfo::FrameRange;
+
+        let mut mem = TestMemory::new();
+        let region = |start, end, region_type| MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        };
+
+        let merged = mem
+            .map(&[
+                region(0x1000, 0x3000, MemoryRegionType::Usable),
+                region(0x3000, 0x4000, MemoryRegionType::Bootloader),
+                region(0x4000, 0x6000, MemoryRegionType::Usable),
+                region(0x6000, 0x8000, MemoryRegionType::Bootloader),
+            ])
+            .into_merged();
+
+        assert_eq!(merged.len(), 1);
+        assert_eq!(
+            merged[0].region,
+            Region {
+                addr: PhysAddr::new(0x1000),
+                size: 0x7000,
+            }
+        );
+        let held: Vec<u64> = merged[0].held().map(|rg| rg.addr.as_u64()).collect();
+        assert_eq!(held, [0x3000, 0x6000]);
+    });
+
+    test_case!(num_pages_tracks_regions, {
+        use bootloader::bootinfo::FrameRange;
+
+        let mut mem = TestMemory::new();
+        let region = |start, end| MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type: MemoryRegionType::Usable,
+        };
+
+        // Partial frames at the ends of two regions, which never count
+        let memory_map = [region(0x1000, 0x3800), region(0x5000, 0x6800), region(0x8000, 0xC000)];
+        let mut map = mem.map(&memory_map);
+        assert_eq!(map.num_pages, 7);
+        map.debug_check_invariant();
+
+        // Every kind of change, mixed up, until the map runs dry
+        let mut step = 0;
+        while map.num_pages > 0 {
+            let before = map.num_pages;
+            match step % 4 {
+                0 => assert!(map.allocate_frame().is_some()),
+                1 => assert!(map.reserve_top_frame().is_some()),
+                2 => assert!(map.allocate_frame_uninit().is_some()),
+                _ => {
+                    let used = {
+                        let mut cursor = FrameCursor::new(&map.regions, map.backing);
+                        cursor.allocate_frame_uninit();
+                        cursor.finish()
+                    };
+                    map.remove_used(used);
+                }
+            }
+
+            assert_eq!(map.num_pages, before - 1, "step {}", step);
+            assert_eq!(map.num_pages, map.pages_in_regions(), "step {}", step);
+            step += 1;
+        }
+
+        // Only the partial frames are left
+        assert_eq!(map.reserve_top_frame(), None);
+        assert!(map.regions.iter().all(|rg| rg.size < Size4KiB::SIZE as usize));
+    });
+
+    test_case!(frame_cursor_matches_bump, {
+        use bootloader::bootinfo::FrameRange;
+
+        // Each map keeps its memory borrowed
+        let (mut bump_mem, mut mem) = (TestMemory::new(), TestMemory::new());
+        // The middle region has a partial frame at the end, and the last one is
+        // too small to allocate from at all
+        let memory_map = [
+            MemoryRegion {
+                range: FrameRange::new(0x1000, 0x3000),
+                region_type: MemoryRegionType::Usable,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0x5000, 0x7800),
+                region_type: MemoryRegionType::Usable,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0x9000, 0x9800),
+                region_type: MemoryRegionType::Usable,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0xA000, 0xC000),
+                region_type: MemoryRegionType::Usable,
+            },
+        ];
+
+        for count in 0..=6 {
+            let mut bump = bump_mem.map(&memory_map);
+            let mut map = mem.map(&memory_map);
+
+            let used = {
+                let mut cursor = FrameCursor::new(&map.regions, map.backing);
+                for _ in 0..count {
+                    assert_eq!(cursor.allocate_frame_uninit(), bump.allocate_frame_uninit());
+                }
+                cursor.finish()
+            };
+            map.remove_used(used);
+
+            assert_eq!(map.regions, bump.regions, "{} frames", count);
+            assert_eq!(map.num_pages, bump.num_pages);
+        }
+
+        // Runs out once every whole frame is gone
+        let map = mem.map(&memory_map);
+        let mut cursor = FrameCursor::new(&map.regions, map.backing);
+        assert_eq!((0..10).filter_map(|_| cursor.allocate_frame_uninit()).count(), 6);
+    });
+
+    test_case!(page_info_mappings_unchanged, {
+        // Which frame each page of the PageInfo array gets, handing them out
+        // as the array is walked, like the Phys backing does
+        fn mappings(map: &MemoryMap<'_>, mut alloc: impl FnMut() -> Option<PhysFrame>) -> Vec<(Page, PhysFrame)> {
+            let mut out: Vec<(Page, PhysFrame)> = Vec::new();
+            for frame in map.page_info_frames() {
+                let page = Page::containing_address(VirtAddr::from_ptr(mm::phys_to_page_info(frame)));
+                if !out.iter().any(|&(mapped, _)| mapped == page) {
+                    out.push((page, alloc().unwrap()));
+                }
+            }
+            out
+        }
+
+        // Far enough apart that their entries are in different pages of the
+        // array, with bootloader memory in between. That's more than the test
+        // buffers hold, but nothing here touches the memory.
+        let build = || {
+            let rg = |addr, size| Region {
+                addr: PhysAddr::new(addr),
+                size,
+            };
+            let mut map = MemoryMap {
+                regions: ArrayVec::new(),
+                bootloader: ArrayVec::new(),
+                layout: PhysLayout::default(),
+                backing: Backing::Buffer {
+                    memory: VirtAddr::zero(),
+                    memory_len: 0,
+                    page_info: VirtAddr::zero(),
+                    page_info_len: 0,
+                },
+                num_pages: 0,
+                buffers: PhantomData,
+            };
+            map.regions.push(rg(0x1000, 0x3000));
+            map.regions.push(rg(0x80_0000, 0x2000));
+            map.bootloader.push(rg(0x40_0000, 0x2000));
+            map.num_pages = map.pages_in_regions();
+            map
+        };
+
+        // The old way: walk a copy of the regions, bump allocating from the
+        // map itself
+        let mut bump = build();
+        let expected = mappings(&build(), || bump.allocate_frame_uninit());
+
+        // The new way: a cursor over the regions, removed from them afterwards
+        let mut map = build();
+        let (got, used) = {
+            let mut cursor = FrameCursor::new(&map.regions, map.backing);
+            let got = mappings(&map, || cursor.allocate_frame_uninit());
+            (got, cursor.finish())
+        };
+        map.remove_used(used);
+
+        assert_eq!(got.len(), 3);
+        assert_eq!(got, expected);
+        assert_eq!(map.regions, bump.regions);
+        assert_eq!(map.num_pages, bump.num_pages);
+    });
+
+    test_case!(region_for, {
+        use bootloader::bootinfo::FrameRange;
+
+        let mut mem = TestMemory::new();
+        // Out of order, with a gap between 0x3000 and 0x5000
+        let map = mem.map(&[
+            MemoryRegion {
+                range: FrameRange::new(0x5000, 0x7000),
+                region_type: MemoryRegionType::Usable,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0x1000, 0x2000),
+                region_type: MemoryRegionType::Usable,
+            },
+            MemoryRegion {
+                range: FrameRange::new(0x2000, 0x3000),
+                region_type: MemoryRegionType::AcpiReclaimable,
+            },
+        ]);
+
+        let usable = Region {
+            addr: PhysAddr::new(0x5000),
+            size: 0x2000,
+        };
+        let p = PhysAddr::new;
+        assert_eq!(map.region_for(p(0x5000)), Some(usable));
+        assert_eq!(map.region_for(p(0x6fff)), Some(usable));
+        assert_eq!(map.region_for(p(0x7000)), None);
+        assert_eq!(map.region_for(p(0x3000)), None);
+        assert_eq!(map.region_for(p(0x4fff)), None);
+        assert_eq!(map.region_for(p(0xfff)), None);
+
+        // Adjacent regions stay separate
+        assert_eq!(map.layout().region_type(p(0x1fff)), Some(MemoryRegionType::Usable));
+        assert_eq!(map.layout().region_type(p(0x2000)), Some(MemoryRegionType::AcpiReclaimable));
+
+        // Allocating from the map doesn't change the answer
+        let mut map = map;
+        map.allocate_frame();
+        assert_eq!(map.region_for(p(0x1000)).map(|rg| rg.size), Some(0x1000));
+    });
+
     test_case!(region, {
         // Bump allocation
         let mut rg_bump = RegionBumpAllocator::from(Region {
@@ -278,4 +1090,53 @@ mod tests {
             )
         );
     });
+
+    // split_at() panics exactly when try_split_at() returns None. That's
+    // checked below for the two edges, which each end the run.
+    test_case!(region_split_edges, {
+        let rg = Region {
+            addr: PhysAddr::new(0x4000),
+            size: 0x3000,
+        };
+
+        // Neither half may be empty
+        assert_eq!(rg.try_split_at(0), None);
+        assert_eq!(rg.try_split_at(rg.size), None);
+        assert_eq!(rg.try_split_at(rg.size + 1), None);
+
+        // The smallest and largest splits allowed
+        let (left, right) = rg.try_split_at(1).unwrap();
+        assert_eq!((left.size, right.size), (1, rg.size - 1));
+        assert_eq!(right.addr, PhysAddr::new(0x4001));
+        let (left, right) = rg.try_split_at(rg.size - 1).unwrap();
+        assert_eq!((left.size, right.size), (rg.size - 1, 1));
+
+        // A page-aligned split, like the PMM's block array, covers the region
+        // exactly, with no gap or overlap
+        let (left, right) = rg.split_at(0x1000);
+        assert_eq!(left.addr, rg.addr);
+        assert_eq!(left.addr + left.size as u64, right.addr);
+        assert_eq!(left.size + right.size, rg.size);
+        assert_eq!(rg.try_split_at(0x1000), Some((left, right)));
+    });
+
+    #[cfg(feature = "region-split-start-panic-test")]
+    exit_test_case!(region_split_at_start_panics, {
+        let rg = Region {
+            addr: PhysAddr::new(0x4000),
+            size: 0x3000,
+        };
+        crate::testing::expect_panic();
+        let _ = rg.split_at(0);
+    });
+
+    #[cfg(feature = "region-split-end-panic-test")]
+    exit_test_case!(region_split_at_end_panics, {
+        let rg = Region {
+            addr: PhysAddr::new(0x4000),
+            size: 0x3000,
+        };
+        crate::testing::expect_panic();
+        let _ = rg.split_at(rg.size);
+    });
 }
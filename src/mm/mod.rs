@@ -1,19 +1,61 @@
 pub const PHYS_OFFSET: u64 = 0xFFFF8000_00000000;
+// The PageInfo array is built before the heap is up, which vmem needs, and
+// phys_to_page_info() has to be plain arithmetic, so it keeps a fixed place
 pub const PAGE_INFO_OFFSET: u64 = 0xFFFF9000_00000000;
+pub const PAGE_INFO_SIZE: u64 = 0x00001000_00000000;
 pub const PAGE_SIZE: u64 = 0x1000;
+// All of physical memory is mapped at PHYS_OFFSET, up to where the PageInfo
+// array starts
+pub const DIRECT_MAP_SIZE: u64 = PAGE_INFO_OFFSET - PHYS_OFFSET;
+// These have to match the bootloader config in Cargo.toml and linker.ld. The
+// boot stack is set up by the bootloader, so it can't come from vmem either;
+// every other kernel stack does, through mm::kstack.
+pub const KERNEL_STACK_START: u64 = 0xFFFFFF80_00000000;
+pub const KERNEL_STACK_PAGES: u64 = 64;
+pub const KERNEL_BASE: u64 = 0xFFFFFFFF_80000000;
+// Kernel mappings without a fixed address, like device memory, get their
+// virtual ranges from here through mm::vmem
+pub const VMEM_START: u64 = 0xFFFFC000_00000000;
+pub const VMEM_SIZE: u64 = 0x00000100_00000000;
+
+// The arena mustn't overlap any of the fixed regions
+const _: () = assert!(PAGE_INFO_OFFSET + PAGE_INFO_SIZE <= VMEM_START);
+const _: () = assert!(VMEM_START + VMEM_SIZE <= KERNEL_STACK_START);
 
 use crate::ds::RwSpinLock;
+use core::sync::atomic::{AtomicU32, Ordering};
+use pmm::PhysAllocator;
 use x86_64::{VirtAddr, PhysAddr};
 use x86_64::structures::paging::PhysFrame;
 
 pub mod addr_space;
+pub mod direct_map;
+pub mod fill;
+pub mod inspect;
+pub mod kstack;
 pub mod map;
 pub mod pmm;
+pub mod slab;
 pub mod slob;
+pub mod vmem;
+
+#[allow(unused_imports)]
+pub use inspect::{hexdump, hexdump_virt};
 
 #[derive(Default)]
 pub struct PageInfo {
-    _dummy: i64,
+    // References to the frame, starting with its owner's when it's allocated.
+    // Frames that are never shared can still be freed directly.
+    refcount: AtomicU32,
+}
+
+impl PageInfo {
+    // Back to how the entry of a freshly allocated frame looks, with only the
+    // owner's reference. Other CPUs can still hold references to the entry, so
+    // it's changed through the atomics.
+    fn reset(&self) {
+        self.refcount.store(1, Ordering::Relaxed);
+    }
 }
 
 pub fn phys_to_page_info(frame: PhysFrame) -> *const PageInfo {
@@ -21,11 +63,50 @@ pub fn phys_to_page_info(frame: PhysFrame) -> *const PageInfo {
     let out_addr = PAGE_INFO_OFFSET + idx * (core::mem::size_of::<RwSpinLock<PageInfo>>()) as u64;
 
     // Check that it's not too large
-    debug_assert!(out_addr < PAGE_INFO_OFFSET + 0x0000100000000000);
+    debug_assert!(out_addr < PAGE_INFO_OFFSET + PAGE_INFO_SIZE);
 
     out_addr as *const PageInfo
 }
 
+// The PageInfo array is mapped for good, and its entries are only changed
+// through atomics, so shared references to them are always fine
+fn page_info(frame: PhysFrame) -> &'static PageInfo {
+    unsafe { &*phys_to_page_info(frame) }
+}
+
+// Takes another reference to a frame, e.g. for a second mapping of it. The
+// owner has the first one already.
+#[allow(dead_code)]
+pub fn get_frame(frame: PhysFrame) {
+    page_info(frame).refcount.fetch_add(1, Ordering::Relaxed);
+}
+
+// Drops a reference, the owner's or one taken with get_frame(), and frees the
+// frame along with the last one. Returns whether it was freed.
+#[allow(dead_code)]
+pub fn put_frame(frame: PhysFrame) -> bool {
+    let old = page_info(frame)
+        .refcount
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+    match old {
+        Ok(1) => {
+            PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+            true
+        }
+        Ok(_) => false,
+        Err(_) => {
+            // Freeing it anyway could be a double free
+            WARN_ONCE!(true, "mm: put of unreferenced frame {:?}", frame);
+            false
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn frame_refcount(frame: PhysFrame) -> u32 {
+    page_info(frame).refcount.load(Ordering::Relaxed)
+}
+
 pub fn kernel_virt_to_phys(virt: VirtAddr) -> PhysAddr {
     debug_assert!(virt.as_u64() >= PHYS_OFFSET);
     PhysAddr::new(virt.as_u64() - PHYS_OFFSET)
@@ -34,3 +115,78 @@ pub fn kernel_virt_to_phys(virt: VirtAddr) -> PhysAddr {
 pub fn phys_to_kernel_virt(phys: PhysAddr) -> VirtAddr {
     VirtAddr::new(phys.as_u64() + PHYS_OFFSET)
 }
+
+// Copies a whole frame through the direct map. Volatile, since either frame
+// may also be mapped somewhere the compiler can't see, like user space.
+#[allow(dead_code)]
+pub fn copy_frame(src: PhysFrame, dst: PhysFrame) {
+    BUG_ON!(src == dst, "mm: copying {:?} onto itself", src);
+    unsafe {
+        let from: *const u8 = phys_to_kernel_virt(src.start_address()).as_ptr();
+        let to: *mut u8 = phys_to_kernel_virt(dst.start_address()).as_mut_ptr();
+        core::intrinsics::volatile_copy_nonoverlapping_memory(to, from, PAGE_SIZE as usize);
+    }
+}
+
+pub fn zero_frame(frame: PhysFrame) {
+    unsafe {
+        let page: *mut u8 = phys_to_kernel_virt(frame.start_address()).as_mut_ptr();
+        core::intrinsics::volatile_set_memory(page, 0, PAGE_SIZE as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(frame_refcount, {
+        // An owner that never shares it is the only reference
+        let frame = PhysAllocator::alloc(0).start;
+        assert_eq!(frame_refcount(frame), 1);
+        assert!(put_frame(frame));
+
+        // The owner and one sharer
+        let (range, _) = PhysAllocator::alloc_with_info(0);
+        let frame = range.start;
+        get_frame(frame);
+        assert_eq!(frame_refcount(frame), 2);
+
+        let allocated = PhysAllocator::allocated_pages();
+        assert!(!put_frame(frame));
+        assert_eq!(frame_refcount(frame), 1);
+        assert_eq!(PhysAllocator::allocated_pages(), allocated);
+
+        assert!(put_frame(frame));
+        assert_eq!(frame_refcount(frame), 0);
+        assert_eq!(PhysAllocator::allocated_pages(), allocated - 1);
+    });
+
+    test_case!(copy_and_zero_frame, {
+        let bytes = |frame: PhysFrame| unsafe {
+            core::slice::from_raw_parts_mut(
+                phys_to_kernel_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            )
+        };
+
+        let range = PhysAllocator::alloc(1);
+        let (src, dst) = (range.start, range.start + 1);
+        for (i, b) in bytes(src).iter_mut().enumerate() {
+            *b = (i * 7) as u8;
+        }
+        for b in bytes(dst).iter_mut() {
+            *b = 0xEE;
+        }
+
+        copy_frame(src, dst);
+        assert_eq!(bytes(src), bytes(dst));
+
+        zero_frame(dst);
+        assert!(bytes(dst).iter().all(|&b| b == 0));
+        // Only the destination was touched
+        assert_eq!(bytes(src)[1], 7);
+        assert_eq!(bytes(src)[PAGE_SIZE as usize - 1], ((PAGE_SIZE - 1) * 7) as u8);
+
+        PhysAllocator::free(range);
+    });
+}
@@ -1,12 +1,20 @@
 use crate::{
-    ds::{RwSpinLock, SpinLock},
+    ds::{InitCell, SpinLock},
     mm::{
-        map::{MemoryMap, Region, RegionBumpAllocator},
+        addr_space::AddrSpace,
+        fill,
+        map::{MemoryMap, MergedRegion, Region, RegionBumpAllocator, MAX_PARTS},
         PageInfo,
     },
 };
 use arrayvec::ArrayVec;
-use core::{alloc::Layout, mem, num::NonZeroU8, slice};
+use core::{
+    alloc::Layout,
+    mem::{self, MaybeUninit},
+    num::NonZeroU8,
+    slice,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 use x86_64::{
     structures::paging::frame::{PhysFrame, PhysFrameRange},
     PhysAddr,
@@ -16,50 +24,216 @@ use x86_64::{
 pub const MAX_ZONES: u64 = 64;
 pub const MAX_ORDER: u64 = 11;
 pub const MAX_ORDER_PAGES: u64 = 1 << 11;
+// Size of the block set aside for alloc_emergency()
+pub const RESERVE_ORDER: u8 = 5;
+// Regions smaller than this are ignored. The block array alone takes most of a
+// region this size, and it has to leave at least two pages to be worth a zone.
+pub const MIN_REGION_PAGES: u64 = 6;
+const _: () = assert!(usable_pages(MIN_REGION_PAGES) > 1);
+// Frames below 1MB kept back for alloc_low_1mb(), e.g. for the AP trampoline
+pub const LOW_RESERVE_FRAMES: usize = 4;
+const LOW_LIMIT: u64 = 0x10_0000;
+
+// Where an allocation would rather come from. Plain allocations take the first
+// zone with room and the lowest block in it.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    // For devices that can only reach low memory
+    LowestFirst,
+    // For everything else, to leave low memory to the things that need it
+    HighestFirst,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneInit {
+    // Build every zone's block tree in init()
+    Eager,
+    // Defer building a zone's tree until the first allocation from it
+    Lazy,
+}
 
 #[derive(Debug)]
 struct Zone {
     pages: PhysFrameRange,
     num_pages: u64,
+    // The block tree starts at the MAX_ORDER_PAGES boundary at or below the
+    // zone, so that every block it hands out is naturally aligned. The `lead`
+    // pages between there and the start of the zone are never free.
+    base: PhysFrame,
+    lead: u64,
+    // Kept up to date by alloc and free, so it's cheap to check
+    free: u64,
     order_list: [&'static mut [Block]; MAX_ORDER as usize + 1],
+    initialised: bool,
+    // Number of blocks written while building the tree
+    init_work: u64,
+    // Offline zones cover memory that's still in use (e.g. by the bootloader),
+    // and can't be allocated from until they're brought online
+    online: bool,
+    // Pages in an online zone that the bootloader still holds. They're left
+    // out of the tree until they're released.
+    held: ArrayVec<[PhysFrameRange; MAX_PARTS]>,
 }
 #[allow(dead_code)]
 impl Zone {
     pub fn new(addr: PhysAddr, size: usize, blocks: &'static mut [Block]) -> Self {
-        let num_pages = (size / super::PAGE_SIZE as usize) as u64;
+        let mut zone = Self::new_lazy(addr, size, blocks);
+        zone.materialise();
+        zone
+    }
 
-        let mut order_list = Self::split_region(num_pages, blocks);
+    // Carves the zone's block array out of the start of the region, and manages
+    // the rest. The zone is built lazily. Returns None if the region is too
+    // small to be worth managing.
+    pub fn from_region(rg: Region) -> Option<Self> {
+        let pages_in_rg = rg.size as u64 / super::PAGE_SIZE;
+        if pages_in_rg < MIN_REGION_PAGES {
+            return None;
+        }
+        let mut usable_pages = usable_pages(pages_in_rg);
 
-        let mut blocks_in_order = num_pages;
-        for (order, list) in order_list.iter_mut().enumerate() {
-            for block in list.iter_mut().take(blocks_in_order as usize) {
-                *block = Block::from_order(order as u8);
+        // Lining the tree up with MAX_ORDER_PAGES can need one more top level
+        // block than usable_pages() allowed for, so give pages up to the block
+        // array until it fits
+        let (reserved, usable, span) = loop {
+            if usable_pages <= 1 {
+                return None;
             }
 
-            blocks_in_order = blocks_in_order / 2 + if blocks_in_order % 2 == 0 { 0 } else { 1 };
-        }
+            let (reserved, usable) = rg.split_at(((pages_in_rg - usable_pages) * super::PAGE_SIZE) as usize);
+            let (_, lead) = Self::tree_base(usable.addr);
+            if blocks_in_region(lead + usable_pages) <= reserved.size as u64 {
+                break (reserved, usable, lead + usable_pages);
+            }
+            usable_pages -= 1;
+        };
+        assert_eq!(usable.addr.as_u64() & (super::PAGE_SIZE - 1), 0); // Make sure it's aligned
 
-        let largest_order =
-            (num_pages.next_power_of_two().trailing_zeros() as usize).min((MAX_ORDER + 1) as usize);
-        for list in order_list[largest_order..].iter_mut() {
-            list[0] = Block::from_order(largest_order as u8);
-        }
+        Some(Self::new_lazy(
+            usable.addr,
+            x86_64::align_down(usable.size as u64, super::PAGE_SIZE) as usize,
+            Block::new_blocks_for_region(reserved, span),
+        ))
+    }
+
+    // The first frame covered by the block tree for a zone starting at `addr`,
+    // and how many pages before the zone that is
+    fn tree_base(addr: PhysAddr) -> (PhysFrame, u64) {
+        let start = PhysFrame::containing_address(addr);
+        let base = PhysFrame::containing_address(addr.align_down(MAX_ORDER_PAGES * super::PAGE_SIZE));
+        (base, start - base)
+    }
+
+    // The contents of `blocks` are ignored until the zone is materialised.
+    // `blocks` has to cover the zone's pages plus its lead.
+    pub fn new_lazy(addr: PhysAddr, size: usize, blocks: &'static mut [Block]) -> Self {
+        let num_pages = (size / super::PAGE_SIZE as usize) as u64;
 
         let start_frame = PhysFrame::containing_address(addr);
         let end_frame = start_frame + num_pages;
+        let (base, lead) = Self::tree_base(addr);
 
         Zone {
             pages: PhysFrame::range(start_frame, end_frame),
             num_pages,
-            order_list,
+            base,
+            lead,
+            free: num_pages,
+            order_list: Self::split_region(lead + num_pages, blocks),
+            initialised: false,
+            init_work: 0,
+            online: true,
+            held: ArrayVec::new(),
+        }
+    }
+
+    // A zone over a merged region, with the held parts kept allocated. None if
+    // it's too small, or if the block array would have to go in held memory.
+    pub fn from_merged(merged: &MergedRegion) -> Option<Self> {
+        let mut zone = Self::from_region(merged.region)?;
+        for rg in merged.held() {
+            let start = PhysFrame::containing_address(rg.addr);
+            let end = (PhysFrame::containing_address(rg.addr + (rg.size - 1)) + 1).min(zone.pages.end);
+            if start < zone.pages.start {
+                return None;
+            }
+            if start < end {
+                zone.free -= end - start;
+                zone.held.push(PhysFrame::range(start, end));
+            }
+        }
+
+        Some(zone)
+    }
+
+    fn materialise(&mut self) {
+        if self.initialised {
+            return;
+        }
+
+        let mut work = 0;
+        for list in self.order_list.iter_mut() {
+            for block in list.iter_mut() {
+                *block = Block::Used;
+            }
+            work += list.len() as u64;
+        }
+
+        // Mark only the pages that actually exist as free, then build each
+        // layer from the one below it. Blocks that would extend past the end of
+        // the zone end up partially or entirely used, so they're never handed
+        // out whole.
+        let leaves = self.order_list[0].iter_mut().skip(self.lead as usize);
+        for block in leaves.take(self.num_pages as usize) {
+            *block = Block::from_order(0);
+            work += 1;
+        }
+        for range in self.held.iter() {
+            let first = (range.start - self.base) as usize;
+            for block in self.order_list[0][first..first + (range.end - range.start) as usize].iter_mut() {
+                *block = Block::Used;
+                work += 1;
+            }
+        }
+
+        let mut blocks_in_order = self.lead + self.num_pages;
+        for order in 1..=MAX_ORDER as usize {
+            blocks_in_order = blocks_in_order / 2 + if blocks_in_order % 2 == 0 { 0 } else { 1 };
+
+            let (lower, upper) = self.order_list.split_at_mut(order);
+            let children = &lower[order - 1];
+            for (idx, block) in upper[0].iter_mut().enumerate().take(blocks_in_order as usize) {
+                *block = Block::parent_state(children[idx * 2], children[idx * 2 + 1]);
+                work += 1;
+            }
+        }
+
+        self.initialised = true;
+        self.init_work = work;
+    }
+
+    // Frees the held pages into the tree, returning how many there were
+    fn release_held(&mut self) -> u64 {
+        if self.held.is_empty() {
+            return 0;
+        }
+
+        self.materialise();
+        let mut pages = 0;
+        for range in mem::take(&mut self.held) {
+            pages += range.end - range.start;
+            self.free_all_in_range(range);
         }
+        pages
     }
 
     fn split_region(
-        num_pages: u64,
+        span: u64,
         mut blocks: &'static mut [Block],
     ) -> [&'static mut [Block]; MAX_ORDER as usize + 1] {
-        let max_order_blocks = x86_64::align_up(num_pages, MAX_ORDER_PAGES) / MAX_ORDER_PAGES;
+        let max_order_blocks = x86_64::align_up(span, MAX_ORDER_PAGES) / MAX_ORDER_PAGES;
 
         // TODO: This whole section is a bit of a hack
         let mut tmp: [Option<&'static mut [Block]>; (MAX_ORDER + 1) as usize] = [
@@ -88,57 +262,220 @@ impl Zone {
     }
 
     fn alloc(&mut self, order: u8) -> Option<PhysFrameRange> {
+        self.alloc_with_policy(order, AllocPolicy::LowestFirst)
+    }
+
+    fn alloc_with_policy(&mut self, order: u8, policy: AllocPolicy) -> Option<PhysFrameRange> {
+        if !self.online {
+            return None;
+        }
+
+        self.materialise();
+        let idx = self.find_free(order, policy)?;
+        Some(self.take(order, idx))
+    }
+
+    // Like alloc(), but only hands out a block whose start address matches
+    // `color` in the bits set in `mask`
+    fn alloc_colored(&mut self, order: u8, color: u64, mask: u64) -> Option<PhysFrameRange> {
+        if !self.online {
+            return None;
+        }
+
+        self.materialise();
+        let idx = (0..self.order_list[MAX_ORDER as usize].len())
+            .find_map(|idx| self.find_colored(MAX_ORDER as u8, idx, order, color, mask))?;
+        Some(self.take(order, idx))
+    }
+
+    fn find_free(&self, order: u8, policy: AllocPolicy) -> Option<usize> {
         // TODO: This can be optimised quite a bit (use linked lists?)
         // Find top level index
-        let mut idx = self.order_list[MAX_ORDER as usize]
-            .iter()
-            .enumerate()
-            .find(|(_, blk)| blk.larger_than(order))?
-            .0 as usize;
+        let top = self.order_list[MAX_ORDER as usize].iter();
+        let mut idx = match policy {
+            AllocPolicy::LowestFirst => top.clone().position(|blk| blk.larger_than(order))?,
+            AllocPolicy::HighestFirst => top.clone().rposition(|blk| blk.larger_than(order))?,
+        };
 
         for current_order in (order..(MAX_ORDER as u8)).rev() {
             idx *= 2;
 
-            idx = if self.order_list[current_order as usize][idx as usize].larger_than(order) {
-                idx
-            } else if self.order_list[current_order as usize][idx as usize + 1].larger_than(order) {
-                idx + 1
+            // Try the child on the preferred side first
+            let (first, second) = match policy {
+                AllocPolicy::LowestFirst => (idx, idx + 1),
+                AllocPolicy::HighestFirst => (idx + 1, idx),
+            };
+            idx = if self.order_list[current_order as usize][first].larger_than(order) {
+                first
+            } else if self.order_list[current_order as usize][second].larger_than(order) {
+                second
             } else {
                 unreachable!();
             };
         }
 
+        Some(idx)
+    }
+
+    // Depth first search under block `idx` of `current` order, skipping any
+    // subtree whose address bits above its own size already rule it out
+    fn find_colored(&self, current: u8, idx: usize, order: u8, color: u64, mask: u64) -> Option<usize> {
+        if !self.order_list[current as usize][idx].larger_than(order) {
+            return None;
+        }
+
+        let start = (self.base + ((idx as u64) << current)).start_address().as_u64();
+        let fixed = mask & !((super::PAGE_SIZE << current) - 1);
+        if (start ^ color) & fixed != 0 {
+            return None;
+        }
+
+        if current == order {
+            return if (start ^ color) & mask == 0 { Some(idx) } else { None };
+        }
+
+        self.find_colored(current - 1, idx * 2, order, color, mask)
+            .or_else(|| self.find_colored(current - 1, idx * 2 + 1, order, color, mask))
+    }
+
+    fn take(&mut self, order: u8, idx: usize) -> PhysFrameRange {
         self.order_list[order as usize][idx as usize] = Block::Used;
         self.update_tree(order, idx as u64);
+        self.free -= 1 << order;
 
-        let start_frame = self.pages.start + 2u64.pow(order as u32) * idx as u64;
-        let end_frame = self.pages.start + 2u64.pow(order as u32) * (idx + 1) as u64;
+        let start_frame = self.base + 2u64.pow(order as u32) * idx as u64;
+        let end_frame = self.base + 2u64.pow(order as u32) * (idx + 1) as u64;
 
-        // Zero out region
-        unsafe {
-            let page: *mut u8 = super::phys_to_kernel_virt(start_frame.start_address()).as_mut_ptr();
-            core::intrinsics::write_bytes(
-                page,
-                if cfg!(debug_assertions) { 0xB8 } else { 0x00 },
-                (super::PAGE_SIZE * 2u64.pow(order as u32)) as usize,
-            )
-        };
+        fill::fill(start_frame.start_address(), (super::PAGE_SIZE * 2u64.pow(order as u32)) as usize);
+
+        PhysFrame::range(start_frame, end_frame)
+    }
+
+    fn free_pages(&self) -> u64 {
+        self.free
+    }
+
+    fn largest_free_order(&self) -> Option<u8> {
+        self.order_list[MAX_ORDER as usize]
+            .iter()
+            .filter_map(|&block| match block {
+                Block::LargestFreeOrder(order) => Some(order.get() - 1),
+                Block::Used => None,
+            })
+            .max()
+    }
+
+    // Check that every parent block matches the state derived from its two
+    // children, returning the (order, index) of the first one that doesn't.
+    // Used blocks are skipped, since allocating a whole block leaves the entries
+    // below it stale.
+    #[cfg(any(debug_assertions, test))]
+    fn verify(&self) -> Result<(), (u8, usize)> {
+        if !self.initialised {
+            return Ok(());
+        }
+
+        for order in 1..=MAX_ORDER as usize {
+            for (idx, &parent) in self.order_list[order].iter().enumerate() {
+                if parent == Block::Used {
+                    continue;
+                }
+
+                let left = self.order_list[order - 1][idx * 2];
+                let right = self.order_list[order - 1][idx * 2 + 1];
+                if parent != Block::parent_state(left, right) {
+                    return Err((order as u8, idx));
+                }
+            }
+        }
 
-        Some(PhysFrame::range(start_frame, end_frame))
+        Ok(())
     }
 
     fn free(&mut self, range: PhysFrameRange) {
-        let len = range.end.start_address() - range.start.start_address();
+        let len = range.end - range.start;
         let order = len.trailing_zeros();
-        debug_assert!(order <= MAX_ORDER as u32);
-        debug_assert!(self.pages.start.start_address() <= range.start.start_address());
-        debug_assert!(self.pages.end.start_address() >= range.end.start_address());
+        BUG_ON!(order > MAX_ORDER as u32, "pmm: free of oversized range {:?}", range);
+        BUG_ON!((range.start - self.base) % len != 0, "pmm: free of misaligned range {:?}", range);
+        BUG_ON!(self.pages.start.start_address() > range.start.start_address());
+        BUG_ON!(self.pages.end.start_address() < range.end.start_address());
+        BUG_ON!(!self.initialised, "pmm: free into an uninitialised zone");
 
-        let idx = (range.start - self.pages.start) / len;
-        debug_assert_eq!(self.order_list[order as usize][idx as usize], Block::Used);
+        let idx = (range.start - self.base) / len;
+        if WARN_ONCE!(
+            self.order_list[order as usize][idx as usize] != Block::Used,
+            "pmm: double free of {:?}",
+            range
+        ) {
+            return;
+        }
 
         self.order_list[order as usize][idx as usize] = Block::from_order(order as u8);
         self.update_tree(order as u8, idx);
+        self.free += len;
+    }
+
+    // Free an arbitrary run of pages using the largest aligned blocks that fit,
+    // returning the number of blocks freed. The pages can have been allocated in
+    // any mix of orders, including as part of a larger block that's only partly
+    // being freed.
+    fn free_all_in_range(&mut self, range: PhysFrameRange) -> usize {
+        BUG_ON!(self.pages.start > range.start || self.pages.end < range.end);
+        BUG_ON!(!self.initialised, "pmm: free into an uninitialised zone");
+
+        let end = range.end - self.base;
+        let mut offset = range.start - self.base;
+        let mut blocks = 0;
+        while offset < end {
+            let align = if offset == 0 { MAX_ORDER } else { offset.trailing_zeros() as u64 };
+            let fit = 63 - (end - offset).leading_zeros() as u64;
+            let order = align.min(fit).min(MAX_ORDER) as u8;
+
+            self.free_block(order, offset >> order);
+            offset += 1 << order;
+            blocks += 1;
+        }
+
+        blocks
+    }
+
+    fn free_block(&mut self, order: u8, idx: u64) {
+        // A block allocated whole only has its own entry marked as used, and the
+        // entries below it are stale. Split any such block above this one on the
+        // way down, so that the part being freed gets an entry of its own.
+        for current in (order as usize + 1..=MAX_ORDER as usize).rev() {
+            let parent = (idx >> (current - order as usize)) as usize;
+            let children = &self.order_list[current - 1];
+            if self.order_list[current][parent] == Block::Used
+                && Block::parent_state(children[parent * 2], children[parent * 2 + 1]) != Block::Used
+            {
+                self.order_list[current - 1][parent * 2] = Block::Used;
+                self.order_list[current - 1][parent * 2 + 1] = Block::Used;
+            }
+        }
+
+        if WARN_ONCE!(
+            self.order_list[order as usize][idx as usize] != Block::Used,
+            "pmm: double free of order {} block {} (or part of it)",
+            order,
+            idx
+        ) {
+            return;
+        }
+
+        // The pages may have been allocated individually, so everything below
+        // has to be reset as well
+        for lower in 0..order {
+            let shift = order - lower;
+            let blocks = (idx << shift) as usize..((idx + 1) << shift) as usize;
+            for block in self.order_list[lower as usize][blocks].iter_mut() {
+                *block = Block::from_order(lower);
+            }
+        }
+
+        self.order_list[order as usize][idx as usize] = Block::from_order(order);
+        self.update_tree(order, idx);
+        self.free += 1 << order;
     }
 }
 
@@ -194,8 +531,8 @@ impl Block {
         }
     }
 
-    fn new_blocks_for_region(region: Region, usable_pages: u64) -> &'static mut [Block] {
-        let block_count = blocks_in_region(usable_pages);
+    fn new_blocks_for_region(region: Region, span: u64) -> &'static mut [Block] {
+        let block_count = blocks_in_region(span);
 
         let mut rg_allocator = RegionBumpAllocator::from(region);
         let ptr = rg_allocator
@@ -213,20 +550,49 @@ impl Block {
             x86_64::align_down(ptr.as_ptr() as u64, super::PAGE_SIZE)
         );
 
-        unsafe {
-            // Zero out the memory, which corresponds to Block::Used
-            core::intrinsics::write_bytes(ptr.as_ptr(), 0, block_count as usize);
-            slice::from_raw_parts_mut(ptr.as_ptr() as *mut Block, block_count as usize)
-        }
+        // Every byte value is a valid Block, and the zone clears the array
+        // itself when it's materialised
+        unsafe { slice::from_raw_parts_mut(ptr.as_ptr() as *mut Block, block_count as usize) }
     }
 }
 
-// TODO: This should really use an UnsafeCell instead of a RwSpinLock. We don't
-// need to mutate the internal ArrayVec after init().
-// We use an option here because ArrayVec doesn't have a const constructor. This
-// could be done with MaybeUninit in future to avoid that check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneInfo {
+    // Stable for the lifetime of the zone, and what alloc_in_zone() takes
+    pub index: usize,
+    pub pages: PhysFrameRange,
+    pub online: bool,
+}
+
+// The zone list itself is never mutated after init(), so it lives in an
+// InitCell and only the individual zones are locked. Slots past the zones found
+// at boot are left empty for add_zone() to fill in.
 pub struct PhysAllocator {
-    zones: RwSpinLock<Option<ArrayVec<[SpinLock<Zone>; MAX_ZONES as usize]>>>,
+    zones: InitCell<ArrayVec<[InitCell<SpinLock<Zone>>; MAX_ZONES as usize]>>,
+    next_zone: AtomicUsize,
+    // Only alloc_emergency() draws from this. Its memory is allocated out of
+    // one of the zones above.
+    reserve: InitCell<SpinLock<Zone>>,
+    // Taken out of the memory map before the zones are made, so nothing else
+    // can allocate them
+    low_reserve: SpinLock<[Option<PhysFrame>; LOW_RESERVE_FRAMES]>,
+    low_memory: SpinLock<LowMemory>,
+    // Pages handed out by alloc and not yet freed, and the most there have
+    // been at once. The emergency reserve counts as allocated as a whole.
+    allocated: AtomicU64,
+    peak: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LowMemory {
+    // The callback runs when free memory drops below `low`, and can't run
+    // again until it's climbed back to `high`
+    low: u64,
+    high: u64,
+    callback: Option<fn(free_pages: u64)>,
+    armed: bool,
+    // Set while the callback runs, so its own allocations don't call back in
+    running: bool,
 }
 
 pub static PMM: PhysAllocator = PhysAllocator::new();
@@ -234,57 +600,534 @@ pub static PMM: PhysAllocator = PhysAllocator::new();
 impl PhysAllocator {
     const fn new() -> Self {
         Self {
-            zones: RwSpinLock::new(None),
+            zones: InitCell::new(),
+            next_zone: AtomicUsize::new(0),
+            reserve: InitCell::new(),
+            low_reserve: SpinLock::new([None; LOW_RESERVE_FRAMES]),
+            low_memory: SpinLock::new(LowMemory {
+                low: 0,
+                high: 0,
+                callback: None,
+                armed: true,
+                running: false,
+            }),
+            allocated: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+        }
+    }
+
+    // Tests can swap in a synthetic allocator, see the fixture module
+    fn current() -> &'static PhysAllocator {
+        #[cfg(test)]
+        {
+            let active = fixture::ACTIVE.load(Ordering::Acquire);
+            if !active.is_null() {
+                return unsafe { &*active };
+            }
         }
+
+        &PMM
     }
 
-    pub fn init(map: MemoryMap) {
+    fn zones() -> impl Iterator<Item = &'static SpinLock<Zone>> {
+        // Safety: init() runs during boot, before anything can allocate
+        unsafe { Self::current().zones.get() }
+            .iter()
+            .filter_map(InitCell::try_get)
+    }
+
+    pub fn init(map: MemoryMap<'_>, mode: ZoneInit) {
+        check_direct_map(map.managed_top());
         let mut zones = ArrayVec::new();
 
-        for rg in map {
-            let pages_in_rg = rg.size as u64 / super::PAGE_SIZE;
-            let usable_pages = usable_pages(pages_in_rg);
-            if usable_pages <= 1 {
-                continue;
+        // Bootloader memory merged onto usable memory is held in an online zone
+        // until it's reclaimed. Where that doesn't work, each part gets a zone of
+        // its own, and bootloader zones start offline, built lazily so that
+        // nothing is written to them before they're reclaimed.
+        let mut add = |mut zone: Zone, online: bool| {
+            zone.online = online;
+            if online && mode == ZoneInit::Eager {
+                zone.materialise();
             }
 
-            let (reserved, usable) = rg.split_at(((pages_in_rg - usable_pages) * super::PAGE_SIZE) as usize);
-            let zone = Zone::new(
-                usable.addr.into(),
-                x86_64::align_down(usable.size as u64, super::PAGE_SIZE) as usize,
-                Block::new_blocks_for_region(reserved, usable_pages),
-            );
+            let slot = InitCell::new();
+            slot.init(SpinLock::new(zone));
+            zones.push(slot);
+        };
 
-            zones.push(SpinLock::new(zone));
+        for merged in map.into_merged() {
+            match Zone::from_merged(&merged) {
+                Some(zone) => add(zone, true),
+                None => {
+                    for &(rg, held) in merged.parts.iter() {
+                        if let Some(zone) = Zone::from_region(rg) {
+                            add(zone, !held);
+                        }
+                    }
+                }
+            }
+        }
 
-            assert_eq!(usable.addr.as_u64() & (super::PAGE_SIZE - 1), 0); // Make sure it's aligned
+        PMM.next_zone.store(zones.len(), Ordering::Relaxed);
+        while !zones.is_full() {
+            zones.push(InitCell::new());
         }
 
-        *PMM.zones.write() = Some(zones);
+        PMM.zones.init(zones);
         debug!("pmm: initialised");
     }
 
+    // Hot-add a region of memory after init(). Hands the region back if it's too
+    // small or there are no zone slots left.
+    pub fn add_zone(rg: Region) -> Result<(), Region> {
+        let zone = Zone::from_region(rg).ok_or(rg)?;
+
+        let pmm = Self::current();
+        let idx = pmm.next_zone.fetch_add(1, Ordering::Relaxed);
+        match unsafe { pmm.zones.get() }.get(idx) {
+            Some(slot) => {
+                slot.init(SpinLock::new(zone));
+                debug!("pmm: added zone for {:?}", rg);
+                Ok(())
+            }
+            None => Err(rg),
+        }
+    }
+
+    // Bring the zones covering bootloader memory online. Must only be called
+    // once nothing the bootloader left behind is needed any more.
+    pub fn reclaim_bootloader() {
+        let mut pages = 0;
+        for zone in Self::zones() {
+            let mut zone = zone.lock();
+            if !zone.online {
+                zone.online = true;
+                pages += zone.num_pages;
+            }
+            pages += zone.release_held();
+        }
+
+        debug!("pmm: reclaimed {} bootloader pages", pages);
+    }
+
+    pub fn zone_info() -> impl Iterator<Item = ZoneInfo> {
+        unsafe { Self::current().zones.get() }
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                let zone = slot.try_get()?.lock();
+                Some(ZoneInfo {
+                    index,
+                    pages: zone.pages,
+                    online: zone.online,
+                })
+            })
+    }
+
+    // The index of the zone managing `addr`, as alloc_in_zone() takes it. Goes
+    // by the same bounds free() does, so the zone's own block array and any
+    // lead pages before it don't count.
+    pub fn zone_of(addr: PhysAddr) -> Option<usize> {
+        unsafe { Self::current().zones.get() }
+            .iter()
+            .position(|slot| {
+                slot.try_get().map_or(false, |zone| {
+                    let zone = zone.lock();
+                    zone.pages.start.start_address() <= addr && addr < zone.pages.end.start_address()
+                })
+            })
+    }
+
+    // Pages that could be allocated right now, not counting the emergency
+    // reserve
+    pub fn free_pages() -> u64 {
+        Self::zones()
+            .map(|zone| zone.lock())
+            .filter(|zone| zone.online)
+            .map(|zone| zone.free_pages())
+            .sum()
+    }
+
+    // Allocate only from the given zone, for callers that care where their
+    // memory comes from. Fails rather than falling back to other zones.
+    pub fn alloc_in_zone(zone_index: usize, order: u8) -> Option<PhysFrameRange> {
+        BUG_ON!(order > MAX_ORDER as u8, "pmm: order {} allocation", order);
+
+        let slot = unsafe { Self::current().zones.get() }.get(zone_index)?;
+        let range = slot.try_get()?.lock().alloc(order);
+        Self::account(range, order);
+        range
+    }
+
     pub fn alloc(order: u8) -> PhysFrameRange {
-        debug_assert!(order <= MAX_ORDER as u8);
+        match Self::try_alloc(order) {
+            Some(range) => range,
+            None => panic!(
+                "physical memory allocator: out of memory (failed to fulfill order {} alloc)",
+                order
+            ),
+        }
+    }
+
+    // Like alloc(), but also hands back the PageInfo of the first frame. The
+    // PageInfo array stays mapped for good, so the slot itself is always there,
+    // but it only describes this allocation until the frame is freed.
+    pub fn alloc_with_info(order: u8) -> (PhysFrameRange, &'static PageInfo) {
+        let range = Self::alloc(order);
+        (range, super::page_info(range.start))
+    }
+
+    // For frames that will hold secrets, like keys. They're zeroed whatever
+    // the fill policy, and should go back through free_secure() so nothing is
+    // left behind for the next owner.
+    #[allow(dead_code)]
+    pub fn alloc_secure(order: u8) -> PhysFrameRange {
+        let range = Self::alloc(order);
+        Self::scrub(range);
+        range
+    }
+
+    #[allow(dead_code)]
+    pub fn free_secure(range: PhysFrameRange) {
+        Self::scrub(range);
+        Self::free(range);
+    }
+
+    fn scrub(range: PhysFrameRange) {
+        for frame in range {
+            super::zero_frame(frame);
+        }
+    }
+
+    pub fn try_alloc(order: u8) -> Option<PhysFrameRange> {
+        BUG_ON!(order > MAX_ORDER as u8, "pmm: order {} allocation", order);
+
+        let range = Self::zones().find_map(|zone| zone.lock().alloc(order));
+        Self::account(range, order);
+        range
+    }
+
+    // Like try_alloc(), but takes the lowest or highest free block of all,
+    // going through the zones in address order rather than the order they
+    // were added in
+    pub fn alloc_with_policy(order: u8, policy: AllocPolicy) -> Option<PhysFrameRange> {
+        BUG_ON!(order > MAX_ORDER as u8, "pmm: order {} allocation", order);
+
+        let mut zones: ArrayVec<[&SpinLock<Zone>; MAX_ZONES as usize]> = Self::zones().collect();
+        zones.sort_unstable_by_key(|zone| zone.lock().pages.start);
+        if policy == AllocPolicy::HighestFirst {
+            zones.reverse();
+        }
+
+        let range = zones
+            .iter()
+            .find_map(|zone| zone.lock().alloc_with_policy(order, policy));
+        Self::account(range, order);
+        range
+    }
+
+    // Page colouring, for buffers that shouldn't compete for the same cache
+    // sets. Prefers a block whose start address matches `color` in the bits
+    // set in `color_mask`, from any zone, but it's only a hint: if none match,
+    // any free block will do.
+    pub fn alloc_colored(order: u8, color: u64, color_mask: u64) -> Option<PhysFrameRange> {
+        BUG_ON!(order > MAX_ORDER as u8, "pmm: order {} allocation", order);
+
+        let range = Self::zones()
+            .find_map(|zone| zone.lock().alloc_colored(order, color, color_mask))
+            .or_else(|| Self::zones().find_map(|zone| zone.lock().alloc(order)));
+        Self::account(range, order);
+        range
+    }
+
+    // For buffers that need more alignment than their size gives them, like a
+    // small hardware ring. Blocks are aligned to their own size, so this takes
+    // a whole 2^align_order block, keeps the start of it and frees the rest.
+    // Returns None if there's no free block that big.
+    #[allow(dead_code)]
+    pub fn alloc_aligned(order: u8, align_order: u8) -> Option<PhysFrameRange> {
+        BUG_ON!(align_order < order, "pmm: order {} allocation aligned to order {}", order, align_order);
+        if align_order > MAX_ORDER as u8 {
+            return None;
+        }
+
+        let block = Self::try_alloc(align_order)?;
+        let range = PhysFrame::range(block.start, block.start + (1 << order));
+        if range.end < block.end {
+            Self::free_all_in_range(PhysFrame::range(range.end, block.end));
+        }
+        Some(range)
+    }
+
+    // Single frames, as many as will fit in `out` up to `count`, taking each
+    // zone's lock once rather than once per frame. Blocks are split as
+    // needed. Returns how many were filled in, which is fewer than asked for
+    // if memory runs out.
+    #[allow(dead_code)]
+    pub fn alloc_batch(count: usize, out: &mut [MaybeUninit<PhysFrame>]) -> usize {
+        let count = count.min(out.len());
+        let mut filled = 0;
+        for zone in Self::zones() {
+            if filled == count {
+                break;
+            }
 
-        for zone in PMM.zones.read().as_ref().unwrap() {
             let mut zone = zone.lock();
-            if let Some(range) = zone.alloc(order) {
-                return range;
+            while filled < count {
+                match zone.alloc(0) {
+                    Some(range) => {
+                        trace_event!(PmmAlloc, range.start.start_address().as_u64());
+                        super::page_info(range.start).reset();
+                        out[filled] = MaybeUninit::new(range.start);
+                        filled += 1;
+                    }
+                    None => break,
+                }
             }
         }
 
-        panic!(
-            "physical memory allocator: out of memory (failed to fulfill order {} alloc)",
-            order
-        );
+        Self::account_pages(filled as u64);
+        filled
     }
 
-    pub fn free(range: PhysFrameRange) {
-        for zone in PMM.zones.read().as_ref().unwrap() {
+    // Also gives the owner its reference to the first frame
+    fn account(range: Option<PhysFrameRange>, order: u8) {
+        if let Some(range) = range {
+            trace_event!(PmmAlloc, range.start.start_address().as_u64());
+            super::page_info(range.start).reset();
+        }
+        Self::account_pages(range.map_or(0, |_| 1 << order));
+    }
+
+    fn account_pages(pages: u64) {
+        if pages > 0 {
+            let pmm = Self::current();
+            let allocated = pmm.allocated.fetch_add(pages, Ordering::Relaxed) + pages;
+            pmm.peak.fetch_max(allocated, Ordering::Relaxed);
+        }
+
+        Self::check_watermark();
+    }
+
+    fn account_free(pages: u64) {
+        let _ = Self::current()
+            .allocated
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n.saturating_sub(pages)));
+        Self::check_watermark();
+    }
+
+    pub fn allocated_pages() -> u64 {
+        Self::current().allocated.load(Ordering::Relaxed)
+    }
+
+    // The most pages that have been allocated at once since boot, or since the
+    // last reset_peak()
+    pub fn peak_allocated() -> u64 {
+        Self::current().peak.load(Ordering::Relaxed)
+    }
+
+    // Restart peak tracking from the current usage
+    pub fn reset_peak() {
+        let pmm = Self::current();
+        pmm.peak.store(pmm.allocated.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    // Set aside a block of memory that normal allocations can't touch, for
+    // alloc_emergency(). Called once, after init().
+    pub fn init_reserve() {
+        let range = match Self::try_alloc(RESERVE_ORDER) {
+            Some(range) => range,
+            None => {
+                warn!("pmm: no memory for the emergency reserve");
+                return;
+            }
+        };
+
+        let zone = Zone::from_region(Region {
+            addr: range.start.start_address(),
+            size: (super::PAGE_SIZE << RESERVE_ORDER) as usize,
+        })
+        .expect("pmm: emergency reserve too small");
+        debug!("pmm: reserved {} pages for emergencies", zone.num_pages);
+        Self::current().reserve.init(SpinLock::new(zone));
+    }
+
+    // Set aside frames below 1MB, for code that has to run in real mode, like
+    // the AP trampoline. Called before init(), with the map it'll get, so
+    // they never end up in a zone. Returns how many were found.
+    pub fn reserve_low(map: &mut MemoryMap<'_>) -> usize {
+        let mut low = Self::current().low_reserve.lock();
+        let mut count = 0;
+        for slot in low.iter_mut().filter(|slot| slot.is_none()) {
+            match map.reserve_low_frame(PhysAddr::new(LOW_LIMIT)) {
+                Some(frame) => *slot = Some(frame),
+                None => break,
+            }
+            count += 1;
+        }
+
+        if count == 0 {
+            warn!("pmm: no usable memory below 1MB");
+        }
+        count
+    }
+
+    // A frame below 1MB, from the low reserve only. Give it back with
+    // free_low_1mb(), not free().
+    pub fn alloc_low_1mb() -> Option<PhysFrame> {
+        let frame = Self::current().low_reserve.lock().iter_mut().find_map(Option::take)?;
+        super::page_info(frame).reset();
+        Some(frame)
+    }
+
+    pub fn free_low_1mb(frame: PhysFrame) {
+        BUG_ON!(frame.start_address().as_u64() >= LOW_LIMIT, "pmm: {:?} isn't low memory", frame);
+        let mut low = Self::current().low_reserve.lock();
+        BUG_ON!(low.contains(&Some(frame)), "pmm: double free of low frame {:?}", frame);
+        match low.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(frame),
+            None => panic!("pmm: {:?} didn't come from the low reserve", frame),
+        }
+    }
+
+    // For paths that must make progress when memory is exhausted, like the OOM
+    // reporter. Falls back to the reserve only if a normal allocation fails.
+    pub fn alloc_emergency(order: u8) -> Option<PhysFrameRange> {
+        Self::try_alloc(order).or_else(|| {
+            let range = Self::current().reserve.try_get()?.lock().alloc(order)?;
+            super::page_info(range.start).reset();
+            warn!("pmm: order {} allocation from the emergency reserve", order);
+            Some(range)
+        })
+    }
+
+    // Call `callback` once free memory drops below the low watermark, for
+    // anything holding frames it could give back. It runs in the context of
+    // whatever allocation took memory below the watermark, but without any PMM
+    // locks held, so it can free or allocate itself. Replaces any previous
+    // callback.
+    pub fn set_low_memory_callback(callback: fn(free_pages: u64)) {
+        let mut low_memory = Self::current().low_memory.lock();
+        low_memory.callback = Some(callback);
+        low_memory.armed = true;
+        drop(low_memory);
+        Self::check_watermark();
+    }
+
+    pub fn clear_low_memory_callback() {
+        Self::current().low_memory.lock().callback = None;
+    }
+
+    // Free page counts for the low memory callback. Once it's run, it won't run
+    // again until free memory has been back up to `high`, so a callback that
+    // only frees a little isn't called on every allocation.
+    pub fn set_watermarks(low: u64, high: u64) {
+        BUG_ON!(low > high, "pmm: low watermark {} above high watermark {}", low, high);
+
+        let mut low_memory = Self::current().low_memory.lock();
+        low_memory.low = low;
+        low_memory.high = high;
+        drop(low_memory);
+        Self::check_watermark();
+    }
+
+    fn check_watermark() {
+        let pmm = Self::current();
+        let mut low_memory = pmm.low_memory.lock();
+        let callback = match low_memory.callback {
+            Some(callback) if !low_memory.running => callback,
+            _ => return,
+        };
+
+        let free = Self::free_pages();
+        if free >= low_memory.high {
+            low_memory.armed = true;
+            return;
+        }
+
+        if free < low_memory.low && low_memory.armed {
+            low_memory.armed = false;
+            low_memory.running = true;
+            drop(low_memory);
+            callback(free);
+
+            // Nothing it freed was checked while it ran
+            let free = Self::free_pages();
+            let mut low_memory = pmm.low_memory.lock();
+            low_memory.running = false;
+            if free >= low_memory.high {
+                low_memory.armed = true;
+            }
+        }
+    }
+
+    // Run Zone::verify on every zone, returning the index of the first bad zone
+    // along with the offending (order, index)
+    #[cfg(any(debug_assertions, test))]
+    pub fn verify_all() -> Result<(), (usize, u8, usize)> {
+        for (i, zone) in Self::zones().enumerate() {
+            zone.lock().verify().map_err(|(order, idx)| (i, order, idx))?;
+        }
+
+        Ok(())
+    }
+
+    // Free every page in the range, however it was allocated, in as few blocks
+    // as possible. The range can span several zones. Returns the number of
+    // blocks freed.
+    pub fn free_all_in_range(range: PhysFrameRange) -> usize {
+        if let Some(reserve) = Self::current().reserve.try_get() {
+            let reserve = reserve.lock();
+            BUG_ON!(
+                range.start < reserve.pages.end && reserve.pages.start < range.end,
+                "pmm: bulk free of emergency reserve pages {:?}",
+                range
+            );
+        }
+
+        let mut pages = 0;
+        let mut blocks = 0;
+        let mut freed = 0;
+        for zone in Self::zones() {
             let mut zone = zone.lock();
+            let start = range.start.max(zone.pages.start);
+            let end = range.end.min(zone.pages.end);
+            if start < end {
+                let before = zone.free;
+                blocks += zone.free_all_in_range(PhysFrame::range(start, end));
+                freed += zone.free - before;
+                pages += end - start;
+            }
+        }
+
+        if pages != range.end - range.start {
+            panic!(
+                "attempt to free memory that isn't managed by the PMM ({:?})",
+                range
+            );
+        }
+
+        Self::account_free(freed);
+        blocks
+    }
+
+    pub fn free(range: PhysFrameRange) {
+        trace_event!(PmmFree, range.start.start_address().as_u64());
+        // The reserve's memory also lies within another zone, so it's checked
+        // first
+        let reserve = Self::current().reserve.try_get();
+        for zone_lock in reserve.into_iter().chain(Self::zones()) {
+            let mut zone = zone_lock.lock();
             if zone.pages.start.start_address() <= range.start.start_address() && zone.pages.end.start_address() >= range.end.start_address() {
+                // A double free doesn't free anything, so go by what the zone
+                // actually got back
+                let before = zone.free;
                 zone.free(range);
+                let freed = zone.free - before;
+                drop(zone);
+
+                let in_reserve = reserve.map_or(false, |r| core::ptr::eq(r, zone_lock));
+                Self::account_free(if in_reserve { 0 } else { freed });
                 return;
             }
         }
@@ -296,13 +1139,42 @@ impl PhysAllocator {
     }
 }
 
-// Each page of memory has a constant memory overhead of size_of::<PageInfo>(),
-// as well as the whole region having a memory overhead of
-// blocks_in_region() * size_of::<Block>().
-// Let N = number of (PMM) usable memory pages
-//     T = total number of pages, usable and unusable
-//     W = overhead per page in bytes
-// We have the equation
+// Whether a direct map `map_size` bytes long reaches all memory below `top`
+fn direct_map_covers(top: PhysAddr, map_size: u64) -> bool {
+    top.as_u64() <= map_size
+}
+
+// Zones are written through the direct map (see phys_to_kernel_virt()), so
+// make sure the bootloader mapped everything they'll cover before any of them
+// are built
+fn check_direct_map(top: PhysAddr) {
+    if !direct_map_covers(top, super::DIRECT_MAP_SIZE) {
+        panic!(
+            "pmm: memory goes up to {:#x}, past the {:#x} byte direct map window",
+            top.as_u64(),
+            super::DIRECT_MAP_SIZE
+        );
+    }
+
+    let last = match top.as_u64().checked_sub(1) {
+        Some(last) => PhysAddr::new(last),
+        None => return,
+    };
+    if AddrSpace::kernel().translate_addr(super::phys_to_kernel_virt(last)) != Some(last) {
+        panic!(
+            "pmm: the bootloader's direct map doesn't reach {:#x}, the top of managed memory",
+            last.as_u64()
+        );
+    }
+}
+
+// Each page of memory has a constant memory overhead of size_of::<PageInfo>(),
+// as well as the whole region having a memory overhead of
+// blocks_in_region() * size_of::<Block>().
+// Let N = number of (PMM) usable memory pages
+//     T = total number of pages, usable and unusable
+//     W = overhead per page in bytes
+// We have the equation
 //       total wasted bytes <= 4096 * (T - N)
 // N * W + blocks_in_region <= 4096T - 4096N
 //           N * (W + 4096) <= 4096T - blocks_in_region
@@ -311,13 +1183,20 @@ impl PhysAllocator {
 // Subtract one extra page, just to be safe about padding and alignment
 // TODO: should really be blocks_in_region(usable_pages), but this hugely
 // complicates the math
-fn usable_pages(total_pages: u64) -> u64 {
-    (4096 * total_pages - blocks_in_region(total_pages))
-        / (mem::size_of::<PageInfo>() as u64 + 4096)
-        - 2
+// Comes out as 0 for regions too small to have any usable pages.
+const fn usable_pages(total_pages: u64) -> u64 {
+    let spare = match (4096 * total_pages).checked_sub(blocks_in_region(total_pages)) {
+        Some(spare) => spare,
+        None => return 0,
+    };
+
+    match (spare / (mem::size_of::<PageInfo>() as u64 + 4096)).checked_sub(2) {
+        Some(pages) => pages,
+        None => 0,
+    }
 }
 
-fn blocks_in_region(pages: u64) -> u64 {
+const fn blocks_in_region(pages: u64) -> u64 {
     let max_order_blocks = x86_64::align_up(pages, MAX_ORDER_PAGES) / MAX_ORDER_PAGES;
     // Evaluate the geometric series
     // a = max_order_blocks
@@ -326,6 +1205,70 @@ fn blocks_in_region(pages: u64) -> u64 {
     max_order_blocks * (2u64.pow(MAX_ORDER as u32 + 1) - 1)
 }
 
+// Lets tests run against a small allocator of their own instead of the global
+// one, so that they can't see each other's allocations. setup() carves the
+// synthetic allocator's memory out of the real PMM, and teardown() hands it
+// back.
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+    use alloc::boxed::Box;
+    use core::{ptr, sync::atomic::AtomicPtr};
+
+    pub const ORDER: u8 = 6;
+    const MAX_FIXTURE_ZONES: usize = 4;
+
+    pub(super) static ACTIVE: AtomicPtr<PhysAllocator> = AtomicPtr::new(ptr::null_mut());
+    static BACKING: SpinLock<[Option<PhysFrameRange>; MAX_FIXTURE_ZONES]> =
+        SpinLock::new([None; MAX_FIXTURE_ZONES]);
+
+    pub fn setup() {
+        setup_zones(1);
+    }
+
+    // Each zone gets its own block of 2^ORDER pages
+    pub fn setup_zones(count: usize) {
+        BUG_ON!(count == 0 || count > MAX_FIXTURE_ZONES);
+
+        let mut backing = BACKING.lock();
+        let mut zones = ArrayVec::new();
+        for slot in backing.iter_mut().take(count) {
+            let range = PhysAllocator::alloc(ORDER);
+            let zone = Zone::from_region(Region {
+                addr: range.start.start_address(),
+                size: (super::super::PAGE_SIZE << ORDER) as usize,
+            })
+            .expect("pmm fixture: backing region too small");
+
+            let cell = InitCell::new();
+            cell.init(SpinLock::new(zone));
+            zones.push(cell);
+            *slot = Some(range);
+        }
+
+        while !zones.is_full() {
+            zones.push(InitCell::new());
+        }
+
+        let pmm = Box::new(PhysAllocator::new());
+        pmm.zones.init(zones);
+        pmm.next_zone.store(count, Ordering::Relaxed);
+
+        let prev = ACTIVE.swap(Box::into_raw(pmm), Ordering::AcqRel);
+        BUG_ON!(!prev.is_null(), "pmm fixture: setup() called twice");
+    }
+
+    pub fn teardown() {
+        let pmm = ACTIVE.swap(ptr::null_mut(), Ordering::AcqRel);
+        BUG_ON!(pmm.is_null(), "pmm fixture: teardown() without setup()");
+
+        drop(unsafe { Box::from_raw(pmm) });
+        for range in BACKING.lock().iter_mut().filter_map(Option::take) {
+            PhysAllocator::free(range);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -339,4 +1282,934 @@ mod tests {
         let block = &b as *const u8 as *const Block;
         assert_eq!(unsafe { *block }, Block::Used);
     });
+
+    fn test_blocks(num_pages: u64) -> &'static mut [Block] {
+        use alloc::{boxed::Box, vec};
+
+        Box::leak(vec![Block::Used; blocks_in_region(num_pages) as usize].into_boxed_slice())
+    }
+
+    fn test_zone(num_pages: u64) -> Zone {
+        Zone::new(
+            PhysAddr::new(0),
+            (num_pages * super::super::PAGE_SIZE) as usize,
+            test_blocks(num_pages),
+        )
+    }
+
+    test_case!(verify_fresh_zone, {
+        let zone = test_zone(MAX_ORDER_PAGES);
+        assert_eq!(zone.verify(), Ok(()));
+    });
+
+    test_case!(verify_detects_corruption, {
+        let zone = test_zone(MAX_ORDER_PAGES);
+        // Mark a page used without updating its parents
+        zone.order_list[0][10] = Block::Used;
+        assert_eq!(zone.verify(), Err((1, 5)));
+
+        // Putting it back leaves the tree consistent again
+        zone.order_list[0][10] = Block::from_order(0);
+        assert_eq!(zone.verify(), Ok(()));
+    });
+
+    test_case!(lazy_zone_init, {
+        // Back both zones with the same real memory, since allocating clears it
+        let backing = PhysAllocator::alloc(3);
+        let addr = backing.start.start_address();
+        let size = (8 * super::super::PAGE_SIZE) as usize;
+
+        let mut eager = Zone::new(addr, size, test_blocks(8));
+        let mut lazy = Zone::new_lazy(addr, size, test_blocks(8));
+        assert!(eager.init_work > 0);
+        assert_eq!(lazy.init_work, 0);
+        assert_eq!(lazy.verify(), Ok(()));
+
+        assert_eq!(eager.alloc(1), lazy.alloc(1));
+        assert_eq!(lazy.init_work, eager.init_work);
+        for (l, e) in lazy.order_list.iter().zip(eager.order_list.iter()) {
+            assert_eq!(l, e);
+        }
+
+        assert_eq!(eager.alloc(0), lazy.alloc(0));
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(offline_zone, {
+        let backing = PhysAllocator::alloc(3);
+        let addr = backing.start.start_address();
+        let size = (8 * super::super::PAGE_SIZE) as usize;
+
+        let mut zone = Zone::new_lazy(addr, size, test_blocks(8));
+        zone.online = false;
+        assert_eq!(zone.alloc(0), None);
+        assert_eq!(zone.init_work, 0);
+
+        zone.online = true;
+        assert_eq!(zone.alloc(0), Some(PhysFrame::range(backing.start, backing.start + 1)));
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(colored_alloc, {
+        let backing = PhysAllocator::alloc(3);
+        let addr = backing.start.start_address();
+        let page = |n: u64| PhysFrame::range(backing.start + n, backing.start + n + 1);
+        let mut zone = Zone::new(addr, (8 * super::super::PAGE_SIZE) as usize, test_blocks(8));
+
+        // Four colours, picked by bits 12 and 13. The backing is 32KiB aligned,
+        // so page n has colour n % 4.
+        let mask = 0x3000;
+        assert_eq!(zone.alloc_colored(0, 0x2000, mask), Some(page(2)));
+        assert_eq!(zone.alloc_colored(0, 0x2000, mask), Some(page(6)));
+        assert_eq!(zone.alloc_colored(0, 0x2000, mask), None);
+        // Plain allocation still starts from the bottom
+        assert_eq!(zone.alloc(0), Some(page(0)));
+
+        // With pages 0, 2 and 6 gone, the only whole order 1 block left is 4-5
+        assert_eq!(zone.alloc_colored(1, 0, 0x4000), None);
+        assert_eq!(
+            zone.alloc_colored(1, 0x4000, 0x4000),
+            Some(PhysFrame::range(backing.start + 4, backing.start + 6))
+        );
+        assert_eq!(zone.verify(), Ok(()));
+
+        let mut zone = Zone::new(addr, (8 * super::super::PAGE_SIZE) as usize, test_blocks(8));
+        assert_eq!(
+            zone.alloc_colored(2, 0x4000, 0x4000),
+            Some(PhysFrame::range(backing.start + 4, backing.end))
+        );
+        assert_eq!(
+            zone.alloc_colored(1, 0x2000, 0x3000),
+            Some(PhysFrame::range(backing.start + 2, backing.start + 4))
+        );
+        assert_eq!(zone.verify(), Ok(()));
+
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(awkward_zone_size, {
+        let backing = PhysAllocator::alloc(MAX_ORDER as u8);
+        let num_pages = 1500;
+        let mut zone = Zone::new(
+            backing.start.start_address(),
+            (num_pages * super::super::PAGE_SIZE) as usize,
+            test_blocks(num_pages),
+        );
+        assert_eq!(zone.verify(), Ok(()));
+
+        // Drain the zone, largest blocks first
+        let mut allocated = 0;
+        for order in (0..=MAX_ORDER as u8).rev() {
+            while let Some(range) = zone.alloc(order) {
+                assert!(range.start >= zone.pages.start && range.end <= zone.pages.end);
+                allocated += range.end - range.start;
+            }
+        }
+        assert_eq!(allocated, num_pages);
+        assert_eq!(zone.verify(), Ok(()));
+
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(natural_alignment, {
+        // Start the zone a few pages past a MAX_ORDER_PAGES boundary
+        let backing = PhysAllocator::alloc(MAX_ORDER as u8);
+        let num_pages = 1500;
+        let mut zone = Zone::new(
+            backing.start.start_address() + 3 * super::super::PAGE_SIZE,
+            (num_pages * super::super::PAGE_SIZE) as usize,
+            test_blocks(num_pages + 3),
+        );
+        assert_eq!(zone.lead, 3);
+
+        let pages = zone.pages;
+        let mut allocated = 0;
+        let mut check = |order: u8, range: PhysFrameRange| {
+            let align = super::super::PAGE_SIZE << order;
+            assert_eq!(range.start.start_address().as_u64() % align, 0);
+            assert_eq!(range.end - range.start, 1 << order);
+            assert!(range.start >= pages.start && range.end <= pages.end);
+            allocated += range.end - range.start;
+        };
+
+        // One block of each order to break the zone up, then drain it largest
+        // first
+        for order in 0..=MAX_ORDER as u8 {
+            if let Some(range) = zone.alloc(order) {
+                check(order, range);
+            }
+        }
+        for order in (0..=MAX_ORDER as u8).rev() {
+            while let Some(range) = zone.alloc(order) {
+                check(order, range);
+            }
+        }
+        assert_eq!(allocated, num_pages);
+        assert_eq!(zone.verify(), Ok(()));
+
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(random_alloc_free, {
+        use alloc::vec::Vec;
+
+        // A zone with a lead and an awkward size, so the edges get exercised too
+        let backing = PhysAllocator::alloc(MAX_ORDER as u8);
+        let num_pages = 1000;
+        let mut zone = Zone::new(
+            backing.start.start_address() + 5 * super::super::PAGE_SIZE,
+            (num_pages * super::super::PAGE_SIZE) as usize,
+            test_blocks(num_pages + 5),
+        );
+
+        // Change the seed to explore other sequences. Any failure can be replayed
+        // by putting back the seed printed here.
+        let mut seed = 0x9E37_79B9u32;
+        println!("seed {:#x}", seed);
+
+        let mut live: Vec<PhysFrameRange> = Vec::new();
+        let mut used = 0;
+        for step in 0..5000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+
+            // Lean towards allocating, and towards small orders, so the zone
+            // fills up and fragments before it drains
+            let order = ((seed >> 8) % (MAX_ORDER as u32 + 1)).min((seed >> 16) % 8) as u8;
+            let range = if seed % 3 == 0 { None } else { zone.alloc(order) };
+
+            match range {
+                Some(range) => {
+                    assert_eq!(range.end - range.start, 1 << order);
+                    assert!(range.start >= zone.pages.start && range.end <= zone.pages.end);
+                    for other in live.iter() {
+                        assert!(
+                            range.end <= other.start || other.end <= range.start,
+                            "step {}: {:?} overlaps {:?}",
+                            step,
+                            range,
+                            other
+                        );
+                    }
+                    used += range.end - range.start;
+                    live.push(range);
+                }
+                None if !live.is_empty() => {
+                    let range = live.swap_remove((seed >> 4) as usize % live.len());
+                    used -= range.end - range.start;
+                    zone.free(range);
+                }
+                None => {}
+            }
+
+            assert_eq!(zone.verify(), Ok(()), "step {}", step);
+            assert_eq!(zone.free_pages(), num_pages - used, "step {}", step);
+        }
+
+        for range in live.drain(..) {
+            zone.free(range);
+        }
+        assert_eq!(zone.free_pages(), num_pages);
+        assert_eq!(zone.largest_free_order(), max_aligned_order(&zone));
+        assert_eq!(zone.verify(), Ok(()));
+
+        PhysAllocator::free(backing);
+    });
+
+    // The largest naturally aligned block that fits in the zone, counting from
+    // its tree base
+    fn max_aligned_order(zone: &Zone) -> Option<u8> {
+        let end = zone.lead + zone.num_pages;
+        (0..=MAX_ORDER as u8).rev().find(|&order| {
+            let start = x86_64::align_up(zone.lead, 1 << order);
+            start + (1 << order) <= end
+        })
+    }
+
+    // Takes every page one at a time, then gives them all back in an order
+    // picked by `pattern`
+    fn drain_and_refill(zone: &mut Zone, pattern: usize) {
+        let mut count = 0;
+        while zone.alloc(0).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, zone.num_pages);
+        assert_eq!(zone.largest_free_order(), None);
+
+        let (start, end) = (zone.pages.start, zone.pages.end);
+        let mut free = |frame: PhysFrame| zone.free(PhysFrame::range(frame, frame + 1));
+        match pattern {
+            0 => PhysFrame::range(start, end).for_each(&mut free),
+            1 => (0..end - start).rev().for_each(|i| free(start + i)),
+            // Every other page first, so nothing can merge until the second pass
+            _ => {
+                PhysFrame::range(start, end).step_by(2).for_each(&mut free);
+                PhysFrame::range(start + 1, end).step_by(2).for_each(&mut free);
+            }
+        }
+
+        assert_eq!(zone.free_pages(), zone.num_pages);
+        assert_eq!(zone.verify(), Ok(()));
+    }
+
+    test_case!(free_everything_coalesces, {
+        let backing = PhysAllocator::alloc(MAX_ORDER as u8);
+
+        // (lead, pages), with zones ending both on and off the boundary
+        let shapes: &[(u64, u64)] = &[(0, 2048), (0, 1500), (3, 1500), (100, 1948), (7, 2041), (1, 1)];
+        for &(lead, num_pages) in shapes {
+            for pattern in 0..3 {
+                let mut zone = Zone::new(
+                    backing.start.start_address() + lead * super::super::PAGE_SIZE,
+                    (num_pages * super::super::PAGE_SIZE) as usize,
+                    test_blocks(lead + num_pages),
+                );
+                assert_eq!(zone.lead, lead);
+                let max = max_aligned_order(&zone);
+                assert_eq!(zone.largest_free_order(), max);
+
+                drain_and_refill(&mut zone, pattern);
+                assert_eq!(zone.largest_free_order(), max, "{} pages at +{}, pattern {}", num_pages, lead, pattern);
+            }
+        }
+
+        // Zones built from a region lose its start to the block array, so their
+        // pages never start on the boundary
+        for pattern in 0..3 {
+            let mut zone = Zone::from_region(Region {
+                addr: backing.start.start_address(),
+                size: (MAX_ORDER_PAGES * super::super::PAGE_SIZE) as usize,
+            })
+            .unwrap();
+            zone.materialise();
+            assert!(zone.lead > 0);
+            let max = max_aligned_order(&zone);
+            assert_eq!(max, Some(MAX_ORDER as u8 - 1));
+
+            drain_and_refill(&mut zone, pattern);
+            assert_eq!(zone.largest_free_order(), max);
+        }
+
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(zone_from_region, {
+        let too_small = Region {
+            addr: PhysAddr::new(0x10_0000),
+            size: 2 * super::super::PAGE_SIZE as usize,
+        };
+        assert!(Zone::from_region(too_small).is_none());
+
+        let backing = PhysAllocator::alloc(4);
+        let mut zone = Zone::from_region(Region {
+            addr: backing.start.start_address(),
+            size: 16 * super::super::PAGE_SIZE as usize,
+        })
+        .unwrap();
+
+        // The start of the region is kept back for the block array
+        assert!(zone.num_pages > 1 && zone.num_pages < 16);
+        let range = zone.alloc(0).unwrap();
+        assert!(range.start >= backing.start && range.end <= backing.end);
+        assert_eq!(zone.verify(), Ok(()));
+
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(zone_from_merged, {
+        use crate::mm::PAGE_SIZE;
+
+        let backing = PhysAllocator::alloc(4);
+        let rg = |page: u64, pages: u64| Region {
+            addr: backing.start.start_address() + page * PAGE_SIZE,
+            size: (pages * PAGE_SIZE) as usize,
+        };
+        let mut parts = ArrayVec::new();
+        parts.push((rg(0, 12), false));
+        parts.push((rg(12, 4), true));
+        let mut zone = Zone::from_merged(&MergedRegion {
+            region: rg(0, 16),
+            parts,
+        })
+        .unwrap();
+
+        // The held pages are left out until they're released
+        let free = zone.free_pages();
+        assert_eq!(free, zone.num_pages - 4);
+        let mut pages = ArrayVec::<[PhysFrameRange; 16]>::new();
+        while let Some(page) = zone.alloc(0) {
+            assert!(page.start < backing.start + 12);
+            pages.push(page);
+        }
+        assert_eq!(pages.len() as u64, free);
+
+        assert_eq!(zone.release_held(), 4);
+        assert_eq!(zone.release_held(), 0);
+        assert_eq!(zone.verify(), Ok(()));
+        while let Some(page) = zone.alloc(0) {
+            assert!(page.start >= backing.start + 12 && page.end <= backing.end);
+            pages.push(page);
+        }
+        assert_eq!(pages.len() as u64, free + 4);
+
+        // Held memory at the start would be where the block array goes
+        let mut parts = ArrayVec::new();
+        parts.push((rg(0, 4), true));
+        parts.push((rg(4, 12), false));
+        assert!(Zone::from_merged(&MergedRegion {
+            region: rg(0, 16),
+            parts,
+        })
+        .is_none());
+
+        PhysAllocator::free(backing);
+    });
+
+    test_case!(direct_map_bounds, {
+        let gib = 1 << 30;
+        assert!(direct_map_covers(PhysAddr::new(4 * gib), 4 * gib));
+        assert!(direct_map_covers(PhysAddr::new(4 * gib - 0x1000), 4 * gib));
+        assert!(!direct_map_covers(PhysAddr::new(4 * gib + 0x1000), 4 * gib));
+        assert!(direct_map_covers(PhysAddr::new(0), 0));
+
+        // The bootloader maps at least what the PMM is managing now
+        let top = PhysAllocator::zone_info().map(|zone| zone.pages.end.start_address()).max().unwrap();
+        check_direct_map(top);
+    });
+
+    test_case!(tiny_region_skipped, {
+        for pages in 0..MIN_REGION_PAGES {
+            assert!(usable_pages(pages) <= 1, "{} pages", pages);
+        }
+
+        let rg = Region {
+            addr: PhysAddr::new(0x10_0000),
+            size: 3 * super::super::PAGE_SIZE as usize,
+        };
+        assert_eq!(usable_pages(3), 0);
+        assert!(Zone::from_region(rg).is_none());
+        assert_eq!(PhysAllocator::add_zone(rg), Err(rg));
+    });
+
+    test_case!(alloc_free_stress, {
+        let mut ranges: ArrayVec<[PhysFrameRange; 32]> = ArrayVec::new();
+        for i in 0..32 {
+            ranges.push(PhysAllocator::alloc((i % 4) as u8));
+            assert_eq!(PhysAllocator::verify_all(), Ok(()));
+        }
+
+        while let Some(range) = ranges.pop() {
+            PhysAllocator::free(range);
+            assert_eq!(PhysAllocator::verify_all(), Ok(()));
+        }
+    });
+
+    test_case!(alloc_with_info, {
+        use crate::mm::phys_to_page_info;
+
+        let (block, block_info) = PhysAllocator::alloc_with_info(2);
+        let (page, page_info) = PhysAllocator::alloc_with_info(0);
+        assert_eq!(block.end - block.start, 4);
+
+        // Each is the slot for the head frame of its own allocation
+        assert_eq!(block_info as *const PageInfo, phys_to_page_info(block.start));
+        assert_eq!(page_info as *const PageInfo, phys_to_page_info(page.start));
+        assert_ne!(block_info as *const PageInfo, phys_to_page_info(block.start + 1));
+
+        PhysAllocator::free(page);
+        PhysAllocator::free(block);
+    });
+
+    test_case!(
+        alloc_secure,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            use crate::mm::{fill::{set_fill_policy, FillPolicy}, phys_to_kernel_virt, PAGE_SIZE};
+
+            let bytes = |range: PhysFrameRange| unsafe {
+                core::slice::from_raw_parts_mut(
+                    phys_to_kernel_virt(range.start.start_address()).as_mut_ptr::<u8>(),
+                    PAGE_SIZE as usize,
+                )
+            };
+
+            // Zeroed even when the policy says otherwise
+            let previous = set_fill_policy(FillPolicy::Pattern(0xB8));
+            let page = PhysAllocator::alloc_secure(0);
+            assert!(bytes(page).iter().all(|&b| b == 0));
+
+            for b in bytes(page).iter_mut() {
+                *b = 0x5A;
+            }
+            PhysAllocator::free_secure(page);
+
+            // With no fill on allocation, what's there is what free_secure() left
+            set_fill_policy(FillPolicy::None);
+            let again = PhysAllocator::alloc(0);
+            set_fill_policy(previous);
+            assert_eq!(again, page);
+            assert!(bytes(again).iter().all(|&b| b == 0));
+            PhysAllocator::free(again);
+        }
+    );
+
+    test_case!(
+        low_reserve,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            use crate::mm::PAGE_SIZE;
+            use alloc::vec::Vec;
+            use bootloader::bootinfo::{FrameRange, MemoryRegion, MemoryRegionType};
+
+            // Six low frames, standing in for conventional memory
+            let mut memory = alloc::vec![0u8; 8 * PAGE_SIZE as usize];
+            let mut page_info: Vec<PageInfo> = (0..8).map(|_| PageInfo::default()).collect();
+            let regions = [MemoryRegion {
+                range: FrameRange::new(0x1000, 0x7000),
+                region_type: MemoryRegionType::Usable,
+            }];
+            let mut map = MemoryMap::new_for_test(&regions, &mut memory, &mut page_info);
+
+            // The lowest frames come out of the map, only as far as the limit
+            assert_eq!(map.reserve_low_frame(PhysAddr::new(0x1800)), None);
+            assert_eq!(PhysAllocator::reserve_low(&mut map), LOW_RESERVE_FRAMES);
+            let rest: Vec<Region> = map.into_iter().collect();
+            assert_eq!(rest[0].addr, PhysAddr::new(0x1000 + LOW_RESERVE_FRAMES as u64 * PAGE_SIZE));
+
+            // Draining the zones leaves the reserve alone
+            let mut pages = Vec::new();
+            while let Some(page) = PhysAllocator::try_alloc(0) {
+                pages.push(page);
+            }
+
+            let mut low = Vec::new();
+            while let Some(frame) = PhysAllocator::alloc_low_1mb() {
+                assert!(frame.start_address().as_u64() < 0x10_0000);
+                assert!(pages.iter().all(|page| page.start != frame));
+                low.push(frame);
+            }
+            assert_eq!(low.len(), LOW_RESERVE_FRAMES);
+
+            PhysAllocator::free_low_1mb(low[1]);
+            assert_eq!(PhysAllocator::alloc_low_1mb(), Some(low[1]));
+            for page in pages {
+                PhysAllocator::free(page);
+            }
+        }
+    );
+
+    test_case!(
+        alloc_policy,
+        setup = fixture::setup_zones(2),
+        teardown = fixture::teardown(),
+        {
+            use alloc::vec::Vec;
+
+            // Fill both zones, then punch two holes in each. The zones are the
+            // same size, so each holds half the pages.
+            let mut pages = Vec::new();
+            while let Some(page) = PhysAllocator::try_alloc(0) {
+                pages.push(page);
+            }
+            pages.sort_unstable_by_key(|page| page.start);
+            let n = pages.len();
+            let holes = [pages[2], pages[n / 2 - 3], pages[n / 2 + 2], pages[n - 3]];
+            for &hole in holes.iter() {
+                PhysAllocator::free(hole);
+            }
+
+            // Each pick is between two holes in the same zone, until the last
+            assert_eq!(PhysAllocator::alloc_with_policy(0, AllocPolicy::LowestFirst), Some(holes[0]));
+            assert_eq!(PhysAllocator::alloc_with_policy(0, AllocPolicy::HighestFirst), Some(holes[3]));
+            assert_eq!(PhysAllocator::alloc_with_policy(0, AllocPolicy::HighestFirst), Some(holes[2]));
+            assert_eq!(PhysAllocator::alloc_with_policy(0, AllocPolicy::LowestFirst), Some(holes[1]));
+            assert_eq!(PhysAllocator::alloc_with_policy(0, AllocPolicy::LowestFirst), None);
+        }
+    );
+
+    fn fixture_pages() -> u64 {
+        PhysAllocator::zones().map(|zone| zone.lock().num_pages).sum()
+    }
+
+    test_case!(
+        fixture_exhaust_pages,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            let pages = fixture_pages();
+            assert!(pages > 0 && pages < 1 << fixture::ORDER);
+
+            let mut count = 0;
+            while PhysAllocator::try_alloc(0).is_some() {
+                count += 1;
+            }
+            assert_eq!(count, pages);
+        }
+    );
+
+    test_case!(
+        fixture_exhaust_blocks,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            // Starts from a full allocator even if the test above ran first
+            let first = PhysAllocator::try_alloc(2).unwrap();
+            let mut count = 1;
+            while PhysAllocator::try_alloc(2).is_some() {
+                count += 1;
+            }
+            assert_eq!(count, fixture_pages() / 4);
+
+            PhysAllocator::free(first);
+            assert_eq!(PhysAllocator::try_alloc(2), Some(first));
+        }
+    );
+
+    fn fixture_start() -> PhysFrame {
+        PhysAllocator::zones().next().unwrap().lock().pages.start
+    }
+
+    // The first frame in the fixture's zone that's aligned to `pages`
+    fn fixture_aligned(pages: u64) -> PhysFrame {
+        let align = pages * super::super::PAGE_SIZE;
+        PhysFrame::containing_address(fixture_start().start_address().align_up(align))
+    }
+
+    test_case!(
+        free_all_in_range_unaligned,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            while PhysAllocator::try_alloc(0).is_some() {}
+
+            // Pages 3..37 past a 16-page boundary are covered by blocks of 1, 4,
+            // 8, 16, 4 and 1 pages
+            let start = fixture_aligned(16);
+            let range = PhysFrame::range(start + 3, start + 37);
+            assert_eq!(PhysAllocator::free_all_in_range(range), 6);
+            assert_eq!(PhysAllocator::verify_all(), Ok(()));
+
+            // Every page comes back exactly once, and nothing outside the range
+            for _ in 3..37 {
+                let page = PhysAllocator::try_alloc(0).unwrap();
+                assert!(page.start >= range.start && page.end <= range.end);
+            }
+            assert_eq!(PhysAllocator::try_alloc(0), None);
+        }
+    );
+
+    test_case!(
+        free_all_in_range_splits_blocks,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            let block = PhysAllocator::try_alloc(4).unwrap();
+            assert_eq!(block.start, fixture_aligned(16));
+
+            // Hand back all but the first and last pages of the block
+            let inner = PhysFrame::range(block.start + 1, block.end - 1);
+            assert_eq!(PhysAllocator::free_all_in_range(inner), 6);
+            assert_eq!(PhysAllocator::verify_all(), Ok(()));
+
+            for _ in 0..14 {
+                let page = PhysAllocator::try_alloc(0).unwrap();
+                assert!(page.start >= inner.start && page.end <= inner.end);
+            }
+
+            // The pages left at either end can be freed on their own, and
+            // everything merges back into the original block
+            PhysAllocator::free(PhysFrame::range(block.start, block.start + 1));
+            PhysAllocator::free(PhysFrame::range(block.end - 1, block.end));
+            PhysAllocator::free_all_in_range(inner);
+            assert_eq!(PhysAllocator::try_alloc(4), Some(block));
+        }
+    );
+
+    test_case!(
+        alloc_in_zone,
+        setup = fixture::setup_zones(2),
+        teardown = fixture::teardown(),
+        {
+            let zones: ArrayVec<[ZoneInfo; 2]> = PhysAllocator::zone_info().collect();
+            assert_eq!(zones.len(), 2);
+            assert_eq!((zones[0].index, zones[1].index), (0, 1));
+
+            // Drain the second zone only, leaving the first untouched
+            let second = zones[1].pages;
+            let mut pages = 0;
+            while let Some(range) = PhysAllocator::alloc_in_zone(1, 0) {
+                assert!(range.start >= second.start && range.end <= second.end);
+                pages += 1;
+            }
+            assert_eq!(pages, second.end - second.start);
+            assert_eq!(PhysAllocator::alloc_in_zone(1, 0), None);
+
+            let first = zones[0].pages;
+            let range = PhysAllocator::alloc_in_zone(0, 2).unwrap();
+            assert!(range.start >= first.start && range.end <= first.end);
+
+            assert_eq!(PhysAllocator::alloc_in_zone(2, 0), None);
+            assert_eq!(PhysAllocator::alloc_in_zone(MAX_ZONES as usize, 0), None);
+        }
+    );
+
+    test_case!(
+        zone_of,
+        setup = fixture::setup_zones(3),
+        teardown = fixture::teardown(),
+        {
+            for zone in PhysAllocator::zone_info() {
+                let start = zone.pages.start.start_address();
+                let end = zone.pages.end.start_address();
+                assert_eq!(PhysAllocator::zone_of(start), Some(zone.index));
+                assert_eq!(PhysAllocator::zone_of(start + 0x1234u64), Some(zone.index));
+                assert_eq!(PhysAllocator::zone_of(end - 1u64), Some(zone.index));
+
+                // The zone's metadata sits just before its pages
+                assert_eq!(PhysAllocator::zone_of(start - 1u64), None);
+            }
+
+            assert_eq!(PhysAllocator::zone_of(PhysAddr::new(0)), None);
+            let top = PhysAllocator::zone_info().map(|zone| zone.pages.end.start_address()).max().unwrap();
+            assert_eq!(PhysAllocator::zone_of(top), None);
+        }
+    );
+
+    fn reserve_pages() -> PhysFrameRange {
+        PhysAllocator::current().reserve.try_get().unwrap().lock().pages
+    }
+
+    test_case!(
+        emergency_reserve,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            PhysAllocator::init_reserve();
+            let reserve = reserve_pages();
+
+            // Normal allocations never dip into the reserve
+            let mut pages = 0;
+            while let Some(page) = PhysAllocator::try_alloc(0) {
+                assert!(page.end <= reserve.start || page.start >= reserve.end);
+                pages += 1;
+            }
+            assert!(pages > 0);
+            assert_eq!(PhysAllocator::free_pages(), 0);
+
+            let page = PhysAllocator::alloc_emergency(0).unwrap();
+            assert!(page.start >= reserve.start && page.end <= reserve.end);
+            assert_eq!(PhysAllocator::try_alloc(0), None);
+
+            // Freed pages go back to the reserve, not the zone it came from
+            PhysAllocator::free(page);
+            assert_eq!(PhysAllocator::try_alloc(0), None);
+            assert_eq!(PhysAllocator::alloc_emergency(0), Some(page));
+        }
+    );
+
+    static LOW_MEMORY_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn on_low_memory(free_pages: u64) {
+        assert!(free_pages < 8);
+        LOW_MEMORY_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    test_case!(
+        low_memory_callback,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            LOW_MEMORY_CALLS.store(0, Ordering::Relaxed);
+            PhysAllocator::set_watermarks(8, 8);
+            PhysAllocator::set_low_memory_callback(on_low_memory);
+
+            let mut ranges: ArrayVec<[PhysFrameRange; 1 << fixture::ORDER]> = ArrayVec::new();
+            while let Some(range) = PhysAllocator::try_alloc(0) {
+                ranges.push(range);
+            }
+            assert_eq!(LOW_MEMORY_CALLS.load(Ordering::Relaxed), 1);
+
+            // Freeing a little isn't enough to re-arm it
+            PhysAllocator::free(ranges.pop().unwrap());
+            ranges.push(PhysAllocator::alloc(0));
+            assert_eq!(LOW_MEMORY_CALLS.load(Ordering::Relaxed), 1);
+
+            // Going back above the watermark is
+            while let Some(range) = ranges.pop() {
+                PhysAllocator::free(range);
+            }
+            PhysAllocator::alloc_in_zone(0, fixture::ORDER - 2).unwrap();
+            assert_eq!(LOW_MEMORY_CALLS.load(Ordering::Relaxed), 1);
+            while PhysAllocator::try_alloc(0).is_some() {}
+            assert_eq!(LOW_MEMORY_CALLS.load(Ordering::Relaxed), 2);
+
+            PhysAllocator::clear_low_memory_callback();
+        }
+    );
+
+    static RECLAIM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    // Allocating from the callback mustn't call it again
+    fn on_reclaim(free_pages: u64) {
+        assert!(free_pages < 8);
+        RECLAIM_CALLS.fetch_add(1, Ordering::Relaxed);
+        let page = PhysAllocator::alloc(0);
+        PhysAllocator::free(page);
+    }
+
+    test_case!(
+        watermarks,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            RECLAIM_CALLS.store(0, Ordering::Relaxed);
+            PhysAllocator::set_watermarks(8, 24);
+            PhysAllocator::set_low_memory_callback(on_reclaim);
+
+            let mut ranges: ArrayVec<[PhysFrameRange; 1 << fixture::ORDER]> = ArrayVec::new();
+            while let Some(range) = PhysAllocator::try_alloc(0) {
+                ranges.push(range);
+            }
+            assert_eq!(RECLAIM_CALLS.load(Ordering::Relaxed), 1);
+
+            // Back above the low watermark but short of the high one
+            for _ in 0..16 {
+                PhysAllocator::free(ranges.pop().unwrap());
+            }
+            while let Some(range) = PhysAllocator::try_alloc(0) {
+                ranges.push(range);
+            }
+            assert_eq!(RECLAIM_CALLS.load(Ordering::Relaxed), 1);
+
+            // Recovering past the high watermark re-arms it
+            for _ in 0..24 {
+                PhysAllocator::free(ranges.pop().unwrap());
+            }
+            while let Some(range) = PhysAllocator::try_alloc(0) {
+                ranges.push(range);
+            }
+            assert_eq!(RECLAIM_CALLS.load(Ordering::Relaxed), 2);
+
+            PhysAllocator::clear_low_memory_callback();
+            while let Some(range) = ranges.pop() {
+                PhysAllocator::free(range);
+            }
+        }
+    );
+
+    test_case!(
+        peak_allocated,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            assert_eq!((PhysAllocator::allocated_pages(), PhysAllocator::peak_allocated()), (0, 0));
+
+            let block = PhysAllocator::alloc(2);
+            let page = PhysAllocator::alloc(0);
+            assert_eq!(PhysAllocator::peak_allocated(), 5);
+
+            // Freeing doesn't lower the peak, and neither does staying below it
+            PhysAllocator::free(block);
+            PhysAllocator::free(page);
+            assert_eq!(PhysAllocator::allocated_pages(), 0);
+            let page = PhysAllocator::alloc(1);
+            assert_eq!((PhysAllocator::allocated_pages(), PhysAllocator::peak_allocated()), (2, 5));
+
+            PhysAllocator::reset_peak();
+            assert_eq!(PhysAllocator::peak_allocated(), 2);
+            let block = PhysAllocator::alloc(3);
+            assert_eq!(PhysAllocator::peak_allocated(), 10);
+
+            // Bulk frees only count pages that were actually allocated
+            PhysAllocator::free(page);
+            PhysAllocator::free_all_in_range(block);
+            assert_eq!(PhysAllocator::allocated_pages(), 0);
+            assert_eq!(PhysAllocator::peak_allocated(), 10);
+        }
+    );
+
+    bench_case!(alloc_free_page, iterations = 1000, {
+        let page = PhysAllocator::alloc(0);
+        PhysAllocator::free(page);
+    });
+
+    test_case!(
+        alloc_aligned,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            let free = PhysAllocator::free_pages();
+            // Something already taken from the start of the zone
+            let first = PhysAllocator::alloc(0);
+
+            // One page, aligned to 16 pages, with the other 15 given back
+            let range = PhysAllocator::alloc_aligned(0, 4).unwrap();
+            assert_eq!(range.end - range.start, 1);
+            assert_eq!(range.start.start_address().as_u64() % (16 * super::super::PAGE_SIZE), 0);
+            assert_eq!(PhysAllocator::free_pages(), free - 2);
+            assert_eq!(PhysAllocator::verify_all(), Ok(()));
+
+            let pair = PhysAllocator::alloc_aligned(1, 3).unwrap();
+            assert_eq!(pair.end - pair.start, 2);
+            assert_eq!(pair.start.start_address().as_u64() % (8 * super::super::PAGE_SIZE), 0);
+            assert_eq!(PhysAllocator::free_pages(), free - 4);
+
+            // A block the size of the alignment has to be free
+            assert_eq!(PhysAllocator::alloc_aligned(0, fixture::ORDER), None);
+            assert_eq!(PhysAllocator::alloc_aligned(0, MAX_ORDER as u8 + 1), None);
+
+            // Freeing them leaves nothing behind
+            PhysAllocator::free(pair);
+            PhysAllocator::free(range);
+            PhysAllocator::free(first);
+            assert_eq!(PhysAllocator::free_pages(), free);
+            assert_eq!(PhysAllocator::verify_all(), Ok(()));
+        }
+    );
+
+    test_case!(
+        alloc_batch,
+        setup = fixture::setup(),
+        teardown = fixture::teardown(),
+        {
+            use alloc::vec::Vec;
+
+            let free = PhysAllocator::free_pages();
+            let allocated = PhysAllocator::allocated_pages();
+
+            let mut out = [MaybeUninit::uninit(); 8];
+            assert_eq!(PhysAllocator::alloc_batch(8, &mut out), 8);
+            let mut frames: Vec<PhysFrame> = out.iter().map(|frame| unsafe { frame.assume_init() }).collect();
+            assert_eq!(PhysAllocator::free_pages(), free - 8);
+            assert_eq!(PhysAllocator::allocated_pages(), allocated + 8);
+
+            // All different
+            frames.sort_unstable();
+            frames.dedup();
+            assert_eq!(frames.len(), 8);
+
+            // Never more than fits
+            let mut small = [MaybeUninit::uninit(); 2];
+            assert_eq!(PhysAllocator::alloc_batch(5, &mut small), 2);
+            frames.extend(small.iter().map(|frame| unsafe { frame.assume_init() }));
+
+            // Asking for more than there is gets what's left, then nothing
+            let left = PhysAllocator::free_pages() as usize;
+            let mut rest = Vec::new();
+            rest.resize(left + 4, MaybeUninit::uninit());
+            assert_eq!(PhysAllocator::alloc_batch(left + 4, &mut rest), left);
+            assert_eq!(PhysAllocator::free_pages(), 0);
+            frames.extend(rest[..left].iter().map(|frame| unsafe { frame.assume_init() }));
+            assert_eq!(PhysAllocator::alloc_batch(1, &mut small), 0);
+
+            for frame in frames {
+                PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+            }
+            assert_eq!(PhysAllocator::free_pages(), free);
+            assert_eq!(PhysAllocator::verify_all(), Ok(()));
+        }
+    );
 }
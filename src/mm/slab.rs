@@ -0,0 +1,219 @@
+use crate::mm::{kernel_virt_to_phys, phys_to_kernel_virt, pmm::PhysAllocator};
+use core::{mem, ptr::NonNull};
+use x86_64::{
+    structures::paging::frame::{PhysFrame, PhysFrameRange},
+    VirtAddr,
+};
+
+const BITMAP_WORDS: usize = 8;
+const MAX_SLOTS: usize = BITMAP_WORDS * 64;
+
+// Lives at the start of every slab, followed by the slots themselves
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    used: usize,
+    bitmap: [u64; BITMAP_WORDS],
+}
+
+// Serves fixed-size objects out of slabs of 2^order pages taken from the PMM.
+// Slabs are handed back to the PMM as soon as they become empty.
+pub struct SlabCache {
+    slot_size: usize,
+    data_offset: usize,
+    slots_per_slab: usize,
+    order: u8,
+    slabs: Option<NonNull<SlabHeader>>,
+}
+
+unsafe impl Send for SlabCache {}
+
+#[allow(dead_code)]
+impl SlabCache {
+    pub fn new(obj_size: usize, align: usize, order: u8) -> Self {
+        assert!(obj_size > 0);
+        assert!(align.is_power_of_two() && align as u64 <= super::PAGE_SIZE);
+
+        let slot_size = x86_64::align_up(obj_size as u64, align as u64) as usize;
+        let data_offset =
+            x86_64::align_up(mem::size_of::<SlabHeader>() as u64, align as u64) as usize;
+        let slab_size = (super::PAGE_SIZE << order) as usize;
+        assert!(
+            data_offset + slot_size <= slab_size,
+            "slab: object of size {} doesn't fit in an order {} slab",
+            obj_size,
+            order
+        );
+
+        Self {
+            slot_size,
+            data_offset,
+            slots_per_slab: ((slab_size - data_offset) / slot_size).min(MAX_SLOTS),
+            order,
+            slabs: None,
+        }
+    }
+
+    pub fn slots_per_slab(&self) -> usize {
+        self.slots_per_slab
+    }
+
+    pub fn num_slabs(&self) -> usize {
+        let mut count = 0;
+        let mut curr_opt = self.slabs;
+        while let Some(curr) = curr_opt {
+            count += 1;
+            curr_opt = unsafe { curr.as_ref().next };
+        }
+
+        count
+    }
+
+    pub fn alloc(&mut self) -> NonNull<u8> {
+        let mut curr_opt = self.slabs;
+        while let Some(mut curr) = curr_opt {
+            let slab = unsafe { curr.as_mut() };
+            if slab.used < self.slots_per_slab {
+                return self.take_slot(curr);
+            }
+
+            curr_opt = slab.next;
+        }
+
+        let slab = self.morecore();
+        self.take_slot(slab)
+    }
+
+    pub unsafe fn free(&mut self, ptr: NonNull<u8>) {
+        let addr = ptr.as_ptr() as usize;
+        let slab_size = (super::PAGE_SIZE << self.order) as usize;
+
+        let mut prev: Option<NonNull<SlabHeader>> = None;
+        let mut curr_opt = self.slabs;
+        while let Some(mut curr) = curr_opt {
+            let start = curr.as_ptr() as usize;
+            if addr >= start + self.data_offset && addr < start + slab_size {
+                let offset = addr - start - self.data_offset;
+                debug_assert_eq!(offset % self.slot_size, 0, "slab: misaligned free of {:p}", ptr);
+
+                let idx = offset / self.slot_size;
+                let slab = curr.as_mut();
+                let (word, bit) = (idx / 64, idx % 64);
+                assert_ne!(slab.bitmap[word] & (1 << bit), 0, "slab: double free of {:p}", ptr);
+
+                slab.bitmap[word] &= !(1 << bit);
+                slab.used -= 1;
+
+                if slab.used == 0 {
+                    match prev {
+                        Some(mut p) => p.as_mut().next = slab.next,
+                        None => self.slabs = slab.next,
+                    }
+
+                    PhysAllocator::free(self.slab_frames(curr));
+                }
+
+                return;
+            }
+
+            prev = curr_opt;
+            curr_opt = curr.as_ref().next;
+        }
+
+        panic!("slab: attempt to free {:p} which isn't owned by this cache", ptr);
+    }
+
+    fn take_slot(&self, mut slab_ptr: NonNull<SlabHeader>) -> NonNull<u8> {
+        let slab = unsafe { slab_ptr.as_mut() };
+        let (word, bits) = slab
+            .bitmap
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != !0)
+            .expect("slab: bitmap full despite free slots");
+
+        let bit = (!*bits).trailing_zeros() as usize;
+        *bits |= 1 << bit;
+        slab.used += 1;
+
+        let idx = word * 64 + bit;
+        debug_assert!(idx < self.slots_per_slab);
+
+        let addr = slab_ptr.as_ptr() as usize + self.data_offset + idx * self.slot_size;
+        NonNull::new(addr as *mut u8).unwrap()
+    }
+
+    fn morecore(&mut self) -> NonNull<SlabHeader> {
+        let frames = PhysAllocator::alloc(self.order);
+        let header = phys_to_kernel_virt(frames.start.start_address()).as_mut_ptr::<SlabHeader>();
+
+        // Slots past the end of the slab are permanently marked as used
+        let mut bitmap = [0u64; BITMAP_WORDS];
+        for idx in self.slots_per_slab..MAX_SLOTS {
+            bitmap[idx / 64] |= 1 << (idx % 64);
+        }
+
+        unsafe {
+            header.write(SlabHeader {
+                next: self.slabs,
+                used: 0,
+                bitmap,
+            });
+        }
+
+        let slab = NonNull::new(header).unwrap();
+        self.slabs = Some(slab);
+        slab
+    }
+
+    fn slab_frames(&self, slab: NonNull<SlabHeader>) -> PhysFrameRange {
+        let start = PhysFrame::containing_address(kernel_virt_to_phys(VirtAddr::from_ptr(
+            slab.as_ptr(),
+        )));
+        PhysFrame::range(start, start + (1u64 << self.order))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    test_case!(slab_overflow_and_return, {
+        let mut cache = SlabCache::new(64, 8, 0);
+        let per_slab = cache.slots_per_slab();
+        assert!(per_slab > 0);
+
+        let mut objs: ArrayVec<[NonNull<u8>; 128]> = ArrayVec::new();
+        for _ in 0..per_slab + 1 {
+            let obj = cache.alloc();
+            assert!(!objs.contains(&obj));
+            objs.push(obj);
+        }
+        assert_eq!(cache.num_slabs(), 2);
+
+        // Freeing the lone object in the second slab returns it immediately
+        unsafe { cache.free(objs.pop().unwrap()) };
+        assert_eq!(cache.num_slabs(), 1);
+
+        while let Some(obj) = objs.pop() {
+            unsafe { cache.free(obj) };
+        }
+        assert_eq!(cache.num_slabs(), 0);
+    });
+
+    test_case!(slab_reuses_slots, {
+        let mut cache = SlabCache::new(24, 8, 0);
+        let a = cache.alloc();
+        let b = cache.alloc();
+        assert_eq!(b.as_ptr() as usize - a.as_ptr() as usize, 24);
+
+        unsafe { cache.free(a) };
+        assert_eq!(cache.alloc(), a);
+
+        unsafe {
+            cache.free(a);
+            cache.free(b);
+        }
+        assert_eq!(cache.num_slabs(), 0);
+    });
+}
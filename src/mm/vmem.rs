@@ -0,0 +1,217 @@
+use crate::ds::{IntrusiveList, Linked, Links, SpinLock};
+use alloc::boxed::Box;
+use core::{cell::Cell, ptr::NonNull};
+use x86_64::VirtAddr;
+
+// Hands out kernel virtual address ranges that don't have a fixed place, so
+// that nothing mapped through it can overlap. The fixed regions (the direct
+// map, the PageInfo array, the kernel image and stack) are all outside the
+// arena. Free space is kept as a list of spans, sorted by address, and merged
+// with its neighbours on free. The spans are allocated on the heap, so nothing
+// can be allocated before the heap is up.
+
+struct Span {
+    start: Cell<u64>,
+    end: Cell<u64>,
+    links: Links<Span>,
+}
+
+unsafe impl Linked for Span {
+    fn links(&self) -> &Links<Self> {
+        &self.links
+    }
+}
+
+fn new_span(start: u64, end: u64) -> NonNull<Span> {
+    NonNull::from(Box::leak(Box::new(Span {
+        start: Cell::new(start),
+        end: Cell::new(end),
+        links: Links::new(),
+    })))
+}
+
+pub struct VmemArena {
+    start: u64,
+    end: u64,
+    // Free spans, sorted by address, and never touching each other
+    free: IntrusiveList<Span>,
+    // The whole arena only goes on the free list the first time it's used,
+    // so new() can be const
+    seeded: bool,
+}
+
+// The spans are only reachable through the arena
+unsafe impl Send for VmemArena {}
+
+#[allow(dead_code)]
+impl VmemArena {
+    pub const fn new(start: u64, end: u64) -> Self {
+        Self {
+            start,
+            end,
+            free: IntrusiveList::new(),
+            seeded: false,
+        }
+    }
+
+    fn seed(&mut self) {
+        if !self.seeded {
+            self.seeded = true;
+            unsafe { self.free.push_back(new_span(self.start, self.end)) };
+        }
+    }
+
+    // First fit. `align` has to be a power of two.
+    pub fn alloc(&mut self, size: u64, align: u64) -> Option<VirtAddr> {
+        BUG_ON!(size == 0 || !align.is_power_of_two(), "vmem: bad allocation of {:#x} aligned to {:#x}", size, align);
+        self.seed();
+
+        let (span, start) = self.free.iter().find_map(|span| {
+            let start = span.start.get().checked_add(align - 1)? & !(align - 1);
+            let end = start.checked_add(size)?;
+            if end <= span.end.get() {
+                Some((NonNull::from(span), start))
+            } else {
+                None
+            }
+        })?;
+
+        // Whatever's left either side of the allocation stays free
+        let span_ref = unsafe { span.as_ref() };
+        let (before, after) = (span_ref.start.get() < start, start + size < span_ref.end.get());
+        match (before, after) {
+            (true, true) => {
+                let rest = new_span(start + size, span_ref.end.get());
+                span_ref.end.set(start);
+                match span_ref.links.next() {
+                    Some(next) => unsafe { self.free.insert_before(next, rest) },
+                    None => unsafe { self.free.push_back(rest) },
+                }
+            }
+            (true, false) => span_ref.end.set(start),
+            (false, true) => span_ref.start.set(start + size),
+            (false, false) => unsafe {
+                self.free.remove(span);
+                drop(Box::from_raw(span.as_ptr()));
+            },
+        }
+
+        Some(VirtAddr::new(start))
+    }
+
+    pub fn free(&mut self, addr: VirtAddr, size: u64) {
+        let (start, end) = (addr.as_u64(), addr.as_u64() + size);
+        BUG_ON!(start < self.start || end > self.end, "vmem: free of {:#x}..{:#x} outside the arena", start, end);
+
+        // The first span after the range, and the one before it
+        let next = self.free.iter().find(|span| span.start.get() >= start).map(NonNull::from);
+        let prev = match next {
+            Some(next) => unsafe { next.as_ref() }.links.prev(),
+            None => self.free.back(),
+        };
+
+        let prev = prev.map(|span| unsafe { span.as_ref() });
+        let next_ref = next.map(|span| unsafe { span.as_ref() });
+        BUG_ON!(
+            prev.map_or(false, |span| span.end.get() > start) || next_ref.map_or(false, |span| span.start.get() < end),
+            "vmem: double free of {:#x}..{:#x}",
+            start,
+            end
+        );
+
+        match (prev.filter(|span| span.end.get() == start), next_ref.filter(|span| span.start.get() == end)) {
+            (Some(prev), Some(next_span)) => {
+                prev.end.set(next_span.end.get());
+                let next = next.unwrap();
+                unsafe {
+                    self.free.remove(next);
+                    drop(Box::from_raw(next.as_ptr()));
+                }
+            }
+            (Some(prev), None) => prev.end.set(end),
+            (None, Some(next_span)) => next_span.start.set(start),
+            (None, None) => match next {
+                Some(next) => unsafe { self.free.insert_before(next, new_span(start, end)) },
+                None => unsafe { self.free.push_back(new_span(start, end)) },
+            },
+        }
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        if !self.seeded {
+            return self.end - self.start;
+        }
+
+        self.free.iter().map(|span| span.end.get() - span.start.get()).sum()
+    }
+}
+
+impl Drop for VmemArena {
+    fn drop(&mut self) {
+        while let Some(span) = self.free.pop_front() {
+            drop(unsafe { Box::from_raw(span.as_ptr()) });
+        }
+    }
+}
+
+static KERNEL_VMEM: SpinLock<VmemArena> = SpinLock::new(VmemArena::new(super::VMEM_START, super::VMEM_START + super::VMEM_SIZE));
+
+pub fn alloc(size: u64, align: u64) -> Option<VirtAddr> {
+    KERNEL_VMEM.lock().alloc(size, align)
+}
+
+#[allow(dead_code)]
+pub fn free(addr: VirtAddr, size: u64) {
+    KERNEL_VMEM.lock().free(addr, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    fn spans(arena: &VmemArena) -> ArrayVec<[(u64, u64); 8]> {
+        arena.free.iter().map(|span| (span.start.get(), span.end.get())).collect()
+    }
+
+    test_case!(vmem_no_overlap, {
+        let mut arena = VmemArena::new(0x10_0000, 0x20_0000);
+        let a = arena.alloc(0x3000, 0x1000).unwrap();
+        let b = arena.alloc(0x1000, 0x1000).unwrap();
+        let c = arena.alloc(0x5000, 0x1000).unwrap();
+        assert_eq!((a.as_u64(), b.as_u64(), c.as_u64()), (0x10_0000, 0x10_3000, 0x10_4000));
+        assert_eq!(arena.free_bytes(), 0x10_0000 - 0x9000);
+
+        // Too big for what's left
+        assert_eq!(arena.alloc(0x10_0000, 0x1000), None);
+    });
+
+    test_case!(vmem_alignment, {
+        let mut arena = VmemArena::new(0x10_1000, 0x40_0000);
+        let a = arena.alloc(0x1000, 0x1000).unwrap();
+        let b = arena.alloc(0x1000, 0x20_0000).unwrap();
+        assert_eq!((a.as_u64(), b.as_u64()), (0x10_1000, 0x20_0000));
+
+        // The gap left by the alignment is still usable
+        assert_eq!(&spans(&arena)[..], &[(0x10_2000, 0x20_0000), (0x20_1000, 0x40_0000)]);
+        assert_eq!(arena.alloc(0x2000, 0x1000).unwrap().as_u64(), 0x10_2000);
+    });
+
+    test_case!(vmem_reuse_after_free, {
+        let mut arena = VmemArena::new(0x10_0000, 0x20_0000);
+        let a = arena.alloc(0x1000, 0x1000).unwrap();
+        let b = arena.alloc(0x1000, 0x1000).unwrap();
+        let c = arena.alloc(0x1000, 0x1000).unwrap();
+
+        arena.free(b, 0x1000);
+        assert_eq!(arena.alloc(0x1000, 0x1000), Some(b));
+
+        // Freeing everything merges back into one span, in any order
+        arena.free(a, 0x1000);
+        arena.free(c, 0x1000);
+        assert_eq!(&spans(&arena)[..], &[(0x10_0000, 0x10_1000), (0x10_2000, 0x20_0000)]);
+        arena.free(b, 0x1000);
+        assert_eq!(&spans(&arena)[..], &[(0x10_0000, 0x20_0000)]);
+        assert_eq!(arena.alloc(0x10_0000, 0x1000).unwrap().as_u64(), 0x10_0000);
+    });
+}
@@ -1,14 +1,40 @@
 #![allow(unused_imports)]
 #![allow(dead_code)]
-use core::panic::PanicInfo;
+use crate::{
+    cpu::{interrupts, pic8259, pit},
+    ds::SpinLock,
+    kernel::time,
+};
+use arrayvec::ArrayVec;
+use core::{
+    arch::x86_64::_rdtsc,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+// Benchmarks run this many times at most, so the samples fit on the stack
+pub const MAX_BENCH_ITERATIONS: usize = 1024;
+
+// A test that takes longer than this is assumed to be hung, and fails the run
+const TEST_TIMEOUT_MS: u64 = 10_000;
+const TIMER_HZ: u64 = 100;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
-enum ExitCode {
+pub enum ExitCode {
     Success = 0x10,
     Failure = 0x11,
+    Timeout = 0x12,
+}
+
+// What QEMU's process exits with, which is what the runner sees. The
+// isa-debug-exit device turns a write of `code` into (code << 1) | 1, and
+// bootimage only counts test-success-exit-code (33) as a pass.
+pub const fn qemu_status(exit_code: ExitCode) -> u32 {
+    (exit_code as u32) << 1 | 1
 }
 
-fn exit_qemu(exit_code: ExitCode) {
+pub fn exit_qemu(exit_code: ExitCode) {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0xF4);
@@ -18,27 +44,225 @@ fn exit_qemu(exit_code: ExitCode) {
     }
 }
 
+// For a test that can't return, like one that's wrecked the stack
+pub fn exit_success() -> ! {
+    exit_qemu(ExitCode::Success);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Set by a test that passes by panicking
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+// For an exit_test_case! whose body should panic. The panic then ends the run
+// as a pass rather than a failure.
+pub fn expect_panic() {
+    EXPECT_PANIC.store(true, Ordering::Relaxed);
+}
+
 #[panic_handler]
 #[cfg(test)]
 fn panic(info: &PanicInfo) -> ! {
+    if EXPECT_PANIC.load(Ordering::Relaxed) {
+        println!("[ok] panicked as expected: {}", info);
+        exit_success();
+    }
+
     println!("[failed] {}", info);
     exit_qemu(ExitCode::Failure);
     loop {}
 }
 
+// The tick by which the running test has to finish, or 0 between tests
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+static CURRENT_TEST: SpinLock<&str> = SpinLock::new("");
+
+pub fn timed_out(now: u64, deadline: u64) -> bool {
+    deadline != 0 && now >= deadline
+}
+
+// Called by test_case! around each test body. Nothing is armed until the
+// runner has the timer going.
+pub fn start_test(name: &'static str) {
+    let hz = time::frequency();
+    if hz == 0 {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        *CURRENT_TEST.lock() = name;
+        DEADLINE.store(time::ticks() + time::ms_to_ticks(TEST_TIMEOUT_MS, hz), Ordering::Relaxed);
+    });
+}
+
+pub fn finish_test() {
+    DEADLINE.store(0, Ordering::Relaxed);
+}
+
+// Called by exit_test_case! if the body comes back, which it shouldn't
+pub fn exit_test_returned(name: &str) -> ! {
+    EXPECT_PANIC.store(false, Ordering::Relaxed);
+    panic!("{} returned instead of ending the run", name);
+}
+
+// Called from the timer interrupt. The tick can't arrive while a test has
+// interrupts disabled, so a test that hangs like that still hangs the run.
+pub fn check_deadline() {
+    if !timed_out(time::ticks(), DEADLINE.load(Ordering::Relaxed)) {
+        return;
+    }
+
+    let name = CURRENT_TEST.try_lock().map_or("<unknown>", |name| *name);
+    println!("[TIMEOUT] {} took longer than {}ms", name, TEST_TIMEOUT_MS);
+    exit_qemu(ExitCode::Timeout);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+fn start_timer() {
+    pit::start_periodic(TIMER_HZ);
+    pic8259::unmask(pic8259::TIMER_IRQ);
+    x86_64::instructions::interrupts::enable();
+}
+
+// Anything collected by #[test_case]. Plain functions are tests, and
+// bench_case! statics are benchmarks.
+pub trait TestItem {
+    fn run(&self);
+
+    fn is_bench(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn()> TestItem for T {
+    fn run(&self) {
+        self()
+    }
+}
+
+pub struct Benchmark {
+    pub name: &'static str,
+    pub iterations: usize,
+    pub body: fn(),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchStats {
+    pub min: u64,
+    pub median: u64,
+}
+
+// Sorts the samples in place
+pub fn bench_stats(samples: &mut [u64]) -> BenchStats {
+    BUG_ON!(samples.is_empty(), "bench: no samples");
+    samples.sort_unstable();
+
+    BenchStats {
+        min: samples[0],
+        median: samples[samples.len() / 2],
+    }
+}
+
+impl TestItem for Benchmark {
+    // Results go to serial as one `bench:` line each, for scripts to pick up.
+    // Nothing is compared against previous runs here.
+    fn run(&self) {
+        BUG_ON!(
+            self.iterations == 0 || self.iterations > MAX_BENCH_ITERATIONS,
+            "bench: {} iterations for {}",
+            self.iterations,
+            self.name
+        );
+
+        let mut samples: ArrayVec<[u64; MAX_BENCH_ITERATIONS]> = ArrayVec::new();
+        for _ in 0..self.iterations {
+            let start = unsafe { _rdtsc() };
+            (self.body)();
+            samples.push(unsafe { _rdtsc() } - start);
+        }
+
+        let stats = bench_stats(&mut samples);
+        println!(
+            "bench: {} iterations={} min={} median={}",
+            self.name, self.iterations, stats.min, stats.median
+        );
+    }
+
+    fn is_bench(&self) -> bool {
+        true
+    }
+}
+
+// Benchmarks only run with the `bench` feature, and then nothing else does
 #[cfg(test)]
-pub fn test_runner(tests: &[&dyn Fn()]) {
-    info!("Running {} tests", tests.len());
+pub fn test_runner(tests: &[&dyn TestItem]) {
+    let benches = cfg!(feature = "bench");
+    let count = tests.iter().filter(|test| test.is_bench() == benches).count();
+    info!("Running {} {}", count, if benches { "benchmarks" } else { "tests" });
     println!("-----------------------");
 
-    for test in tests {
-        test();
+    // Benchmarks would only count the timer interrupts as noise
+    if !benches {
+        start_timer();
+    }
+
+    for test in tests.iter().filter(|test| test.is_bench() == benches) {
+        test.run();
     }
 
     exit_qemu(ExitCode::Success);
 }
 
+// Runs a test's teardown hook when dropped
+pub struct Teardown<F: FnMut()>(pub F);
+
+impl<F: FnMut()> Drop for Teardown<F> {
+    fn drop(&mut self) {
+        (self.0)();
+    }
+}
+
 // Example test
 test_case!(basic_test, {
     assert_eq!(1, 1);
 });
+
+test_case!(test_deadline, {
+    assert!(!timed_out(500, 0));
+    assert!(!timed_out(99, 100));
+    assert!(timed_out(100, 100));
+    assert!(timed_out(101, 100));
+});
+
+// Never finishes, so the run should end with a timeout rather than hang
+#[cfg(feature = "timeout-demo")]
+test_case!(spins_forever, {
+    loop {
+        core::hint::spin_loop();
+    }
+});
+
+test_case!(qemu_exit_statuses, {
+    // Has to match test-success-exit-code in Cargo.toml
+    assert_eq!(qemu_status(ExitCode::Success), 33);
+    assert_eq!(qemu_status(ExitCode::Failure), 35);
+    assert_eq!(qemu_status(ExitCode::Timeout), 37);
+});
+
+// Passes by exiting QEMU with the success status from inside the run, so
+// nothing after it runs
+#[cfg(feature = "exit-test")]
+exit_test_case!(exits_with_success, {
+    exit_qemu(ExitCode::Success);
+});
+
+test_case!(bench_stats_median, {
+    let mut samples = [30, 10, 50, 20, 40];
+    assert_eq!(bench_stats(&mut samples), BenchStats { min: 10, median: 30 });
+    assert_eq!(bench_stats(&mut [7]), BenchStats { min: 7, median: 7 });
+    // The upper of the two middle samples
+    assert_eq!(bench_stats(&mut [4, 1, 3, 2]), BenchStats { min: 1, median: 3 });
+});